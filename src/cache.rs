@@ -0,0 +1,651 @@
+//! The index of the cache, and checking it for corrupted or tampered entries.
+//!
+//! `<cache>/manifest.json` records every downloaded archive with its blake3 hash, every extracted
+//! source directory with the hash of the archive it was extracted from, and the objdirs, kernel
+//! images and rootfs images that were built. Each entry has its size, when it was created and last
+//! used and the toolchains that used it (see [`Usage`]), `toolup cache index` prints it.
+//!
+//! `toolup cache verify` re-hashes the archives and compares them with the manifest, `--repair`
+//! removes the broken entries and downloads or extracts them again.
+//!
+//! Updates of the manifest hold a lock on `<cache>/manifest.json.lock`, toolup processes share the
+//! cache, and the manifest is replaced with a renamed temporary file.
+//!
+//! `toolup clean-builds` (and `--clean-after-install`) removes the objdirs of installed toolchains,
+//! the archives and sources stay to build them again, see [`build_trees`].
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    commands::{is_plan, staging},
+    download::{
+        archive_filename, archives_dir, cache_dir, cross_prefix, download_and_decompress,
+        download_archive, extraction_stamp,
+    },
+    gc,
+    locks::{self, lock_file, write_atomic},
+    registry,
+};
+
+const MANIFEST: &str = "manifest.json";
+
+/// Serializes the read-modify-write of the manifest in `cache` between threads and toolup
+/// processes sharing the cache. The manifest is replaced atomically, reading it needs no lock.
+fn lock_manifest(cache: &Path) -> Result<fs::File> {
+    lock_file(&cache.join(format!("{MANIFEST}.lock")))
+}
+
+static CLEAN_AFTER_INSTALL: AtomicBool = AtomicBool::new(false);
+
+/// Remove the build trees of a toolchain once it's installed.
+pub fn set_clean_after_install(clean: bool) {
+    CLEAN_AFTER_INSTALL.store(clean, Ordering::Relaxed);
+}
+
+/// Whether the build trees of a toolchain are removed once it's installed.
+pub fn cleans_after_install() -> bool {
+    CLEAN_AFTER_INSTALL.load(Ordering::Relaxed)
+}
+
+/// The size and use of an entry of the manifest. Entries recorded before toolup tracked them
+/// have none.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// In bytes, of every file for directories
+    #[serde(default)]
+    pub size: u64,
+    /// RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<String>,
+    /// The ids of the toolchains the entry was created or used for, see
+    /// [`crate::profile::Toolchain::id`]
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub toolchains: BTreeSet<String>,
+}
+
+impl Usage {
+    /// Record a use now by `toolchain`.
+    fn touch(&mut self, toolchain: Option<String>) {
+        let now = gc::now();
+        self.created.get_or_insert_with(|| now.clone());
+        self.last_used = Some(now);
+        self.toolchains.extend(toolchain);
+    }
+}
+
+/// A downloaded archive, keyed by its name in [`archives_dir`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedArchive {
+    pub url: String,
+    pub blake3: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// An extracted source directory, keyed by its name in the cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtractedSource {
+    pub url: String,
+    /// The hash of the archive it was extracted from
+    pub blake3: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// What a build artifact is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArtifactKind {
+    /// The build directory of a package, see [`crate::packages::Package::objdir`]
+    Objdir,
+    /// A kernel image built by `toolup linux`
+    KernelImage,
+    /// A busybox rootfs packed by `toolup linux`
+    Rootfs,
+}
+
+/// Something built by toolup, keyed by its path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    /// Only for files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub archives: BTreeMap<String, CachedArchive>,
+    #[serde(default)]
+    pub sources: BTreeMap<String, ExtractedSource>,
+    #[serde(default)]
+    pub artifacts: BTreeMap<PathBuf, Artifact>,
+}
+
+/// Something wrong with a cached entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The archive doesn't match the hash it had when it was downloaded
+    CorruptedArchive { file: String, url: String },
+    /// The directory was extracted from an archive that has changed since
+    StaleSource { dirname: String, url: String },
+    /// The extraction of the directory was interrupted
+    PartialSource { dirname: String, url: String },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::CorruptedArchive { file, .. } => {
+                write!(f, "corrupted archive {file}: the blake3 hash has changed")
+            }
+            Problem::StaleSource { dirname, .. } => write!(
+                f,
+                "stale source {dirname}: extracted from a different archive"
+            ),
+            Problem::PartialSource { dirname, .. } => {
+                write!(
+                    f,
+                    "partial source {dirname}: the extraction was interrupted"
+                )
+            }
+        }
+    }
+}
+
+impl Manifest {
+    fn path(cache: &Path) -> PathBuf {
+        cache.join(MANIFEST)
+    }
+
+    pub fn load(cache: &Path) -> Result<Self> {
+        let path = Self::path(cache);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content =
+            fs::read_to_string(&path).context(format!("failed to read `{}`", path.display()))?;
+        serde_json::from_str(&content).context(format!("failed to parse `{}`", path.display()))
+    }
+
+    /// Replace the manifest in `cache`, see [`lock_manifest`].
+    pub fn save(&self, cache: &Path) -> Result<()> {
+        write_atomic(&Self::path(cache), serde_json::to_string_pretty(self)?)
+    }
+
+    /// Drop the entries whose archive, directory or artifact was removed.
+    pub fn prune(&mut self, cache: &Path, archives: &Path) {
+        self.archives.retain(|file, _| archives.join(file).exists());
+        self.sources
+            .retain(|dirname, _| cache.join(dirname).exists());
+        self.artifacts.retain(|path, _| path.exists());
+    }
+
+    /// Re-hash the archives in `archives` and check the sources in `cache` against them.
+    ///
+    /// Entries whose archive or directory was removed are dropped from the manifest.
+    pub fn check(&mut self, cache: &Path, archives: &Path) -> Result<Vec<Problem>> {
+        let mut problems = vec![];
+
+        self.prune(cache, archives);
+        for (file, archive) in &self.archives {
+            let path = archives.join(file);
+            let actual = blake3::hash(
+                &fs::read(&path).context(format!("failed to read `{}`", path.display()))?,
+            )
+            .to_hex();
+            if actual.as_str() != archive.blake3 {
+                problems.push(Problem::CorruptedArchive {
+                    file: file.clone(),
+                    url: archive.url.clone(),
+                });
+            }
+        }
+
+        for (dirname, source) in &self.sources {
+            let file = archive_filename(&source.url)?;
+            if extraction_stamp(cache, &file).exists() {
+                problems.push(Problem::PartialSource {
+                    dirname: dirname.clone(),
+                    url: source.url.clone(),
+                });
+                continue;
+            }
+            let stale = self
+                .archives
+                .get(&file)
+                .is_some_and(|archive| archive.blake3 != source.blake3);
+            if stale {
+                problems.push(Problem::StaleSource {
+                    dirname: dirname.clone(),
+                    url: source.url.clone(),
+                });
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+fn update(f: impl FnOnce(&mut Manifest) -> Result<()>) -> Result<()> {
+    let cache = cache_dir()?;
+    let _lock = lock_manifest(&cache)?;
+    let mut manifest = Manifest::load(&cache)?;
+    f(&mut manifest)?;
+    manifest.save(&cache)
+}
+
+/// Record the hash of the archive of `url`, already stored at `path`.
+pub fn record_archive(url: &str, path: &Path) -> Result<()> {
+    let blake3 =
+        blake3::hash(&fs::read(path).context(format!("failed to read `{}`", path.display()))?)
+            .to_hex()
+            .to_string();
+    record_archive_hash(url, blake3)
+}
+
+/// Record `blake3` as the hash of the archive of `url`.
+pub fn record_archive_hash(url: &str, blake3: String) -> Result<()> {
+    let file = archive_filename(url)?;
+    let size = disk_size(&archives_dir()?.join(&file));
+    update(|manifest| {
+        let mut usage = Usage {
+            size,
+            ..Usage::default()
+        };
+        usage.touch(staging());
+        manifest.archives.insert(
+            file,
+            CachedArchive {
+                url: url.to_string(),
+                blake3,
+                usage,
+            },
+        );
+        Ok(())
+    })
+}
+
+/// Record that the cached archive of `url` was used again.
+pub fn touch_archive(url: &str) -> Result<()> {
+    let file = archive_filename(url)?;
+    update(|manifest| {
+        if let Some(archive) = manifest.archives.get_mut(&file) {
+            archive.usage.touch(staging());
+        }
+        Ok(())
+    })
+}
+
+/// Record that `dirname` was extracted from the archive of `url`.
+pub fn record_source(url: &str, dirname: &str) -> Result<()> {
+    let file = archive_filename(url)?;
+    let size = disk_size(&cache_dir()?.join(dirname));
+    update(|manifest| {
+        // archives cached before hashes were recorded can't be checked
+        let Some(archive) = manifest.archives.get(&file) else {
+            return Ok(());
+        };
+        let mut usage = Usage {
+            size,
+            ..Usage::default()
+        };
+        usage.touch(staging());
+        let source = ExtractedSource {
+            url: url.to_string(),
+            blake3: archive.blake3.clone(),
+            usage,
+        };
+        manifest.sources.insert(dirname.to_string(), source);
+        Ok(())
+    })
+}
+
+/// Record that the extracted `dirname` was used again.
+pub fn touch_source(dirname: &str) -> Result<()> {
+    if is_plan() {
+        return Ok(());
+    }
+    update(|manifest| {
+        if let Some(source) = manifest.sources.get_mut(dirname) {
+            source.usage.touch(staging());
+        }
+        Ok(())
+    })
+}
+
+/// Record that `path` was built, or used again, for the toolchain `toolchain` (its id). The size
+/// and the hash of files are updated.
+pub fn record_artifact(path: &Path, kind: ArtifactKind, toolchain: Option<String>) -> Result<()> {
+    if is_plan() {
+        return Ok(());
+    }
+    let size = disk_size(path);
+    let blake3 = if path.is_file() {
+        Some(
+            blake3::hash(&fs::read(path).context(format!("failed to read `{}`", path.display()))?)
+                .to_hex()
+                .to_string(),
+        )
+    } else {
+        None
+    };
+    update(|manifest| {
+        let artifact = manifest
+            .artifacts
+            .entry(path.to_path_buf())
+            .or_insert_with(|| Artifact {
+                kind,
+                blake3: None,
+                usage: Usage::default(),
+            });
+        artifact.kind = kind;
+        artifact.blake3 = blake3;
+        artifact.usage.size = size;
+        artifact.usage.touch(toolchain);
+        Ok(())
+    })
+}
+
+/// The size of the file at `path`, or of every file under it, without following symlinks. `0` if
+/// it doesn't exist.
+pub fn disk_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// The manifest without the entries that were removed from the cache, `toolup cache index`.
+pub fn index() -> Result<Manifest> {
+    let cache = cache_dir()?;
+    let _lock = lock_manifest(&cache)?;
+    let mut manifest = Manifest::load(&cache)?;
+    manifest.prune(&cache, &archives_dir()?);
+    manifest.save(&cache)?;
+    Ok(manifest)
+}
+
+/// Check the cache against the manifest, with `repair` the broken entries are downloaded or
+/// extracted again. Returns the problems that were found.
+pub fn verify(repair: bool) -> Result<Vec<Problem>> {
+    let cache = cache_dir()?;
+    let archives = archives_dir()?;
+
+    let problems = {
+        let _lock = lock_manifest(&cache)?;
+        let mut manifest = Manifest::load(&cache)?;
+        let problems = manifest.check(&cache, &archives)?;
+        manifest.save(&cache)?;
+        problems
+    };
+    if !repair {
+        return Ok(problems);
+    }
+
+    for problem in &problems {
+        log::info!("=> repairing {problem}");
+        match problem {
+            Problem::CorruptedArchive { url, .. } => {
+                download_archive(url, false)?;
+            }
+            Problem::StaleSource { dirname, url } | Problem::PartialSource { dirname, url } => {
+                let dir = cache.join(dirname);
+                fs::remove_dir_all(&dir)
+                    .context(format!("failed to remove `{}`", dir.display()))?;
+                download_and_decompress(url, dirname, true)?;
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// The ids of the installed toolchains, in `~/.toolup/toolchains` and in the prefixes of the
+/// [`registry`].
+pub fn installed_toolchains() -> Result<BTreeSet<String>> {
+    let mut ids = BTreeSet::new();
+    let prefix = cross_prefix()?;
+    for entry in fs::read_dir(&prefix).context(format!("failed to read `{}`", prefix.display()))? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        // skip the `.<id>.partial` directories of failed or running installs
+        if path.is_dir() && !name.starts_with('.') {
+            ids.insert(name.to_string());
+        }
+    }
+    for (id, prefix) in registry::load()? {
+        if prefix.join(&id).is_dir() {
+            ids.insert(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// The build trees of the toolchains `ids`: the `objdir-*-<id>` directories of the sources in the
+/// cache, and the objdirs of the manifest that only they used. Objdirs shared with other toolchains
+/// are kept.
+pub fn build_trees(ids: &BTreeSet<String>) -> Result<Vec<PathBuf>> {
+    let cache = cache_dir()?;
+    let mut trees = BTreeSet::new();
+
+    for source in fs::read_dir(&cache).context(format!("failed to read `{}`", cache.display()))? {
+        let source = source?.path();
+        if !source.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&source)? {
+            let path = entry?.path();
+            if path.is_dir() && owns_objdir(ids, &path) {
+                trees.insert(path);
+            }
+        }
+    }
+
+    for (path, artifact) in Manifest::load(&cache)?.artifacts {
+        let toolchains = &artifact.usage.toolchains;
+        let owned = !toolchains.is_empty() && toolchains.is_subset(ids);
+        if artifact.kind == ArtifactKind::Objdir && owned && path.is_dir() {
+            trees.insert(path);
+        }
+    }
+
+    Ok(trees.into_iter().collect())
+}
+
+/// Whether `path` is named after one of the toolchains `ids`, e.g. `objdir-gdbserver-<id>`.
+fn owns_objdir(ids: &BTreeSet<String>, path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some(rest) = name.strip_prefix("objdir-") else {
+        return false;
+    };
+    ids.iter()
+        .any(|id| rest == id || rest.ends_with(&format!("-{id}")))
+}
+
+/// Remove the build trees `trees` (see [`build_trees`]) and return the number of bytes freed.
+pub fn remove_build_trees(trees: &[PathBuf]) -> Result<u64> {
+    let mut freed = 0;
+    for tree in trees {
+        // an install building in the tree finishes first
+        let _lock = locks::lock(format!("objdir:{}", tree.display()));
+        freed += disk_size(tree);
+        log::info!("=> removing {}", tree.display());
+        fs::remove_dir_all(tree).context(format!("failed to remove `{}`", tree.display()))?;
+    }
+    update(|manifest| {
+        manifest.artifacts.retain(|path, _| path.exists());
+        Ok(())
+    })?;
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeSet, path::Path};
+
+    use super::{
+        Artifact, ArtifactKind, CachedArchive, ExtractedSource, Manifest, Problem, Usage,
+        disk_size, lock_manifest,
+    };
+    use crate::download::archive_filename;
+
+    #[test]
+    fn test_check_finds_corrupted_and_stale_entries() -> anyhow::Result<()> {
+        let cache = tempfile::TempDir::new()?;
+        let archives = cache.path().join("archives");
+        std::fs::create_dir_all(&archives)?;
+
+        let url = "https://ftp.gnu.org/gnu/make/make-4.4.1.tar.gz";
+        let file = archive_filename(url)?;
+        std::fs::write(archives.join(&file), b"tampered")?;
+        std::fs::create_dir_all(cache.path().join("make-4.4.1"))?;
+
+        let original = blake3::hash(b"original").to_hex().to_string();
+        let mut manifest = Manifest::default();
+        manifest.archives.insert(
+            file.clone(),
+            CachedArchive {
+                url: url.to_string(),
+                blake3: original.clone(),
+                ..Default::default()
+            },
+        );
+        manifest.archives.insert(
+            "removed.tar.gz".to_string(),
+            CachedArchive {
+                url: "https://example.com/removed.tar.gz".to_string(),
+                blake3: original.clone(),
+                ..Default::default()
+            },
+        );
+        manifest.sources.insert(
+            "make-4.4.1".to_string(),
+            ExtractedSource {
+                url: url.to_string(),
+                blake3: blake3::hash(b"older").to_hex().to_string(),
+                ..Default::default()
+            },
+        );
+
+        let problems = manifest.check(cache.path(), &archives)?;
+        assert_eq!(
+            problems,
+            vec![
+                Problem::CorruptedArchive {
+                    file: file.clone(),
+                    url: url.to_string(),
+                },
+                Problem::StaleSource {
+                    dirname: "make-4.4.1".to_string(),
+                    url: url.to_string(),
+                },
+            ]
+        );
+        // the removed archive is forgotten
+        assert_eq!(manifest.archives.keys().collect::<Vec<_>>(), vec![&file]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_kept() -> anyhow::Result<()> {
+        let cache = tempfile::TempDir::new()?;
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let cache = cache.path();
+                scope.spawn(move || -> anyhow::Result<()> {
+                    let _lock = lock_manifest(cache)?;
+                    let mut manifest = Manifest::load(cache)?;
+                    manifest
+                        .archives
+                        .insert(format!("{i}.tar.gz"), CachedArchive::default());
+                    manifest.save(cache)
+                });
+            }
+        });
+        assert_eq!(Manifest::load(cache.path())?.archives.len(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_usage_touch() {
+        let mut usage = Usage::default();
+        usage.touch(Some("x86_64-elf-gcc-15.2.0".into()));
+        let created = usage.created.clone();
+        assert!(created.is_some());
+        assert_eq!(usage.last_used, created);
+        usage.touch(None);
+        usage.touch(Some("x86_64-elf-gcc-15.2.0".into()));
+        // the first use is kept
+        assert_eq!(usage.created, created);
+        assert_eq!(usage.toolchains.len(), 1);
+    }
+
+    #[test]
+    fn test_prune_and_size_artifacts() -> anyhow::Result<()> {
+        let cache = tempfile::TempDir::new()?;
+        let objdir = cache.path().join("make-4.4.1/objdir");
+        std::fs::create_dir_all(objdir.join("lib"))?;
+        std::fs::write(objdir.join("Makefile"), [0; 100])?;
+        std::fs::write(objdir.join("lib/libmake.a"), [0; 28])?;
+        assert_eq!(disk_size(&objdir), 128);
+
+        let mut manifest = Manifest::default();
+        for path in [objdir.clone(), cache.path().join("removed.cpio.gz")] {
+            manifest.artifacts.insert(
+                path,
+                Artifact {
+                    kind: ArtifactKind::Objdir,
+                    blake3: None,
+                    usage: Usage::default(),
+                },
+            );
+        }
+        manifest.prune(cache.path(), &cache.path().join("archives"));
+        assert_eq!(manifest.artifacts.keys().collect::<Vec<_>>(), vec![&objdir]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_owns_objdir() {
+        let ids = BTreeSet::from(["riscv64-elf-gcc-15.2.0-bin-2.45-newlib-4.5.0".to_string()]);
+        for (path, owned) in [
+            (
+                "gcc-15.2.0/objdir-riscv64-elf-gcc-15.2.0-bin-2.45-newlib-4.5.0",
+                true,
+            ),
+            (
+                "gdb-16.3/objdir-gdbserver-riscv64-elf-gcc-15.2.0-bin-2.45-newlib-4.5.0",
+                true,
+            ),
+            (
+                "gcc-15.2.0/objdir-riscv64-elf-gcc-14.3.0-bin-2.45-newlib-4.5.0",
+                false,
+            ),
+            ("busybox-1_36_1/objdir-riscv64-elf", false),
+            (
+                "gcc-15.2.0/riscv64-elf-gcc-15.2.0-bin-2.45-newlib-4.5.0",
+                false,
+            ),
+        ] {
+            assert_eq!(owns_objdir(&ids, Path::new(path)), owned, "{path}");
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! What toolup can do with each target, checked before any work starts.
+//!
+//! The parser accepts more targets than every command supports, e.g. `avr-unknown-linux-gnu` is a
+//! valid triple but GCC has no Linux port for avr and neither does the kernel. Commands call
+//! [`check`] with their [`Operation`] so an unsupported target fails with a list of what is
+//! supported instead of deep inside a build.
+use std::fmt::Display;
+
+use anyhow::Result;
+
+use crate::{
+    error::Failure,
+    packages::llvm::llvm_backend,
+    profile::{Abi, Arch, Os, Target},
+};
+
+/// The architectures with a Linux port in GCC, the kernel and QEMU.
+pub const LINUX_ARCHS: &[Arch] = &[
+    Arch::X86_64,
+    Arch::I486,
+    Arch::I586,
+    Arch::I686,
+    Arch::Aarch64,
+    Arch::Armv7,
+    Arch::Riscv64,
+    Arch::Ppc64Le,
+    Arch::Ppc64,
+];
+
+/// Something a command does with a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Build a toolchain, `toolup install`
+    Install,
+    /// Build a kernel and boot it in QEMU, `toolup linux`
+    Linux,
+    /// Build clang and lld next to GCC, `toolup install --compiler clang`
+    Clang,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Operation::Install => "toolup install",
+            Operation::Linux => "toolup linux",
+            Operation::Clang => "toolup install --compiler clang",
+        };
+        write!(f, "`{s}`")
+    }
+}
+
+impl Operation {
+    /// Whether `target` supports the operation.
+    pub fn supports(self, target: &Target) -> bool {
+        let linux = target.os == Os::Linux && LINUX_ARCHS.contains(&target.arch);
+        match self {
+            // MSVC targets need Microsoft's compiler and libraries
+            Operation::Install => target.abi != Abi::Msvc && (target.os != Os::Linux || linux),
+            Operation::Linux => linux && target.abi != Abi::Msvc,
+            Operation::Clang => {
+                Operation::Install.supports(target) && llvm_backend(target.arch).is_some()
+            }
+        }
+    }
+
+    /// The targets the operation supports, for error messages.
+    pub fn supported(self) -> String {
+        let archs = LINUX_ARCHS
+            .iter()
+            .map(|arch| arch.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        match self {
+            Operation::Install => format!(
+                "freestanding targets (`<arch>-elf`, `<arch>-unknown-none-eabi[hf]`), linux \
+                 targets on {archs}, `x86_64-w64-mingw32`, `<arch>-unknown-freebsd` on x86_64, \
+                 aarch64 and riscv64, `<arch>-linux-android` on aarch64 and x86_64 and \
+                 `wasm32-wasi`"
+            ),
+            Operation::Linux => format!("linux targets on {archs}"),
+            Operation::Clang => {
+                "the targets of `toolup install` except xtensa, LLVM's xtensa backend is \
+                 experimental"
+                    .into()
+            }
+        }
+    }
+}
+
+/// The error of an operation that `target` doesn't support.
+pub fn unsupported(target: &Target, operation: Operation) -> anyhow::Error {
+    anyhow::Error::new(Failure::Usage).context(format!(
+        "{target} doesn't support {operation}, it supports {}",
+        operation.supported()
+    ))
+}
+
+/// Check that `target` supports `operation`.
+pub fn check(target: &Target, operation: Operation) -> Result<()> {
+    if operation.supports(target) {
+        return Ok(());
+    }
+    Err(unsupported(target, operation))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::{Operation, check};
+    use crate::profile::Target;
+
+    #[test]
+    fn test_supports() {
+        for (target, install, linux) in [
+            ("x86_64-unknown-linux-gnu", true, true),
+            ("riscv64-unknown-linux-musl", true, true),
+            ("avr-unknown-linux-gnu", false, false),
+            ("x86_64-unknown-linux-msvc", false, false),
+            ("avr-elf", true, false),
+            ("aarch64-unknown-none-eabi", true, false),
+            ("x86_64-w64-mingw32", true, false),
+            ("wasm32-wasi", true, false),
+        ] {
+            let target = Target::from_str(target).unwrap();
+            assert_eq!(Operation::Install.supports(&target), install, "{target}");
+            assert_eq!(Operation::Linux.supports(&target), linux, "{target}");
+        }
+        let xtensa = Target::from_str("xtensa-esp32-elf").unwrap();
+        assert!(!Operation::Clang.supports(&xtensa));
+        assert!(Operation::Clang.supports(&Target::from_str("riscv64-elf").unwrap()));
+        assert!(Operation::Clang.supports(&Target::from_str("wasm32-wasi").unwrap()));
+    }
+
+    #[test]
+    fn test_check() {
+        let target = Target::from_str("avr-unknown-linux-gnu").unwrap();
+        let err = check(&target, Operation::Linux).unwrap_err();
+        assert!(
+            format!("{err:#}")
+                .starts_with("avr-unknown-linux-gnu doesn't support `toolup linux`, it supports")
+        );
+    }
+}
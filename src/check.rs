@@ -0,0 +1,340 @@
+//! Run the upstream test suites of an installed toolchain.
+//!
+//! The suites run in the toolchain's build trees. Execution tests run on the build machine
+//! through qemu user-mode emulation, using a DejaGnu board (gcc, binutils) or glibc's
+//! `test-wrapper`. The `.sum` files are summarized and compared with a baseline, by default the
+//! previous run of the same suite.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    commands::run_command_in,
+    download::cache_dir,
+    packages::{
+        Package,
+        binutils::BinutilsPackage,
+        gcc::{GccPackage, GccStage},
+        glibc::GlibcPackage,
+    },
+    profile::{Libc, Toolchain},
+};
+
+/// The DejaGnu result kinds counted in a summary.
+const RESULT_KINDS: &[&str] = &[
+    "PASS",
+    "FAIL",
+    "XPASS",
+    "XFAIL",
+    "UNRESOLVED",
+    "UNSUPPORTED",
+    "UNTESTED",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suite {
+    Gcc,
+    Binutils,
+    Glibc,
+}
+
+impl FromStr for Suite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gcc" => Ok(Suite::Gcc),
+            "binutils" => Ok(Suite::Binutils),
+            "glibc" => Ok(Suite::Glibc),
+            _ => Err(anyhow!(
+                "unknown test suite `{s}`, expected one of: gcc, binutils, glibc"
+            )),
+        }
+    }
+}
+
+impl Display for Suite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Suite::Gcc => "gcc",
+            Suite::Binutils => "binutils",
+            Suite::Glibc => "glibc",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// The summarized results of a test suite run.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CheckResults {
+    /// The number of tests for each result kind, e.g. `PASS`
+    pub counts: BTreeMap<String, u64>,
+    /// The names of the failed tests
+    pub failures: BTreeSet<String>,
+}
+
+/// The results of a run compared to a baseline.
+pub struct CheckReport {
+    pub results: CheckResults,
+    pub baseline: Option<CheckResults>,
+}
+
+impl CheckReport {
+    /// Failures that aren't in the baseline.
+    pub fn regressions(&self) -> Vec<&String> {
+        match &self.baseline {
+            Some(baseline) => self
+                .results
+                .failures
+                .difference(&baseline.failures)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Baseline failures that pass now.
+    pub fn fixed(&self) -> Vec<&String> {
+        match &self.baseline {
+            Some(baseline) => baseline
+                .failures
+                .difference(&self.results.failures)
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for kind in RESULT_KINDS {
+            let count = self.results.counts.get(*kind).copied().unwrap_or(0);
+            let base = self
+                .baseline
+                .as_ref()
+                .map(|b| b.counts.get(*kind).copied().unwrap_or(0));
+            match base {
+                Some(base) if base != count => {
+                    writeln!(f, "{kind:>12}: {count} (baseline {base})")?
+                }
+                _ => writeln!(f, "{kind:>12}: {count}")?,
+            }
+        }
+        for test in self.regressions() {
+            writeln!(f, "{} {test}", "new FAIL:".red())?;
+        }
+        for test in self.fixed() {
+            writeln!(f, "{} {test}", "fixed:".green())?;
+        }
+        if self.baseline.is_none() {
+            write!(f, "no baseline to compare with")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the file the results of the last `suite` run for `toolchain` are saved to.
+pub fn results_path(toolchain: &Toolchain, suite: Suite) -> Result<PathBuf> {
+    let dir = cache_dir()?.join("check").join(toolchain.id());
+    std::fs::create_dir_all(&dir).context("creating toolup check dir")?;
+    Ok(dir.join(format!("{suite}.json")))
+}
+
+/// Run `suite` for an installed `toolchain` and compare it with `baseline`, a results file from
+/// a previous run. The previous run of the suite is used if `baseline` is `None`.
+pub fn check(
+    toolchain: &Toolchain,
+    suite: Suite,
+    jobs: u64,
+    baseline: Option<PathBuf>,
+) -> Result<CheckReport> {
+    let results_file = results_path(toolchain, suite)?;
+    let baseline_file = baseline.unwrap_or_else(|| results_file.clone());
+    let baseline = if baseline_file.exists() {
+        Some(read_results(&baseline_file)?)
+    } else {
+        None
+    };
+
+    let objdir = build_tree(toolchain, suite)?;
+    log::info!("=> {suite} test suite in {}", objdir.display());
+
+    // `make check` exits with an error if any test fails, the summary is what matters
+    if let Err(err) = run_suite(toolchain, suite, &objdir, jobs) {
+        log::warn!("{err:#}");
+    }
+
+    let results = summarize(&objdir)?;
+    if results.counts.is_empty() {
+        bail!("no test results found in `{}`", objdir.display());
+    }
+    std::fs::write(&results_file, serde_json::to_string_pretty(&results)?)
+        .context(format!("failed to write `{}`", results_file.display()))?;
+    log::info!("results saved to {}", results_file.display());
+
+    Ok(CheckReport { results, baseline })
+}
+
+fn read_results(path: &Path) -> Result<CheckResults> {
+    let content =
+        std::fs::read_to_string(path).context(format!("failed to read `{}`", path.display()))?;
+    serde_json::from_str(&content).context(format!("invalid results in `{}`", path.display()))
+}
+
+/// Returns the build tree the toolchain was installed from.
+fn build_tree(toolchain: &Toolchain, suite: Suite) -> Result<PathBuf> {
+    let package: Box<dyn Package> = match (suite, &toolchain.libc) {
+        (Suite::Gcc, _) => {
+            let stage = if toolchain.has_newlib() {
+                GccStage::Newlib
+            } else if toolchain.is_freestanding() {
+                GccStage::Stage1
+            } else {
+                GccStage::Final(None)
+            };
+            Box::new(GccPackage { toolchain, stage })
+        }
+        (Suite::Binutils, _) => Box::new(BinutilsPackage { toolchain }),
+        (Suite::Glibc, Libc::Glibc(version)) => Box::new(GlibcPackage {
+            toolchain,
+            version: *version,
+        }),
+        (
+            Suite::Glibc,
+            Libc::Musl(_)
+            | Libc::Mingw(_)
+            | Libc::FreeBsd(_)
+            | Libc::Bionic(_)
+            | Libc::Newlib(_)
+            | Libc::WasiLibc(_),
+        ) => {
+            bail!("{} doesn't use glibc", toolchain.id())
+        }
+    };
+    let source = package.sources().remove(0);
+    let objdir = package.objdir(&cache_dir()?.join(source.dirname))?;
+
+    if !objdir.exists() {
+        bail!(
+            "the build tree `{}` doesn't exist, install the toolchain first",
+            objdir.display()
+        );
+    }
+    Ok(objdir)
+}
+
+fn run_suite(toolchain: &Toolchain, suite: Suite, objdir: &Path, jobs: u64) -> Result<()> {
+    let qemu = toolchain
+        .target
+        .qemu_user_command()
+        .context(format!("qemu can't run {} binaries", toolchain.target))?;
+    let sysroot = toolchain.sysroot()?;
+    let jobs = jobs.to_string();
+
+    let mut env: Vec<(OsString, OsString)> = vec![("PATH".into(), toolchain.env_path()?)];
+    let mut args: Vec<String> = vec!["-k".into(), "-j".into(), jobs];
+
+    match suite {
+        Suite::Gcc | Suite::Binutils => {
+            let site = write_dejagnu_board(&qemu, &sysroot)?;
+            env.push(("DEJAGNU".into(), site.into()));
+            args.push(match suite {
+                Suite::Gcc => "check-gcc".into(),
+                _ => "check".into(),
+            });
+            args.push("RUNTESTFLAGS=--target_board=toolup-qemu".into());
+        }
+        Suite::Glibc => {
+            args.push("check".into());
+            args.push(format!("test-wrapper={qemu} -L {}", sysroot.display()));
+        }
+    }
+
+    run_command_in(objdir, "make check", "make", &args, Some(env))
+}
+
+/// Write a DejaGnu board that runs test programs with qemu, returns the `site.exp` to use.
+fn write_dejagnu_board(qemu: &str, sysroot: &Path) -> Result<PathBuf> {
+    let dir = cache_dir()?.join("dejagnu");
+    let boards = dir.join("boards");
+    std::fs::create_dir_all(&boards).context("creating dejagnu boards dir")?;
+
+    let board = format!(
+        r#"load_generic_config "sim"
+process_multilib_options ""
+set_board_info sim "{qemu} -L {sysroot}"
+set_board_info is_simulator 1
+set_board_info compiler "[find_gcc]"
+set_board_info gdb,nosignals 1
+"#,
+        sysroot = sysroot.display()
+    );
+    std::fs::write(boards.join("toolup-qemu.exp"), board)?;
+
+    let site = dir.join("site.exp");
+    std::fs::write(
+        &site,
+        format!("lappend boards_dir \"{}\"\n", boards.display()),
+    )?;
+    Ok(site)
+}
+
+/// Count the results of every `.sum` file under `dir`.
+pub fn summarize(dir: &Path) -> Result<CheckResults> {
+    let mut results = CheckResults::default();
+    for entry in WalkDir::new(dir) {
+        let entry = entry.context(format!("failed to walk `{}`", dir.display()))?;
+        if entry.path().extension().is_some_and(|ext| ext == "sum") {
+            parse_sum(&std::fs::read_to_string(entry.path())?, &mut results);
+        }
+    }
+    Ok(results)
+}
+
+/// Add the results of a DejaGnu (or glibc `tests.sum`) summary to `results`.
+pub fn parse_sum(content: &str, results: &mut CheckResults) {
+    for line in content.lines() {
+        let Some((kind, test)) = line.split_once(": ") else {
+            continue;
+        };
+        if !RESULT_KINDS.contains(&kind) {
+            continue;
+        }
+        *results.counts.entry(kind.to_string()).or_default() += 1;
+        if kind == "FAIL" {
+            results.failures.insert(test.trim().to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CheckResults, parse_sum};
+
+    #[test]
+    fn test_parse_sum() {
+        let mut results = CheckResults::default();
+        parse_sum(
+            "Running target toolup-qemu\n\
+             PASS: gcc.dg/pr1.c (test for excess errors)\n\
+             FAIL: gcc.dg/pr2.c execution test\n\
+             UNSUPPORTED: gcc.dg/pr3.c\n\
+             \t\t=== gcc Summary ===\n\
+             # of expected passes\t\t1\n",
+            &mut results,
+        );
+
+        assert_eq!(results.counts["PASS"], 1);
+        assert_eq!(results.counts["FAIL"], 1);
+        assert_eq!(results.counts["UNSUPPORTED"], 1);
+        assert!(results.failures.contains("gcc.dg/pr2.c execution test"));
+    }
+}
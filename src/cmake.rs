@@ -0,0 +1,171 @@
+//! CMake toolchain files for installed toolchains, `toolup cmake-toolchain`.
+//!
+//! The file sets the compilers and binutils of the toolchain, the sysroot, and restricts
+//! `find_library`, `find_path` and `find_package` to it so CMake projects don't pick up the host's
+//! libraries. Programs are still looked up on the host, they run during the build.
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    error::Failure,
+    profile::{Arch, Os, Toolchain},
+};
+
+/// `CMAKE_SYSTEM_NAME` of `toolchain`'s target.
+pub fn system_name(toolchain: &Toolchain) -> &'static str {
+    if toolchain.is_freestanding() {
+        return "Generic";
+    }
+    // wasi-sdk's name, CMake uses generic settings without wasi-sdk's `Platform/WASI.cmake`
+    if toolchain.target.is_wasi() {
+        return "WASI";
+    }
+    match toolchain.target.os {
+        // `Android` makes CMake look for the NDK, the toolchain is a plain GCC
+        Os::Linux => "Linux",
+        Os::Windows => "Windows",
+        Os::FreeBsd(_) => "FreeBSD",
+        Os::None => "Generic",
+    }
+}
+
+/// `CMAKE_SYSTEM_PROCESSOR` of `arch`, the names `uname -m` reports.
+pub fn system_processor(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X86_64 => "x86_64",
+        Arch::I486 => "i486",
+        Arch::I586 => "i586",
+        Arch::I686 => "i686",
+        Arch::Aarch64 => "aarch64",
+        Arch::Armv7 => "armv7l",
+        Arch::Riscv64 => "riscv64",
+        Arch::Ppc64Le => "ppc64le",
+        Arch::Ppc64 => "ppc64",
+        Arch::Avr => "avr",
+        Arch::Bpf => "bpf",
+        Arch::Xtensa => "xtensa",
+        Arch::Wasm32 => "wasm32",
+    }
+}
+
+/// The sysroot of `toolchain`, `None` for freestanding targets without newlib.
+fn cmake_sysroot(toolchain: &Toolchain) -> Result<Option<PathBuf>> {
+    if !toolchain.is_freestanding() {
+        return Ok(Some(toolchain.sysroot()?));
+    }
+    if toolchain.has_newlib() {
+        return Ok(Some(
+            toolchain.dir()?.join(toolchain.target.to_target_string()),
+        ));
+    }
+    Ok(None)
+}
+
+/// The CMake toolchain file of an installed `toolchain`.
+pub fn toolchain_file(toolchain: &Toolchain) -> Result<String> {
+    if !toolchain.gcc_bin()?.exists() {
+        return Err(Failure::Usage).context(format!(
+            "{} is not installed, install it with `toolup install {}`",
+            toolchain.id(),
+            toolchain.name()
+        ));
+    }
+    let target = toolchain.target.to_target_string();
+    let bin_dir = toolchain.bin_dir()?;
+    // WASI toolchains have clang and the LLVM tools instead of GCC and binutils
+    let wasi = toolchain.target.is_wasi();
+    let tool = |name: &str| match name {
+        "gcc" if wasi => bin_dir.join(format!("{target}-clang")),
+        "g++" if wasi => bin_dir.join(format!("{target}-clang++")),
+        _ if wasi => bin_dir.join(format!("llvm-{name}")),
+        _ => bin_dir.join(format!("{target}-{name}")),
+    };
+
+    let mut file = format!(
+        "# generated by `toolup cmake-toolchain` for {}\n",
+        toolchain.id()
+    );
+    file.push_str(&format!(
+        "set(CMAKE_SYSTEM_NAME {})\nset(CMAKE_SYSTEM_PROCESSOR {})\n\n",
+        system_name(toolchain),
+        system_processor(toolchain.target.arch)
+    ));
+    file.push_str(&format!(
+        "set(CMAKE_C_COMPILER \"{}\")\n",
+        toolchain.gcc_bin()?.display()
+    ));
+    // freestanding toolchains without newlib have no C++ compiler
+    if tool("g++").exists() {
+        file.push_str(&format!(
+            "set(CMAKE_CXX_COMPILER \"{}\")\n",
+            tool("g++").display()
+        ));
+    }
+    for (variable, name) in [
+        ("CMAKE_ASM_COMPILER", "gcc"),
+        ("CMAKE_AR", "ar"),
+        ("CMAKE_RANLIB", "ranlib"),
+        ("CMAKE_STRIP", "strip"),
+        ("CMAKE_OBJCOPY", "objcopy"),
+        ("CMAKE_OBJDUMP", "objdump"),
+    ] {
+        file.push_str(&format!("set({variable} \"{}\")\n", tool(name).display()));
+    }
+    if toolchain.target.os == Os::Windows {
+        file.push_str(&format!(
+            "set(CMAKE_RC_COMPILER \"{}\")\n",
+            tool("windres").display()
+        ));
+    }
+    if toolchain.is_freestanding() {
+        // there's nothing to run a test program with, and no crt files without newlib
+        file.push_str("set(CMAKE_TRY_COMPILE_TARGET_TYPE STATIC_LIBRARY)\n");
+    }
+    file.push('\n');
+
+    let mut find_root = vec![toolchain.dir()?.join(&target)];
+    if let Some(sysroot) = cmake_sysroot(toolchain)? {
+        file.push_str(&format!("set(CMAKE_SYSROOT \"{}\")\n", sysroot.display()));
+        if !find_root.contains(&sysroot) {
+            find_root.insert(0, sysroot);
+        }
+    }
+    let find_root = find_root
+        .iter()
+        .map(|dir| format!("\"{}\"", dir.display()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    file.push_str(&format!("set(CMAKE_FIND_ROOT_PATH {find_root})\n"));
+    file.push_str(
+        "set(CMAKE_FIND_ROOT_PATH_MODE_PROGRAM NEVER)\n\
+         set(CMAKE_FIND_ROOT_PATH_MODE_LIBRARY ONLY)\n\
+         set(CMAKE_FIND_ROOT_PATH_MODE_INCLUDE ONLY)\n\
+         set(CMAKE_FIND_ROOT_PATH_MODE_PACKAGE ONLY)\n",
+    );
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::system_name;
+    use crate::profile::{Target, Toolchain};
+
+    #[test]
+    fn test_system_name() -> anyhow::Result<()> {
+        for (target, name) in [
+            ("aarch64-unknown-linux-gnu", "Linux"),
+            ("aarch64-linux-android", "Linux"),
+            ("x86_64-w64-mingw32", "Windows"),
+            ("riscv64-elf", "Generic"),
+            ("aarch64-unknown-none-eabi", "Generic"),
+            ("wasm32-wasi", "WASI"),
+        ] {
+            let toolchain = Toolchain::target_default(&Target::from_str(target)?);
+            assert_eq!(system_name(&toolchain), name, "{target}");
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,165 @@
 use std::{
-    ffi::OsStr,
+    cell::RefCell,
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
     fs::File,
     io::{BufRead, BufReader, Write},
-    path::Path,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
 };
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result};
 use chrono::{Local, SecondsFormat};
-use indicatif::{ProgressBar, ProgressStyle};
+use colored::Colorize;
 
-use crate::download::logs_dir;
+use crate::{
+    download::logs_dir,
+    error::Failure,
+    journal::{self, JournalEntry},
+    packages::host_tools::layered_path,
+    sandbox::{self, is_sandboxed},
+    ui,
+};
+
+static PLAN: AtomicBool = AtomicBool::new(false);
+static PLAN_QUIET: AtomicBool = AtomicBool::new(false);
+static INHERIT_ENV: AtomicBool = AtomicBool::new(false);
+static ENV_FILE: AtomicBool = AtomicBool::new(false);
+
+// the state of the toolchain being installed, per thread so toolchains can be installed in
+// parallel, see `install_toolchains`
+thread_local! {
+    static MAKE_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    static STAGING: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Host environment variables passed to build commands when the environment is scrubbed.
+///
+/// Anything else (e.g. `CFLAGS`, `LD_LIBRARY_PATH`, `CPATH`, `MAKEFLAGS`) could leak into
+/// configure/make and make builds depend on the shell they were started from.
+pub const HOST_ENV_ALLOWLIST: &[&str] =
+    &["PATH", "HOME", "USER", "LOGNAME", "SHELL", "TMPDIR", "TERM"];
+
+/// Pass the full host environment to build commands instead of a scrubbed one.
+pub fn set_inherit_env(inherit: bool) {
+    INHERIT_ENV.store(inherit, Ordering::Relaxed);
+}
+
+/// Whether build commands inherit the full host environment.
+pub fn inherits_env() -> bool {
+    INHERIT_ENV.load(Ordering::Relaxed)
+}
+
+/// Write the environment and command line of every build command to `env.sh` in its working
+/// directory, see [`write_env_file`].
+pub fn set_env_file(enabled: bool) {
+    ENV_FILE.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether build commands write an `env.sh`.
+pub fn writes_env_file() -> bool {
+    ENV_FILE.load(Ordering::Relaxed)
+}
+
+/// Prepend `dir` to `PATH` of build commands, used to pin a GNU Make version for builds that
+/// break with the host's. See [`crate::packages::gnu_make::pin_make`].
+pub fn set_make_dir(dir: Option<PathBuf>) {
+    MAKE_DIR.set(dir);
+}
+
+/// Set the id of the toolchain being installed, its [`crate::profile::Toolchain::dir`] points to
+/// the staging directory until this is reset.
+pub fn set_staging(id: Option<String>) {
+    STAGING.set(id);
+}
+
+/// The id of the toolchain being installed into its staging directory.
+pub fn staging() -> Option<String> {
+    STAGING.with_borrow(Clone::clone)
+}
+
+/// Enable plan mode: commands, downloads and filesystem changes are printed instead of executed.
+pub fn set_plan(plan: bool) {
+    PLAN.store(plan, Ordering::Relaxed);
+}
+
+/// Whether toolup is only printing what it would do.
+pub fn is_plan() -> bool {
+    PLAN.load(Ordering::Relaxed)
+}
+
+/// Don't print the steps of plan mode, used to only collect the downloads of a plan.
+pub fn set_plan_quiet(quiet: bool) {
+    PLAN_QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether plan steps are hidden.
+pub fn is_plan_quiet() -> bool {
+    PLAN_QUIET.load(Ordering::Relaxed)
+}
+
+/// Print a step that would run in plan mode.
+pub fn plan_step(step: impl AsRef<str>) {
+    if is_plan_quiet() {
+        return;
+    }
+    println!("{}", format!("# {}", step.as_ref()).dimmed());
+}
+
+/// Like [`std::fs::create_dir_all`] but skipped in plan mode, so planning never leaves
+/// directories behind that later runs would mistake for extracted sources.
+pub fn create_dir_all(path: impl AsRef<Path>) -> Result<()> {
+    if is_plan() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(path.as_ref())
+        .context(format!("failed to create `{}`", path.as_ref().display()))
+}
+
+/// Print a command as a shell snippet that can be copied and run by hand.
+pub fn print_command(
+    workdir: &Path,
+    title: &str,
+    command: &OsStr,
+    args: &[impl AsRef<OsStr>],
+    env: &[(impl AsRef<OsStr>, impl AsRef<OsStr>)],
+) {
+    if is_plan_quiet() {
+        return;
+    }
+    plan_step(format!("{title} (in {})", workdir.display()));
+    let mut line = String::new();
+    for (key, value) in env {
+        line.push_str(&format!(
+            "{}={} \\\n  ",
+            key.as_ref().to_string_lossy(),
+            shell_quote(&value.as_ref().to_string_lossy())
+        ));
+    }
+    line.push_str(&shell_quote(&command.to_string_lossy()));
+    for arg in args {
+        line.push(' ');
+        line.push_str(&shell_quote(&arg.as_ref().to_string_lossy()));
+    }
+    println!("{line}");
+}
+
+/// Quote `s` for a POSIX shell if needed.
+pub fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
 
 pub fn log_filename(id: impl AsRef<str>) -> String {
     let ts = Local::now()
@@ -54,6 +201,10 @@ pub fn _run_configure_in<P: AsRef<Path>, S: AsRef<OsStr>>(
 
 /// Run a command in directory and show output in a spinner.
 ///
+/// The command starts from a scrubbed environment containing only [`HOST_ENV_ALLOWLIST`] and
+/// `env`, unless [`set_inherit_env`] was used. It runs in a sandbox after
+/// [`sandbox::set_sandbox`].
+///
 /// If the command doesn't finish successfuly the full output will saved to a file and the path
 /// will be printed.
 pub fn run_command_in(
@@ -63,21 +214,117 @@ pub fn run_command_in(
     args: &[impl AsRef<OsStr>],
     env: Option<Vec<(impl AsRef<OsStr>, impl AsRef<OsStr>)>>,
 ) -> Result<()> {
-    let pb = ProgressBar::new_spinner();
-    pb.set_style(ProgressStyle::with_template("{spinner:.dim} {msg:.dim}")?);
-    pb.enable_steady_tick(Duration::from_millis(80));
-    pb.set_message(title);
-
-    let mut _cmd = Command::new(command);
-    _cmd.args(args)
-        .current_dir(workdir.as_ref())
+    if is_plan() {
+        print_command(
+            workdir.as_ref(),
+            title,
+            command.as_ref(),
+            args,
+            env.as_deref().unwrap_or_default(),
+        );
+        return Ok(());
+    }
+
+    let _span = tracing::info_span!(
+        "run_command",
+        title,
+        command = %command.as_ref().to_string_lossy(),
+        workdir = %workdir.as_ref().display()
+    )
+    .entered();
+
+    let pb = ui::spinner(title);
+
+    // host tools built by toolup (see `packages::host_tools`) and a pinned make take precedence
+    // over the host's
+    let path = env
+        .iter()
+        .flatten()
+        .find(|(key, _)| key.as_ref() == "PATH")
+        .map(|(_, value)| value.as_ref().to_os_string())
+        .or_else(|| std::env::var_os("PATH"))
+        .unwrap_or_default();
+    let mut host_path = layered_path(&[], &path)?;
+    if let Some(make_dir) = MAKE_DIR.with_borrow(Clone::clone) {
+        let paths = std::iter::once(make_dir).chain(std::env::split_paths(&host_path));
+        host_path = std::env::join_paths(paths)?;
+    }
+
+    let mut entry = JournalEntry {
+        title: title.to_string(),
+        cwd: workdir.as_ref().to_path_buf(),
+        argv: std::iter::once(command.as_ref())
+            .chain(args.iter().map(|a| a.as_ref()))
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect(),
+        env: env
+            .iter()
+            .flatten()
+            .map(|(k, v)| {
+                (
+                    k.as_ref().to_string_lossy().into_owned(),
+                    v.as_ref().to_string_lossy().into_owned(),
+                )
+            })
+            .collect(),
+        status: None,
+        success: false,
+        started: Local::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        duration_secs: 0.0,
+        log: None,
+        package: journal::package(),
+    };
+    let started = Instant::now();
+
+    let mut _cmd = if is_sandboxed() {
+        let (bwrap, bwrap_args) = sandbox::wrap(workdir.as_ref(), command.as_ref(), args)?;
+        let mut cmd = Command::new(bwrap);
+        cmd.args(bwrap_args);
+        cmd
+    } else {
+        let mut cmd = Command::new(command);
+        cmd.args(args);
+        cmd
+    };
+    _cmd.current_dir(workdir.as_ref())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
 
+    if !inherits_env() {
+        _cmd.env_clear();
+        for key in HOST_ENV_ALLOWLIST {
+            if let Some(value) = std::env::var_os(key) {
+                _cmd.env(key, value);
+            }
+        }
+    }
     if let Some(_env) = env {
         _cmd.envs(_env);
     }
-    let mut child = _cmd.spawn().context(format!("spawning `{title}`"))?;
+    entry
+        .env
+        .insert("PATH".into(), host_path.to_string_lossy().into_owned());
+    _cmd.env("PATH", host_path);
+    let env_file = if writes_env_file() {
+        Some(write_env_file(
+            workdir.as_ref(),
+            title,
+            command.as_ref(),
+            args,
+            &_cmd,
+        )?)
+    } else {
+        None
+    };
+    let mut child = match _cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            journal::record(&entry)?;
+            return Err(err)
+                .context(Failure::Build)
+                .context(format!("spawning `{title}`"));
+        }
+    };
 
     let stdout = child.stdout.take().expect("stdout is not None");
     let stderr = child.stderr.take().expect("stderr is not None");
@@ -93,7 +340,7 @@ pub fn run_command_in(
         let log_out = log.clone();
         std::thread::spawn(move || {
             let reader = BufReader::new(stdout);
-            for line in reader.lines().flatten() {
+            for line in reader.lines().map_while(Result::ok) {
                 pb_out.set_message(line.chars().take(80).collect::<String>());
                 if let Ok(mut f) = log_out.lock() {
                     let _ = f.write_all(line.as_bytes());
@@ -109,7 +356,7 @@ pub fn run_command_in(
         let log_out = log.clone();
         std::thread::spawn(move || {
             let reader = BufReader::new(stderr);
-            for line in reader.lines().flatten() {
+            for line in reader.lines().map_while(Result::ok) {
                 pb_err.set_message(line.chars().take(80).collect::<String>());
                 if let Ok(mut f) = log_out.lock() {
                     let _ = f.write_all(line.as_bytes());
@@ -125,15 +372,77 @@ pub fn run_command_in(
     let _ = t_out.join();
     let _ = t_err.join();
 
+    entry.status = status.code();
+    entry.success = status.success();
+    entry.duration_secs = started.elapsed().as_secs_f64();
+    entry.log = Some(log_path.clone());
+    journal::record(&entry)?;
+
     if status.success() {
         pb.finish_with_message(format!("{title} finished successfully"));
         Ok(())
     } else {
         pb.finish();
-        bail!(
+        let mut message = format!(
             "{title} exited with status {}\nFull output is available at {}",
             status,
             log_path.display()
         );
+        if let Some(env_file) = env_file {
+            message.push_str(&format!(
+                "\nRun it again with `. {} && toolup_rerun`",
+                env_file.display()
+            ));
+        }
+        Err(Failure::Build).context(message)
+    }
+}
+
+/// Write `env.sh` in `workdir`: the environment `cmd` runs in and a `toolup_rerun` function running
+/// `command` with `args` again from `workdir`, to iterate on a failing step by hand. Returns its
+/// path.
+fn write_env_file(
+    workdir: &Path,
+    title: &str,
+    command: &OsStr,
+    args: &[impl AsRef<OsStr>],
+    cmd: &Command,
+) -> Result<PathBuf> {
+    let mut vars: BTreeMap<OsString, OsString> = if inherits_env() {
+        std::env::vars_os().collect()
+    } else {
+        BTreeMap::new()
+    };
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => vars.insert(key.to_os_string(), value.to_os_string()),
+            None => vars.remove(key),
+        };
     }
+
+    let mut script = format!("# the environment of `{title}` run by toolup\n");
+    for (key, value) in &vars {
+        let key = key.to_string_lossy();
+        // e.g. the exported bash functions of an inherited environment
+        if !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            continue;
+        }
+        script.push_str(&format!(
+            "export {key}={}\n",
+            shell_quote(&value.to_string_lossy())
+        ));
+    }
+    let mut line = shell_quote(&command.to_string_lossy());
+    for arg in args {
+        line.push(' ');
+        line.push_str(&shell_quote(&arg.as_ref().to_string_lossy()));
+    }
+    script.push_str(&format!(
+        "\ntoolup_rerun() {{\n    (cd {} && {line})\n}}\n",
+        shell_quote(&workdir.to_string_lossy())
+    ));
+
+    let path = workdir.join("env.sh");
+    std::fs::write(&path, script).context(format!("failed to write `{}`", path.display()))?;
+    Ok(path)
 }
@@ -0,0 +1,428 @@
+//! `toolup kernel-headers-compat`: check that a program can run on the kernel and libc of a
+//! `toolup linux` VM before booting it.
+//!
+//! A program fails in the guest when it needs more than the VM provides:
+//! - glibc refuses to start with `FATAL: kernel too old` when the kernel is older than the
+//!   `NT_GNU_ABI_TAG` note of the program (static) or of the libc it loads (dynamic)
+//! - the dynamic loader refuses symbol versions (`GLIBC_2.34`) newer than the libc
+//! - syscalls added after the kernel fail with `ENOSYS`
+//!
+//! Syscalls are found with a small static analysis of `objdump -d`: the number loaded into the
+//! syscall register before each `syscall`/`svc`/`ecall`. Calls through `syscall(2)` with a number
+//! computed at runtime aren't seen, and only x86_64, aarch64 and riscv64 are analyzed.
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, bail};
+use colored::Colorize;
+
+use crate::{
+    packages::{glibc::GlibcVersion, linux::KernelVersion},
+    profile::{Arch, Libc, Toolchain},
+};
+
+/// Syscalls added after 3.0: name, the kernel that added it, its number on x86_64 and on the
+/// architectures using the generic syscall table (aarch64, riscv64). From 5.1 the numbers are
+/// the same everywhere.
+const SYSCALLS: &[(&str, KernelVersion, u64, u64)] = &[
+    ("renameat2", KernelVersion::new(3, 15, 0), 316, 276),
+    ("seccomp", KernelVersion::new(3, 17, 0), 317, 277),
+    ("getrandom", KernelVersion::new(3, 17, 0), 318, 278),
+    ("memfd_create", KernelVersion::new(3, 17, 0), 319, 279),
+    ("bpf", KernelVersion::new(3, 18, 0), 321, 280),
+    ("execveat", KernelVersion::new(3, 19, 0), 322, 281),
+    ("userfaultfd", KernelVersion::new(4, 3, 0), 323, 282),
+    ("membarrier", KernelVersion::new(4, 3, 0), 324, 283),
+    ("mlock2", KernelVersion::new(4, 4, 0), 325, 284),
+    ("copy_file_range", KernelVersion::new(4, 5, 0), 326, 285),
+    ("preadv2", KernelVersion::new(4, 6, 0), 327, 286),
+    ("pwritev2", KernelVersion::new(4, 6, 0), 328, 287),
+    ("pkey_mprotect", KernelVersion::new(4, 9, 0), 329, 288),
+    ("statx", KernelVersion::new(4, 11, 0), 332, 291),
+    ("io_pgetevents", KernelVersion::new(4, 18, 0), 333, 292),
+    ("rseq", KernelVersion::new(4, 18, 0), 334, 293),
+    ("pidfd_send_signal", KernelVersion::new(5, 1, 0), 424, 424),
+    ("io_uring_setup", KernelVersion::new(5, 1, 0), 425, 425),
+    ("io_uring_enter", KernelVersion::new(5, 1, 0), 426, 426),
+    ("io_uring_register", KernelVersion::new(5, 1, 0), 427, 427),
+    ("open_tree", KernelVersion::new(5, 2, 0), 428, 428),
+    ("move_mount", KernelVersion::new(5, 2, 0), 429, 429),
+    ("fsopen", KernelVersion::new(5, 2, 0), 430, 430),
+    ("fsconfig", KernelVersion::new(5, 2, 0), 431, 431),
+    ("fsmount", KernelVersion::new(5, 2, 0), 432, 432),
+    ("fspick", KernelVersion::new(5, 2, 0), 433, 433),
+    ("pidfd_open", KernelVersion::new(5, 3, 0), 434, 434),
+    ("clone3", KernelVersion::new(5, 3, 0), 435, 435),
+    ("close_range", KernelVersion::new(5, 9, 0), 436, 436),
+    ("openat2", KernelVersion::new(5, 6, 0), 437, 437),
+    ("pidfd_getfd", KernelVersion::new(5, 6, 0), 438, 438),
+    ("faccessat2", KernelVersion::new(5, 8, 0), 439, 439),
+    ("process_madvise", KernelVersion::new(5, 10, 0), 440, 440),
+    ("epoll_pwait2", KernelVersion::new(5, 11, 0), 441, 441),
+    ("mount_setattr", KernelVersion::new(5, 12, 0), 442, 442),
+    ("quotactl_fd", KernelVersion::new(5, 14, 0), 443, 443),
+    (
+        "landlock_create_ruleset",
+        KernelVersion::new(5, 13, 0),
+        444,
+        444,
+    ),
+    ("landlock_add_rule", KernelVersion::new(5, 13, 0), 445, 445),
+    (
+        "landlock_restrict_self",
+        KernelVersion::new(5, 13, 0),
+        446,
+        446,
+    ),
+    ("memfd_secret", KernelVersion::new(5, 14, 0), 447, 447),
+    ("process_mrelease", KernelVersion::new(5, 15, 0), 448, 448),
+    ("futex_waitv", KernelVersion::new(5, 16, 0), 449, 449),
+    (
+        "set_mempolicy_home_node",
+        KernelVersion::new(5, 17, 0),
+        450,
+        450,
+    ),
+    ("cachestat", KernelVersion::new(6, 5, 0), 451, 451),
+    ("fchmodat2", KernelVersion::new(6, 6, 0), 452, 452),
+    ("map_shadow_stack", KernelVersion::new(6, 6, 0), 453, 453),
+    ("futex_wake", KernelVersion::new(6, 7, 0), 454, 454),
+    ("futex_wait", KernelVersion::new(6, 7, 0), 455, 455),
+    ("futex_requeue", KernelVersion::new(6, 7, 0), 456, 456),
+    ("statmount", KernelVersion::new(6, 8, 0), 457, 457),
+    ("listmount", KernelVersion::new(6, 8, 0), 458, 458),
+    ("lsm_get_self_attr", KernelVersion::new(6, 8, 0), 459, 459),
+    ("lsm_set_self_attr", KernelVersion::new(6, 8, 0), 460, 460),
+    ("lsm_list_modules", KernelVersion::new(6, 8, 0), 461, 461),
+    ("mseal", KernelVersion::new(6, 10, 0), 462, 462),
+];
+
+/// What a program needs from the VM and what it gets.
+pub struct CompatReport {
+    pub program: PathBuf,
+    pub kernel: KernelVersion,
+    pub libc: Libc,
+    /// The oldest kernel the program's `NT_GNU_ABI_TAG` allows
+    pub abi_tag: Option<KernelVersion>,
+    /// The oldest kernel the VM's glibc allows, for dynamic programs
+    pub libc_abi_tag: Option<KernelVersion>,
+    /// The newest `GLIBC_*` symbol version the program uses
+    pub glibc: Option<GlibcVersion>,
+    /// The syscalls newer than 3.0 the program makes, with the kernel that added them
+    pub syscalls: Vec<(&'static str, KernelVersion)>,
+    /// Why the program probably fails in the VM, empty if it's compatible
+    pub problems: Vec<String>,
+}
+
+impl CompatReport {
+    pub fn is_compatible(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for CompatReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unknown = || "-".to_string();
+        writeln!(f, "{}", self.program.display().to_string().bold())?;
+        writeln!(f, "  VM: linux {}, {}", self.kernel, self.libc)?;
+        writeln!(
+            f,
+            "  minimum kernel (ABI tag): {}",
+            self.abi_tag.map_or_else(unknown, |v| v.to_string())
+        )?;
+        writeln!(
+            f,
+            "  newest glibc symbol: {}",
+            self.glibc.map_or_else(unknown, |v| format!("GLIBC_{v}"))
+        )?;
+        if let Some((name, since)) = self.syscalls.iter().max_by_key(|(_, since)| *since) {
+            writeln!(f, "  newest syscall: {name} (linux {since})")?;
+        }
+        if self.problems.is_empty() {
+            return write!(f, "  {}", "compatible".green());
+        }
+        for problem in &self.problems {
+            writeln!(f, "  {} {problem}", "✗".red())?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the minimum kernel of the `NT_GNU_ABI_TAG` note in the output of `readelf -n`.
+pub fn parse_abi_tag(notes: &str) -> Option<KernelVersion> {
+    notes.lines().find_map(|line| {
+        let abi = line.trim().strip_prefix("OS: Linux, ABI: ")?;
+        let mut parts = abi.split('.').map(|part| part.trim().parse::<u64>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) => {
+                Some(KernelVersion::new(major, minor, patch))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Returns the `GLIBC_*` versions in the `Version References` of `objdump -p`.
+pub fn parse_glibc_versions(headers: &str) -> BTreeSet<GlibcVersion> {
+    headers
+        .lines()
+        .filter_map(|line| line.split_whitespace().last())
+        .filter_map(|name| match name {
+            // relative relocations (`-z pack-relative-relocs`) need 2.36
+            "GLIBC_ABI_DT_RELR" => Some(GlibcVersion(2, 36, 0)),
+            name => GlibcVersion::from_str(name.strip_prefix("GLIBC_")?).ok(),
+        })
+        .collect()
+}
+
+/// Returns the syscall numbers made by the code in the output of `objdump -d`: the last
+/// immediate loaded into the syscall number register of the function before each trap.
+pub fn parse_syscalls(arch: Arch, disassembly: &str) -> BTreeSet<u64> {
+    let parse_immediate = |value: &str| {
+        let value = value.trim().trim_start_matches(['$', '#']);
+        match value.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    };
+
+    let mut syscalls = BTreeSet::new();
+    let mut number = None;
+    for line in disassembly.lines() {
+        // a new function, e.g. `0000000000401000 <main>:`
+        if line.ends_with(">:") {
+            number = None;
+            continue;
+        }
+        // `  401000:\tmov    $0x13e,%eax`, the instruction is after the address
+        let Some((_, instruction)) = line.split_once(":\t") else {
+            continue;
+        };
+        let (mnemonic, operands) = instruction
+            .trim()
+            .split_once(char::is_whitespace)
+            .unwrap_or((instruction.trim(), ""));
+        // aarch64 immediates start with `#`, its comments with `//`
+        let comment = if arch == Arch::Aarch64 { "//" } else { "#" };
+        let operands = operands.split(comment).next().unwrap_or(operands);
+        let operands: Vec<&str> = operands.split(',').map(str::trim).collect();
+        match (arch, mnemonic, operands.as_slice()) {
+            (Arch::X86_64, "mov" | "movl" | "movq", [value, "%eax" | "%rax"])
+            | (Arch::Aarch64, "mov" | "movz", ["x8" | "w8", value])
+            | (Arch::Riscv64, "li" | "addi", ["a7", value] | ["a7", "zero", value]) => {
+                number = parse_immediate(value);
+            }
+            (Arch::X86_64, "syscall", _)
+            | (Arch::Aarch64, "svc", _)
+            | (Arch::Riscv64, "ecall", _) => {
+                syscalls.extend(number);
+            }
+            _ => {}
+        }
+    }
+    syscalls
+}
+
+/// The name of syscall `number` on `arch` and the kernel that added it, `None` for syscalls
+/// available since 3.0.
+fn syscall_since(arch: Arch, number: u64) -> Option<(&'static str, KernelVersion)> {
+    SYSCALLS
+        .iter()
+        .find(|(_, _, x86_64, generic)| match arch {
+            Arch::X86_64 => *x86_64 == number,
+            _ => *generic == number,
+        })
+        .map(|(name, since, ..)| (*name, *since))
+}
+
+/// Run a binutils program of `toolchain` on `program`, returns its output.
+fn binutils(toolchain: &Toolchain, tool: &str, args: &[&str], program: &Path) -> Result<String> {
+    let bin = toolchain
+        .bin_dir()?
+        .join(format!("{}-{tool}", toolchain.target.to_target_string()));
+    let output = Command::new(&bin)
+        .args(args)
+        .arg(program)
+        .output()
+        .context(format!("failed to run `{}`", bin.display()))?;
+    if !output.status.success() {
+        bail!(
+            "{tool} failed on `{}`: {}",
+            program.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The glibc of the sysroot of `toolchain`, loaded by dynamic programs in the VM.
+fn sysroot_libc(toolchain: &Toolchain) -> Result<Option<PathBuf>> {
+    let sysroot = toolchain.sysroot()?;
+    Ok(["lib", "lib64", "usr/lib", "usr/lib64"]
+        .iter()
+        .map(|dir| sysroot.join(dir).join("libc.so.6"))
+        .find(|libc| libc.exists()))
+}
+
+/// Check that `program` can run in a VM booting `kernel` with the libc of `toolchain`.
+pub fn check(toolchain: &Toolchain, kernel: KernelVersion, program: &Path) -> Result<CompatReport> {
+    let arch = toolchain.target.arch;
+    let headers = binutils(toolchain, "objdump", &["-p"], program)?;
+    let is_static = !headers
+        .lines()
+        .any(|line| line.trim_start().starts_with("INTERP"));
+    let abi_tag = parse_abi_tag(&binutils(toolchain, "readelf", &["-n"], program)?);
+    let glibc = parse_glibc_versions(&headers).last().copied();
+    let disassembly = binutils(toolchain, "objdump", &["-d", "--no-show-raw-insn"], program)?;
+    let mut syscalls: Vec<_> = parse_syscalls(arch, &disassembly)
+        .into_iter()
+        .filter_map(|number| syscall_since(arch, number))
+        .collect();
+    syscalls.sort_by_key(|(_, since)| *since);
+
+    let mut problems = vec![];
+    if let Some(abi_tag) = abi_tag
+        && abi_tag > kernel
+    {
+        problems.push(format!(
+            "it was linked for linux {abi_tag} and newer, glibc will abort with \
+             `FATAL: kernel too old`"
+        ));
+    }
+    let libc_abi_tag = match (&toolchain.libc, is_static) {
+        (Libc::Glibc(_), false) => match sysroot_libc(toolchain)? {
+            Some(libc) => parse_abi_tag(&binutils(toolchain, "readelf", &["-n"], &libc)?),
+            None => None,
+        },
+        _ => None,
+    };
+    if let Some(libc_abi_tag) = libc_abi_tag
+        && libc_abi_tag > kernel
+    {
+        problems.push(format!(
+            "the VM's {} requires linux {libc_abi_tag}, it will abort with `FATAL: kernel too \
+             old`",
+            toolchain.libc
+        ));
+    }
+    match (&toolchain.libc, glibc) {
+        (Libc::Glibc(provided), Some(needed)) if needed > *provided => problems.push(format!(
+            "it uses GLIBC_{needed} symbols, the VM has {}: `version `GLIBC_{needed}' not found`",
+            toolchain.libc
+        )),
+        (Libc::Musl(_), Some(needed)) if !is_static => problems.push(format!(
+            "it's linked against glibc (GLIBC_{needed}), the VM has {}",
+            toolchain.libc
+        )),
+        _ => {}
+    }
+    for (name, since) in &syscalls {
+        if *since > kernel {
+            problems.push(format!(
+                "it calls {name}, added in linux {since}, which fails with ENOSYS"
+            ));
+        }
+    }
+
+    Ok(CompatReport {
+        program: program.to_path_buf(),
+        kernel,
+        libc: toolchain.libc.clone(),
+        abi_tag,
+        libc_abi_tag,
+        glibc,
+        syscalls,
+        problems,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_abi_tag, parse_glibc_versions, parse_syscalls};
+    use crate::{
+        packages::{glibc::GlibcVersion, linux::KernelVersion},
+        profile::Arch,
+    };
+
+    #[test]
+    fn test_parse_abi_tag() {
+        let notes = "Displaying notes found in: .note.ABI-tag
+  Owner                Data size \tDescription
+  GNU                  0x00000010\tNT_GNU_ABI_TAG (ABI version tag)
+    OS: Linux, ABI: 3.2.0
+";
+        assert_eq!(parse_abi_tag(notes), Some(KernelVersion::new(3, 2, 0)));
+        assert_eq!(parse_abi_tag("no notes"), None);
+    }
+
+    #[test]
+    fn test_parse_glibc_versions() {
+        let headers = "Version References:
+  required from libc.so.6:
+    0x09691a75 0x00 04 GLIBC_2.2.5
+    0x069691b4 0x00 03 GLIBC_2.34
+    0x0d696917 0x00 02 GLIBC_PRIVATE
+    0x0a7a2c4e 0x00 05 GLIBC_ABI_DT_RELR
+";
+        assert_eq!(
+            parse_glibc_versions(headers)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [
+                GlibcVersion(2, 2, 5),
+                GlibcVersion(2, 34, 0),
+                GlibcVersion(2, 36, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_syscalls() {
+        let x86_64 = "
+0000000000401000 <getrandom>:
+  401000:\tmov    $0x13e,%eax
+  401005:\tsyscall
+0000000000401010 <other>:
+  401010:\tsyscall
+0000000000401020 <statx>:
+  401020:\tmov    $0x14c,%eax
+  401025:\tmov    %rdi,%rsi
+  401028:\tsyscall
+";
+        assert_eq!(
+            parse_syscalls(Arch::X86_64, x86_64)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [318, 332]
+        );
+
+        let aarch64 = "
+0000000000400000 <clone3>:
+  400000:\tmov\tx8, #0x1b3                \t// #435
+  400004:\tsvc\t#0x0
+";
+        assert_eq!(
+            parse_syscalls(Arch::Aarch64, aarch64)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [435]
+        );
+
+        let riscv64 = "
+0000000000010000 <openat2>:
+   10000:\tli\ta7,437
+   10004:\tecall
+";
+        assert_eq!(
+            parse_syscalls(Arch::Riscv64, riscv64)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [437]
+        );
+    }
+}
@@ -5,15 +5,83 @@
 //! The toolchain version specified in the local configuration will be used instead of the version
 //! Specified in the global configuration.
 //!
+//! A `[workspace]` table holds settings shared by every toolchain declared in the same file. A
+//! `[toolchain.*]` table may override `jobs` and will append its own `cflags` and `default_flags`
+//! to the workspace ones. `[[workspace.kernel_flags]]` rules from the global and the local
+//! configuration are all applied.
+//!
+//! `cflags` are only added by toolup: `toolup cc` passes them and `toolup shell` exports them as
+//! `CFLAGS`, the compiler itself doesn't know about them. `CC` in that shell, CMake toolchain files
+//! and the configure/make steps of toolup's builds (and the `env.sh` of `--env-file`) run without
+//! them, `default_flags` are the flags every invocation of the compiler gets.
+//!
+//! A `[toolchain."<target>@<variant>"]` table declares a variant, a toolchain installed next to the
+//! other toolchains of its target, see [`Toolchain::name`].
+//!
 //! # Example configuration
 //! ```toml
+//!  [workspace]
+//!  jobs = 16
+//!  cache_dir = "/mnt/fast/toolup-cache"
+//!  cflags = ["-O2"]
+//!  default_flags = ["-Os"]
+//!  limit_rate = "2M"
+//!  downloader = "curl"
+//!  prefix = "/opt/cross"
+//!  system_dir = "/usr/local/toolup"
+//!  prebuilt_index = "https://toolchains.example.com/index.json"
+//!  sandbox = true
+//!  clean_after_install = true
+//!
+//!  [workspace.mirrors]
+//!  "https://ftp.gnu.org/gnu" = "https://mirrors.kernel.org/gnu"
+//!
+//!  [workspace.hooks]
+//!  post_sysroot = ["./scripts/add-ca-certificates.sh"]
+//!
+//!  [workspace.retry]
+//!  retries = 2
+//!  backoff_secs = 60
+//!  stages = ["binutils", "libc", "gcc-final"]
+//!
+//!  [[workspace.kernel_flags]]
+//!  reason = "gcc 15 warns about missing prototypes"
+//!  from = "5.4"
+//!  until = "5.10"
+//!  flags = { KCFLAGS = ["-Wno-missing-prototypes"] }
+//!
+//!  [sources]
+//!  gcc = "https://artifactory.example.com/gnu/gcc/gcc-{version}/gcc-{version}.tar.xz"
+//!  busybox = { url = "https://busybox.net/downloads/busybox-{version}.tar.bz2", dirname = "busybox-{version}" }
+//!
 //!  [toolchain.x86_64-unknown-linux-gnu]
 //!  gcc = "15.2.0"
 //!  binutils = "2.45"
 //!  libc = "2.42"
+//!
+//!  [toolchain.aarch64-unknown-linux-musl]
+//!  gcc = "15.2.0"
+//!  binutils = "2.45"
+//!  libc = "1.2.5"
+//!  jobs = 4
+//!  cflags = ["-march=armv8.2-a"]
+//!  default_flags = ["-mcpu=cortex-a76"]
+//!  static_musl = true
+//!  linker = "gold"
+//!  gdb = "16.3"
+//!  gdb_python = true
+//!  llvm = "20.1.8"
+//!  qemu_binary = "/opt/qemu/bin/qemu-system-aarch64"
+//!  qemu_args = ["-device", "virtio-rng-pci"]
+//!
+//!  [toolchain."aarch64-unknown-linux-musl@hardened"]
+//!  gcc = "15.2.0"
+//!  binutils = "2.45"
+//!  libc = "1.2.5"
+//!  default_flags = ["-fstack-protector-strong", "-D_FORTIFY_SOURCE=2"]
 //! ```
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
     str::FromStr,
 };
@@ -23,13 +91,25 @@ use serde::{Deserialize, Serialize};
 use toml_edit::DocumentMut;
 
 use crate::{
+    download::{Backend, Rate},
+    hooks::Hook,
     packages::{
-        binutils::{Binutils, BinutilsVersion},
+        SourceOverride,
+        android::AndroidApi,
+        binutils::{Binutils, BinutilsVersion, Linker},
+        freebsd::FreeBsdVersion,
         gcc::{GCC, GCCVersion},
+        gdb::{Gdb, GdbVersion},
         glibc::GlibcVersion,
+        linux::KernelFlagRule,
+        llvm::{Llvm, LlvmVersion},
+        mingw::MingwVersion,
         musl::MuslVersion,
+        newlib,
+        wasi::WasiLibcVersion,
     },
-    profile::{Libc, Target, Toolchain},
+    profile::{Libc, Profile, Toolchain, parse_name},
+    stage::RetryPolicy,
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -37,13 +117,216 @@ pub struct ToolchainConfig {
     binutils: String,
     gcc: String,
     libc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jobs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    cflags: Vec<String>,
+    /// Appended to the `[workspace]` default flags, see [`crate::specs`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    default_flags: Vec<String>,
+    /// Link `toolup cc` output statically, only for musl targets
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    static_musl: bool,
+    /// The linker used by `toolup cc`, binutils are built with gold if it's `gold`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    linker: Option<Linker>,
+    /// How a freestanding toolchain is built, e.g. `nano`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile: Option<Profile>,
+    /// Also build a cross gdb of this version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gdb: Option<GdbVersion>,
+    /// Build gdb with Python scripting
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    gdb_python: bool,
+    /// Also build clang and lld of this version, see [`crate::packages::llvm`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    llvm: Option<LlvmVersion>,
+    /// The QEMU used by `toolup linux` and `toolup run-baremetal` instead of the one on `PATH`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    qemu_binary: Option<PathBuf>,
+    /// Appended to the `[workspace]` QEMU arguments
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    qemu_args: Vec<String>,
+    /// Where `toolup install` installs the toolchain instead of `~/.toolup/toolchains`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prefix: Option<PathBuf>,
+}
+
+/// Settings under `[workspace]`, inherited by all `[toolchain.*]` tables.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct WorkspaceConfig {
+    /// The number of threads to use for running commands
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<u64>,
+    /// Where to store downloads and build trees instead of `~/.cache/toolup`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_dir: Option<PathBuf>,
+    /// URL prefix replacements applied to every download, e.g. to use a closer GNU mirror
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub mirrors: HashMap<String, String>,
+    /// Flags passed to the compiler by `toolup cc` and exported as `CFLAGS` by `toolup shell`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cflags: Vec<String>,
+    /// Flags every invocation of the installed compiler defaults to, through a specs file, see
+    /// [`crate::specs`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub default_flags: Vec<String>,
+    /// The maximum download rate, e.g. `2M`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit_rate: Option<Rate>,
+    /// Download with the system `curl` or `wget` instead of the built-in client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloader: Option<Backend>,
+    /// Arguments appended to every QEMU command line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub qemu_args: Vec<String>,
+    /// Where `toolup install` installs toolchains instead of `~/.toolup/toolchains`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<PathBuf>,
+    /// Share toolchains and the cache with every user of the machine from this directory, see
+    /// [`crate::download::set_system_dir`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_dir: Option<PathBuf>,
+    /// Flags added when building kernels, after the built-in workarounds, see
+    /// [`KernelFlagRule`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kernel_flags: Vec<KernelFlagRule>,
+    /// Programs run before and after stages, see [`crate::hooks`]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hooks: BTreeMap<Hook, Vec<PathBuf>>,
+    /// How failed stages are retried, see [`RetryPolicy`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    /// The index `toolup install --prebuilt` installs toolchains from, see [`crate::prebuilt`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prebuilt_index: Option<String>,
+    /// Run configure and make in a bubblewrap sandbox, see [`crate::sandbox`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<bool>,
+    /// Remove the build trees of a toolchain once it's installed, see [`crate::cache::build_trees`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clean_after_install: Option<bool>,
+}
+
+impl WorkspaceConfig {
+    /// Merge two workspaces, values set in `self` take precedence over `fallback`.
+    pub fn or(self, fallback: WorkspaceConfig) -> WorkspaceConfig {
+        let mut mirrors = fallback.mirrors;
+        mirrors.extend(self.mirrors);
+        let mut hooks = fallback.hooks;
+        hooks.extend(self.hooks);
+        WorkspaceConfig {
+            jobs: self.jobs.or(fallback.jobs),
+            cache_dir: self.cache_dir.or(fallback.cache_dir),
+            mirrors,
+            cflags: if self.cflags.is_empty() {
+                fallback.cflags
+            } else {
+                self.cflags
+            },
+            default_flags: if self.default_flags.is_empty() {
+                fallback.default_flags
+            } else {
+                self.default_flags
+            },
+            limit_rate: self.limit_rate.or(fallback.limit_rate),
+            downloader: self.downloader.or(fallback.downloader),
+            qemu_args: if self.qemu_args.is_empty() {
+                fallback.qemu_args
+            } else {
+                self.qemu_args
+            },
+            prefix: self.prefix.or(fallback.prefix),
+            system_dir: self.system_dir.or(fallback.system_dir),
+            kernel_flags: [fallback.kernel_flags, self.kernel_flags].concat(),
+            hooks,
+            retry: self.retry.or(fallback.retry),
+            prebuilt_index: self.prebuilt_index.or(fallback.prebuilt_index),
+            sandbox: self.sandbox.or(fallback.sandbox),
+            clean_after_install: self.clean_after_install.or(fallback.clean_after_install),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Config {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    workspace: Option<WorkspaceConfig>,
+    /// Where to download package sources from instead of their default URLs, keyed by package
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    sources: HashMap<String, SourceOverride>,
+    #[serde(default)]
     toolchain: HashMap<String, ToolchainConfig>,
 }
 
+/// The effective settings of a single toolchain after inheriting from `[workspace]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ToolchainSettings {
+    pub jobs: Option<u64>,
+    pub cflags: Vec<String>,
+    pub default_flags: Vec<String>,
+    pub static_musl: bool,
+    pub linker: Option<Linker>,
+    pub qemu_binary: Option<PathBuf>,
+    pub qemu_args: Vec<String>,
+    pub prefix: Option<PathBuf>,
+}
+
+impl Config {
+    /// Returns the `[workspace]` table, or an empty one.
+    pub fn workspace(&self) -> WorkspaceConfig {
+        self.workspace.clone().unwrap_or_default()
+    }
+
+    /// Returns the settings for `target` with the workspace defaults applied.
+    ///
+    /// `target` doesn't have to be declared, in that case the workspace settings are returned.
+    pub fn settings(&self, target: &str) -> ToolchainSettings {
+        let workspace = self.workspace();
+        let mut settings = ToolchainSettings {
+            jobs: workspace.jobs,
+            cflags: workspace.cflags,
+            default_flags: workspace.default_flags,
+            static_musl: false,
+            linker: None,
+            qemu_binary: None,
+            qemu_args: workspace.qemu_args,
+            prefix: workspace.prefix,
+        };
+        if let Some(toolchain) = self.toolchain.get(target) {
+            settings.jobs = toolchain.jobs.or(settings.jobs);
+            settings.cflags.extend(toolchain.cflags.iter().cloned());
+            settings
+                .default_flags
+                .extend(toolchain.default_flags.iter().cloned());
+            settings.static_musl = toolchain.static_musl;
+            settings.linker = toolchain.linker;
+            settings.qemu_binary = toolchain.qemu_binary.clone();
+            settings
+                .qemu_args
+                .extend(toolchain.qemu_args.iter().cloned());
+            settings.prefix = toolchain.prefix.clone().or(settings.prefix);
+        }
+        settings
+    }
+
+    /// Returns every toolchain declared in the configuration, sorted by target.
+    pub fn toolchains(&self) -> Result<Vec<(Toolchain, ToolchainSettings)>> {
+        let mut targets: Vec<&String> = self.toolchain.keys().collect();
+        targets.sort();
+        targets
+            .into_iter()
+            .map(|target| {
+                let toolchain = self.toolchain[target]
+                    .to_toolchain(target)
+                    .context(format!("invalid toolchain `{target}`"))?;
+                Ok((toolchain, self.settings(target)))
+            })
+            .collect()
+    }
+}
+
 impl From<&Toolchain> for ToolchainConfig {
     fn from(value: &Toolchain) -> Self {
         Self {
@@ -52,27 +335,72 @@ impl From<&Toolchain> for ToolchainConfig {
             libc: match value.libc {
                 Libc::Musl(musl) => musl.to_string(),
                 Libc::Glibc(glibc) => glibc.to_string(),
+                Libc::Mingw(mingw) => mingw.to_string(),
+                Libc::FreeBsd(freebsd) => freebsd.to_string(),
+                Libc::Bionic(api) => api.to_string(),
+                Libc::WasiLibc(wasi_libc) => wasi_libc.to_string(),
+                // `newlib` selects it, see `packages::newlib::parse_libc`
+                Libc::Newlib(newlib) => format!("newlib-{newlib}"),
             },
+            jobs: None,
+            cflags: vec![],
+            default_flags: vec![],
+            static_musl: false,
+            linker: value.binutils.gold.then_some(Linker::Gold),
+            qemu_binary: None,
+            qemu_args: vec![],
+            prefix: None,
+            profile: (value.profile != Profile::Default).then_some(value.profile),
+            gdb: value.gdb.as_ref().map(|gdb| gdb.version),
+            gdb_python: value.gdb.as_ref().is_some_and(|gdb| gdb.python),
+            llvm: value.llvm.as_ref().map(|llvm| llvm.version),
         }
     }
 }
 
 impl ToolchainConfig {
-    /// Convert the toolchain configuration from TOML to a `Toolchain`
-    fn to_toolchain(self: &ToolchainConfig, target: &str) -> Result<Toolchain> {
-        let target = Target::from_str(target)?;
+    /// Convert the toolchain configuration from TOML to a `Toolchain`, `name` is its key, see
+    /// [`Toolchain::name`]
+    fn to_toolchain(self: &ToolchainConfig, name: &str) -> Result<Toolchain> {
+        let (target, variant) = parse_name(name)?;
         let binutils = Binutils {
             version: BinutilsVersion::from_str(&self.binutils)?,
+            gold: self.linker == Some(Linker::Gold),
         };
         let gcc = GCC {
             version: GCCVersion::from_str(&self.gcc)?,
         };
-        let libc = if target.is_musl() {
+        let newlib = newlib::parse_libc(&self.libc).filter(|_| target.is_freestanding());
+        let libc = if let Some(newlib) = newlib {
+            Libc::Newlib(newlib?)
+        } else if target.is_musl() {
             Libc::Musl(MuslVersion::from_str(self.libc.as_str())?)
+        } else if target.is_windows() {
+            Libc::Mingw(MingwVersion::from_str(self.libc.as_str())?)
+        } else if target.is_freebsd() {
+            Libc::FreeBsd(FreeBsdVersion::from_str(self.libc.as_str())?)
+        } else if target.is_android() {
+            Libc::Bionic(AndroidApi::from_str(self.libc.as_str())?)
+        } else if target.is_wasi() {
+            Libc::WasiLibc(WasiLibcVersion::from_str(self.libc.as_str())?)
         } else {
             Libc::Glibc(GlibcVersion::from_str(self.libc.as_str())?)
         };
-        Ok(Toolchain::new(target.into(), binutils, gcc, libc))
+        let mut toolchain = Toolchain::new(target, binutils, gcc, libc);
+        toolchain.profile = self.profile.unwrap_or_default();
+        toolchain.variant = variant;
+        // `gdb_python` alone builds the default gdb
+        if self.gdb.is_some() || self.gdb_python {
+            toolchain.gdb = Some(Gdb {
+                version: self.gdb.unwrap_or_default(),
+                python: self.gdb_python,
+            });
+        }
+        // WASI toolchains always have one, see `Toolchain::new`
+        if let Some(version) = self.llvm {
+            toolchain.llvm = Some(Llvm { version });
+        }
+        Ok(toolchain)
     }
 }
 
@@ -87,13 +415,14 @@ pub fn load_config(filepath: impl AsRef<Path>) -> Result<Option<Config>> {
         filepath.as_ref().display()
     ))?;
 
-    Ok(toml::from_str(content.as_str()).context(format!(
+    toml::from_str(content.as_str()).context(format!(
         "failed to parse TOML in `{}`",
         filepath.as_ref().display()
-    ))?)
+    ))
 }
 
-fn global_config_path() -> Result<PathBuf> {
+/// Returns the path of the global `toolup.toml`.
+pub fn global_config_path() -> Result<PathBuf> {
     let config_dir = dirs::config_dir().context("failed to get config directory")?;
     Ok(Path::new(&config_dir).join("toolup.toml"))
 }
@@ -115,10 +444,49 @@ fn load_global_config() -> Result<Config> {
 }
 
 /// Load configuration `toolup.toml` in the current working directory.
-fn load_local_config() -> Result<Option<Config>> {
+pub fn load_local_config() -> Result<Option<Config>> {
     load_config(Path::new("toolup.toml"))
 }
 
+/// Returns the effective `[workspace]` settings. Values in the local configuration take
+/// precedence over the global configuration.
+///
+/// Unlike [`resolve_target_toolchain`], this will not create a global configuration file.
+pub fn resolve_workspace() -> Result<WorkspaceConfig> {
+    let global = load_config(global_config_path()?)?
+        .map(|c| c.workspace())
+        .unwrap_or_default();
+    let local = load_local_config()?
+        .map(|c| c.workspace())
+        .unwrap_or_default();
+
+    Ok(local.or(global))
+}
+
+/// Returns the `[sources]` overrides, local entries take precedence over global ones.
+pub fn resolve_sources() -> Result<HashMap<String, SourceOverride>> {
+    let mut sources = load_config(global_config_path()?)?
+        .map(|c| c.sources)
+        .unwrap_or_default();
+    if let Some(local) = load_local_config()? {
+        sources.extend(local.sources);
+    }
+    Ok(sources)
+}
+
+/// Returns the settings for `target` from the configuration that declares its toolchain, with
+/// `[workspace]` defaults applied.
+pub fn resolve_target_settings(target: &str) -> Result<ToolchainSettings> {
+    let workspace = resolve_workspace()?;
+    let mut declared = match load_local_config()? {
+        Some(local) if local.toolchain.contains_key(target) => local,
+        _ => load_config(global_config_path()?)?.unwrap_or_default(),
+    };
+    declared.workspace = Some(workspace);
+
+    Ok(declared.settings(target))
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ToolchainConfigResult {
     /// From the local configuration file
@@ -139,11 +507,11 @@ impl From<ToolchainConfigResult> for Toolchain {
     }
 }
 
-/// Updates the toolchain configuration for a target in the global configuration. This will
-/// preserve comments and the original layout of the file.
+/// Updates the toolchain configuration for a target (or a variant) in the global configuration.
+/// This will preserve comments and the original layout of the file.
 fn set_global_toolchain(toolchain: &Toolchain) -> Result<()> {
     let global_config = global_config_path()?;
-    let target = toolchain.target.to_string();
+    let target = toolchain.name();
 
     let toml_str = std::fs::read_to_string(&global_config)
         .context(format!("failed to read `{}`", global_config.display()))?;
@@ -178,8 +546,9 @@ fn set_global_toolchain(toolchain: &Toolchain) -> Result<()> {
 /// whether the configuration was created or not.
 fn get_or_init_global_toolchain(target_str: &str) -> Result<(Toolchain, bool)> {
     let global = load_global_config()?;
-    let target = Target::from_str(target_str)?;
-    let default = Toolchain::target_default(&target);
+    let (target, variant) = parse_name(target_str)?;
+    let mut default = Toolchain::target_default(&target);
+    default.variant = variant;
 
     Ok(match global.toolchain.get(target_str) {
         Some(cfg) => (cfg.to_toolchain(target_str)?, false),
@@ -187,7 +556,7 @@ fn get_or_init_global_toolchain(target_str: &str) -> Result<(Toolchain, bool)> {
             // A toolchain for `target` was never configured, edit the file and set a default toolchain for
             // `target`.
             set_global_toolchain(&default)?;
-            (default.into(), true)
+            (default, true)
         }
     })
 }
@@ -1,30 +1,67 @@
-use std::path::Path;
-use std::process::{Command, Stdio};
+//! Packing a directory into a gzipped `newc` cpio archive, the format the kernel unpacks an
+//! initramfs from.
+//!
+//! The archive is written in Rust so packing a rootfs works on minimal hosts and containers that
+//! have no `find`, `cpio` or `gzip`.
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
 
-pub fn pack_rootfs(rootfs: &Path, out: &Path) -> std::io::Result<()> {
-    let mut cpio = Command::new("cpio")
-        .args(["-o", "-H", "newc"])
-        .current_dir(rootfs)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
+use ::cpio::newc::{Builder, trailer};
+use flate2::{Compression, write::GzEncoder};
 
-    // feed file list from `find .`
-    let mut find = Command::new("find")
-        .arg(".")
-        .current_dir(rootfs)
-        .stdout(cpio.stdin.take().unwrap())
-        .spawn()?;
+pub fn pack_rootfs(rootfs: &Path, out: &Path) -> io::Result<()> {
+    let mut gz = GzEncoder::new(BufWriter::new(File::create(out)?), Compression::best());
+    let mut ino = 0;
+    pack_dir(rootfs, Path::new(""), &mut gz, &mut ino)?;
+    trailer(gz)?.finish()?.flush()
+}
+
+/// Append the entries of `dir` to `out`, named relative to the rootfs (`prefix` is the name of
+/// `dir`). Every file is owned by root.
+fn pack_dir<W: Write>(dir: &Path, prefix: &Path, out: &mut W, ino: &mut u32) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    // sorted so packing the same tree gives the same archive
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = prefix.join(entry.file_name());
+        let metadata = fs::symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
 
-    // gzip the cpio output
-    let mut gz = Command::new("gzip")
-        .arg("-9")
-        .stdin(cpio.stdout.take().unwrap())
-        .stdout(Stdio::from(std::fs::File::create(out)?))
-        .spawn()?;
+        *ino += 1;
+        let builder = Builder::new(&name.to_string_lossy())
+            .ino(*ino)
+            .mode(metadata.mode())
+            .uid(0)
+            .gid(0)
+            .nlink(if file_type.is_dir() { 2 } else { 1 })
+            .mtime(metadata.mtime() as u32);
 
-    find.wait()?;
-    cpio.wait()?;
-    gz.wait()?;
+        if file_type.is_dir() {
+            builder.write(&mut *out, 0).finish()?;
+            pack_dir(&path, &name, out, ino)?;
+        } else if file_type.is_symlink() {
+            // the content of a symlink entry is its target
+            let target = fs::read_link(&path)?;
+            let target = target.as_os_str().as_bytes();
+            let mut writer = builder.write(&mut *out, target.len() as u32);
+            writer.write_all(target)?;
+            writer.finish()?;
+        } else if file_type.is_file() {
+            let mut writer = builder.write(&mut *out, metadata.len() as u32);
+            io::copy(&mut File::open(&path)?, &mut writer)?;
+            writer.finish()?;
+        } else {
+            log::warn!(
+                "=> skipping {}, it's not a file, directory or symlink",
+                path.display()
+            );
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,48 @@
+//! `toolup linux --debug`: boot with QEMU's gdbstub and wait for gdb.
+//!
+//! The kernel is built with debug info and its `vmlinux` is kept next to the image (see
+//! [`crate::packages::linux::vmlinux`]). QEMU listens for gdb on [`GDB_PORT`] with the CPUs
+//! stopped before the first instruction, and KASLR is off so the addresses of the symbols match.
+//! Programs in the guest are debugged with `--gdbserver` and a forwarded port instead.
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::{packages::host_tools::find_program, profile::Toolchain};
+
+/// The port QEMU's `-s` listens on.
+pub const GDB_PORT: u16 = 1234;
+
+/// The QEMU arguments starting the gdbstub, with the CPUs stopped until gdb continues.
+pub fn qemu_args() -> Vec<String> {
+    vec!["-s".into(), "-S".into()]
+}
+
+/// The kernel command line of a VM debugged with gdb.
+pub fn kernel_args() -> Vec<String> {
+    // the symbols of `vmlinux` are at their link addresses
+    vec!["nokaslr".into()]
+}
+
+/// The gdb command attaching to the VM and loading the symbols of `vmlinux`: the toolchain's own
+/// `<target>-gdb` (`toolup install --gdb`), one on the `PATH`, or `gdb-multiarch`.
+pub fn gdb_command(toolchain: &Toolchain, vmlinux: &Path) -> Result<String> {
+    let name = format!("{}-gdb", toolchain.target);
+    let own = toolchain.dir()?.join("bin").join(&name);
+    let gdb = if own.is_file() {
+        own.display().to_string()
+    } else if let Some(gdb) = find_program(&name).or_else(|| find_program("gdb-multiarch")) {
+        gdb.display().to_string()
+    } else {
+        log::warn!(
+            "=> no gdb for {} was found, install one with `toolup install {} --gdb`",
+            toolchain.target,
+            toolchain.target
+        );
+        name
+    };
+    Ok(format!(
+        "{gdb} {} -ex 'target remote :{GDB_PORT}'",
+        vmlinux.display()
+    ))
+}
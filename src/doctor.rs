@@ -0,0 +1,173 @@
+//! `toolup doctor`: problems with the toolup directories that make builds fail in confusing ways.
+//!
+//! A rebuild (`toolup install --force-stage`, or an install after an interrupted one) reuses the objdirs
+//! left in the cache, which were configured for the host of the previous build. When the host
+//! compiler, make, binutils or the build environment changed since (see
+//! [`HostFingerprint::changes`]), configure caches and generated makefiles are stale and the
+//! rebuild fails far from the cause.
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    download::{cache_dir, cross_prefix},
+    metadata::{self, HostFingerprint},
+    registry,
+};
+
+/// A toolchain whose objdirs were configured in another host environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaleObjdirs {
+    /// See [`crate::profile::Toolchain::id`]
+    pub id: String,
+    pub changes: Vec<String>,
+    pub objdirs: Vec<PathBuf>,
+}
+
+impl Display for StaleObjdirs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "the objdirs of {} were configured in another host environment:",
+            self.id
+        )?;
+        for change in &self.changes {
+            writeln!(f, "    {change}")?;
+        }
+        write!(f, "  remove them before rebuilding it:")?;
+        for objdir in &self.objdirs {
+            write!(f, "\n    {}", objdir.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the installed toolchains with objdirs in the cache that were configured in a host
+/// environment different from the current one.
+pub fn stale_objdirs() -> Result<Vec<StaleObjdirs>> {
+    let current = HostFingerprint::current();
+    let cache = cache_dir()?;
+
+    let mut dirs = vec![];
+    for entry in std::fs::read_dir(cross_prefix()?)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    for (id, prefix) in registry::load()? {
+        dirs.push(prefix.join(id));
+    }
+
+    let mut stale = vec![];
+    for dir in dirs {
+        // toolchains installed before toolup recorded the host can't be compared
+        let Some(metadata) = metadata::read_dir(&dir)? else {
+            continue;
+        };
+        let Some(host) = metadata.host else {
+            continue;
+        };
+        let changes = host.changes(&current);
+        if changes.is_empty() {
+            continue;
+        }
+        let objdirs = objdirs(&cache, &metadata.id)?;
+        if !objdirs.is_empty() {
+            stale.push(StaleObjdirs {
+                id: metadata.id,
+                changes,
+                objdirs,
+            });
+        }
+    }
+    Ok(stale)
+}
+
+/// Returns the objdirs of toolchain `id` in the source directories of `cache`, e.g.
+/// `gcc-15.2.0/objdir-final-<id>`.
+pub fn objdirs(cache: &Path, id: &str) -> Result<Vec<PathBuf>> {
+    let mut objdirs = vec![];
+    if !cache.is_dir() {
+        return Ok(objdirs);
+    }
+    for source in
+        std::fs::read_dir(cache).context(format!("failed to read `{}`", cache.display()))?
+    {
+        let source = source?.path();
+        if !source.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(&source)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if path.is_dir() && name.starts_with("objdir-") && name.ends_with(&format!("-{id}")) {
+                objdirs.push(path);
+            }
+        }
+    }
+    objdirs.sort();
+    Ok(objdirs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use anyhow::Result;
+
+    use super::objdirs;
+    use crate::metadata::HostFingerprint;
+
+    #[test]
+    fn test_objdirs() -> Result<()> {
+        let cache = tempfile::TempDir::new()?;
+        let id = "aarch64-unknown-linux-gnu-gcc-15.2.0-bin-2.45-glibc-2.42";
+        for dir in [
+            format!("gcc-15.2.0/objdir-final-{id}"),
+            format!("gcc-15.2.0/objdir-final-{id}-nano"),
+            format!("binutils-2.45/objdir-arch-{id}"),
+            "make-4.4.1/objdir-host".to_string(),
+        ] {
+            std::fs::create_dir_all(cache.path().join(dir))?;
+        }
+        std::fs::write(cache.path().join("gcc-15.2.0.tar.xz"), "")?;
+
+        assert_eq!(
+            objdirs(cache.path(), id)?,
+            vec![
+                cache.path().join(format!("binutils-2.45/objdir-arch-{id}")),
+                cache.path().join(format!("gcc-15.2.0/objdir-final-{id}")),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_changes() {
+        let before = HostFingerprint {
+            distro: Some("Ubuntu 24.04.1 LTS".into()),
+            gcc: Some("gcc (Ubuntu 13.3.0-6ubuntu2~24.04) 13.3.0".into()),
+            make: Some("GNU Make 4.3".into()),
+            binutils: None,
+            env: BTreeMap::from([("CFLAGS".into(), "-O2".into())]),
+        };
+        assert!(before.changes(&before).is_empty());
+
+        let after = HostFingerprint {
+            gcc: Some("gcc (GCC) 14.2.1".into()),
+            env: BTreeMap::new(),
+            ..before.clone()
+        };
+        assert_eq!(
+            before.changes(&after),
+            vec![
+                "gcc: gcc (Ubuntu 13.3.0-6ubuntu2~24.04) 13.3.0 -> gcc (GCC) 14.2.1",
+                "$CFLAGS: -O2 -> <none>",
+            ]
+        );
+    }
+}
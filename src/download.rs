@@ -1,22 +1,466 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow, bail};
 use flate2::read::GzDecoder;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar};
+use liblzma::{
+    bufread::XzDecoder,
+    stream::{CONCATENATED, Stream},
+};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
     fs::{self, File},
-    io::{self, BufReader},
-    path::{Path, PathBuf},
-    time::Duration,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    str::FromStr,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
-use tar::Archive;
-use xz2::bufread::XzDecoder;
+use tar::{Archive, EntryType};
+
+use crate::cache;
+use crate::commands::{is_plan, is_plan_quiet, plan_step};
+use crate::error::Failure;
+use crate::locks::{self, NamedLock};
+use crate::provenance;
+use crate::ui;
+
+static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+static SYSTEM_DIR: OnceLock<PathBuf> = OnceLock::new();
+static MIRRORS: OnceLock<HashMap<String, String>> = OnceLock::new();
+static LIMIT_RATE: OnceLock<Rate> = OnceLock::new();
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+static DOWNLOADED_BYTES: AtomicU64 = AtomicU64::new(0);
+static DOWNLOADED_ARCHIVES: AtomicU64 = AtomicU64::new(0);
+static CACHED_BYTES: AtomicU64 = AtomicU64::new(0);
+static CACHED_ARCHIVES: AtomicU64 = AtomicU64::new(0);
+
+/// The URLs of every archive needed by a plan, see [`planned_urls`].
+static PLANNED_URLS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+/// Use `dir` as the cache directory instead of `~/.cache/toolup`.
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_cache_dir(dir: PathBuf) {
+    let _ = CACHE_DIR.set(dir);
+}
+
+/// The default directory of [`set_system_dir`].
+pub const DEFAULT_SYSTEM_DIR: &str = "/usr/local/toolup";
+
+/// Keep toolchains, sysroots, kernel images and the cache in `dir` instead of `~/.toolup` and
+/// `~/.cache/toolup`, so they are shared by every user of the machine.
+///
+/// Users share the directory through its group: it's created setgid and group-writable, and the
+/// umask is relaxed so everything toolup and its build commands create stays group-writable.
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_system_dir(dir: PathBuf) -> Result<()> {
+    // SAFETY: umask only changes the process file mode creation mask
+    unsafe { libc::umask(0o002) };
+    if !dir.exists() {
+        fs::create_dir_all(&dir)
+            .and_then(|_| fs::set_permissions(&dir, fs::Permissions::from_mode(0o2775)))
+            .context(Failure::Usage)
+            .context(format!(
+                "failed to create `{}`, create it as root with `install -d -m 2775 -g <group> {}`",
+                dir.display(),
+                dir.display()
+            ))?;
+    }
+    let _ = SYSTEM_DIR.set(dir);
+    Ok(())
+}
+
+/// `mode` made group-writable and not world-writable, like what's created under the umask of
+/// [`set_system_dir`]. Directories also stay setgid, chmod clears the bit they inherited.
+fn shared_mode(mode: u32, dir: bool) -> u32 {
+    let mode = mode & !0o002 | 0o020;
+    if dir { mode | 0o2000 } else { mode }
+}
+
+/// The mode of a file or directory extracted with `mode` from an archive, see [`shared_mode`].
+/// Modes set explicitly ignore the umask.
+fn extracted_mode(mode: u32, dir: bool) -> u32 {
+    if SYSTEM_DIR.get().is_some() {
+        shared_mode(mode, dir)
+    } else {
+        mode
+    }
+}
+
+/// Where toolchains, sysroots and kernel images are installed: `~/.toolup`, or the directory of
+/// [`set_system_dir`].
+pub fn toolup_dir() -> Result<PathBuf> {
+    match SYSTEM_DIR.get() {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(PathBuf::from(std::env::var("HOME").context("reading $HOME")?).join(".toolup")),
+    }
+}
+
+/// Set URL prefix replacements applied to every download.
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_mirrors(mirrors: HashMap<String, String>) {
+    let _ = MIRRORS.set(mirrors);
+}
+
+/// A download rate in bytes per second, written like curl's `--limit-rate`: `500k`, `2M` or `1G`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("`{s}` is an invalid rate, use e.g. `500k`, `2M` or `1G`");
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (amount, unit) = s.split_at(split);
+        let amount: u64 = amount.parse().map_err(|_| invalid())?;
+
+        let multiplier = match unit {
+            "" => 1,
+            "k" | "K" => 1 << 10,
+            "m" | "M" => 1 << 20,
+            "g" | "G" => 1 << 30,
+            _ => return Err(invalid()),
+        };
+        match amount.checked_mul(multiplier) {
+            Some(rate) if rate > 0 => Ok(Rate(rate)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (amount, unit) = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "k")]
+            .into_iter()
+            .find(|(multiplier, _)| self.0.is_multiple_of(*multiplier))
+            .map(|(multiplier, unit)| (self.0 / multiplier, unit))
+            .unwrap_or((self.0, ""));
+        write!(f, "{amount}{unit}")
+    }
+}
+
+impl TryFrom<String> for Rate {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Rate> for String {
+    fn from(rate: Rate) -> Self {
+        rate.to_string()
+    }
+}
+
+/// Throttle every download to `rate`.
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_limit_rate(rate: Rate) {
+    let _ = LIMIT_RATE.set(rate);
+}
+
+/// The program source archives are downloaded with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The built-in HTTP client
+    #[default]
+    Reqwest,
+    /// The system `curl`, which picks up its own CA, proxy and authentication setup
+    Curl,
+    /// The system `wget`
+    Wget,
+}
+
+impl Backend {
+    fn downloader(self) -> Box<dyn Downloader> {
+        match self {
+            Backend::Reqwest => Box::new(ReqwestDownloader),
+            Backend::Curl | Backend::Wget => Box::new(CommandDownloader(self)),
+        }
+    }
+}
+
+/// Download every archive with `backend` instead of the built-in HTTP client.
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_backend(backend: Backend) {
+    let _ = BACKEND.set(backend);
+}
+
+fn downloader() -> Box<dyn Downloader> {
+    BACKEND.get().copied().unwrap_or_default().downloader()
+}
+
+/// The body of a download.
+pub struct Response {
+    /// The size of the body, if the server reported one
+    pub content_length: Option<u64>,
+    pub body: Box<dyn Read>,
+}
+
+/// Fetches URLs over HTTP(S).
+pub trait Downloader {
+    /// Start downloading `url`, failing for a non-success status.
+    fn get(&self, url: &str) -> Result<Response>;
+
+    /// Returns the size of the file at `url` without downloading it.
+    fn content_length(&self, url: &str) -> Option<u64>;
+}
+
+/// Downloads with [`reqwest`].
+pub struct ReqwestDownloader;
+
+impl ReqwestDownloader {
+    fn client() -> Result<reqwest::blocking::Client> {
+        Ok(reqwest::blocking::Client::builder()
+            .user_agent("curl/8.5.0")
+            .build()?)
+    }
+}
+
+impl Downloader for ReqwestDownloader {
+    fn get(&self, url: &str) -> Result<Response> {
+        let response = Self::client()?
+            .get(url)
+            .send()
+            .context(format!("sending GET request to {}", url))?
+            .error_for_status()
+            .context(format!("non-success status from {}", url))?;
+        Ok(Response {
+            content_length: response.content_length(),
+            body: Box::new(response),
+        })
+    }
+
+    fn content_length(&self, url: &str) -> Option<u64> {
+        Self::client()
+            .ok()?
+            .head(url)
+            .send()
+            .ok()
+            .and_then(|response| response.content_length())
+    }
+}
+
+/// Downloads by running `curl` or `wget` and reading its stdout.
+pub struct CommandDownloader(Backend);
+
+impl CommandDownloader {
+    fn program(&self) -> &'static str {
+        match self.0 {
+            Backend::Curl => "curl",
+            _ => "wget",
+        }
+    }
+}
+
+impl Downloader for CommandDownloader {
+    fn get(&self, url: &str) -> Result<Response> {
+        let program = self.program();
+        let args: &[&str] = match self.0 {
+            Backend::Curl => &["--fail", "--silent", "--show-error", "--location"],
+            _ => &["--quiet", "--output-document=-"],
+        };
+        let mut child = Command::new(program)
+            .args(args)
+            .arg(url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context(format!("failed to run `{program}`"))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Response {
+            content_length: self.content_length(url),
+            body: Box::new(ChildReader {
+                program,
+                child,
+                stdout,
+            }),
+        })
+    }
+
+    fn content_length(&self, url: &str) -> Option<u64> {
+        // both print the response headers, curl on stdout and wget on stderr
+        let output = match self.0 {
+            Backend::Curl => Command::new("curl")
+                .args(["--fail", "--silent", "--head", "--location", url])
+                .output(),
+            _ => Command::new("wget")
+                .args(["--spider", "--server-response", url])
+                .output(),
+        }
+        .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let headers = [output.stdout, output.stderr].concat();
+        // with redirects, the last response is the file
+        String::from_utf8_lossy(&headers)
+            .lines()
+            .filter_map(|line| {
+                let (name, value) = line.trim().split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse().ok())?
+            })
+            .last()
+    }
+}
+
+/// The stdout of a download command, reading fails if the command exits with an error.
+struct ChildReader {
+    program: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("`{}` {status}", self.program)));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ChildReader {
+    fn drop(&mut self) {
+        // the download was abandoned before the end
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// A reader that sleeps to keep the average throughput at or below a rate.
+struct Throttled<R> {
+    inner: R,
+    rate: u64,
+    start: Instant,
+    read: u64,
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // read at most a tenth of a second worth of data at once, so the rate stays smooth
+        let max = (self.rate / 10).max(1) as usize;
+        let len = buf.len().min(max);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.read += n as u64;
+
+        let expected = Duration::from_secs_f64(self.read as f64 / self.rate as f64);
+        if let Some(ahead) = expected.checked_sub(self.start.elapsed()) {
+            std::thread::sleep(ahead);
+        }
+        Ok(n)
+    }
+}
+
+/// How many bytes were downloaded and how many were served from the archive cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferSummary {
+    pub downloaded_bytes: u64,
+    pub downloaded_archives: u64,
+    pub cached_bytes: u64,
+    pub cached_archives: u64,
+}
+
+impl Display for TransferSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "downloaded {} ({} archives), {} served from cache ({} archives)",
+            HumanBytes(self.downloaded_bytes),
+            self.downloaded_archives,
+            HumanBytes(self.cached_bytes),
+            self.cached_archives
+        )
+    }
+}
+
+/// Returns the archives fetched by this process, `None` if no archive was needed.
+pub fn transfer_summary() -> Option<TransferSummary> {
+    let summary = TransferSummary {
+        downloaded_bytes: DOWNLOADED_BYTES.load(Ordering::Relaxed),
+        downloaded_archives: DOWNLOADED_ARCHIVES.load(Ordering::Relaxed),
+        cached_bytes: CACHED_BYTES.load(Ordering::Relaxed),
+        cached_archives: CACHED_ARCHIVES.load(Ordering::Relaxed),
+    };
+    (summary != TransferSummary::default()).then_some(summary)
+}
+
+/// Returns the URL to fetch `url` from after applying the configured mirrors. The longest
+/// matching prefix wins.
+pub fn mirrored_url(url: &str) -> String {
+    let Some(mirrors) = MIRRORS.get() else {
+        return url.to_string();
+    };
+
+    mirrors
+        .iter()
+        .filter(|(prefix, _)| url.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(prefix, mirror)| format!("{}{}", mirror, &url[prefix.len()..]))
+        .unwrap_or_else(|| url.to_string())
+}
 
 pub fn cache_dir() -> Result<PathBuf> {
-    let cache =
-        PathBuf::from(std::env::var("HOME").context("reading $HOME")?).join(".cache/toolup");
+    let cache = match (CACHE_DIR.get(), SYSTEM_DIR.get()) {
+        (Some(dir), _) => dir.clone(),
+        (None, Some(system)) => system.join("cache"),
+        (None, None) => {
+            PathBuf::from(std::env::var("HOME").context("reading $HOME")?).join(".cache/toolup")
+        }
+    };
     fs::create_dir_all(&cache).context("creating toolup cache")?;
     Ok(cache)
 }
 
+/// A `:`-separated list of read-only caches, e.g. baked into a CI image.
+pub const CACHE_RO_DIRS_ENV: &str = "TOOLUP_CACHE_RO_DIRS";
+
+/// Returns the read-only caches in [`CACHE_RO_DIRS_ENV`].
+///
+/// Archives are looked up in them, in order, before [`cache_dir`]. Everything toolup writes,
+/// including sources extracted from their archives, goes to [`cache_dir`].
+pub fn ro_cache_dirs() -> Vec<PathBuf> {
+    std::env::var_os(CACHE_RO_DIRS_ENV)
+        .map(|dirs| {
+            std::env::split_paths(&dirs)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the archive `filename` from the first read-only cache that has it.
+fn ro_cached_archive(filename: &str) -> Option<PathBuf> {
+    ro_cache_dirs()
+        .into_iter()
+        .map(|dir| dir.join("archives").join(filename))
+        .find(|path| path.is_file())
+}
+
 pub fn logs_dir() -> Result<PathBuf> {
     let logs = cache_dir()?.join("logs");
     fs::create_dir_all(&logs).context("creating toolup logs dir")?;
@@ -24,13 +468,16 @@ pub fn logs_dir() -> Result<PathBuf> {
 }
 
 pub fn cross_prefix() -> Result<PathBuf> {
-    let toolchains = PathBuf::from(std::env::var("HOME").context("reading $HOME")?)
-        .join(".toolup")
-        .join("toolchains");
-    fs::create_dir_all(&toolchains).context("creating .toolup/toolchains")?;
+    let toolchains = toolup_dir()?.join("toolchains");
+    fs::create_dir_all(&toolchains).context(format!("creating {}", toolchains.display()))?;
     Ok(toolchains)
 }
 
+/// The prefix host tools built by toolup are installed into, see [`crate::packages::host_tools`].
+pub fn host_tools_dir() -> Result<PathBuf> {
+    Ok(toolup_dir()?.join("host-tools"))
+}
+
 pub enum DownloadResult {
     /// Replaced the cached file (user requested to not use cache)
     Replaced(PathBuf),
@@ -47,68 +494,80 @@ pub fn archives_dir() -> Result<PathBuf> {
 }
 
 pub fn sysroots_dir() -> Result<PathBuf> {
-    let dir = PathBuf::from(std::env::var("HOME").context("reading $HOME")?)
-        .join(".toolup")
-        .join("sysroot");
+    let dir = toolup_dir()?.join("sysroot");
     std::fs::create_dir_all(&dir)?;
     Ok(dir)
 }
 
 pub fn linux_images_dir() -> Result<PathBuf> {
-    let dir = PathBuf::from(std::env::var("HOME").context("reading $HOME")?)
-        .join(".toolup")
-        .join("linux-images");
+    let dir = toolup_dir()?.join("linux-images");
     std::fs::create_dir_all(&dir)?;
     Ok(dir)
 }
 
-/// Download an archive.
-pub fn download_archive<S: AsRef<str>>(url: S, use_cache: bool) -> Result<DownloadResult> {
-    let filename = url.as_ref().split("/").last().context(format!(
-        "couldn't derive a filename from URL: {}",
-        url.as_ref()
-    ))?;
-    let hash = &blake3::hash(url.as_ref().as_bytes()).to_hex()[..12];
+/// Returns the name `url` is cached as in [`archives_dir`].
+pub fn archive_filename(url: &str) -> Result<String> {
+    let filename = url
+        .split("/")
+        .last()
+        .context(format!("couldn't derive a filename from URL: {url}"))?;
+    let hash = &blake3::hash(url.as_bytes()).to_hex()[..12];
     // prepend the url hash to the filename
-    let filename = format!("{hash}-{filename}");
+    Ok(format!("{hash}-{filename}"))
+}
 
+/// Download an archive.
+pub fn download_archive<S: AsRef<str>>(url: S, use_cache: bool) -> Result<DownloadResult> {
     let url = url.as_ref();
+    let filename = archive_filename(url)?;
     let file_path = archives_dir()?.join(&filename);
+    // another thread may be downloading the same archive, it's cached once the lock is released
+    let _lock = locks::lock(format!("archive:{filename}"));
     let cache_exists = file_path.exists();
 
+    if use_cache && let Some(ro_path) = ro_cached_archive(&filename) {
+        log::debug!("=> using {} from a read-only cache", ro_path.display());
+        CACHED_ARCHIVES.fetch_add(1, Ordering::Relaxed);
+        CACHED_BYTES.fetch_add(fs::metadata(&ro_path)?.len(), Ordering::Relaxed);
+        return Ok(DownloadResult::Cached(ro_path));
+    }
     if use_cache && cache_exists {
+        CACHED_ARCHIVES.fetch_add(1, Ordering::Relaxed);
+        CACHED_BYTES.fetch_add(fs::metadata(&file_path)?.len(), Ordering::Relaxed);
+        cache::touch_archive(url)?;
         return Ok(DownloadResult::Cached(file_path));
     }
-    let response = reqwest::blocking::Client::builder()
-        .user_agent("curl/8.5.0")
-        .build()?
-        .get(url)
-        .send()
-        .context(format!("sending GET request to {}", url))?
-        .error_for_status()
-        .context(format!("non-success status from {}", url))?;
-
-    let style = ProgressStyle::with_template(
-        "{msg:.dim} {bar:30.green/dim} {binary_bytes:>7}/{binary_total_bytes:7}",
-    )
-    .expect("this should be a valid template")
-    .progress_chars("--");
-
-    let pb = match response.content_length() {
-        Some(size) => ProgressBar::new(size),
-        None => ProgressBar::new_spinner(),
-    };
 
-    pb.set_style(style);
-    pb.set_message(filename.clone());
+    // the cache filename is derived from the original URL so changing mirrors keeps the cache
+    let mirror = mirrored_url(url);
+    if mirror != url {
+        log::debug!("=> using mirror {mirror}");
+    }
+    let response = downloader().get(&mirror).context(Failure::Download)?;
+
+    let pb = ui::transfer_bar(response.content_length, filename.clone());
 
     let mut download_path = file_path.clone();
     download_path.add_extension("download");
 
     let mut dest = File::create(&download_path).context(format!("creating {}", filename))?;
+    let response: Box<dyn Read> = match LIMIT_RATE.get() {
+        Some(rate) => Box::new(Throttled {
+            inner: response.body,
+            rate: rate.0,
+            start: Instant::now(),
+            read: 0,
+        }),
+        None => response.body,
+    };
     let mut source = pb.wrap_read(response);
-    io::copy(&mut source, &mut dest).context(format!("writing {}", filename))?;
+    let size = io::copy(&mut source, &mut dest)
+        .context(Failure::Download)
+        .context(format!("writing {}", filename))?;
+    DOWNLOADED_ARCHIVES.fetch_add(1, Ordering::Relaxed);
+    DOWNLOADED_BYTES.fetch_add(size, Ordering::Relaxed);
     std::fs::rename(&download_path, &file_path).context("moving .download file")?;
+    cache::record_archive(url, &file_path)?;
 
     pb.finish();
 
@@ -119,7 +578,373 @@ pub fn download_archive<S: AsRef<str>>(url: S, use_cache: bool) -> Result<Downlo
     }
 }
 
+/// Limits applied while extracting an archive, see [`decompress_tar_with`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractPolicy {
+    /// The maximum size of a single file
+    pub max_entry_size: u64,
+    /// The maximum size of all files together
+    pub max_total_size: u64,
+    /// The maximum number of components in an entry path
+    pub max_depth: usize,
+}
+
+impl Default for ExtractPolicy {
+    fn default() -> Self {
+        // source trees are big (a kernel is ~1.5 GiB extracted) but nowhere near these
+        Self {
+            max_entry_size: 4 << 30,
+            max_total_size: 32 << 30,
+            max_depth: 64,
+        }
+    }
+}
+
+/// Whether `path` (relative to the extraction root) stays inside the root.
+fn stays_inside(path: &Path) -> bool {
+    let mut depth: usize = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Check an entry against `policy` before it's extracted. Returns `false` for entries that carry
+/// no files (e.g. pax global headers).
+fn check_entry<R: Read>(entry: &tar::Entry<R>, policy: &ExtractPolicy) -> Result<bool> {
+    let path = entry.path()?;
+    if path.components().count() > policy.max_depth {
+        bail!("the path is deeper than {} components", policy.max_depth);
+    }
+    if !stays_inside(&path) {
+        bail!("the path escapes the extraction directory");
+    }
+
+    match entry.header().entry_type() {
+        EntryType::Regular | EntryType::Continuous => {
+            let size = entry.header().size()?;
+            if size > policy.max_entry_size {
+                bail!(
+                    "the file is {}, larger than the {} limit",
+                    HumanBytes(size),
+                    HumanBytes(policy.max_entry_size)
+                );
+            }
+        }
+        EntryType::Directory => {}
+        EntryType::Symlink => {
+            let target = entry.link_name()?.context("the symlink has no target")?;
+            // symlinks are relative to the directory they are in
+            let resolved = path.parent().unwrap_or(Path::new("")).join(&target);
+            if target.is_absolute() || !stays_inside(&resolved) {
+                bail!(
+                    "the symlink to `{}` points outside the extraction directory",
+                    target.display()
+                );
+            }
+        }
+        EntryType::Link => {
+            // hard links are relative to the extraction directory
+            let target = entry.link_name()?.context("the hard link has no target")?;
+            if !stays_inside(&target) {
+                bail!(
+                    "the hard link to `{}` points outside the extraction directory",
+                    target.display()
+                );
+            }
+        }
+        EntryType::XGlobalHeader => return Ok(false),
+        other => bail!("{other:?} entries are not allowed"),
+    }
+    Ok(true)
+}
+
+/// The file that exists while `archive` is being extracted into `dest_dir`, see
+/// [`is_extracted`].
+pub fn extraction_stamp(dest_dir: &Path, archive: &str) -> PathBuf {
+    dest_dir.join(format!(".{archive}.extracting"))
+}
+
+/// Whether `dirname` was fully extracted from the archive of `url` into the cache. A directory
+/// left by an interrupted extraction is removed.
+pub fn is_extracted(url: &str, dirname: &str) -> Result<bool> {
+    let dir = cache_dir()?.join(dirname);
+    if !dir.exists() {
+        return Ok(false);
+    }
+    let stamp = extraction_stamp(&cache_dir()?, &archive_filename(url)?);
+    if !stamp.exists() || is_plan() {
+        return Ok(true);
+    }
+
+    log::warn!("=> {dirname} was partially extracted, extracting it again");
+    fs::remove_dir_all(&dir).context(format!("failed to remove `{}`", dir.display()))?;
+    Ok(false)
+}
+
+/// The compression of a tarball.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Xz,
+    Gzip,
+    Bzip2,
+    Zstd,
+    Lzip,
+}
+
+impl Compression {
+    /// Detect the compression from the first bytes of a file, mirrors don't always keep the
+    /// extension of the original file.
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        const MAGIC: &[(&[u8], Compression)] = &[
+            (b"\xFD7zXZ\x00", Compression::Xz),
+            (b"\x1F\x8B", Compression::Gzip),
+            (b"BZh", Compression::Bzip2),
+            (b"\x28\xB5\x2F\xFD", Compression::Zstd),
+            (b"LZIP", Compression::Lzip),
+        ];
+        MAGIC
+            .iter()
+            .find(|(prefix, _)| magic.starts_with(prefix))
+            .map(|(_, compression)| *compression)
+    }
+}
+
+/// Files up to this size are read into memory and written by a writer thread, bigger ones are
+/// written while reading the archive.
+const MAX_PENDING_FILE: u64 = 16 << 20;
+
+/// A regular file read from an archive, waiting to be written by a writer thread.
+struct PendingFile {
+    path: PathBuf,
+    data: Vec<u8>,
+    mode: u32,
+    mtime: u64,
+}
+
+impl PendingFile {
+    fn write(&self) -> Result<()> {
+        let mut file =
+            File::create(&self.path).context(format!("creating {}", self.path.display()))?;
+        file.write_all(&self.data)
+            .context(format!("writing {}", self.path.display()))?;
+        file.set_permissions(fs::Permissions::from_mode(extracted_mode(self.mode, false)))?;
+        // autotools decide what to regenerate from timestamps, they have to match the archive
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(self.mtime))?;
+        Ok(())
+    }
+}
+
+/// The number of threads writing extracted files.
+fn extraction_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get().min(8))
+}
+
+/// Whether `xz` is installed, it decompresses on every core while [`XzDecoder`] uses one.
+fn has_xz() -> bool {
+    static HAS_XZ: OnceLock<bool> = OnceLock::new();
+    *HAS_XZ.get_or_init(|| {
+        Command::new("xz")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    })
+}
+
+/// Decompress `path` with `xz -T0`.
+fn xz_decoder(path: &Path) -> Result<ChildReader> {
+    let mut child = Command::new("xz")
+        .args(["--decompress", "--stdout", "--threads=0"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("failed to run `xz`")?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok(ChildReader {
+        program: "xz",
+        child,
+        stdout,
+    })
+}
+
+/// Check that the hard link `link` to `target` (both relative to `dest_dir`) is created in and
+/// points to a file inside `dest_dir`, through the symlinks extracted before it. The lexical
+/// check of [`check_entry`] doesn't follow them. The parent of `link` is created if needed.
+fn check_hard_link(dest_dir: &Path, link: &Path, target: &Path) -> Result<()> {
+    let root = dest_dir.canonicalize()?;
+    let parent = dest_dir.join(link);
+    let parent = parent.parent().context("the hard link has no name")?;
+    if !has_symlinked_parent(dest_dir, link) {
+        fs::create_dir_all(parent).context(format!("creating directory {}", parent.display()))?;
+    }
+    for (what, path) in [("is created", parent), ("points", &dest_dir.join(target))] {
+        let resolved = path
+            .canonicalize()
+            .context(format!("failed to resolve `{}`", path.display()))?;
+        if !resolved.starts_with(&root) {
+            bail!(
+                "the hard link {what} outside of `{}`, through `{}`",
+                dest_dir.display(),
+                resolved.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Whether a directory between `dest_dir` and the entry at `relative` is a symlink.
+///
+/// Only [`tar::Entry::unpack_in`] refuses to write through a symlink that leads outside of
+/// `dest_dir`, so the entries below one are never handed to the writer threads.
+fn has_symlinked_parent(dest_dir: &Path, relative: &Path) -> bool {
+    let Some(parent) = relative.parent() else {
+        return false;
+    };
+    let mut dir = dest_dir.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return true,
+            Ok(_) => {}
+            // nothing below a directory that doesn't exist yet does either
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+/// The writer thread that writes the file at `path`. Entries of the same path always go to the
+/// same writer, which writes them in the order of the archive.
+fn writer_for(path: &Path, writers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    (hasher.finish() % writers as u64) as usize
+}
+
+/// Extract the entries of `archive`, handing regular files to the writer threads through `files`.
+/// Returns the hard links, which can only be created after every file was written.
+///
+/// A path that appears again after it was handed to a writer thread goes to the same one, so the
+/// last entry wins like with `tar`. Entries that can't (e.g. a symlink replacing the file) are
+/// refused.
+fn extract_entries<R: Read>(
+    archive: &mut Archive<R>,
+    dest_dir: &Path,
+    policy: &ExtractPolicy,
+    archive_name: &str,
+    pb_entry: &ProgressBar,
+    files: &[mpsc::SyncSender<PendingFile>],
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut hard_links = vec![];
+    let mut queued = HashSet::new();
+    let mut total: u64 = 0;
+    for entry_res in archive
+        .entries()
+        .context(format!("reading the entries of {archive_name}"))?
+    {
+        let mut entry = entry_res.context(format!("reading an entry of {archive_name}"))?;
+        let path = entry.path()?.display().to_string();
+        pb_entry.set_message(path.clone());
+
+        if !check_entry(&entry, policy)
+            .context(format!("refusing to extract `{path}` from {archive_name}"))?
+        {
+            continue;
+        }
+        let size = entry.header().size()?;
+        total += size;
+        if total > policy.max_total_size {
+            bail!(
+                "{archive_name} is larger than the {} limit",
+                HumanBytes(policy.max_total_size)
+            );
+        }
+
+        let entry_type = entry.header().entry_type();
+        if entry_type == EntryType::Link {
+            let target = entry.link_name()?.context("the hard link has no target")?;
+            hard_links.push((entry.path()?.into_owned(), target.into_owned()));
+            continue;
+        }
+
+        let relative = entry.path()?.into_owned();
+        let was_queued = queued.contains(&relative);
+        let is_file = matches!(entry_type, EntryType::Regular | EntryType::Continuous);
+        if is_file
+            && (size <= MAX_PENDING_FILE || was_queued)
+            && !has_symlinked_parent(dest_dir, &relative)
+        {
+            let dest = dest_dir.join(&relative);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)
+                    .context(format!("creating directory {}", parent.display()))?;
+            }
+            let mut data = Vec::with_capacity(size as usize);
+            entry
+                .read_to_end(&mut data)
+                .context(format!("failed to read `{path}` from {archive_name}"))?;
+            let file = PendingFile {
+                path: dest,
+                data,
+                mode: entry.header().mode()? & 0o777,
+                mtime: entry.header().mtime()?,
+            };
+            files[writer_for(&relative, files.len())]
+                .send(file)
+                .expect("the writer threads outlive the extraction");
+            queued.insert(relative);
+            continue;
+        }
+        if was_queued {
+            bail!("`{path}` in {archive_name} replaces a file extracted before it");
+        }
+
+        if !entry
+            .unpack_in(dest_dir)
+            .context(format!("failed to extract `{path}` from {archive_name}"))?
+        {
+            bail!("failed to extract `{path}` from {archive_name}: the entry was skipped");
+        }
+        let dir = entry_type == EntryType::Directory;
+        if SYSTEM_DIR.get().is_some() && (is_file || dir) {
+            // inside `dest_dir`, `unpack_in` refused anything else
+            let mode = shared_mode(entry.header().mode()? & 0o777, dir);
+            fs::set_permissions(dest_dir.join(&relative), fs::Permissions::from_mode(mode))
+                .context(format!("failed to set the mode of `{path}`"))?;
+        }
+    }
+    Ok(hard_links)
+}
+
+/// Extract an archive with the default [`ExtractPolicy`].
 pub fn decompress_tar<P: AsRef<Path>, Q: AsRef<Path>>(tar_xz_path: P, dest_dir: Q) -> Result<()> {
+    decompress_tar_with(tar_xz_path, dest_dir, &ExtractPolicy::default())
+}
+
+/// Extract a `.tar.{xz,gz,bz2,zst,lz}` archive into `dest_dir`, rejecting entries that break `policy` or
+/// would be written outside `dest_dir`.
+///
+/// Files are written by a pool of threads, and `.tar.xz` archives are decompressed with `xz -T0`
+/// when it's installed.
+///
+/// A stamp (see [`extraction_stamp`]) marks the extraction as in progress until every entry was
+/// written.
+pub fn decompress_tar_with<P: AsRef<Path>, Q: AsRef<Path>>(
+    tar_xz_path: P,
+    dest_dir: Q,
+    policy: &ExtractPolicy,
+) -> Result<()> {
     let tar_xz_path = tar_xz_path.as_ref();
     let dest_dir = dest_dir.as_ref();
 
@@ -128,49 +953,169 @@ pub fn decompress_tar<P: AsRef<Path>, Q: AsRef<Path>>(tar_xz_path: P, dest_dir:
         dest_dir.display()
     ))?;
 
-    let file = File::open(tar_xz_path).context(format!("opening {}", tar_xz_path.display()))?;
+    let archive_name = tar_xz_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let stamp = extraction_stamp(dest_dir, &archive_name);
+    File::create(&stamp).context(format!("failed to create `{}`", stamp.display()))?;
 
-    let mp = MultiProgress::new();
+    let file = File::open(tar_xz_path).context(format!("opening {}", tar_xz_path.display()))?;
 
-    let pb_entry = mp.add(ProgressBar::new_spinner());
-    pb_entry.set_style(ProgressStyle::with_template("{spinner:.dim} {msg:.dim}")?);
-    pb_entry.enable_steady_tick(Duration::from_millis(100));
+    let pb_entry = ui::spinner(archive_name.clone());
 
     // stream-decompress and extract
-    let reader = BufReader::new(file);
-    let reader = pb_entry.wrap_read(reader);
-    let decoder: Box<dyn std::io::Read> = match tar_xz_path.extension().unwrap().to_str().unwrap() {
-        "xz" => Box::new(XzDecoder::new_multi_decoder(reader)),
-        "gz" => Box::new(GzDecoder::new(reader)),
-        "bz2" => Box::new(bzip2::read::BzDecoder::new(reader)),
-        _ => unimplemented!(),
+    let mut reader = BufReader::new(pb_entry.wrap_read(file));
+    let compression = Compression::detect(reader.fill_buf()?).context(format!(
+        "`{archive_name}` is not a .tar.xz, .tar.gz, .tar.bz2, .tar.zst or .tar.lz archive"
+    ))?;
+    let decoder: Box<dyn Read> = match compression {
+        Compression::Xz if has_xz() => Box::new(xz_decoder(tar_xz_path)?),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+        Compression::Gzip => Box::new(GzDecoder::new(reader)),
+        Compression::Bzip2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Compression::Lzip => Box::new(XzDecoder::new_stream(
+            reader,
+            Stream::new_lzip_decoder(u64::MAX, CONCATENATED)?,
+        )),
     };
     let mut archive = Archive::new(decoder);
 
-    for entry_res in archive.entries().context("reading .tar entries")? {
-        let mut entry = entry_res.context("reading a .tar entry")?;
-        if let Ok(path) = entry.path() {
-            pb_entry.set_message(path.display().to_string());
+    let write_error: Mutex<Option<anyhow::Error>> = Mutex::new(None);
+
+    let extracted = thread::scope(|scope| {
+        // a channel per writer, see `writer_for`
+        let mut senders = vec![];
+        for _ in 0..extraction_threads() {
+            let (sender, receiver) = mpsc::sync_channel::<PendingFile>(4);
+            senders.push(sender);
+            let write_error = &write_error;
+            scope.spawn(move || {
+                // keep draining after an error so the reader never blocks on a full channel
+                for file in receiver {
+                    if let Err(err) = file.write() {
+                        write_error
+                            .lock()
+                            .expect("the lock is not poisoned")
+                            .get_or_insert(err);
+                    }
+                }
+            });
         }
-        entry.unpack_in(dest_dir).context("extracting entry")?;
+        let extracted = extract_entries(
+            &mut archive,
+            dest_dir,
+            policy,
+            &archive_name,
+            &pb_entry,
+            &senders,
+        );
+        drop(senders);
+        extracted
+    });
+    let hard_links = extracted?;
+    if let Some(err) = write_error.into_inner().expect("the lock is not poisoned") {
+        return Err(err.context(format!("failed to extract {archive_name}")));
+    }
+
+    // the files they point to are only guaranteed to exist once every writer is done
+    for (link, target) in hard_links {
+        check_hard_link(dest_dir, &link, &target).context(format!(
+            "refusing to extract `{}` from {archive_name}",
+            link.display()
+        ))?;
+        fs::hard_link(dest_dir.join(&target), dest_dir.join(&link)).context(format!(
+            "failed to link `{}` to `{}` from {archive_name}",
+            link.display(),
+            target.display()
+        ))?;
     }
 
     pb_entry.finish_and_clear();
+    fs::remove_file(&stamp).context(format!("failed to remove `{}`", stamp.display()))?;
 
     Ok(())
 }
 
+/// Returns the URLs of every archive that plan mode went through, including the ones that are
+/// already extracted in the cache.
+pub fn planned_urls() -> Vec<String> {
+    PLANNED_URLS
+        .lock()
+        .expect("the lock is not poisoned")
+        .clone()
+}
+
+/// Print the download that would happen in plan mode, including its size if the server reports
+/// one.
+fn plan_download(url: &str, dirname: &str) -> Result<()> {
+    static PLANNED: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+    let mut planned = PLANNED.lock().expect("the lock is not poisoned");
+    if planned.iter().any(|d| d == dirname) {
+        return Ok(());
+    }
+    planned.push(dirname.to_string());
+    if is_plan_quiet() {
+        return Ok(());
+    }
+
+    let url = mirrored_url(url);
+    let size = downloader().content_length(&url);
+
+    plan_step(match size {
+        Some(size) => format!("download {url} ({})", HumanBytes(size)),
+        None => format!("download {url}"),
+    });
+    plan_step(format!(
+        "extract into {}",
+        cache_dir()?.join(dirname).display()
+    ));
+    Ok(())
+}
+
+/// The lock held while the source extracted to `dirname` is downloaded and extracted.
+pub fn lock_source(dirname: &str) -> NamedLock {
+    locks::lock(format!("source:{dirname}"))
+}
+
 /// Returns the extracted directory path.
 pub fn download_and_decompress(
     url: impl AsRef<str>,
     dirname: impl AsRef<str>,
     use_cache: bool,
 ) -> Result<PathBuf> {
-    if cache_dir()?.join(dirname.as_ref()).exists() {
+    provenance::record_source(url.as_ref(), dirname.as_ref());
+    let _lock = lock_source(dirname.as_ref());
+    extract_source(url, dirname, use_cache)
+}
+
+/// [`download_and_decompress`] for a caller that holds the [`lock_source`] of `dirname`.
+pub fn extract_source(
+    url: impl AsRef<str>,
+    dirname: impl AsRef<str>,
+    use_cache: bool,
+) -> Result<PathBuf> {
+    if is_plan() {
+        let mut planned = PLANNED_URLS.lock().expect("the lock is not poisoned");
+        if !planned.iter().any(|u| u == url.as_ref()) {
+            planned.push(url.as_ref().to_string());
+        }
+    }
+
+    if is_extracted(url.as_ref(), dirname.as_ref())? {
+        cache::touch_source(dirname.as_ref())?;
         return Ok(cache_dir()?.join(dirname.as_ref()));
     }
 
-    let download_result = download_archive(url, use_cache)?;
+    if is_plan() {
+        plan_download(url.as_ref(), dirname.as_ref())?;
+        return Ok(cache_dir()?.join(dirname.as_ref()));
+    }
+
+    let download_result = download_archive(url.as_ref(), use_cache)?;
     let archive_path = match download_result {
         DownloadResult::Cached(p) => {
             log::debug!("=> using cached {}", dirname.as_ref());
@@ -180,6 +1125,257 @@ pub fn download_and_decompress(
     };
 
     decompress_tar(archive_path, cache_dir()?)?;
+    cache::record_source(url.as_ref(), dirname.as_ref())?;
 
     Ok(cache_dir()?.join(dirname.as_ref()))
 }
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use std::path::Path;
+
+    use flate2::write::GzEncoder;
+
+    use super::{
+        ChildReader, Compression, Rate, decompress_tar, extraction_stamp, shared_mode, stays_inside,
+    };
+
+    #[test]
+    fn test_shared_mode() {
+        assert_eq!(shared_mode(0o644, false), 0o664);
+        assert_eq!(shared_mode(0o755, false), 0o775);
+        assert_eq!(shared_mode(0o666, false), 0o664);
+        assert_eq!(shared_mode(0o755, true), 0o2775);
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(Rate::from_str("500k").unwrap(), Rate(500 * 1024));
+        assert_eq!(Rate::from_str("2M").unwrap(), Rate(2 * 1024 * 1024));
+        assert_eq!(Rate::from_str("4096").unwrap(), Rate(4096));
+        assert_eq!(Rate::from_str("2M").unwrap().to_string(), "2M");
+        assert!(Rate::from_str("0").is_err());
+        assert!(Rate::from_str("k").is_err());
+        assert!(Rate::from_str("5T").is_err());
+    }
+
+    #[test]
+    fn test_child_reader_fails_with_the_command() -> anyhow::Result<()> {
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "printf partial; exit 22"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        let mut reader = ChildReader {
+            program: "sh",
+            child,
+            stdout,
+        };
+
+        let mut body = vec![];
+        let err = std::io::Read::read_to_end(&mut reader, &mut body).unwrap_err();
+        assert_eq!(body, b"partial");
+        assert!(err.to_string().contains("22"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_compression() {
+        assert_eq!(
+            Compression::detect(b"\xFD7zXZ\x00\x00"),
+            Some(Compression::Xz)
+        );
+        assert_eq!(
+            Compression::detect(b"\x28\xB5\x2F\xFD\x04"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(
+            Compression::detect(b"LZIP\x01\x0C"),
+            Some(Compression::Lzip)
+        );
+        assert_eq!(Compression::detect(b"ustar"), None);
+    }
+
+    #[test]
+    fn test_stays_inside() {
+        assert!(stays_inside(Path::new("gcc-15.2.0/gcc/../README")));
+        assert!(!stays_inside(Path::new("gcc-15.2.0/../../etc/passwd")));
+        assert!(!stays_inside(Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn test_extract_zstd() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        // no extension, the compression is detected from the content
+        let archive = dir.path().join("hello-1.0.tar");
+
+        let mut builder =
+            tar::Builder::new(zstd::Encoder::new(std::fs::File::create(&archive)?, 3)?);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "hello-1.0/README", &b"hello"[..])?;
+        builder.into_inner()?.finish()?;
+
+        let dest = dir.path().join("out");
+        decompress_tar(&archive, &dest)?;
+        assert_eq!(std::fs::read(dest.join("hello-1.0/README"))?, b"hello");
+        assert!(!extraction_stamp(&dest, "hello-1.0.tar").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_keeps_modes_mtimes_and_hard_links() -> anyhow::Result<()> {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::TempDir::new()?;
+        let archive = dir.path().join("hello-1.0.tar.gz");
+
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            std::fs::File::create(&archive)?,
+            flate2::Compression::fast(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(10);
+        header.set_mode(0o755);
+        header.set_mtime(1_700_000_000);
+        builder.append_data(&mut header, "hello-1.0/configure", &b"#!/bin/sh\n"[..])?;
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, "hello-1.0/config.sh", "hello-1.0/configure")?;
+        builder.into_inner()?.finish()?;
+
+        let dest = dir.path().join("out");
+        decompress_tar(&archive, &dest)?;
+        let configure = std::fs::metadata(dest.join("hello-1.0/configure"))?;
+        assert_eq!(configure.permissions().mode() & 0o777, 0o755);
+        assert_eq!(configure.mtime(), 1_700_000_000);
+        let link = std::fs::metadata(dest.join("hello-1.0/config.sh"))?;
+        assert_eq!(link.ino(), configure.ino());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_escaping_symlink() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let archive = dir.path().join("evil-1.0.tar.gz");
+
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            std::fs::File::create(&archive)?,
+            flate2::Compression::fast(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "evil-1.0/passwd", "../../etc/passwd")?;
+        builder.into_inner()?.finish()?;
+
+        let dest = dir.path().join("out");
+        let err = decompress_tar(&archive, &dest).unwrap_err();
+        assert!(format!("{err:#}").contains("`evil-1.0/passwd`"), "{err:#}");
+        assert!(!dest.join("evil-1.0/passwd").exists());
+        // the extraction is left marked as partial
+        assert!(extraction_stamp(&dest, "evil-1.0.tar.gz").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_file_through_escaping_symlinks() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let archive = dir.path().join("evil-1.0.tar.gz");
+
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            std::fs::File::create(&archive)?,
+            flate2::Compression::fast(),
+        ));
+        // each symlink stays inside on its own, `evil-1.0/up/out` is the parent of `out`
+        for (path, target) in [("evil-1.0/up", ".."), ("evil-1.0/up/out", "..")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            builder.append_link(&mut header, path, target)?;
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "evil-1.0/up/out/pwned", &b"pwned"[..])?;
+        builder.into_inner()?.finish()?;
+
+        let dest = dir.path().join("out");
+        let err = decompress_tar(&archive, &dest).unwrap_err();
+        assert!(format!("{err:#}").contains("pwned"), "{err:#}");
+        assert!(!dir.path().join("pwned").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_hard_links_through_escaping_symlinks() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        std::fs::write(dir.path().join("secret"), b"secret")?;
+
+        // a link created outside, and a link to a file outside
+        for (link, target) in [
+            ("evil-1.0/up/out/pwned", "evil-1.0/README"),
+            ("evil-1.0/secret", "evil-1.0/up/out/secret"),
+        ] {
+            let archive = dir.path().join("evil-1.0.tar.gz");
+            let mut builder = tar::Builder::new(GzEncoder::new(
+                std::fs::File::create(&archive)?,
+                flate2::Compression::fast(),
+            ));
+            for (path, target) in [("evil-1.0/up", ".."), ("evil-1.0/up/out", "..")] {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target)?;
+            }
+            let mut header = tar::Header::new_gnu();
+            header.set_size(5);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, "evil-1.0/README", &b"hello"[..])?;
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Link);
+            header.set_size(0);
+            builder.append_link(&mut header, link, target)?;
+            builder.into_inner()?.finish()?;
+
+            let dest = dir.path().join("out");
+            let err = decompress_tar(&archive, &dest).unwrap_err();
+            assert!(format!("{err:#}").contains(link), "{err:#}");
+            assert!(!dir.path().join("pwned").exists());
+            assert!(!dest.join("evil-1.0/secret").exists());
+            std::fs::remove_dir_all(&dest)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_duplicate_entries_in_order() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let archive = dir.path().join("hello-1.0.tar.gz");
+
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            std::fs::File::create(&archive)?,
+            flate2::Compression::fast(),
+        ));
+        for i in 0..64 {
+            let data = format!("version {i}");
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, "hello-1.0/README", data.as_bytes())?;
+        }
+        builder.into_inner()?.finish()?;
+
+        let dest = dir.path().join("out");
+        decompress_tar(&archive, &dest)?;
+        assert_eq!(
+            std::fs::read_to_string(dest.join("hello-1.0/README"))?,
+            "version 63"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,464 @@
+//! A minimal ELF reader, enough to tell which architecture a program was built for and what it
+//! needs at runtime: its dynamic loader (`PT_INTERP`) and libraries (`DT_NEEDED`).
+use std::{path::Path, str::FromStr};
+
+use anyhow::{Context, Result, bail};
+
+use crate::profile::{Abi, Arch, Target};
+
+const ELFOSABI_NONE: u8 = 0;
+const ELFOSABI_LINUX: u8 = 3;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+
+const EM_386: u16 = 3;
+const EM_PPC64: u16 = 21;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elf {
+    pub is_64: bool,
+    pub big_endian: bool,
+    /// `EI_OSABI`, Linux programs use `ELFOSABI_NONE` or `ELFOSABI_LINUX`
+    pub osabi: u8,
+    /// `e_type`, e.g. an executable, a shared object or a relocatable object
+    pub kind: u16,
+    /// `e_machine`
+    pub machine: u16,
+    /// The dynamic loader, `None` for static programs
+    pub interpreter: Option<String>,
+    /// The libraries it's linked against, in order
+    pub needed: Vec<String>,
+    /// `e_entry`
+    pub entry: u64,
+    /// The lowest physical address a segment with file contents is loaded at, where the output
+    /// of `objcopy -O binary` has to be loaded
+    pub load_address: Option<u64>,
+}
+
+/// Bounds-checked reads of the fields of an ELF file.
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    big_endian: bool,
+}
+
+/// The result of offset arithmetic on values read from the file, which fails on malformed ones
+/// instead of overflowing.
+fn checked(offset: Option<u64>) -> Result<u64> {
+    match offset {
+        Some(offset) => Ok(offset),
+        None => bail!("truncated/malformed ELF, an offset overflows"),
+    }
+}
+
+impl Reader<'_> {
+    fn bytes(&self, at: u64, len: u64) -> Result<&[u8]> {
+        let start = usize::try_from(at)?;
+        let end = start
+            .checked_add(usize::try_from(len)?)
+            .context("truncated/malformed ELF, an offset overflows")?;
+        self.data
+            .get(start..end)
+            .context(format!("truncated ELF file, {end} is past the end"))
+    }
+
+    fn uint<const N: usize>(&self, at: u64) -> Result<[u8; N]> {
+        let mut bytes: [u8; N] = self.bytes(at, N as u64)?.try_into()?;
+        if self.big_endian {
+            bytes.reverse();
+        }
+        Ok(bytes)
+    }
+
+    fn u16(&self, at: u64) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.uint(at)?))
+    }
+
+    fn u32(&self, at: u64) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.uint(at)?))
+    }
+
+    fn u64(&self, at: u64) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.uint(at)?))
+    }
+
+    /// A 32 or 64-bit field, depending on the class.
+    fn word(&self, at: u64) -> Result<u64> {
+        if self.is_64 {
+            self.u64(at)
+        } else {
+            self.u32(at).map(u64::from)
+        }
+    }
+
+    /// The NUL-terminated string at `at`.
+    fn str(&self, at: u64) -> Result<String> {
+        let rest = self
+            .data
+            .get(usize::try_from(at)?..)
+            .context(format!("truncated ELF file, {at} is past the end"))?;
+        let end = rest
+            .iter()
+            .position(|&b| b == 0)
+            .context("unterminated string")?;
+        Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+    }
+}
+
+/// A program header.
+struct Segment {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+}
+
+impl Elf {
+    /// Parse the headers of an ELF file, returns `None` if `data` isn't one.
+    pub fn parse(data: &[u8]) -> Result<Option<Self>> {
+        if data.len() < 16 || !data.starts_with(b"\x7fELF") {
+            return Ok(None);
+        }
+        let reader = Reader {
+            data,
+            is_64: match data[4] {
+                1 => false,
+                2 => true,
+                class => bail!("unknown ELF class {class}"),
+            },
+            big_endian: match data[5] {
+                1 => false,
+                2 => true,
+                encoding => bail!("unknown ELF data encoding {encoding}"),
+            },
+        };
+
+        let kind = reader.u16(16)?;
+        let machine = reader.u16(18)?;
+        let (phoff, phentsize, phnum) = if reader.is_64 {
+            (reader.u64(32)?, reader.u16(54)?, reader.u16(56)?)
+        } else {
+            (u64::from(reader.u32(28)?), reader.u16(42)?, reader.u16(44)?)
+        };
+        let mut segments = vec![];
+        for i in 0..u64::from(phnum) {
+            // the fields after the first one can't overflow, `at` is inside the file once it's read
+            let at = checked(
+                i.checked_mul(u64::from(phentsize))
+                    .and_then(|offset| phoff.checked_add(offset)),
+            )?;
+            let segment = if reader.is_64 {
+                Segment {
+                    kind: reader.u32(at)?,
+                    offset: reader.u64(at + 8)?,
+                    vaddr: reader.u64(at + 16)?,
+                    paddr: reader.u64(at + 24)?,
+                    filesz: reader.u64(at + 32)?,
+                }
+            } else {
+                Segment {
+                    kind: reader.u32(at)?,
+                    offset: u64::from(reader.u32(at + 4)?),
+                    vaddr: u64::from(reader.u32(at + 8)?),
+                    paddr: u64::from(reader.u32(at + 12)?),
+                    filesz: u64::from(reader.u32(at + 16)?),
+                }
+            };
+            checked(segment.offset.checked_add(segment.filesz))?;
+            checked(segment.vaddr.checked_add(segment.filesz))?;
+            segments.push(segment);
+        }
+
+        let load_address = segments
+            .iter()
+            .filter(|segment| segment.kind == PT_LOAD && segment.filesz > 0)
+            .map(|segment| segment.paddr)
+            .min();
+
+        let interpreter = segments
+            .iter()
+            .find(|segment| segment.kind == PT_INTERP)
+            .map(|segment| reader.str(segment.offset))
+            .transpose()?;
+
+        let mut needed = vec![];
+        if let Some(dynamic) = segments.iter().find(|segment| segment.kind == PT_DYNAMIC) {
+            let entry_size = if reader.is_64 { 16 } else { 8 };
+            let mut strtab = None;
+            let mut names = vec![];
+            for i in 0..dynamic.filesz / entry_size {
+                // within `offset + filesz`, which doesn't overflow
+                let at = dynamic.offset + i * entry_size;
+                let tag = reader.word(at)?;
+                let value = reader.word(at + entry_size / 2)?;
+                match tag {
+                    DT_NULL => break,
+                    DT_NEEDED => names.push(value),
+                    DT_STRTAB => strtab = Some(value),
+                    _ => {}
+                }
+            }
+            if !names.is_empty() {
+                // the string table is given as an address, find where it's loaded from
+                let strtab = strtab.context("DT_NEEDED without a string table")?;
+                let strtab = segments
+                    .iter()
+                    .find(|segment| {
+                        segment.kind == PT_LOAD
+                            && (segment.vaddr..segment.vaddr + segment.filesz).contains(&strtab)
+                    })
+                    .map(|segment| strtab - segment.vaddr + segment.offset)
+                    .context("the string table isn't in a loaded segment")?;
+                for name in names {
+                    needed.push(reader.str(checked(strtab.checked_add(name))?)?);
+                }
+            }
+        }
+
+        Ok(Some(Self {
+            is_64: reader.is_64,
+            big_endian: reader.big_endian,
+            osabi: data[7],
+            kind,
+            machine,
+            interpreter,
+            needed,
+            entry: reader.word(24)?,
+            load_address,
+        }))
+    }
+
+    /// Read the headers of the ELF file at `path`, returns `None` if it isn't one.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let data = std::fs::read(path).context(format!("failed to read `{}`", path.display()))?;
+        Self::parse(&data).context(format!("failed to parse `{}`", path.display()))
+    }
+
+    /// e.g. "64-bit little endian aarch64"
+    pub fn describe(&self) -> String {
+        let machine = match self.machine {
+            EM_386 => "i386".to_string(),
+            EM_PPC64 => "ppc64".to_string(),
+            EM_ARM => "arm".to_string(),
+            EM_X86_64 => "x86_64".to_string(),
+            EM_AARCH64 => "aarch64".to_string(),
+            EM_RISCV => "riscv".to_string(),
+            other => format!("machine {other}"),
+        };
+        format!(
+            "{}-bit {} endian {machine}",
+            if self.is_64 { 64 } else { 32 },
+            if self.big_endian { "big" } else { "little" },
+        )
+    }
+
+    /// Check that this is a program for the architecture, OS and libc of `target`.
+    pub fn check_runs_on(&self, target: &Target) -> Result<()> {
+        if !matches!(self.kind, ET_EXEC | ET_DYN) {
+            bail!("it's not an executable (ELF type {})", self.kind);
+        }
+        if !self.runs_on(target) {
+            bail!("it's a {} program, the VM is {target}", self.describe());
+        }
+        if !matches!(self.osabi, ELFOSABI_NONE | ELFOSABI_LINUX) {
+            bail!("it's built for another OS (ELF OS ABI {})", self.osabi);
+        }
+        let libc = |musl: bool| if musl { "musl" } else { "glibc" };
+        match self.is_musl() {
+            Some(is_musl) if is_musl != target.is_musl() => bail!(
+                "it's linked against {}, {target} uses {}",
+                libc(is_musl),
+                libc(target.is_musl())
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// The freestanding target a bare-metal program was built for, e.g. `riscv64-elf`, `None` if
+    /// toolup has no freestanding toolchain for its architecture.
+    pub fn freestanding_target(&self) -> Option<Target> {
+        let triple = match (self.machine, self.is_64, self.big_endian) {
+            (EM_X86_64, true, false) => "x86_64-elf",
+            (EM_386, false, false) => "i686-elf",
+            (EM_AARCH64, true, false) => "aarch64-elf",
+            (EM_RISCV, true, false) => "riscv64-elf",
+            (EM_ARM, false, false) => "armv7-unknown-none-eabihf",
+            _ => return None,
+        };
+        Target::from_str(triple).ok()
+    }
+
+    /// Whether the machine, class and byte order match `target`.
+    fn runs_on(&self, target: &Target) -> bool {
+        let (machine, is_64, big_endian) = match target.arch {
+            Arch::X86_64 => (EM_X86_64, target.abi != Abi::GnuX32, false),
+            Arch::I486 | Arch::I586 | Arch::I686 => (EM_386, false, false),
+            Arch::Aarch64 => (EM_AARCH64, true, false),
+            Arch::Armv7 => (EM_ARM, false, false),
+            Arch::Riscv64 => (EM_RISCV, true, false),
+            Arch::Ppc64 => (EM_PPC64, true, true),
+            Arch::Ppc64Le => (EM_PPC64, true, false),
+            Arch::Avr | Arch::Bpf | Arch::Xtensa | Arch::Wasm32 => return false,
+        };
+        (self.machine, self.is_64, self.big_endian) == (machine, is_64, big_endian)
+    }
+
+    /// Whether the program is loaded by musl's dynamic loader, `None` for static programs.
+    pub fn is_musl(&self) -> Option<bool> {
+        self.interpreter
+            .as_ref()
+            .map(|interpreter| interpreter.contains("ld-musl-"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::Elf;
+    use crate::profile::Target;
+
+    #[test]
+    fn test_parse_current_exe() -> anyhow::Result<()> {
+        let elf = Elf::read(&std::env::current_exe()?)?.expect("the test binary is an ELF file");
+        assert_eq!(elf.is_64, cfg!(target_pointer_width = "64"));
+        assert_eq!(elf.big_endian, cfg!(target_endian = "big"));
+        if elf.interpreter.is_some() {
+            assert!(elf.needed.iter().any(|lib| lib.starts_with("libc")));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_runs_on() -> anyhow::Result<()> {
+        let elf = Elf {
+            is_64: true,
+            big_endian: false,
+            osabi: 0,
+            kind: 3,
+            machine: 62,
+            interpreter: Some("/lib64/ld-linux-x86-64.so.2".to_string()),
+            needed: vec!["libc.so.6".to_string()],
+            entry: 0x1000,
+            load_address: Some(0),
+        };
+        elf.check_runs_on(&Target::from_str("x86_64-unknown-linux-gnu")?)?;
+
+        let err = elf
+            .check_runs_on(&Target::from_str("aarch64-unknown-linux-gnu")?)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "it's a 64-bit little endian x86_64 program, the VM is aarch64-unknown-linux-gnu"
+        );
+        let err = elf
+            .check_runs_on(&Target::from_str("x86_64-unknown-linux-musl")?)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "it's linked against glibc, x86_64-unknown-linux-musl uses musl"
+        );
+        let object = Elf { kind: 1, ..elf };
+        assert!(
+            object
+                .check_runs_on(&Target::from_str("x86_64-unknown-linux-gnu")?)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_freestanding_target() {
+        let elf = Elf {
+            is_64: true,
+            big_endian: false,
+            osabi: 0,
+            kind: 2,
+            machine: 243,
+            interpreter: None,
+            needed: vec![],
+            entry: 0x8000_0000,
+            load_address: Some(0x8000_0000),
+        };
+        assert_eq!(
+            elf.freestanding_target().map(|target| target.to_string()),
+            Some("riscv64-elf".to_string())
+        );
+        let ppc64 = Elf {
+            machine: 21,
+            big_endian: true,
+            ..elf
+        };
+        assert!(ppc64.freestanding_target().is_none());
+    }
+
+    /// A little endian ELF64 shared object with a loadable segment at the start of the file and a
+    /// dynamic section needing the library named at `needed` in a string table at offset 8.
+    fn elf64(needed: u64) -> Vec<u8> {
+        let mut data = vec![0; 64];
+        data[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        data[16..18].copy_from_slice(&3u16.to_le_bytes());
+        data[18..20].copy_from_slice(&62u16.to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&2u16.to_le_bytes());
+        // (type, offset, vaddr, filesz) at 64 and 120, the dynamic section at 176
+        for (kind, offset, vaddr, filesz) in [(1u32, 0u64, 0u64, 218u64), (2, 176, 176, 32)] {
+            let mut header = vec![0; 56];
+            header[..4].copy_from_slice(&kind.to_le_bytes());
+            header[8..16].copy_from_slice(&offset.to_le_bytes());
+            header[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            header[32..40].copy_from_slice(&filesz.to_le_bytes());
+            data.extend(header);
+        }
+        for (tag, value) in [(1u64, needed), (5, 8)] {
+            data.extend(tag.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_malformed() -> anyhow::Result<()> {
+        let mut data = elf64(200);
+        data.extend(b"libc.so.6\0");
+        let elf = Elf::parse(&data)?.expect("an ELF file");
+        assert_eq!(elf.needed, vec!["libc.so.6".to_string()]);
+
+        // cut in the program headers
+        let error = Elf::parse(&data[..100]).unwrap_err();
+        assert!(error.to_string().contains("truncated ELF file"), "{error}");
+
+        let mut program_headers = data.clone();
+        program_headers[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        // the filesz of the dynamic segment
+        let mut segment = data.clone();
+        segment[152..160].copy_from_slice(&u64::MAX.to_le_bytes());
+        for data in [program_headers, segment, elf64(u64::MAX)] {
+            let error = Elf::parse(&data).unwrap_err();
+            assert!(
+                error.to_string().contains("truncated/malformed ELF"),
+                "{error}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_elf() -> anyhow::Result<()> {
+        assert_eq!(Elf::parse(b"#!/bin/sh\necho hello\n")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! The kinds of failures that `toolup` reports through its exit code.
+//!
+//! Errors are tagged by attaching a [`Failure`] as context where they happen, e.g.
+//! `.context(Failure::Download)`. The process exits with the code of the outermost [`Failure`] in
+//! the error chain, or 1 for untagged errors.
+use std::fmt::Display;
+
+/// Exit codes documented in `toolup --help`.
+pub const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  other errors
+  2  usage error
+  3  download failure
+  4  build failure
+  5  VM boot failure
+  6  guest program failure, `run-baremetal` and `linux --exec` exit with the code of the
+     (first failing) program when it's 1-255
+  7  kernel panic";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Invalid arguments or configuration
+    Usage,
+    /// Downloading or verifying a source archive
+    Download,
+    /// A configure/make step exited unsuccessfully
+    Build,
+    /// QEMU failed to start or exited with an error
+    VmBoot,
+    /// A program run in QEMU failed
+    GuestProgram,
+    /// The kernel booted by `toolup linux` panicked
+    KernelPanic,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Failure::Usage => 2,
+            Failure::Download => 3,
+            Failure::Build => 4,
+            Failure::VmBoot => 5,
+            Failure::GuestProgram => 6,
+            Failure::KernelPanic => 7,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Failure::Usage => "usage error",
+            Failure::Download => "download failed",
+            Failure::Build => "build failed",
+            Failure::VmBoot => "the VM failed to boot",
+            Failure::GuestProgram => "the guest program failed",
+            Failure::KernelPanic => "kernel panic",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Returns the process exit code for `err`.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    err.downcast_ref::<Failure>()
+        .map(|failure| failure.exit_code())
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::{Context, anyhow};
+
+    use super::{Failure, exit_code};
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&anyhow!("untagged")), 1);
+
+        let err = Err::<(), _>(anyhow!("404"))
+            .context(Failure::Download)
+            .context("failed to fetch gcc")
+            .unwrap_err();
+        assert_eq!(exit_code(&err), 3);
+
+        let err = Err::<(), _>(anyhow!("make exited with 2"))
+            .context(Failure::Build)
+            .context(Failure::KernelPanic)
+            .unwrap_err();
+        assert_eq!(exit_code(&err), 7);
+    }
+}
@@ -0,0 +1,113 @@
+//! The firmware QEMU boots `toolup linux` kernels with.
+//!
+//! `-bios default` and pseries' implicit `slof.bin` are looked up in QEMU's data directory, which
+//! distros package separately (`opensbi`, `qemu-system-data`, ...) or not at all. The blobs are
+//! downloaded into the cache instead, pinned to a version known to boot the kernels toolup builds:
+//!
+//! - riscv64: OpenSBI's generic `fw_dynamic.bin`, which jumps to the kernel passed with `-kernel`
+//! - ppc64 and ppc64le: SLOF, the pseries firmware, as shipped with QEMU
+//!
+//! The other architectures load the kernel directly, the `virt` boards don't need u-boot for
+//! `-kernel`.
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    commands::{is_plan, plan_step},
+    download::{DownloadResult, cache_dir, download_archive, extract_source, lock_source},
+    error::Failure,
+    profile::Arch,
+};
+
+const OPENSBI_VERSION: &str = "1.7";
+/// The QEMU release whose `pc-bios/slof.bin` is used.
+const SLOF_QEMU_VERSION: &str = "10.1.0";
+
+/// A firmware blob passed to QEMU with `-bios`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+    OpenSbi,
+    Slof,
+}
+
+impl Firmware {
+    /// The firmware booting a kernel on `arch`, `None` if QEMU loads the kernel itself.
+    pub fn for_arch(arch: Arch) -> Option<Self> {
+        match arch {
+            Arch::Riscv64 => Some(Firmware::OpenSbi),
+            Arch::Ppc64 | Arch::Ppc64Le => Some(Firmware::Slof),
+            _ => None,
+        }
+    }
+
+    fn url(self) -> String {
+        match self {
+            Firmware::OpenSbi => format!(
+                "https://github.com/riscv-software-src/opensbi/releases/download/v{OPENSBI_VERSION}/opensbi-{OPENSBI_VERSION}-rv-bin.tar.xz"
+            ),
+            Firmware::Slof => format!(
+                "https://gitlab.com/qemu-project/qemu/-/raw/v{SLOF_QEMU_VERSION}/pc-bios/slof.bin"
+            ),
+        }
+    }
+
+    /// Download the firmware if it's not cached yet and return its path.
+    pub fn path(self) -> Result<PathBuf> {
+        let url = self.url();
+        match self {
+            Firmware::OpenSbi => {
+                let dirname = format!("opensbi-{OPENSBI_VERSION}-rv-bin");
+                let dir = {
+                    let _lock = lock_source(&dirname);
+                    extract_source(&url, &dirname, true).context(Failure::Download)?
+                };
+                Ok(dir.join("share/opensbi/lp64/generic/firmware/fw_dynamic.bin"))
+            }
+            Firmware::Slof => {
+                let path = cache_dir()?
+                    .join("firmware")
+                    .join(format!("slof-{SLOF_QEMU_VERSION}.bin"));
+                if path.exists() {
+                    return Ok(path);
+                }
+                if is_plan() {
+                    plan_step(format!("download {url}"));
+                    return Ok(path);
+                }
+                let archive = match download_archive(&url, true).context(Failure::Download)? {
+                    DownloadResult::Cached(p)
+                    | DownloadResult::Replaced(p)
+                    | DownloadResult::Created(p) => p,
+                };
+                std::fs::create_dir_all(path.parent().expect("the firmware is in a directory"))?;
+                std::fs::copy(&archive, &path)
+                    .context(format!("failed to copy `{}`", archive.display()))?;
+                Ok(path)
+            }
+        }
+    }
+}
+
+/// The QEMU arguments loading the firmware of `arch`.
+pub fn qemu_args(arch: Arch) -> Result<Vec<String>> {
+    let Some(firmware) = Firmware::for_arch(arch) else {
+        return Ok(vec![]);
+    };
+    let path = firmware.path()?;
+    Ok(vec!["-bios".into(), path.display().to_string()])
+}
+
+#[cfg(test)]
+mod test {
+    use super::Firmware;
+    use crate::profile::Arch;
+
+    #[test]
+    fn test_for_arch() {
+        assert_eq!(Firmware::for_arch(Arch::Riscv64), Some(Firmware::OpenSbi));
+        assert_eq!(Firmware::for_arch(Arch::Ppc64Le), Some(Firmware::Slof));
+        assert_eq!(Firmware::for_arch(Arch::Aarch64), None);
+        assert_eq!(Firmware::for_arch(Arch::X86_64), None);
+    }
+}
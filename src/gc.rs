@@ -0,0 +1,321 @@
+//! Removing toolchains and kernel images that haven't been used recently.
+//!
+//! `toolup cc` and `toolup linux` record when they last used a toolchain (and a kernel image) in
+//! `~/.toolup/last-used.json`. Anything installed before that file existed falls back to the
+//! modification time of its directory.
+//!
+//! The file is updated under `last-used.json.lock`, every `toolup cc` of a parallel build records
+//! its use.
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Local, SecondsFormat, TimeDelta};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::is_plan,
+    download::{cross_prefix, linux_images_dir, sysroots_dir, toolup_dir},
+    locks::{lock_file, write_atomic},
+    profile::{Target, Toolchain},
+    registry,
+};
+
+/// RFC 3339 last-use times keyed by toolchain id and kernel image directory name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LastUsed {
+    #[serde(default)]
+    pub toolchains: BTreeMap<String, String>,
+    #[serde(default)]
+    pub kernel_images: BTreeMap<String, String>,
+}
+
+fn last_used_path() -> Result<PathBuf> {
+    Ok(toolup_dir()?.join("last-used.json"))
+}
+
+impl LastUsed {
+    pub fn load() -> Result<Self> {
+        let path = last_used_path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(&path)
+            .context(format!("failed to read `{}`", path.display()))?;
+        serde_json::from_str(&content).context(format!("failed to parse `{}`", path.display()))
+    }
+
+    fn save(&self) -> Result<()> {
+        write_atomic(&last_used_path()?, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Load the file, change it with `f` and save it, other processes wait for the update.
+    fn update(f: impl FnOnce(&mut Self)) -> Result<()> {
+        let _lock = lock_file(&toolup_dir()?.join("last-used.json.lock"))?;
+        let mut last_used = Self::load()?;
+        f(&mut last_used);
+        last_used.save()
+    }
+}
+
+pub(crate) fn now() -> String {
+    Local::now().to_rfc3339_opts(SecondsFormat::Secs, false)
+}
+
+/// Record that `toolchain` was used now. A failure is only reported, it never fails the command
+/// using the toolchain.
+pub fn record_toolchain_use(toolchain: &Toolchain) {
+    if is_plan() {
+        return;
+    }
+    let id = toolchain.id();
+    if let Err(err) = LastUsed::update(|last_used| {
+        last_used.toolchains.insert(id.clone(), now());
+    }) {
+        log::warn!("failed to record the use of {id}: {err:#}");
+    }
+}
+
+/// Record that the kernel image for `target` and `version` was used now. A failure is only
+/// reported.
+pub fn record_kernel_use(target: &Target, version: &str) {
+    if is_plan() {
+        return;
+    }
+    let name = format!("{target}-{version}");
+    if let Err(err) = LastUsed::update(|last_used| {
+        last_used.kernel_images.insert(name.clone(), now());
+    }) {
+        log::warn!("failed to record the use of the kernel image {name}: {err:#}");
+    }
+}
+
+/// An age such as `90d`, `2w` or `12h`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Age(pub TimeDelta);
+
+impl FromStr for Age {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("`{s}` is an invalid age, use e.g. `90d`, `2w` or `12h`");
+        let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        let (amount, unit) = s.split_at(split);
+        let amount: i64 = amount.parse().map_err(|_| invalid())?;
+
+        let delta = match unit {
+            "h" => TimeDelta::try_hours(amount),
+            "d" => TimeDelta::try_days(amount),
+            "w" => TimeDelta::try_weeks(amount),
+            _ => None,
+        };
+        delta.map(Age).ok_or_else(invalid)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GcKind {
+    /// A toolchain and its sysroot
+    Toolchain,
+    KernelImage,
+}
+
+/// Something that wasn't used recently.
+#[derive(Debug, Clone)]
+pub struct GcCandidate {
+    pub kind: GcKind,
+    /// A toolchain id or a kernel image name
+    pub name: String,
+    pub last_used: DateTime<Local>,
+    /// The directories that are removed
+    pub paths: Vec<PathBuf>,
+}
+
+impl Display for GcCandidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            GcKind::Toolchain => "toolchain",
+            GcKind::KernelImage => "kernel",
+        };
+        write!(
+            f,
+            "{kind:<9} {} (last used {})",
+            self.name,
+            self.last_used.format("%Y-%m-%d")
+        )
+    }
+}
+
+fn modified(path: &Path) -> Result<DateTime<Local>> {
+    let time: SystemTime = std::fs::metadata(path)?.modified()?;
+    Ok(time.into())
+}
+
+/// The last-use time of `name` at `path`, the modification time if it was never recorded.
+fn last_used_time(
+    name: &str,
+    path: &Path,
+    recorded: &BTreeMap<String, String>,
+) -> Result<DateTime<Local>> {
+    match recorded
+        .get(name)
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+    {
+        Some(time) => Ok(time.with_timezone(&Local)),
+        None => modified(path),
+    }
+}
+
+/// Returns the subdirectories of `dir` with their last-use time, without the `.<id>.partial`
+/// directories of installs that failed or are running.
+fn dirs_last_used(
+    dir: &Path,
+    recorded: &BTreeMap<String, String>,
+) -> Result<Vec<(String, PathBuf, DateTime<Local>)>> {
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(dir).context(format!("failed to read `{}`", dir.display()))? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if !path.is_dir() || name.starts_with('.') {
+            continue;
+        }
+        let last_used = last_used_time(&name, &path, recorded)?;
+        entries.push((name, path, last_used));
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Returns the toolchains installed with a custom prefix (see [`registry`]) with their last-use
+/// time. Their sysroot is next to them.
+fn prefixed_last_used(
+    recorded: &BTreeMap<String, String>,
+) -> Result<Vec<(String, PathBuf, DateTime<Local>)>> {
+    let mut entries = vec![];
+    for (id, prefix) in registry::load()? {
+        let path = prefix.join(&id);
+        if path.is_dir() {
+            let last_used = last_used_time(&id, &path, recorded)?;
+            entries.push((id, path, last_used));
+        }
+    }
+    Ok(entries)
+}
+
+/// Returns the toolchains and kernel images that weren't used for `unused_for`.
+pub fn candidates(unused_for: Age) -> Result<Vec<GcCandidate>> {
+    let last_used = LastUsed::load()?;
+    let cutoff = Local::now() - unused_for.0;
+    let mut candidates = vec![];
+
+    let default_prefix = cross_prefix()?;
+    let toolchains = dirs_last_used(&default_prefix, &last_used.toolchains)?
+        .into_iter()
+        .chain(prefixed_last_used(&last_used.toolchains)?);
+    for (id, path, time) in toolchains {
+        if time >= cutoff {
+            continue;
+        }
+        let sysroot = match path.parent() {
+            Some(prefix) if prefix != default_prefix => prefix.join(format!("sysroot-{id}")),
+            _ => sysroots_dir()?.join(format!("sysroot-{id}")),
+        };
+        let mut paths = vec![path];
+        if sysroot.exists() {
+            paths.push(sysroot);
+        }
+        candidates.push(GcCandidate {
+            kind: GcKind::Toolchain,
+            name: id,
+            last_used: time,
+            paths,
+        });
+    }
+
+    for (name, path, time) in dirs_last_used(&linux_images_dir()?, &last_used.kernel_images)? {
+        if time < cutoff {
+            candidates.push(GcCandidate {
+                kind: GcKind::KernelImage,
+                name,
+                last_used: time,
+                paths: vec![path],
+            });
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Remove the directories of `candidates` and forget their last-use times.
+pub fn remove(candidates: &[GcCandidate]) -> Result<()> {
+    for candidate in candidates {
+        log::info!("=> removing {}", candidate.name);
+        for path in &candidate.paths {
+            std::fs::remove_dir_all(path)
+                .context(format!("failed to remove `{}`", path.display()))?;
+        }
+    }
+    LastUsed::update(|last_used| {
+        for candidate in candidates {
+            match candidate.kind {
+                GcKind::Toolchain => last_used.toolchains.remove(&candidate.name),
+                GcKind::KernelImage => last_used.kernel_images.remove(&candidate.name),
+            };
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use std::collections::BTreeMap;
+
+    use chrono::TimeDelta;
+
+    use super::{Age, dirs_last_used};
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(Age::from_str("90d").unwrap(), Age(TimeDelta::days(90)));
+        assert_eq!(Age::from_str("2w").unwrap(), Age(TimeDelta::weeks(2)));
+        assert_eq!(Age::from_str("12h").unwrap(), Age(TimeDelta::hours(12)));
+        assert!(Age::from_str("90").is_err());
+        assert!(Age::from_str("d").is_err());
+        assert!(Age::from_str("3y").is_err());
+    }
+
+    #[test]
+    fn test_dirs_last_used_skips_partial_installs() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        std::fs::create_dir(dir.path().join("x86_64-elf-gcc-15.2.0-bin-2.45-glibc-2.42"))?;
+        std::fs::create_dir(
+            dir.path()
+                .join(".x86_64-elf-gcc-15.2.0-bin-2.45-glibc-2.42.partial"),
+        )?;
+        std::fs::write(
+            dir.path()
+                .join(".x86_64-elf-gcc-15.2.0-bin-2.45-glibc-2.42.lock"),
+            "",
+        )?;
+
+        let recorded = BTreeMap::from([(
+            "x86_64-elf-gcc-15.2.0-bin-2.45-glibc-2.42".to_string(),
+            "2020-06-01T12:00:00+00:00".to_string(),
+        )]);
+        let entries = dirs_last_used(dir.path(), &recorded)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, "x86_64-elf-gcc-15.2.0-bin-2.45-glibc-2.42");
+        assert_eq!(entries[0].2.format("%Y").to_string(), "2020");
+        Ok(())
+    }
+}
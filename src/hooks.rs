@@ -0,0 +1,123 @@
+//! User hooks run before and after the stages of toolup, configured under `[workspace.hooks]`:
+//!
+//! ```toml
+//! [workspace.hooks]
+//! post_sysroot = ["./scripts/add-ca-certificates.sh"]
+//! pre_rootfs_pack = ["./scripts/add-test-data.sh"]
+//! ```
+//!
+//! A hook is an executable run from the current directory. It gets what it needs through
+//! `TOOLUP_HOOK`, `TOOLUP_TARGET`, `TOOLUP_TOOLCHAIN` and a `TOOLUP_<NAME>` variable per directory
+//! of the stage (e.g. `TOOLUP_SYSROOT`), and the same values as a JSON object on stdin. A hook
+//! that fails fails the stage.
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::{is_plan, plan_step},
+    error::Failure,
+    profile::Toolchain,
+};
+
+/// Where a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Hook {
+    /// After a toolchain was installed, before it's moved out of its staging directory
+    PostInstall,
+    /// After the kernel headers and the libc were installed into the sysroot
+    PostSysroot,
+    /// Before `make` builds a kernel, after it was configured
+    PreKernelBuild,
+    /// After a kernel was built
+    PostKernelBuild,
+    /// Before the rootfs directory is packed into an initramfs
+    PreRootfsPack,
+    /// After the rootfs was packed
+    PostRootfsPack,
+}
+
+impl Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Hook::PostInstall => "post_install",
+            Hook::PostSysroot => "post_sysroot",
+            Hook::PreKernelBuild => "pre_kernel_build",
+            Hook::PostKernelBuild => "post_kernel_build",
+            Hook::PreRootfsPack => "pre_rootfs_pack",
+            Hook::PostRootfsPack => "post_rootfs_pack",
+        };
+        write!(f, "{s}")
+    }
+}
+
+static HOOKS: OnceLock<BTreeMap<Hook, Vec<PathBuf>>> = OnceLock::new();
+
+/// Set the `[workspace.hooks]` programs.
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_hooks(hooks: BTreeMap<Hook, Vec<PathBuf>>) {
+    let _ = HOOKS.set(hooks);
+}
+
+/// The environment variable of a directory passed to a hook, e.g. `TOOLUP_SYSROOT`.
+fn env_name(name: &str) -> String {
+    format!("TOOLUP_{}", name.to_uppercase())
+}
+
+/// Run the programs of `hook` for `toolchain`, with the directories of the stage in `dirs`.
+pub fn run(hook: Hook, toolchain: &Toolchain, dirs: &[(&str, &Path)]) -> Result<()> {
+    let Some(programs) = HOOKS.get().and_then(|hooks| hooks.get(&hook)) else {
+        return Ok(());
+    };
+
+    let mut context = BTreeMap::from([
+        ("hook".to_string(), hook.to_string()),
+        ("target".to_string(), toolchain.target.to_string()),
+        ("toolchain".to_string(), toolchain.id()),
+    ]);
+    for (name, dir) in dirs {
+        context.insert(name.to_string(), dir.display().to_string());
+    }
+    let stdin = serde_json::to_string(&context)?;
+
+    for program in programs {
+        if is_plan() {
+            plan_step(format!("run the {hook} hook `{}`", program.display()));
+            continue;
+        }
+        log::info!("=> {hook} hook: {}", program.display());
+        let mut child = Command::new(program)
+            .envs(context.iter().map(|(name, value)| (env_name(name), value)))
+            .stdin(Stdio::piped())
+            .spawn()
+            .context(Failure::Usage)
+            .context(format!(
+                "failed to run the {hook} hook `{}`",
+                program.display()
+            ))?;
+        // a hook that doesn't read its stdin closes it early, that's not an error
+        let _ = child
+            .stdin
+            .take()
+            .context("the hook's stdin is piped")?
+            .write_all(stdin.as_bytes());
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(Failure::Build).context(format!(
+                "the {hook} hook `{}` failed with {status}",
+                program.display()
+            ));
+        }
+    }
+    Ok(())
+}
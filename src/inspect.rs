@@ -0,0 +1,517 @@
+//! Report ABI-relevant defaults of an installed toolchain.
+//!
+//! Small probes are compiled with the toolchain and its predefined macros, option defaults and
+//! produced binaries are inspected. Reports are saved so toolchains can be compared later, e.g.
+//! after changing the GCC or libc version.
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    download::cache_dir,
+    packages::newlib::NewlibVersion,
+    profile::{Libc, Profile, Toolchain},
+};
+
+/// The probed properties of a toolchain, by name.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inspection(pub BTreeMap<String, String>);
+
+impl Display for Inspection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, value) in &self.0 {
+            writeln!(f, "{:>20}: {value}", key.bold())?;
+        }
+        Ok(())
+    }
+}
+
+/// The properties that differ between two inspections.
+pub struct InspectionDiff(pub Vec<(String, Option<String>, Option<String>)>);
+
+impl Display for InspectionDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "no differences");
+        }
+        for (key, first, second) in &self.0 {
+            writeln!(f, "{}", key.bold())?;
+            writeln!(
+                f,
+                "  {} {}",
+                "-".red(),
+                first.as_deref().unwrap_or("(none)")
+            )?;
+            writeln!(
+                f,
+                "  {} {}",
+                "+".green(),
+                second.as_deref().unwrap_or("(none)")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Inspection {
+    pub fn diff(&self, other: &Inspection) -> InspectionDiff {
+        let mut keys: Vec<&String> = self.0.keys().chain(other.0.keys()).collect();
+        keys.sort();
+        keys.dedup();
+
+        InspectionDiff(
+            keys.into_iter()
+                .filter(|key| self.0.get(*key) != other.0.get(*key))
+                .map(|key| {
+                    (
+                        key.clone(),
+                        self.0.get(key).cloned(),
+                        other.0.get(key).cloned(),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+pub fn inspections_dir() -> Result<PathBuf> {
+    let dir = cache_dir()?.join("inspect");
+    std::fs::create_dir_all(&dir).context("creating toolup inspect dir")?;
+    Ok(dir)
+}
+
+/// Returns the saved inspection for a toolchain `id`.
+pub fn saved(id: &str) -> Result<Option<Inspection>> {
+    let path = inspections_dir()?.join(format!("{id}.json"));
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content =
+        std::fs::read_to_string(&path).context(format!("failed to read `{}`", path.display()))?;
+    Ok(Some(serde_json::from_str(&content)?))
+}
+
+/// Probe an installed `toolchain` and save the report.
+pub fn inspect(toolchain: &Toolchain) -> Result<Inspection> {
+    if !toolchain.gcc_bin()?.exists() {
+        bail!("{} is not installed", toolchain.id());
+    }
+
+    let workdir = inspections_dir()?.join(toolchain.id());
+    std::fs::create_dir_all(&workdir)?;
+    let mut report = BTreeMap::new();
+
+    let macros = predefined_macros(toolchain, &workdir, "")?;
+    let get = |name: &str| macros.get(name).cloned();
+
+    report.insert(
+        "default pie".into(),
+        get("__PIE__").map_or("no".into(), |level| format!("yes (level {level})")),
+    );
+    let ssp = [
+        "__SSP_ALL__",
+        "__SSP_STRONG__",
+        "__SSP_EXPLICIT__",
+        "__SSP__",
+    ]
+    .into_iter()
+    .find(|m| macros.contains_key(*m));
+    report.insert(
+        "default ssp".into(),
+        ssp.map_or("no".into(), |m| m.trim_matches('_').to_lowercase()),
+    );
+    report.insert(
+        "long double".into(),
+        match (get("__SIZEOF_LONG_DOUBLE__"), get("__LDBL_MANT_DIG__")) {
+            (Some(size), Some(mant)) => format!("{size} bytes, {mant} bit mantissa"),
+            _ => "unknown".into(),
+        },
+    );
+    report.insert(
+        "char".into(),
+        if macros.contains_key("__CHAR_UNSIGNED__") {
+            "unsigned".into()
+        } else {
+            "signed".into()
+        },
+    );
+
+    let libc = predefined_macros(toolchain, &workdir, "#include <limits.h>\n").ok();
+    report.insert("libc".into(), libc_name(toolchain, libc.as_ref()));
+
+    // help classes given together are intersected, so query them separately
+    let mut options = String::new();
+    for class in ["--help=common", "--help=target"] {
+        let output = gcc(toolchain, &workdir, &["-Q", class])?;
+        options.push_str(&String::from_utf8_lossy(&output.stdout));
+    }
+    for (option, key) in [
+        ("-ftls-model=", "tls model"),
+        ("-march=", "default -march"),
+        ("-mabi=", "default -mabi"),
+    ] {
+        if let Some(value) = option_default(&options, option) {
+            report.insert(key.into(), value);
+        }
+    }
+
+    // gcc lists the accepted values when given an invalid one
+    let invalid = gcc(
+        toolchain,
+        &workdir,
+        &[
+            "-march=toolup-invalid",
+            "-x",
+            "c",
+            "-c",
+            "/dev/null",
+            "-o",
+            "/dev/null",
+        ],
+    )?;
+    let stderr = String::from_utf8_lossy(&invalid.stderr);
+    if let Some(values) = stderr
+        .lines()
+        .filter(|line| line.contains("valid arguments to '-march='"))
+        .find_map(|line| line.split_once("are: "))
+    {
+        report.insert("supported -march".into(), values.1.trim().to_string());
+    }
+
+    if let Some(interpreter) = dynamic_loader(toolchain, &workdir)? {
+        report.insert("dynamic loader".into(), interpreter);
+    }
+
+    let inspection = Inspection(report);
+    std::fs::write(
+        inspections_dir()?.join(format!("{}.json", toolchain.id())),
+        serde_json::to_string_pretty(&inspection)?,
+    )?;
+    Ok(inspection)
+}
+
+fn gcc(toolchain: &Toolchain, workdir: &Path, args: &[&str]) -> Result<Output> {
+    Command::new(toolchain.gcc_bin()?)
+        .args(args)
+        .current_dir(workdir)
+        .env("PATH", toolchain.env_path()?)
+        // `--help` output truncates values to the terminal width
+        .env("COLUMNS", "1000")
+        .output()
+        .context("failed to run gcc")
+}
+
+/// Names the C library `toolchain` is configured with. `macros` are the ones its headers define,
+/// `None` when they can't be included, and only confirm the version.
+fn libc_name(toolchain: &Toolchain, macros: Option<&BTreeMap<String, String>>) -> String {
+    if toolchain.is_freestanding() && !toolchain.has_newlib() {
+        return "none".into();
+    }
+    // nano toolchains without `Libc::Newlib` are built with the default newlib
+    let libc = if toolchain.has_newlib() && !matches!(toolchain.libc, Libc::Newlib(_)) {
+        Libc::Newlib(NewlibVersion::default())
+    } else {
+        toolchain.libc.clone()
+    };
+    let get = |name: &str| macros.and_then(|macros| macros.get(name)).cloned();
+    // the name, the configured version, and the version the headers should report next to the
+    // one they do
+    let (name, version, expected, probed) = match libc {
+        Libc::Glibc(v) => (
+            "glibc",
+            v.to_string(),
+            Some(format!("{}.{}", v.0, v.1)),
+            get("__GLIBC__")
+                .zip(get("__GLIBC_MINOR__"))
+                .map(|(major, minor)| format!("{major}.{minor}")),
+        ),
+        // musl deliberately doesn't define a version macro
+        Libc::Musl(v) => ("musl", v.to_string(), None, None),
+        Libc::Mingw(v) => (
+            "mingw-w64",
+            v.to_string(),
+            Some(v.0.to_string()),
+            get("__MINGW64_VERSION_MAJOR"),
+        ),
+        Libc::FreeBsd(v) => (
+            "FreeBSD libc",
+            v.to_string(),
+            Some(v.0.to_string()),
+            get("__FreeBSD__"),
+        ),
+        Libc::Bionic(api) => (
+            "bionic API level",
+            api.to_string(),
+            Some(api.to_string()),
+            get("__ANDROID_API__"),
+        ),
+        // there's no version macro either, the version is the wasi-sdk release
+        Libc::WasiLibc(v) => ("wasi-libc", v.to_string(), None, None),
+        Libc::Newlib(v) => (
+            match toolchain.profile {
+                Profile::Nano => "newlib-nano",
+                Profile::Default => "newlib",
+            },
+            v.to_string(),
+            Some(format!("{}.{}.{}", v.0, v.1, v.2)),
+            get("_NEWLIB_VERSION").map(|version| version.trim_matches('"').to_string()),
+        ),
+    };
+    let name = format!("{name} {version}");
+    match (macros, expected, probed) {
+        (None, ..) => format!("{name} (headers not found)"),
+        (Some(_), Some(expected), Some(probed)) if probed != expected => {
+            format!("{name} (headers report {probed})")
+        }
+        _ => name,
+    }
+}
+
+/// Returns the macros defined by the compiler after preprocessing `source`.
+fn predefined_macros(
+    toolchain: &Toolchain,
+    workdir: &Path,
+    source: &str,
+) -> Result<BTreeMap<String, String>> {
+    let probe = workdir.join("probe.c");
+    std::fs::write(&probe, source)?;
+    let output = gcc(toolchain, workdir, &["-dM", "-E", "probe.c"])?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.strip_prefix("#define ")?.splitn(2, ' ');
+            Some((
+                parts.next()?.to_string(),
+                parts.next().unwrap_or("").to_string(),
+            ))
+        })
+        .collect())
+}
+
+/// Find the default of `option` in the output of `gcc -Q --help=...`.
+fn option_default(help: &str, option: &str) -> Option<String> {
+    help.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        // enum options list their values, e.g. `-ftls-model=[global-dynamic|...]`
+        if !parts.next()?.starts_with(option) {
+            return None;
+        }
+        parts.next().map(String::from)
+    })
+}
+
+/// Link a probe and read its program interpreter, `None` for freestanding toolchains.
+fn dynamic_loader(toolchain: &Toolchain, workdir: &Path) -> Result<Option<String>> {
+    std::fs::write(workdir.join("main.c"), "int main(void) { return 0; }\n")?;
+    if !gcc(toolchain, workdir, &["main.c", "-o", "main"])?
+        .status
+        .success()
+    {
+        return Ok(None);
+    }
+
+    Ok(program_headers(toolchain, &workdir.join("main"))?
+        .lines()
+        .find_map(|line| {
+            line.trim()
+                .strip_prefix("[Requesting program interpreter: ")
+        })
+        .map(|interpreter| interpreter.trim_end_matches(']').to_string()))
+}
+
+/// Returns the output of `readelf -l` for `binary`.
+fn program_headers(toolchain: &Toolchain, binary: &Path) -> Result<String> {
+    let readelf = toolchain
+        .bin_dir()?
+        .join(format!("{}-readelf", toolchain.target.to_target_string()));
+    let output = Command::new(readelf)
+        .arg("-l")
+        .arg(binary)
+        .output()
+        .context("failed to run readelf")?;
+    if !output.status.success() {
+        bail!(
+            "readelf failed on `{}`: {}",
+            binary.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// What `toolup cc --print` prints about a toolchain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    Sysroot,
+    TargetTriple,
+    Libc,
+    /// The `#include <...>` search list, with the flags `toolup cc` would pass
+    IncludeDirs,
+}
+
+impl FromStr for Query {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sysroot" => Ok(Query::Sysroot),
+            "target-triple" => Ok(Query::TargetTriple),
+            "libc" => Ok(Query::Libc),
+            "include-dirs" => Ok(Query::IncludeDirs),
+            _ => Err(anyhow!(
+                "unknown query `{s}`, expected one of: sysroot, target-triple, libc, include-dirs"
+            )),
+        }
+    }
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Query::Sysroot => "sysroot",
+            Query::TargetTriple => "target-triple",
+            Query::Libc => "libc",
+            Query::IncludeDirs => "include-dirs",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Answer `query` for an installed `toolchain`, one value per line. `flags` are the flags of the
+/// compilation, they can add include directories.
+pub fn query(toolchain: &Toolchain, query: Query, flags: &[OsString]) -> Result<Vec<String>> {
+    Ok(match query {
+        Query::Sysroot => vec![toolchain.sysroot()?.display().to_string()],
+        Query::TargetTriple => vec![toolchain.target.to_target_string()],
+        Query::Libc => vec![toolchain.libc.to_string()],
+        Query::IncludeDirs => {
+            // gcc prints the search list on stderr when preprocessing verbosely
+            let output = Command::new(toolchain.gcc_bin()?)
+                .args(flags)
+                .args(["-E", "-v", "-x", "c", "/dev/null", "-o", "/dev/null"])
+                .env("PATH", toolchain.env_path()?)
+                .output()
+                .context("failed to run gcc")?;
+            if !output.status.success() {
+                bail!("{}", String::from_utf8_lossy(&output.stderr));
+            }
+            include_dirs(&String::from_utf8_lossy(&output.stderr))
+        }
+    })
+}
+
+/// The directories between `#include "..." search starts here:` and `End of search list.` in the
+/// output of `gcc -v`, quoted ones first.
+fn include_dirs(verbose: &str) -> Vec<String> {
+    verbose
+        .lines()
+        .skip_while(|line| !line.starts_with("#include \"...\" search starts here:"))
+        .take_while(|line| *line != "End of search list.")
+        .filter(|line| line.starts_with(' '))
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
+/// Whether `binary` is fully static: no program interpreter and no dynamic section.
+pub fn is_fully_static(toolchain: &Toolchain, binary: &Path) -> Result<bool> {
+    let headers = program_headers(toolchain, binary)?;
+    Ok(!headers.lines().any(|line| {
+        matches!(
+            line.split_whitespace().next(),
+            Some("INTERP") | Some("DYNAMIC")
+        )
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, str::FromStr};
+
+    use super::{include_dirs, libc_name};
+    use crate::profile::{Libc, Profile, Target, Toolchain};
+
+    fn toolchain(target: &str, libc: &str) -> anyhow::Result<Toolchain> {
+        let mut toolchain = Toolchain::target_default(&Target::from_str(target)?);
+        toolchain.libc = Libc::from_str(libc)?;
+        Ok(toolchain)
+    }
+
+    fn macros(defined: &[(&str, &str)]) -> BTreeMap<String, String> {
+        defined
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_libc_name() -> anyhow::Result<()> {
+        let glibc = toolchain("x86_64-unknown-linux-gnu", "glibc-2.41")?;
+        let probed = macros(&[("__GLIBC__", "2"), ("__GLIBC_MINOR__", "41")]);
+        assert_eq!(libc_name(&glibc, Some(&probed)), "glibc 2.41");
+        let older = macros(&[("__GLIBC__", "2"), ("__GLIBC_MINOR__", "39")]);
+        assert_eq!(
+            libc_name(&glibc, Some(&older)),
+            "glibc 2.41 (headers report 2.39)"
+        );
+        assert_eq!(libc_name(&glibc, None), "glibc 2.41 (headers not found)");
+
+        // none of these are musl, though their headers don't define glibc's macros
+        let newlib = toolchain("riscv64-elf", "newlib-4.5.0.20241231")?;
+        let probed = macros(&[("_NEWLIB_VERSION", "\"4.5.0\"")]);
+        assert_eq!(libc_name(&newlib, Some(&probed)), "newlib 4.5.0.20241231");
+        let mut nano = toolchain("armv7-unknown-none-eabihf", "glibc-2.41")?;
+        nano.profile = Profile::Nano;
+        assert!(libc_name(&nano, Some(&BTreeMap::new())).starts_with("newlib-nano "));
+        let freestanding = toolchain("x86_64-elf", "glibc-2.41")?;
+        assert_eq!(libc_name(&freestanding, None), "none");
+
+        let mingw = toolchain("x86_64-w64-mingw32", "mingw-w64-13.0.0")?;
+        let probed = macros(&[("__MINGW64_VERSION_MAJOR", "13")]);
+        assert_eq!(libc_name(&mingw, Some(&probed)), "mingw-w64 13.0.0");
+        let bionic = toolchain("aarch64-linux-android", "bionic-35")?;
+        let probed = macros(&[("__BIONIC__", "1"), ("__ANDROID_API__", "35")]);
+        assert_eq!(libc_name(&bionic, Some(&probed)), "bionic API level 35");
+        let freebsd = toolchain("x86_64-unknown-freebsd14", "freebsd-14.3")?;
+        let probed = macros(&[("__FreeBSD__", "14")]);
+        assert_eq!(libc_name(&freebsd, Some(&probed)), "FreeBSD libc 14.3");
+        let musl = toolchain("x86_64-unknown-linux-musl", "musl-1.2.5")?;
+        assert_eq!(libc_name(&musl, Some(&BTreeMap::new())), "musl 1.2.5");
+        let wasi = toolchain("wasm32-wasi", "wasi-libc-25")?;
+        assert_eq!(libc_name(&wasi, Some(&BTreeMap::new())), "wasi-libc 25");
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_dirs() {
+        let verbose = r#"ignoring nonexistent directory "/opt/missing"
+#include "..." search starts here:
+ include
+#include <...> search starts here:
+ /root/.toolup/lib/gcc/aarch64-unknown-linux-gnu/15.2.0/include
+ /root/.toolup/aarch64-unknown-linux-gnu/sysroot/usr/include
+End of search list.
+ /not/a/search/dir
+"#;
+        assert_eq!(
+            include_dirs(verbose),
+            vec![
+                "include",
+                "/root/.toolup/lib/gcc/aarch64-unknown-linux-gnu/15.2.0/include",
+                "/root/.toolup/aarch64-unknown-linux-gnu/sysroot/usr/include",
+            ]
+        );
+    }
+}
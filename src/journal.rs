@@ -0,0 +1,148 @@
+//! A journal of the external commands executed while installing a toolchain or building a kernel.
+//!
+//! Every command run through [`crate::commands::run_command_in`] is appended as a JSON line to
+//! the active journal under `<cache>/journal/<id>.jsonl`, so individual steps can be reproduced by
+//! hand when debugging build failures.
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::{is_plan, print_command},
+    download::cache_dir,
+};
+
+thread_local! {
+    // per thread like the rest of the install state, see `commands::set_staging`
+    static ACTIVE: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    static PACKAGE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JournalEntry {
+    pub title: String,
+    pub cwd: PathBuf,
+    pub argv: Vec<String>,
+    /// Environment variables set on top of the inherited environment
+    pub env: BTreeMap<String, String>,
+    /// The exit code, `None` if the command was killed by a signal or failed to spawn
+    pub status: Option<i32>,
+    pub success: bool,
+    pub started: String,
+    pub duration_secs: f64,
+    pub log: Option<PathBuf>,
+    /// The package being installed when the command ran, e.g. `binutils 2.45`
+    #[serde(default)]
+    pub package: Option<String>,
+}
+
+pub fn journal_dir() -> Result<PathBuf> {
+    let dir = cache_dir()?.join("journal");
+    std::fs::create_dir_all(&dir).context("creating toolup journal dir")?;
+    Ok(dir)
+}
+
+/// Returns the journal file for `id`, usually a toolchain id.
+pub fn journal_path(id: &str) -> Result<PathBuf> {
+    Ok(journal_dir()?.join(format!("{id}.jsonl")))
+}
+
+/// Start a new journal for `id`, replacing the previous one. Commands executed afterwards are
+/// recorded in it.
+pub fn start(id: &str) -> Result<()> {
+    if is_plan() {
+        return Ok(());
+    }
+
+    let path = journal_path(id)?;
+    File::create(&path).context(format!("failed to create `{}`", path.display()))?;
+    ACTIVE.set(Some(path));
+    Ok(())
+}
+
+/// Set the package that commands are recorded for, see [`JournalEntry::package`].
+pub fn set_package(package: Option<String>) {
+    PACKAGE.set(package);
+}
+
+/// The package being installed by this thread.
+pub fn package() -> Option<String> {
+    PACKAGE.with_borrow(Clone::clone)
+}
+
+/// Append an entry to the active journal, does nothing if no journal was started.
+pub fn record(entry: &JournalEntry) -> Result<()> {
+    let Some(path) = ACTIVE.with_borrow(Clone::clone) else {
+        return Ok(());
+    };
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .context(format!("failed to open `{}`", path.display()))?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// Read the journal for `id`.
+pub fn read(id: &str) -> Result<Vec<JournalEntry>> {
+    let path = journal_path(id)?;
+    let file = File::open(&path).context(format!("no journal found at `{}`", path.display()))?;
+
+    BufReader::new(file)
+        .lines()
+        .map(|line| {
+            let line = line?;
+            serde_json::from_str(&line).context(format!("invalid entry in `{}`", path.display()))
+        })
+        .collect()
+}
+
+/// Print the entries of a journal as shell snippets that reproduce each step.
+pub fn show(entries: &[JournalEntry]) {
+    for entry in entries {
+        let status = match entry.status {
+            Some(code) => format!("exit code {code}"),
+            None => "no exit code".into(),
+        };
+        let mark = if entry.success {
+            "✓".green()
+        } else {
+            "✗".red()
+        };
+        println!(
+            "{mark} {} at {} ({status}, {:.1}s)",
+            entry.title, entry.started, entry.duration_secs
+        );
+        if let Some(log) = &entry.log {
+            println!("  log: {}", log.display());
+        }
+
+        let env: Vec<(&String, &String)> = entry.env.iter().collect();
+        let (command, args) = entry.argv.split_first().expect("argv has a command");
+        print_command(&entry.cwd, &entry.title, OsStr::new(command), args, &env);
+        println!();
+    }
+}
+
+/// Returns the ids of every journal in the cache.
+pub fn list() -> Result<Vec<String>> {
+    let mut ids: Vec<String> = std::fs::read_dir(journal_dir()?)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_suffix(".jsonl").map(String::from)
+        })
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
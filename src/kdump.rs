@@ -0,0 +1,148 @@
+//! `toolup linux --kdump`: boot with memory reserved for a crash kernel, crash the guest and check
+//! that the crash kernel captured a vmcore.
+//!
+//! The guest loads the same kernel as its crash kernel with `kexec_file_load`, through a small
+//! helper built with the target's toolchain (busybox has no `kexec -p`). After the `--exec`
+//! commands, which may crash the kernel themselves, the guest crashes on purpose with the magic
+//! sysrq key. The crash kernel boots the cached rootfs, whose `/init` copies `/proc/vmcore` to the
+//! share.
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    commands::{is_plan, plan_step, run_command_in},
+    download::cache_dir,
+    error::Failure,
+    profile::{Arch, Target, Toolchain},
+    qemu::{EXEC_BIN, Exec},
+};
+
+/// Memory reserved for the crash kernel out of the guest's 1G.
+const CRASH_KERNEL_SIZE: &str = "256M";
+/// The file the crash kernel copies the vmcore to in the share.
+const VMCORE: &str = "vmcore";
+
+/// Loads `KERNEL` and `INITRD` as the crash kernel.
+const KDUMP_HELPER: &str = r#"#include <fcntl.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/syscall.h>
+#include <unistd.h>
+
+#define KEXEC_FILE_ON_CRASH 0x2
+
+int main(int argc, char **argv) {
+    if (argc != 4) {
+        fprintf(stderr, "usage: %s KERNEL INITRD CMDLINE\n", argv[0]);
+        return 2;
+    }
+#ifdef SYS_kexec_file_load
+    int kernel = open(argv[1], O_RDONLY);
+    int initrd = open(argv[2], O_RDONLY);
+    if (kernel < 0 || initrd < 0) {
+        perror("open");
+        return 1;
+    }
+    if (syscall(SYS_kexec_file_load, kernel, initrd, strlen(argv[3]) + 1, argv[3],
+                KEXEC_FILE_ON_CRASH) != 0) {
+        perror("kexec_file_load");
+        return 1;
+    }
+    return 0;
+#else
+    fputs("kexec_file_load isn't supported on this architecture\n", stderr);
+    return 1;
+#endif
+}
+"#;
+
+/// Check that the kernel can load a crash kernel with `kexec_file_load` on `target`.
+pub fn check_supported(target: &Target) -> Result<()> {
+    match target.arch {
+        Arch::X86_64 | Arch::Aarch64 | Arch::Ppc64 | Arch::Ppc64Le | Arch::Riscv64 => Ok(()),
+        arch => Err(Failure::Usage).context(format!(
+            "`--kdump` isn't supported on {arch}, the kernel can't load a crash kernel with \
+             kexec_file_load"
+        )),
+    }
+}
+
+/// The kernel command line of a VM that reserves memory for a crash kernel and loads one.
+/// `panic_on_oops` makes an oops in any process, e.g. the sysrq crash, start the crash kernel.
+pub fn kernel_args() -> Vec<String> {
+    vec![
+        format!("crashkernel={CRASH_KERNEL_SIZE}"),
+        "panic_on_oops=1".into(),
+        "toolup_kdump=1".into(),
+    ]
+}
+
+/// Build the helper loading the crash kernel, returns the static binary.
+fn build_helper(toolchain: &Toolchain) -> Result<PathBuf> {
+    let dir = cache_dir()?.join("kdump").join(toolchain.id());
+    let helper = dir.join("toolup-kdump");
+    if helper.exists() {
+        return Ok(helper);
+    }
+    if !is_plan() {
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("toolup-kdump.c"), KDUMP_HELPER)?;
+    }
+    run_command_in(
+        &dir,
+        "gcc",
+        format!("{}-gcc", toolchain.target),
+        &["-static", "-O2", "toolup-kdump.c", "-o", "toolup-kdump"],
+        Some(vec![("PATH", toolchain.env_path()?)]),
+    )
+    .context(Failure::Build)
+    .context("failed to build the kdump helper")?;
+    Ok(helper)
+}
+
+/// Add loading `kernel` (with `rootfs`) as the crash kernel and crashing the guest after the
+/// other commands to `exec`. The vmcore is copied to `vmcore`.
+pub fn prepare(
+    toolchain: &Toolchain,
+    kernel: &Path,
+    rootfs: &Path,
+    exec: &mut Exec,
+    vmcore: &Path,
+) -> Result<()> {
+    let helper = build_helper(toolchain)?;
+    if is_plan() {
+        plan_step("crash the guest with sysrq after the commands and copy the vmcore");
+    } else if vmcore.exists() {
+        // a vmcore from a previous run would hide a failed capture
+        std::fs::remove_file(vmcore).context(format!("failed to remove `{}`", vmcore.display()))?;
+    }
+
+    exec.files
+        .insert(Path::new(EXEC_BIN).join("toolup-kdump"), helper);
+    exec.files
+        .insert("/toolup/kdump/kernel".into(), kernel.to_path_buf());
+    exec.files
+        .insert("/toolup/kdump/initrd".into(), rootfs.to_path_buf());
+    exec.commands
+        .push("echo 1 > /proc/sys/kernel/sysrq && echo c > /proc/sysrq-trigger".into());
+    exec.outputs.insert(VMCORE.into(), vmcore.to_path_buf());
+    Ok(())
+}
+
+/// Check that the crash kernel wrote an ELF core to `vmcore`, returns its size.
+pub fn check_vmcore(vmcore: &Path) -> Result<u64> {
+    let mut magic = [0u8; 4];
+    std::fs::File::open(vmcore)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .context(Failure::GuestProgram)
+        .context("the crash kernel didn't capture a vmcore")?;
+    if &magic != b"\x7fELF" {
+        return Err(Failure::GuestProgram)
+            .context(format!("`{}` is not an ELF core", vmcore.display()));
+    }
+    Ok(std::fs::metadata(vmcore)?.len())
+}
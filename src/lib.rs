@@ -1,26 +1,131 @@
-use std::str::FromStr;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt::Display,
+    fs::{File, TryLockError},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::Instant,
+};
 
 use crate::{
+    cache::{self, cleans_after_install},
+    capability::{self, Operation},
+    commands::{is_plan, is_plan_quiet, plan_step, set_make_dir, set_staging},
+    error::Failure,
+    hooks::Hook,
+    packages::{
+        binutils::install_binutils,
+        gcc::{GccStage, Sysroot, install_gcc},
+        gdb::install_gdb,
+        gnu_make::pin_make,
+        llvm::install_llvm,
+        newlib::{install_nano_specs, install_newlib, parse_libc as parse_newlib, report_size},
+        wasi::install_wasi_libc,
+    },
+    profile::parse_name,
+    sandbox::set_sandbox_dirs,
+    smoke::hello_world,
+    stage::{StageOutcome, StageRun, StageRuns},
+    sysroot::{copy_tree, setup_sysroot},
+};
+use anyhow::{Context, Result};
+use colored::Colorize;
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+// The canonical package and toolchain types, `packages::*` is the only place they are defined.
+pub use crate::{
     packages::{
-        binutils::{Binutils, BinutilsVersion, install_binutils},
-        gcc::{GCC, GCCVersion, GccStage, Sysroot, install_gcc},
+        Package, Source,
+        android::AndroidApi,
+        binutils::{Binutils, BinutilsVersion},
+        freebsd::FreeBsdVersion,
+        gcc::{GCC, GCCVersion},
+        gdb::{Gdb, GdbVersion},
         glibc::GlibcVersion,
+        install_package,
         linux::KernelVersion,
+        llvm::{Compiler, Llvm, LlvmVersion},
+        mingw::MingwVersion,
         musl::MuslVersion,
+        newlib::NewlibVersion,
+        wasi::WasiLibcVersion,
     },
-    profile::{Abi, Libc, Target, Toolchain},
-    sysroot::setup_sysroot,
+    profile::{Abi, Arch, Libc, Os, Profile, Target, Toolchain, ToolchainPaths, Vendor},
+    stage::{Force, Stage},
 };
-use anyhow::Result;
 
+/// Implement `Serialize` and `Deserialize` for types with `Display` and `FromStr` as their string
+/// form, e.g. `"aarch64-unknown-linux-gnu"` for a [`Target`] and `"15.2.0"` for a [`GCCVersion`].
+macro_rules! serde_string {
+    ($($ty:ty),+ $(,)?) => {
+        $(
+            impl ::serde::Serialize for $ty {
+                fn serialize<S: ::serde::Serializer>(
+                    &self,
+                    serializer: S,
+                ) -> ::std::result::Result<S::Ok, S::Error> {
+                    serializer.collect_str(self)
+                }
+            }
+
+            impl<'de> ::serde::Deserialize<'de> for $ty {
+                fn deserialize<D: ::serde::Deserializer<'de>>(
+                    deserializer: D,
+                ) -> ::std::result::Result<Self, D::Error> {
+                    let s = <String as ::serde::Deserialize>::deserialize(deserializer)?;
+                    s.parse().map_err(|err| ::serde::de::Error::custom(format!("{err:#}")))
+                }
+            }
+        )+
+    };
+}
+
+pub mod cache;
+pub mod capability;
+pub mod check;
+pub mod cmake;
 pub mod commands;
+pub mod compat;
 pub mod config;
 pub mod cpio;
+pub mod debug;
+pub mod doctor;
 pub mod download;
+pub mod elf;
+pub mod error;
+pub mod firmware;
+pub mod gc;
+pub mod hooks;
+pub mod inspect;
+pub mod journal;
+pub mod kdump;
+pub mod libc_test;
+pub mod locks;
+pub mod logging;
+pub mod metadata;
+pub mod outdated;
+pub mod pack;
 pub mod packages;
+pub mod prebuilt;
 pub mod profile;
+pub mod provenance;
 pub mod qemu;
+pub mod registry;
+pub mod reproduce;
+pub mod runner;
+pub mod sandbox;
+pub mod self_update;
+pub mod shell;
+pub mod smoke;
+pub mod snapshot;
+pub mod specs;
+pub mod stage;
 pub mod sysroot;
+pub mod ui;
+pub mod vendor;
+pub mod vm;
 
 /// Similar to `install_toolchain` but will parse the toolchain from strings.
 pub fn install_toolchain_str(
@@ -30,30 +135,160 @@ pub fn install_toolchain_str(
     binutils_str: String,
     kernel_version: Option<&KernelVersion>,
     jobs: u64,
-    force: bool,
+    force: &Force,
+) -> Result<InstallReport> {
+    let toolchain = parse_toolchain(
+        &target_str,
+        &gcc_str,
+        &libc_str,
+        &binutils_str,
+        kernel_version,
+    )?;
+    install_toolchain(toolchain, jobs, force)
+}
+
+/// Parse a toolchain from strings, `target_str` is a target or a variant name (see
+/// [`Toolchain::name`]) and `libc_str` is a glibc, musl, mingw-w64, FreeBSD or wasi-libc version
+/// or an Android API level depending on the target.
+pub fn parse_toolchain(
+    target_str: &str,
+    gcc_str: &str,
+    libc_str: &str,
+    binutils_str: &str,
+    kernel_version: Option<&KernelVersion>,
 ) -> Result<Toolchain> {
-    let target = Target::from_str(&target_str)?;
-    let binutils = Binutils::new(BinutilsVersion::from_str(&binutils_str)?);
-    let gcc = GCC::new(GCCVersion::from_str(&gcc_str)?);
-    let libc = match target.abi {
-        Abi::Musl => Libc::Musl(MuslVersion::from_str(&libc_str)?),
-        _ => Libc::Glibc(GlibcVersion::from_str(&libc_str)?),
+    let (target, variant) = parse_name(target_str)?;
+    let binutils = Binutils::new(BinutilsVersion::from_str(binutils_str)?);
+    let gcc = GCC::new(GCCVersion::from_str(gcc_str)?);
+    let newlib = parse_newlib(libc_str).filter(|_| target.is_freestanding());
+    let libc = if let Some(newlib) = newlib {
+        Libc::Newlib(newlib?)
+    } else {
+        match target.abi {
+            Abi::Musl => Libc::Musl(MuslVersion::from_str(libc_str)?),
+            _ if target.is_windows() => Libc::Mingw(MingwVersion::from_str(libc_str)?),
+            _ if target.is_freebsd() => Libc::FreeBsd(FreeBsdVersion::from_str(libc_str)?),
+            Abi::Android => Libc::Bionic(AndroidApi::from_str(libc_str)?),
+            Abi::Wasi => Libc::WasiLibc(WasiLibcVersion::from_str(libc_str)?),
+            _ => Libc::Glibc(GlibcVersion::from_str(libc_str)?),
+        }
     };
 
-    let toolchain = if let Some(kernel_version) = kernel_version {
-        Toolchain::new_with_kernel(target, binutils, gcc, libc, kernel_version.clone())
+    let mut toolchain = if let Some(kernel_version) = kernel_version {
+        Toolchain::new_with_kernel(target, binutils, gcc, libc, *kernel_version)
     } else {
         Toolchain::new(target, binutils, gcc, libc)
     };
+    toolchain.variant = variant;
+    Ok(toolchain)
+}
 
-    install_toolchain(toolchain, jobs, force)
+/// What [`install_toolchain`] did: the stages that were built or cached, where the toolchain is
+/// and the logs of the build.
+#[derive(Debug, Clone, Serialize)]
+pub struct InstallReport {
+    #[serde(skip)]
+    pub toolchain: Toolchain,
+    /// See [`Toolchain::id`]
+    pub id: String,
+    /// The toolchain was installed before the stages in `stages` ran
+    pub already_installed: bool,
+    /// In the order they ran, empty if nothing was rebuilt
+    pub stages: Vec<StageRun>,
+    pub dir: PathBuf,
+    pub bin_dir: PathBuf,
+    /// `None` for freestanding targets
+    pub sysroot: Option<PathBuf>,
+    /// The commands run by the install, see [`journal`]
+    pub journal: Option<PathBuf>,
+    /// The output of the commands run by the install
+    pub logs: Vec<PathBuf>,
+    pub secs: f64,
+}
+
+impl InstallReport {
+    fn new(
+        toolchain: Toolchain,
+        already_installed: bool,
+        stages: StageRuns,
+        started: Instant,
+    ) -> Result<Self> {
+        let id = toolchain.id();
+        let (journal, logs) = if stages.0.is_empty() || is_plan() {
+            (None, vec![])
+        } else {
+            let logs = journal::read(&id)?
+                .into_iter()
+                .filter_map(|entry| entry.log)
+                .collect();
+            (Some(journal::journal_path(&id)?), logs)
+        };
+        Ok(Self {
+            id,
+            already_installed,
+            stages: stages.0,
+            dir: toolchain.dir()?,
+            bin_dir: toolchain.bin_dir()?,
+            sysroot: if toolchain.is_freestanding() {
+                None
+            } else {
+                Some(toolchain.sysroot()?)
+            },
+            journal,
+            logs,
+            secs: started.elapsed().as_secs_f64(),
+            toolchain,
+        })
+    }
+
+    /// The stages that were built rather than reused.
+    pub fn built(&self) -> impl Iterator<Item = &StageRun> {
+        self.stages
+            .iter()
+            .filter(|stage| stage.outcome == StageOutcome::Built)
+    }
+}
+
+impl Display for InstallReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.stages.is_empty() {
+            return write!(f, "{} was already installed", self.id);
+        }
+        write!(
+            f,
+            "{}: built {} of {} stages in {:.1}s",
+            self.id,
+            self.built().count(),
+            self.stages.len(),
+            self.secs
+        )?;
+        for stage in &self.stages {
+            write!(f, "\n  {stage}")?;
+        }
+        Ok(())
+    }
 }
 
 /// Install a toolchain.
 ///
-/// use `force` to forcefully re-install a toolchain if it was already installed.
-pub fn install_toolchain(toolchain: Toolchain, jobs: u64, force: bool) -> Result<Toolchain> {
-    println!("{}", toolchain);
+/// use `force` to forcefully re-install a toolchain, or some of its stages, if it was already
+/// installed.
+pub fn install_toolchain(toolchain: Toolchain, jobs: u64, force: &Force) -> Result<InstallReport> {
+    let _span = tracing::info_span!(
+        "install_toolchain",
+        target = %toolchain.target,
+        toolchain = toolchain.id()
+    )
+    .entered();
+    let started = Instant::now();
+    capability::check(&toolchain.target, Operation::Install)?;
+    if toolchain.llvm.is_some() {
+        capability::check(&toolchain.target, Operation::Clang)?;
+    }
+    // stdout is reserved for the output of commands that plan quietly, e.g. `toolup cache key`
+    if !is_plan_quiet() {
+        println!("{}", toolchain);
+    }
 
     log::info!("export PATH=\"{}:$PATH\"", toolchain.bin_dir()?.display());
     log::info!("export SYSROOT={}", toolchain.sysroot()?.display());
@@ -64,9 +299,52 @@ pub fn install_toolchain(toolchain: Toolchain, jobs: u64, force: bool) -> Result
     log::info!("export TARGET={}", toolchain.target);
     log::info!("");
 
-    if toolchain.gcc_bin()?.exists() && !force {
+    if toolchain.profile != Profile::Default && !toolchain.is_freestanding() {
+        return Err(Failure::Usage).context(format!(
+            "the `{}` profile is only for freestanding targets",
+            toolchain.profile
+        ));
+    }
+
+    // another process may be installing the same toolchain, wait for it before checking
+    let _lock = lock_toolchain(&toolchain)?;
+    set_sandbox_dirs(vec![toolchain.install_prefix()?]);
+    // the make of a previous toolchain must not stay pinned for the commands run with this one
+    set_make_dir(None);
+
+    let installed = toolchain.gcc_bin()?.exists();
+    if installed && *force == Force::Nothing {
         log::info!("toolchain is already installed");
-        return Ok(toolchain);
+        return InstallReport::new(toolchain, true, StageRuns::default(), started);
+    }
+
+    // only whole toolchains are prebuilt
+    if force.from_scratch(installed) && prebuilt::install(&toolchain)? {
+        hooks::run(
+            Hook::PostInstall,
+            &toolchain,
+            &[
+                ("dir", &toolchain.dir()?),
+                ("bin_dir", &toolchain.bin_dir()?),
+            ],
+        )?;
+        return InstallReport::new(toolchain, installed, StageRuns::default(), started);
+    }
+
+    // only once something is built, an installed or prebuilt toolchain doesn't need its make
+    pin_make(&toolchain, jobs)?;
+    journal::start(&toolchain.id())?;
+    provenance::start();
+    let staged = StagedInstall::start(&toolchain, !force.from_scratch(installed))?;
+
+    let mut stages = StageRuns::default();
+    // clang's WebAssembly backend and lld replace binutils
+    if !toolchain.target.is_wasi() {
+        stages.run(
+            Stage::Binutils,
+            force.should_run(Stage::Binutils, installed),
+            || install_binutils(&toolchain, jobs),
+        )?;
     }
 
     match toolchain.target {
@@ -75,19 +353,286 @@ pub fn install_toolchain(toolchain: Toolchain, jobs: u64, force: bool) -> Result
             abi: Abi::Elf | Abi::Eabihf | Abi::Eabi,
             ..
         } => {
-            install_binutils(&toolchain, jobs)?;
-            install_gcc(&toolchain, jobs, GccStage::Stage1)?;
+            if toolchain.has_newlib() {
+                stages.run(
+                    Stage::Libc,
+                    force.should_run(Stage::Libc, installed),
+                    || {
+                        // an installed compiler can build newlib, see `setup_sysroot`
+                        if force.from_scratch(installed) {
+                            install_gcc(&toolchain, jobs, GccStage::Stage1)?;
+                        }
+                        install_newlib(&toolchain, jobs)
+                    },
+                )?;
+                stages.run(
+                    Stage::GccFinal,
+                    force.should_run(Stage::GccFinal, installed),
+                    || install_gcc(&toolchain, jobs, GccStage::Newlib),
+                )?;
+                if toolchain.profile == Profile::Nano {
+                    install_nano_specs(&toolchain)?;
+                    report_size(&toolchain)?;
+                }
+            } else {
+                stages.run(
+                    Stage::GccFinal,
+                    force.should_run(Stage::GccFinal, installed),
+                    || install_gcc(&toolchain, jobs, GccStage::Stage1),
+                )?;
+            }
         }
         Target {
-            abi: Abi::Gnu | Abi::GnuEabi | Abi::GnuEabihf | Abi::Musl,
+            abi: Abi::Gnu | Abi::GnuEabi | Abi::GnuEabihf | Abi::GnuX32 | Abi::Musl | Abi::Android,
             ..
         } => {
-            install_binutils(&toolchain, jobs)?;
-            let sysroot = setup_sysroot(&toolchain, jobs)?;
-            install_gcc(&toolchain, jobs, GccStage::Final(Some(Sysroot(sysroot))))?;
+            let sysroot = setup_sysroot(&toolchain, jobs, force, installed, &mut stages)?;
+            stages.run(
+                Stage::GccFinal,
+                force.should_run(Stage::GccFinal, installed),
+                || {
+                    install_gcc(
+                        &toolchain,
+                        jobs,
+                        GccStage::Final(Some(Sysroot(sysroot.clone()))),
+                    )
+                },
+            )?;
+            // before the staging directory is renamed, a broken build never replaces the toolchain
+            hello_world(&toolchain)?;
+        }
+        // clang, then the builtins and wasi-libc built with it
+        Target { abi: Abi::Wasi, .. } => {
+            stages.run(
+                Stage::Llvm,
+                force.should_run(Stage::Llvm, installed),
+                || install_llvm(&toolchain, jobs),
+            )?;
+            stages.run(
+                Stage::Libc,
+                force.should_run(Stage::Libc, installed),
+                || install_wasi_libc(&toolchain, jobs),
+            )?;
+            hello_world(&toolchain)?;
+        }
+        _ => {
+            return Err(capability::unsupported(
+                &toolchain.target,
+                Operation::Install,
+            ));
         }
-        _ => unimplemented!(),
     };
 
-    Ok(toolchain)
+    if toolchain.gdb.is_some() {
+        stages.run(Stage::Gdb, force.should_run(Stage::Gdb, installed), || {
+            install_gdb(&toolchain, jobs)
+        })?;
+    }
+    if toolchain.llvm.is_some() && !toolchain.target.is_wasi() {
+        stages.run(
+            Stage::Llvm,
+            force.should_run(Stage::Llvm, installed),
+            || install_llvm(&toolchain, jobs),
+        )?;
+    }
+
+    metadata::write(&toolchain)?;
+    provenance::write(&toolchain)?;
+    hooks::run(
+        Hook::PostInstall,
+        &toolchain,
+        &[
+            ("dir", &toolchain.dir()?),
+            ("bin_dir", &toolchain.bin_dir()?),
+        ],
+    )?;
+    staged.finish()?;
+    if let Some(prefix) = &toolchain.prefix {
+        registry::record(&toolchain.id(), prefix)?;
+    }
+    if cleans_after_install() {
+        clean_builds(&toolchain);
+    }
+    InstallReport::new(toolchain, installed, stages, started)
+}
+
+/// Remove the build trees of the installed `toolchain`, `--clean-after-install`. The toolchain is
+/// installed either way, a failure is only reported.
+fn clean_builds(toolchain: &Toolchain) {
+    if is_plan() {
+        plan_step(format!("remove the build trees of {}", toolchain.id()));
+        return;
+    }
+    let ids = BTreeSet::from([toolchain.id()]);
+    match cache::build_trees(&ids).and_then(|trees| cache::remove_build_trees(&trees)) {
+        Ok(freed) => log::info!("=> freed {} of build trees", HumanBytes(freed)),
+        Err(err) => log::warn!(
+            "failed to remove the build trees of {}: {err:#}",
+            toolchain.id()
+        ),
+    }
+}
+
+/// Install several toolchains, `parallel` at a time.
+///
+/// `jobs` is shared by the installs running at the same time, each gets `jobs / parallel` threads
+/// capped by its own setting (the second element of the tuple). Source archives and extracted
+/// sources are shared, see [`locks`]. A failed install doesn't stop the others, the results are
+/// returned in the order of `toolchains`.
+pub fn install_toolchains(
+    toolchains: Vec<(Toolchain, Option<u64>)>,
+    parallel: usize,
+    jobs: u64,
+    force: &Force,
+) -> Vec<(Toolchain, Result<InstallReport>)> {
+    let parallel = parallel.clamp(1, toolchains.len().max(1));
+    let share = (jobs / parallel as u64).max(1);
+    let queue = Mutex::new(toolchains.into_iter().enumerate().collect::<VecDeque<_>>());
+    let results = Mutex::new(vec![]);
+    std::thread::scope(|scope| {
+        for _ in 0..parallel {
+            scope.spawn(|| {
+                loop {
+                    let next = queue.lock().expect("the queue is not poisoned").pop_front();
+                    let Some((i, (toolchain, max_jobs))) = next else {
+                        return;
+                    };
+                    let jobs = max_jobs.map_or(share, |max_jobs| max_jobs.min(share));
+                    let result = install_toolchain(toolchain.clone(), jobs, force);
+                    if let Err(err) = &result {
+                        log::error!("{}: {err:#}", toolchain.id());
+                    }
+                    results
+                        .lock()
+                        .expect("the results are not poisoned")
+                        .push((i, toolchain, result));
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().expect("the results are not poisoned");
+    results.sort_by_key(|(i, ..)| *i);
+    results
+        .into_iter()
+        .map(|(_, toolchain, result)| (toolchain, result))
+        .collect()
+}
+
+/// Print a line per toolchain installed by [`install_toolchains`]: the stages it built and how
+/// long it took, or why it failed.
+pub fn print_install_summary(results: &[(Toolchain, Result<InstallReport>)]) {
+    for (toolchain, result) in results {
+        match result {
+            Ok(report) if report.stages.is_empty() => {
+                println!("{} {:<48} already installed", "✓".green(), report.id)
+            }
+            Ok(report) => println!(
+                "{} {:<48} built {} of {} stages in {:.1}s",
+                "✓".green(),
+                report.id,
+                report.built().count(),
+                report.stages.len(),
+                report.secs
+            ),
+            Err(err) => println!("{} {:<48} {err:#}", "✗".red(), toolchain.id()),
+        }
+    }
+}
+
+/// Take an exclusive lock on installing `toolchain`, released when the file is dropped.
+fn lock_toolchain(toolchain: &Toolchain) -> Result<Option<File>> {
+    if is_plan() {
+        return Ok(None);
+    }
+
+    let prefix = toolchain.install_prefix()?;
+    std::fs::create_dir_all(&prefix).context(format!("failed to create `{}`", prefix.display()))?;
+    let path = prefix.join(format!(".{}.lock", toolchain.id()));
+    let file = File::create(&path).context(format!("failed to create `{}`", path.display()))?;
+    if let Err(TryLockError::WouldBlock) = file.try_lock() {
+        log::info!("waiting for another toolup process to install this toolchain");
+        file.lock()
+            .context(format!("failed to lock `{}`", path.display()))?;
+    }
+    Ok(Some(file))
+}
+
+/// A toolchain being built in its staging directory, see [`Toolchain::staging_dir`].
+///
+/// The toolchain directory only appears once every stage succeeded, so a failed or interrupted
+/// build is never mistaken for an installed toolchain.
+struct StagedInstall {
+    dir: PathBuf,
+    staging: PathBuf,
+    /// Where the installed toolchain is moved while the staging directory replaces it.
+    old: PathBuf,
+}
+
+impl StagedInstall {
+    /// Start from a copy of the installed toolchain with `reuse_installed`, otherwise from scratch.
+    fn start(toolchain: &Toolchain, reuse_installed: bool) -> Result<Self> {
+        let staged = StagedInstall {
+            dir: toolchain.dir()?,
+            staging: toolchain.staging_dir()?,
+            old: toolchain
+                .install_prefix()?
+                .join(format!(".{}.old", toolchain.id())),
+        };
+
+        if is_plan() {
+            plan_step(format!(
+                "install into {} and rename it to {}",
+                staged.staging.display(),
+                staged.dir.display()
+            ));
+        } else {
+            // left behind by an interrupted build
+            remove_dir_if_exists(&staged.staging)?;
+            if reuse_installed {
+                // rebuild some stages on a copy, the installed toolchain keeps working if it fails
+                copy_tree(&staged.dir, &staged.staging)?;
+            }
+        }
+
+        set_staging(Some(toolchain.id()));
+        Ok(staged)
+    }
+
+    fn finish(self) -> Result<()> {
+        set_staging(None);
+        if is_plan() {
+            return Ok(());
+        }
+
+        // the installed toolchain is moved aside rather than removed, so that it's only gone once
+        // the new one is in place
+        remove_dir_if_exists(&self.old)?;
+        if self.dir.exists() {
+            rename(&self.dir, &self.old)?;
+        }
+        rename(&self.staging, &self.dir)?;
+        remove_dir_if_exists(&self.old)
+    }
+}
+
+fn remove_dir_if_exists(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).context(format!("failed to remove `{}`", dir.display()))?;
+    }
+    Ok(())
+}
+
+fn rename(from: &Path, to: &Path) -> Result<()> {
+    std::fs::rename(from, to).context(format!(
+        "failed to rename `{}` to `{}`",
+        from.display(),
+        to.display()
+    ))
+}
+
+impl Drop for StagedInstall {
+    fn drop(&mut self) {
+        set_staging(None);
+    }
 }
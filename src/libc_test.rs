@@ -0,0 +1,310 @@
+//! `toolup libc-test`: cross-build the musl libc-test suite with a toolchain and run it in a VM.
+//!
+//! libc-test isn't tied to musl, running it against glibc and musl (or several versions of one)
+//! shows where their behavior differs. The results are saved per libc version of a target, and
+//! compared with the previous run of the same version or with another version.
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use walkdir::WalkDir;
+
+use crate::{
+    check::{CheckReport, CheckResults, parse_sum},
+    commands::{is_plan, plan_step, run_command_in},
+    download::{DownloadResult, cache_dir, decompress_tar, download_archive, lock_source},
+    error::Failure,
+    packages::{Source, busybox::add_libraries},
+    profile::Toolchain,
+    qemu::{Exec, QemuOverrides, start_vm},
+};
+
+/// libc-test has no releases, the snapshot of its master branch is downloaded once and cached.
+const LIBC_TEST_URL: &str = "https://repo.or.cz/libc-test.git/snapshot/refs/heads/master.tar.gz";
+/// The directories of the build tree with tests, `common` and `api` only hold the test runtime
+/// and compile-only checks.
+const TEST_DIRS: &[&str] = &["functional", "regression", "math"];
+/// Where the tests are copied in the guest.
+const GUEST_DIR: &str = "/toolup/libc-test";
+/// The summary the guest writes to the share, in the format of a DejaGnu `.sum` file.
+const SUMMARY: &str = "libc-test.sum";
+
+/// Runs every test in `tests` with a timeout, a test passes if it exits with 0.
+const RUN_SCRIPT: &str = r#"#!/bin/sh
+cd /toolup/libc-test || exit 1
+out=/toolup/share/libc-test.sum
+: > "$out" || exit 1
+while IFS= read -r test; do
+    if timeout 60 "./$test" >/dev/null 2>&1; then
+        echo "PASS: $test" >> "$out"
+    else
+        echo "FAIL: $test" >> "$out"
+        echo "FAIL: $test"
+    fi
+done < tests
+"#;
+
+/// The results of a libc-test run, along with the other libc versions tested for the target.
+pub struct LibcTestReport {
+    /// e.g. `musl-1.2.5`
+    pub libc: String,
+    pub check: CheckReport,
+    /// The results of every libc version tested for the target, including this run
+    pub versions: BTreeMap<String, CheckResults>,
+}
+
+impl Display for LibcTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (libc, results) in &self.versions {
+            let count = |kind: &str| results.counts.get(kind).copied().unwrap_or(0);
+            let current = if *libc == self.libc { "*" } else { " " };
+            writeln!(
+                f,
+                "{current} {libc:<14} {} passed, {} failed",
+                count("PASS"),
+                count("FAIL")
+            )?;
+        }
+        write!(f, "{}", self.check)
+    }
+}
+
+fn results_dir(toolchain: &Toolchain) -> Result<PathBuf> {
+    let dir = cache_dir()?
+        .join("libc-test")
+        .join(toolchain.target.to_string());
+    std::fs::create_dir_all(&dir).context("creating toolup libc-test dir")?;
+    Ok(dir)
+}
+
+/// Returns the saved results of every libc version tested for the target of `toolchain`.
+pub fn saved_results(toolchain: &Toolchain) -> Result<BTreeMap<String, CheckResults>> {
+    let mut versions = BTreeMap::new();
+    for entry in std::fs::read_dir(results_dir(toolchain)?)? {
+        let path = entry?.path();
+        let Some(libc) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        let content = std::fs::read_to_string(&path)
+            .context(format!("failed to read `{}`", path.display()))?;
+        let results = serde_json::from_str(&content)
+            .context(format!("invalid results in `{}`", path.display()))?;
+        versions.insert(libc.to_string(), results);
+    }
+    Ok(versions)
+}
+
+/// Download libc-test, returns the source tree.
+fn download_libc_test() -> Result<PathBuf> {
+    log::info!("=> downloading libc-test");
+    let source = Source::for_package("libc-test", "master", LIBC_TEST_URL, "libc-test");
+    let dir = cache_dir()?.join(&source.dirname);
+    let _lock = lock_source(&source.dirname);
+
+    if is_plan() {
+        plan_step(format!("download {} to {}", source.url, dir.display()));
+        return Ok(dir.join("libc-test"));
+    }
+    if !dir.exists() {
+        let (DownloadResult::Cached(archive)
+        | DownloadResult::Replaced(archive)
+        | DownloadResult::Created(archive)) = download_archive(&source.url, true)?;
+        decompress_tar(archive, &dir).context(format!("failed to extract {}", source.url))?;
+    }
+
+    // the snapshot's top directory is named after the commit
+    let mut entries = std::fs::read_dir(&dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    match (entries.pop(), entries.is_empty()) {
+        (Some(entry), true) if entry.path().join("Makefile").exists() => Ok(entry.path()),
+        _ => bail!("`{}` is not a libc-test source tree", dir.display()),
+    }
+}
+
+/// Cross-build libc-test with `toolchain`, returns the build directory.
+fn build(toolchain: &Toolchain, jobs: u64) -> Result<PathBuf> {
+    let source = download_libc_test()?;
+    let build_dir = cache_dir()?.join("libc-test-build").join(toolchain.id());
+    log::info!("=> building libc-test with {}", toolchain.id());
+
+    if !is_plan() {
+        std::fs::create_dir_all(&build_dir)?;
+        let config = source.join("config.mak");
+        if !config.exists() {
+            std::fs::copy(source.join("config.mak.def"), &config)
+                .context("failed to create libc-test's `config.mak`")?;
+        }
+    }
+
+    let env = vec![("PATH", toolchain.env_path()?)];
+    // tests that don't compile are missing from the results, so a failed build isn't fatal. The
+    // tests aren't run on the build machine, `RUN_TEST` replaces the runner.
+    if let Err(err) = run_command_in(
+        &source,
+        "make libc-test",
+        "make",
+        &[
+            "-k".to_string(),
+            format!("-j{jobs}"),
+            format!("B={}", build_dir.display()),
+            format!("CROSS_COMPILE={}-", toolchain.target),
+            format!("CC={}-gcc", toolchain.target),
+            "RUN_TEST=true".to_string(),
+        ],
+        Some(env),
+    ) {
+        log::warn!("{err:#}");
+    }
+    Ok(build_dir)
+}
+
+/// The test programs in `build_dir`, relative to it.
+fn collect_tests(build_dir: &Path) -> Result<Vec<String>> {
+    let mut tests = vec![];
+    for dir in TEST_DIRS {
+        let dir = build_dir.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in WalkDir::new(&dir).max_depth(1).sort_by_file_name() {
+            let entry = entry?;
+            if entry.file_type().is_file()
+                && entry.path().extension().is_some_and(|ext| ext == "exe")
+            {
+                let test = entry.path().strip_prefix(build_dir)?;
+                tests.push(test.to_string_lossy().into_owned());
+            }
+        }
+    }
+    Ok(tests)
+}
+
+/// The `exec` command running the tests in `build_dir`, with the libraries they need from the
+/// sysroot of `toolchain`. They replace the libraries of the rootfs, which may come from another
+/// libc version.
+fn exec(toolchain: &Toolchain, build_dir: &Path, summary: &Path) -> Result<Exec> {
+    let tests = collect_tests(build_dir)?;
+    if tests.is_empty() {
+        return Err(Failure::Build).context(format!(
+            "no libc-test programs were built in `{}`",
+            build_dir.display()
+        ));
+    }
+
+    log::info!("=> running {} libc-test programs", tests.len());
+
+    let guest = Path::new(GUEST_DIR);
+    let mut exec = Exec::default();
+    for test in &tests {
+        let host = build_dir.join(test);
+        add_libraries(toolchain, &host, &mut exec.files)?;
+        exec.files.insert(guest.join(test), host);
+    }
+    let list = build_dir.join("tests");
+    std::fs::write(&list, tests.join("\n") + "\n")?;
+    exec.files.insert(guest.join("tests"), list);
+    let script = build_dir.join("run.sh");
+    std::fs::write(&script, RUN_SCRIPT)?;
+    exec.files.insert(guest.join("run.sh"), script);
+
+    exec.commands.push(format!("sh {GUEST_DIR}/run.sh"));
+    exec.outputs.insert(SUMMARY.into(), summary.to_path_buf());
+    Ok(exec)
+}
+
+/// Build libc-test with `toolchain` and run it in a VM booting `kernel` with `rootfs`. The results
+/// are compared with `against`, the results of another libc version (e.g. `glibc-2.39`), or with
+/// the previous run of the same version. Returns `None` with `--plan`.
+pub fn libc_test(
+    toolchain: &Toolchain,
+    kernel: &Path,
+    rootfs: &Path,
+    jobs: u64,
+    against: Option<&str>,
+    overrides: &QemuOverrides,
+) -> Result<Option<LibcTestReport>> {
+    let libc = toolchain.libc.to_string();
+    let results_file = results_dir(toolchain)?.join(format!("{libc}.json"));
+    let mut versions = saved_results(toolchain)?;
+    let baseline = match against {
+        Some(other) => Some(
+            versions
+                .get(other)
+                .cloned()
+                .context(Failure::Usage)
+                .context(format!(
+                    "{other} wasn't tested on {} yet, tested: {}",
+                    toolchain.target,
+                    versions.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))?,
+        ),
+        None => versions.get(&libc).cloned(),
+    };
+
+    let build_dir = build(toolchain, jobs)?;
+    if is_plan() {
+        plan_step("run the libc-test programs in the VM");
+        return Ok(None);
+    }
+    let summary = build_dir.join(SUMMARY);
+    if summary.exists() {
+        std::fs::remove_file(&summary)?;
+    }
+    let exec = exec(toolchain, &build_dir, &summary)?;
+    start_vm(&toolchain.target, kernel, rootfs, &exec, overrides)?;
+
+    if !summary.exists() {
+        bail!(
+            "the guest didn't write the results, the kernel needs 9p over virtio \
+             (CONFIG_NET_9P_VIRTIO)"
+        );
+    }
+    let mut results = CheckResults::default();
+    parse_sum(&std::fs::read_to_string(&summary)?, &mut results);
+    std::fs::write(&results_file, serde_json::to_string_pretty(&results)?)
+        .context(format!("failed to write `{}`", results_file.display()))?;
+    log::info!("results saved to {}", results_file.display());
+
+    versions.insert(libc.clone(), results.clone());
+    Ok(Some(LibcTestReport {
+        libc,
+        check: CheckReport { results, baseline },
+        versions,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::collect_tests;
+
+    #[test]
+    fn test_collect_tests() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        for file in [
+            "functional/string.exe",
+            "functional/string.o",
+            "regression/daemon-failure-static.exe",
+            "common/runtest.exe",
+            "math/acos.exe",
+        ] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, "")?;
+        }
+        assert_eq!(
+            collect_tests(dir.path())?,
+            [
+                "functional/string.exe",
+                "regression/daemon-failure-static.exe",
+                "math/acos.exe",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! In-process locks keyed by name.
+//!
+//! Toolchains installed in parallel share source archives, extracted sources and in-tree build
+//! directories (e.g. the kernel headers are installed from the source tree). A thread takes the
+//! lock of a name before touching them, other threads wait until it's released and then usually
+//! find the work already done.
+//!
+//! State files shared by every toolup process (e.g. the cache manifest) are updated under a
+//! [`lock_file`] and replaced with [`write_atomic`], readers never see a partial file.
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    path::Path,
+    sync::{Condvar, Mutex},
+};
+
+use anyhow::{Context, Result};
+
+static HELD: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+static RELEASED: Condvar = Condvar::new();
+
+/// The lock of a name, released on drop.
+#[must_use]
+pub struct NamedLock(String);
+
+/// Wait until no other thread holds the lock of `name` and take it.
+///
+/// Locks are not reentrant, a thread taking a lock it already holds waits forever.
+pub fn lock(name: impl Into<String>) -> NamedLock {
+    let name = name.into();
+    let mut held = HELD.lock().expect("the lock is not poisoned");
+    while held.contains(&name) {
+        held = RELEASED.wait(held).expect("the lock is not poisoned");
+    }
+    held.insert(name.clone());
+    NamedLock(name)
+}
+
+impl Drop for NamedLock {
+    fn drop(&mut self) {
+        HELD.lock()
+            .expect("the lock is not poisoned")
+            .remove(&self.0);
+        RELEASED.notify_all();
+    }
+}
+
+/// Wait until no other process holds the lock on `path` and take it, the file is created if it
+/// doesn't exist. The lock is released when the file is dropped.
+///
+/// Threads of the same process are serialized too, each opens its own file.
+pub fn lock_file(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .context(format!("failed to create `{}`", parent.display()))?;
+    }
+    let file = File::create(path).context(format!("failed to create `{}`", path.display()))?;
+    file.lock()
+        .context(format!("failed to lock `{}`", path.display()))?;
+    Ok(file)
+}
+
+/// Replace `path` with `contents` through a temporary file renamed over it.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir).context(format!("failed to create `{}`", dir.display()))?;
+    let mut temp = tempfile::NamedTempFile::new_in(dir).context(format!(
+        "failed to create a temporary file in `{}`",
+        dir.display()
+    ))?;
+    std::io::Write::write_all(&mut temp, contents.as_ref())
+        .context(format!("failed to write `{}`", temp.path().display()))?;
+    temp.persist(path)
+        .context(format!("failed to write `{}`", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lock_file, write_atomic};
+
+    #[test]
+    fn test_write_atomic() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("state/manifest.json");
+        let _lock = lock_file(&dir.path().join("state/manifest.json.lock"))?;
+        write_atomic(&path, "{}")?;
+        write_atomic(&path, "{\"a\": 1}")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "{\"a\": 1}");
+        // only the file and its lock, no temporary files are left behind
+        assert_eq!(std::fs::read_dir(dir.path().join("state"))?.count(), 2);
+        Ok(())
+    }
+}
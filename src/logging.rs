@@ -0,0 +1,128 @@
+//! Log output and traces of the install pipeline.
+//!
+//! Stages and commands run in `tracing` spans (e.g. `install_package` with the package name and
+//! version), and `log::` records are forwarded to the same subscriber. Text output only prints the
+//! messages, JSON output adds the current span and its parents to every line. A Chrome trace of
+//! all spans can be written for `chrome://tracing` or <https://ui.perfetto.dev>.
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use anyhow::{Result, anyhow};
+use colored::Colorize;
+use tracing::{Event, Level, Subscriber, level_filters::LevelFilter};
+use tracing_subscriber::{
+    Layer,
+    fmt::{
+        FmtContext, FormatEvent, FormatFields,
+        format::{self, Writer},
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+pub use tracing_chrome::FlushGuard;
+
+use crate::ui;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!(
+                "`{s}` is an invalid log format, use `text` or `json`"
+            )),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Prints info messages as they are and everything else in yellow, without span context.
+struct PlainFormat;
+
+impl<S, N> FormatEvent<S, N> for PlainFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        if *event.metadata().level() == Level::INFO {
+            ctx.field_format().format_fields(writer.by_ref(), event)?;
+        } else {
+            let mut message = String::new();
+            ctx.field_format()
+                .format_fields(Writer::new(&mut message), event)?;
+            write!(writer, "{}", message.yellow())?;
+        }
+        writeln!(writer)
+    }
+}
+
+/// Install the global subscriber.
+///
+/// `verbose` is the number of `-v` flags. When `chrome_trace` is set every span is also recorded to
+/// that file, the trace is written when the returned guard is dropped.
+pub fn init(
+    verbose: u8,
+    log_format: LogFormat,
+    chrome_trace: Option<&Path>,
+) -> Result<Option<FlushGuard>> {
+    let level = match verbose {
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+
+    let output = match log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(ui::log_writer)
+            .event_format(PlainFormat)
+            .with_filter(level)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .with_writer(ui::log_writer)
+            .event_format(format::json().with_current_span(true).with_span_list(true))
+            .fmt_fields(format::JsonFields::new())
+            .with_filter(level)
+            .boxed(),
+    };
+
+    let (chrome, guard) = match chrome_trace {
+        Some(path) => {
+            let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+                .file(path)
+                .include_args(true)
+                .build();
+            (Some(layer.with_filter(level)), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(output)
+        .with(chrome)
+        .try_init()?;
+    Ok(guard)
+}
@@ -1,21 +1,90 @@
-use std::{ffi::OsString, io::Write, process::Command, str::FromStr};
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+    str::FromStr,
+};
 
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result, bail};
+use clap::{Args, Parser, Subcommand};
+use indicatif::HumanBytes;
 
 use toolup::{
-    config::resolve_target_toolchain,
-    download::cache_dir,
-    install_toolchain, install_toolchain_str,
-    profile::{Target, Toolchain},
-    qemu::start_vm,
+    cache,
+    capability::{self, Operation},
+    check::{Suite, check},
+    cmake,
+    commands::{set_env_file, set_inherit_env, set_plan},
+    compat,
+    config::{
+        ToolchainSettings, load_local_config, resolve_sources, resolve_target_settings,
+        resolve_target_toolchain, resolve_workspace,
+    },
+    doctor,
+    download::{
+        DEFAULT_SYSTEM_DIR, Rate, cache_dir, set_backend, set_cache_dir, set_limit_rate,
+        set_mirrors, set_system_dir, transfer_summary,
+    },
+    error::{EXIT_CODES, Failure, exit_code},
+    gc::{self, Age},
+    hooks, inspect, install_toolchain, install_toolchains, journal, kdump, libc_test,
+    logging::{self, LogFormat},
+    metadata, outdated, pack,
+    packages::binutils::{Linker, ensure_linker},
+    packages::linux::{KernelFeatures, KernelVersion, kernel_toolchain, set_kernel_flag_rules},
+    packages::llvm::{self, Compiler},
+    packages::sysroot_libs::SysrootLib,
+    packages::wasi::WasiLibcVersion,
+    packages::{clean_toolchain, set_source_overrides},
+    parse_toolchain, prebuilt, print_install_summary,
+    profile::{Arch, Profile, Target, Toolchain, parse_name},
+    provenance,
+    qemu::{QemuOverrides, detach_vm, run_baremetal, start_vm},
+    reproduce::reproduce,
+    runner, sandbox,
+    self_update::{self, UpdateStatus},
+    shell, snapshot, specs,
+    stage::{Force, Stage, set_retry_policy},
+    sysroot::{PackageDb, add_package, clone_sysroot, remove_package},
+    vendor, vm,
 };
 
+/// Used when neither the command line nor the configuration specify the number of jobs.
+const DEFAULT_JOBS: u64 = 10;
+/// Used when `--parallel` isn't given.
+const DEFAULT_PARALLEL: usize = 4;
+
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES)]
 struct Cli {
     #[arg(long, short, action = clap::ArgAction::Count, global = true)]
     verbose: u8,
+    #[arg(long, global = true, default_value_t = LogFormat::Text)]
+    /// `text` or `json`, JSON lines include the current stage (span) and its fields
+    log_format: LogFormat,
+    #[arg(long, global = true, value_name = "FILE")]
+    /// Write a Chrome trace of every stage to FILE, open it in chrome://tracing or ui.perfetto.dev
+    trace_chrome: Option<PathBuf>,
+    #[arg(long, global = true, value_name = "RATE")]
+    /// Limit downloads to RATE bytes per second, e.g. `500k` or `2M`
+    limit_rate: Option<Rate>,
+    #[arg(long, global = true, default_value_t = false)]
+    /// Pass the full host environment to configure/make instead of a minimal one
+    inherit_env: bool,
+    #[arg(long, global = true, default_value_t = false)]
+    /// Write the environment and command line of every configure/make step to env.sh in its
+    /// build directory, `. env.sh && toolup_rerun` runs the step again by hand
+    env_file: bool,
+    #[arg(long, global = true, default_value_t = false)]
+    /// Run configure/make in a bubblewrap sandbox that only sees the system directories, the
+    /// toolup directories and the install prefix
+    sandbox: bool,
+    #[arg(long, global = true, value_name = "DIR", num_args = 0..=1, require_equals = true, default_missing_value = DEFAULT_SYSTEM_DIR)]
+    /// Share toolchains and the cache with every user of the machine from DIR (a group-writable
+    /// directory, `--system=DIR`) instead of ~/.toolup and ~/.cache/toolup [default:
+    /// /usr/local/toolup]
+    system: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -25,120 +94,1517 @@ enum Commands {
     /// Install a toolchain for target
     Install {
         /// e.g. aarch64-unknown-linux-gnu
-        target: String,
+        #[arg(required_unless_present_any = ["all", "targets"], value_parser = canonical_target)]
+        target: Option<String>,
+        #[arg(long, conflicts_with_all = ["target", "targets", "gcc", "libc", "binutils"])]
+        /// Install every toolchain declared in `toolup.toml` in the current directory, with the
+        /// versions it declares
+        all: bool,
+        #[arg(long, value_delimiter = ',', value_parser = canonical_target, conflicts_with = "target")]
+        /// Install toolchains for several targets in parallel, e.g. aarch64-unknown-linux-gnu,riscv64-unknown-linux-musl
+        targets: Vec<String>,
+        #[arg(long)]
+        /// How many toolchains `--targets` and `--all` install at the same time [default: 4]
+        parallel: Option<usize>,
         #[arg(long, default_value = "15.2.0")]
         /// GCC version
         gcc: String,
         #[arg(long)]
-        /// glibc or musl version; depending on the target
+        /// glibc or musl version; depending on the target. `newlib` or `newlib-<version>` for
+        /// freestanding targets
         libc: Option<String>,
         #[arg(long, default_value = "2.45")]
         /// binutils version
         binutils: String,
-        #[arg(short, long, default_value_t = 10)]
-        /// The number of threads to use for running commands
-        jobs: u64,
+        #[arg(short, long)]
+        /// The number of threads to use for running commands, shared by the toolchains installed in
+        /// parallel [default: 10]
+        jobs: Option<u64>,
+        #[arg(long, default_value_t = false)]
+        /// Re-install the toolchain even if it's already installed
+        force: bool,
+        #[arg(long, value_delimiter = ',', conflicts_with = "force")]
+        /// Only rebuild these stages of an installed toolchain: binutils, kernel-headers, libc,
+        /// gcc-final, gdb, llvm
+        force_stage: Vec<Stage>,
+        #[arg(long, default_value_t = false)]
+        /// Print the downloads and commands that would run without running them
+        plan: bool,
+        #[arg(long, default_value_t = false)]
+        /// Also build the gold linker. Use `--force-stage binutils` for an installed toolchain
+        gold: bool,
+        #[arg(long, default_value_t = false)]
+        /// Also build a cross gdb. Use `--force-stage gdb` for an installed toolchain
+        gdb: bool,
+        #[arg(long, default_value_t = false)]
+        /// Build gdb with Python scripting, implies `--gdb`
+        gdb_python: bool,
+        #[arg(long, default_value = "gcc")]
+        /// `clang` also builds clang and lld, which compile against the sysroot and link with
+        /// libgcc and libstdc++ of GCC. Use `--force-stage llvm` for an installed toolchain
+        compiler: Compiler,
+        #[arg(long, default_value = "default")]
+        /// For freestanding targets, `nano` builds newlib-nano and a size-optimized GCC
+        profile: Profile,
+        #[arg(long, value_name = "BUNDLE")]
+        /// Use the source archives in a bundle created by `toolup vendor` instead of downloading
+        vendor: Option<PathBuf>,
+        #[arg(long, value_name = "DIR")]
+        /// Install into DIR instead of ~/.toolup/toolchains, e.g. /opt/cross
+        prefix: Option<PathBuf>,
+        #[arg(long, value_name = "INDEX", num_args = 0..=1, require_equals = true, conflicts_with = "force_stage")]
+        /// Install prebuilt toolchains listed in INDEX (a URL or a path, `--prebuilt=INDEX`) and
+        /// only build the missing ones [default: `prebuilt_index` under `[workspace]`]
+        prebuilt: Option<Option<String>>,
+        #[arg(long, default_value_t = false)]
+        /// Remove the build trees of each toolchain once it's installed, the downloaded archives
+        /// and sources are kept [default: `clean_after_install` under `[workspace]`]
+        clean_after_install: bool,
+    },
+    /// Bundle the source archives needed to install toolchains (and kernels) offline
+    Vendor {
+        /// e.g. aarch64-unknown-linux-gnu, the versions are resolved like `toolup cc`
+        #[arg(long = "target", short, value_parser = canonical_target, required_unless_present = "all")]
+        targets: Vec<String>,
+        #[arg(long, conflicts_with = "targets")]
+        /// Bundle every toolchain declared in `toolup.toml` in the current directory
+        all: bool,
+        #[arg(long)]
+        /// Also bundle the sources of this kernel and the busybox rootfs for every target
+        kernel: Option<String>,
+        #[arg(long, default_value = "sources.tar")]
+        out: PathBuf,
     },
     /// Invoke the GCC compiler for the selected toolchain
     CC {
+        /// e.g. aarch64-unknown-linux-gnu, or a variant like aarch64-unknown-linux-gnu@hardened
+        #[arg(required_unless_present = "toolchain", allow_hyphen_values = true)]
+        target: Option<String>,
+        #[arg(long, value_parser = canonical_target)]
+        /// The toolchain (e.g. a variant) to compile with, TARGET is then left out
+        toolchain: Option<String>,
+        #[arg(long, default_value_t = false)]
+        /// Link with `-static -no-pie` and check the output is fully static (musl targets only)
+        static_musl: bool,
+        #[arg(long)]
+        /// The linker to use: bfd, gold or lld [default: the configured linker or bfd]
+        linker: Option<Linker>,
+        #[arg(long, value_name = "QUERY")]
+        /// Print sysroot, target-triple, libc or include-dirs (with the configured cflags and
+        /// OPTIONS) instead of compiling
+        print: Option<inspect::Query>,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        options: Vec<OsString>,
+    },
+    /// Start an interactive shell set up to cross-compile with the toolchain of a target: PATH,
+    /// CC, CXX, SYSROOT, pkg-config and a prompt naming the target
+    Shell {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[arg(long, default_value_t = false)]
+        /// Also set ARCH and CROSS_COMPILE to build the Linux kernel
+        kernel: bool,
+    },
+    /// Print a clang configuration file for the system's clang to compile against an installed
+    /// toolchain: the target, the sysroot, the toolchain's binutils and GCC's libraries. Use it
+    /// with `clang --config=FILE`
+    ClangConfig {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[arg(long)]
+        /// Write the configuration to FILE instead of printing it
+        out: Option<PathBuf>,
+    },
+    /// Print a CMake toolchain file for an installed toolchain: its compilers, binutils and
+    /// sysroot. Use it with `cmake -DCMAKE_TOOLCHAIN_FILE=FILE`
+    CmakeToolchain {
         /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
         target: String,
+        #[arg(long)]
+        /// Write the toolchain file to FILE instead of printing it
+        out: Option<PathBuf>,
+    },
+    /// Run a bare-metal program for a freestanding target with QEMU semihosting and exit with its
+    /// exit code
+    RunBaremetal {
+        /// e.g. riscv64-elf
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[command(flatten)]
+        qemu: QemuArgs,
+        /// The program, linked with a semihosting runtime
+        elf: PathBuf,
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
-        options: Vec<OsString>,
+        args: Vec<OsString>,
+    },
+    /// Run a bare-metal ELF built by cargo with QEMU semihosting, e.g. Rust `no_std` firmware. Set
+    /// `runner = "toolup runner"` for the target in `.cargo/config.toml`
+    Runner {
+        #[arg(long, value_parser = canonical_target)]
+        /// e.g. riscv64-elf [default: the freestanding target of the ELF's architecture]
+        target: Option<String>,
+        #[command(flatten)]
+        qemu: QemuArgs,
+        /// The program, linked with a semihosting runtime
+        elf: PathBuf,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
     },
     /// Manage Linux kernel builds
     Linux {
         /// The kernel version to build. e.g. 6.17
         version: String,
-        #[arg(long, short, default_value = "x86_64-unknown-linux-gnu")]
-        toolchain: String,
-        #[arg(short, long, default_value_t = 10)]
-        /// The number of threads to use for running commands
-        jobs: u64,
+        #[arg(
+            long,
+            short,
+            visible_alias = "toolchain",
+            default_value = "x86_64-unknown-linux-gnu",
+            value_parser = linux_target
+        )]
+        /// e.g. aarch64-unknown-linux-musl, or only an architecture for a glibc target: aarch64
+        target: String,
+        #[arg(short, long)]
+        /// The number of threads to use for running commands [default: 10]
+        jobs: Option<u64>,
         #[arg(short, long, default_value_t = false)]
         /// Open the kernel's menuconfig before building
         menuconfig: bool,
         #[arg(short, long, default_value_t = false)]
         /// Whether to run defconfig or not. This will erase old config.
         defconfig: bool,
+        #[arg(long)]
+        /// Run this shell command in the guest instead of a shell and exit with its status, can be
+        /// repeated to run several commands in one boot
+        exec: Vec<String>,
+        #[arg(long)]
+        /// Run the shell commands in this file, one per line, after the `--exec` ones
+        exec_list: Option<PathBuf>,
+        #[arg(long)]
+        /// Write the exit status, boot time and run times of the VM to this JSON file
+        report: Option<PathBuf>,
+        #[arg(long, default_value_t = false, conflicts_with_all = ["exec", "exec_list", "report"])]
+        /// Start the VM in the background and print its id, see `toolup vm`
+        detach: bool,
+        #[arg(long, default_value_t = false, conflicts_with = "detach")]
+        /// Reserve memory for a crash kernel, crash the guest after the `--exec` commands and check
+        /// that the crash kernel captured a vmcore, copied to ./vmcore
+        kdump: bool,
+        #[arg(long, default_value_t = false)]
+        /// Cross-compile gdbserver into the rootfs (/usr/bin/gdbserver) to debug the programs run
+        /// in the guest from the host's gdb
+        gdbserver: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with_all = ["exec", "exec_list", "report", "detach", "kdump"]
+        )]
+        /// Boot into an interactive shell with the toolchain's sysroot mounted read-only at
+        /// /toolup/sysroot
+        shell: bool,
+        #[arg(long, default_value_t = false)]
+        /// Build the kernel with debug info and start the VM stopped, waiting for gdb on port 1234.
+        /// The gdb command loading the symbols of the kernel is printed
+        debug: bool,
+        #[command(flatten)]
+        qemu: QemuArgs,
+        #[arg(long, value_delimiter = ',')]
+        /// Rebuild these stages even if they were built before: binutils, kernel-headers,
+        /// kernel-image, libc, gcc-final
+        force_stage: Vec<Stage>,
+        #[arg(long, default_value_t = false)]
+        /// Print the downloads and commands that would run without running them
+        plan: bool,
+    },
+    /// Check that a program can run in a `toolup linux` VM: its glibc symbol versions, the
+    /// minimum kernel of its ABI tag and the syscalls it makes against the VM's kernel and libc
+    KernelHeadersCompat {
+        /// The kernel version of the VM, e.g. 4.19
+        #[arg(long)]
+        kernel: String,
+        #[arg(
+            long,
+            short,
+            default_value = "x86_64-unknown-linux-gnu",
+            value_parser = linux_target
+        )]
+        /// e.g. aarch64-unknown-linux-musl, or only an architecture for a glibc target: aarch64
+        target: String,
+        #[arg(short, long)]
+        /// The number of threads to use for installing the VM's toolchain [default: 10]
+        jobs: Option<u64>,
+        /// The program, e.g. one passed to `toolup linux --exec`
+        program: PathBuf,
+    },
+    /// Build a toolchain twice from clean build trees and report files that differ
+    Reproduce {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[arg(long)]
+        /// Compare a single build against a snapshot from a previous run instead of building twice
+        against: Option<PathBuf>,
+        #[arg(short, long)]
+        /// The number of threads to use for running commands [default: 10]
+        jobs: Option<u64>,
+    },
+    /// Run the upstream test suite of an installed toolchain and compare it with a baseline
+    Check {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[arg(long)]
+        /// gcc, binutils or glibc
+        suite: Suite,
+        #[arg(long)]
+        /// A results file from a previous run [default: the previous run of the suite]
+        baseline: Option<PathBuf>,
+        #[arg(short, long)]
+        /// The number of threads to use for running commands [default: 10]
+        jobs: Option<u64>,
+    },
+    /// Cross-build the musl libc-test suite, run it in a VM and compare the failures with other
+    /// libc versions tested on the target
+    LibcTest {
+        #[arg(value_parser = linux_target)]
+        /// e.g. aarch64-unknown-linux-musl, or only an architecture for a glibc target: aarch64
+        target: String,
+        #[arg(long)]
+        /// The kernel version to boot, e.g. 6.17
+        kernel: String,
+        #[arg(long)]
+        /// The libc version to test [default: the toolchain's]
+        libc: Option<String>,
+        #[arg(long)]
+        /// Compare with the results of another libc version, e.g. musl-1.2.4 [default: the
+        /// previous run of the same version]
+        against: Option<String>,
+        #[arg(short, long)]
+        /// The number of threads to use for running commands [default: 10]
+        jobs: Option<u64>,
+        #[command(flatten)]
+        qemu: QemuArgs,
+        #[arg(long, default_value_t = false)]
+        /// Print the downloads and commands that would run without running them
+        plan: bool,
+    },
+    /// Report ABI defaults of an installed toolchain: PIE/SSP, libc version, -march, long double,
+    /// TLS model and the dynamic loader
+    Inspect {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[arg(long)]
+        /// Compare with another target or with a toolchain id inspected before
+        diff: Option<String>,
+    },
+    /// Warn about objdirs in the cache that were configured in another host environment (host
+    /// compiler, make, binutils, distro or build variables) and may fail to rebuild
+    Doctor {},
+    /// Show the versions of an installed toolchain and the configure commands it was built with
+    Describe {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+    },
+    /// Pack an installed toolchain and its sysroot into a relocatable .tar.gz, with a
+    /// `toolup-pack.json` naming its versions, target and host
+    Pack {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[arg(long)]
+        /// [default: <toolchain id>-<host>.tar.gz]
+        out: Option<PathBuf>,
+    },
+    /// Publish packed toolchains for `toolup install --prebuilt`
+    Publish {
+        #[command(subcommand)]
+        action: PublishAction,
+    },
+    /// Print how an installed toolchain was built: its sources, patches, configure commands, the
+    /// build machine and the hash of every file
+    Provenance {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[arg(long, default_value_t = false)]
+        /// Check the toolchain files against the hashes instead
+        verify: bool,
+    },
+    /// Manage toolchain sysroots
+    Sysroot {
+        #[command(subcommand)]
+        action: SysrootAction,
+    },
+    /// Inspect the commands executed while building toolchains and kernels
+    Journal {
+        #[command(subcommand)]
+        action: JournalAction,
+    },
+    /// Show pinned versions in `toolup.toml` that are behind the latest upstream releases
+    Outdated {
+        #[arg(long, default_value_t = false)]
+        /// Update the outdated pins, keeping the layout of the configuration
+        update: bool,
+    },
+    /// Remove toolchains, sysroots and kernel images that weren't used recently
+    Gc {
+        #[arg(long, default_value = "90d")]
+        /// e.g. 90d, 2w or 12h
+        unused_for: Age,
+        #[arg(long, default_value_t = false)]
+        /// Only list what would be removed
+        dry_run: bool,
+    },
+    /// Remove the build trees (objdirs) of the installed toolchains, keeping the downloaded
+    /// archives, the extracted sources and the installed toolchains
+    CleanBuilds {
+        #[arg(value_parser = canonical_target)]
+        /// Only the toolchain of this target, e.g. aarch64-unknown-linux-gnu
+        target: Option<String>,
+        #[arg(long, default_value_t = false)]
+        /// Only list what would be removed
+        dry_run: bool,
+    },
+    /// Manage the VMs started by `toolup linux`
+    Vm {
+        #[command(subcommand)]
+        action: VmAction,
     },
     /// Manage cache
     Cache {
         #[command(subcommand)]
         action: CacheAction,
     },
+    /// Manage the toolup installation
+    #[command(name = "self")]
+    SelfCmd {
+        #[command(subcommand)]
+        action: SelfAction,
+    },
+}
+
+#[derive(Args)]
+struct QemuArgs {
+    #[arg(long)]
+    /// Run this QEMU instead of the `qemu-system-*` on PATH
+    qemu_binary: Option<PathBuf>,
+    #[arg(long = "qemu-arg", allow_hyphen_values = true)]
+    /// An extra argument for QEMU, can be repeated: --qemu-arg=-device --qemu-arg=virtio-rng-pci
+    qemu_args: Vec<String>,
+}
+
+impl QemuArgs {
+    /// The overrides for `target`, the command line takes precedence over the configuration.
+    fn overrides(self, target: &str) -> Result<QemuOverrides> {
+        let settings = resolve_target_settings(target)?;
+        let mut args = settings.qemu_args;
+        args.extend(self.qemu_args);
+        Ok(QemuOverrides {
+            binary: self.qemu_binary.or(settings.qemu_binary),
+            args,
+            kernel_args: vec![],
+        })
+    }
+}
+
+#[derive(Subcommand)]
+enum JournalAction {
+    /// Show the commands executed by the last install of a toolchain
+    Show {
+        /// A target (resolved through `toolup.toml`) or a journal id from `toolup journal list`
+        toolchain: String,
+    },
+    /// List the recorded journals
+    List {},
+}
+
+#[derive(Subcommand)]
+enum SysrootAction {
+    /// Create a writable per-project copy of a toolchain's sysroot
+    Clone {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[arg(long)]
+        /// Where to create the sysroot, e.g. ./sysroot
+        into: PathBuf,
+        #[arg(long, default_value_t = false)]
+        /// Mount an overlay on top of the shared sysroot instead of copying it
+        overlay: bool,
+    },
+    /// Build a library and install it into a sysroot
+    Add {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        /// zlib or openssl, optionally with a version: zlib@1.3.1
+        package: SysrootLib,
+        #[arg(long)]
+        /// A sysroot created with `toolup sysroot clone` [default: the toolchain's sysroot]
+        sysroot: Option<PathBuf>,
+        #[arg(short, long)]
+        /// The number of threads to use for running commands [default: 10]
+        jobs: Option<u64>,
+    },
+    /// List the packages added to a sysroot
+    List {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        #[arg(long)]
+        /// A sysroot created with `toolup sysroot clone` [default: the toolchain's sysroot]
+        sysroot: Option<PathBuf>,
+    },
+    /// Remove a package added with `toolup sysroot add`
+    Remove {
+        /// e.g. aarch64-unknown-linux-gnu
+        #[arg(value_parser = canonical_target)]
+        target: String,
+        package: String,
+        #[arg(long)]
+        /// A sysroot created with `toolup sysroot clone` [default: the toolchain's sysroot]
+        sysroot: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PublishAction {
+    /// Write an index.json of the toolchains packed with `toolup pack` in a directory
+    Index {
+        dir: PathBuf,
+        #[arg(long)]
+        /// Where the archives are served from [default: next to the index]
+        base_url: Option<String>,
+        #[arg(long)]
+        /// [default: <dir>/index.json]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SelfAction {
+    /// Replace this binary with the latest GitHub release
+    Update {
+        #[arg(long, default_value_t = false)]
+        /// Only report whether an update is available
+        check: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum VmAction {
+    /// List the running VMs
+    List {},
+    /// Shut a VM down, killing QEMU if it doesn't quit
+    Kill {
+        /// An id from `toolup vm list`
+        id: String,
+    },
+    /// Open the QEMU monitor of a VM, e.g. to run `info registers` or `savevm`
+    Console {
+        /// An id from `toolup vm list`
+        id: String,
+    },
 }
 
 #[derive(Subcommand)]
 enum CacheAction {
-    /// Remove cache for a specific toolchain
+    /// Remove the build trees of a toolchain's packages, the sources and the installed toolchain
+    /// are kept
     Clean {
-        toolchain: String,
+        #[arg(value_parser = canonical_target)]
+        target: String,
     },
     Dir {},
     Prune {},
+    /// Re-hash the cached archives and report corrupted or stale entries
+    Verify {
+        #[arg(long, default_value_t = false)]
+        /// Remove the broken entries and download or extract them again
+        repair: bool,
+    },
+    /// Print the index of the cache as JSON: every archive, extracted source, objdir, kernel image
+    /// and rootfs with its size, hash, creation and last use, and the toolchains using it
+    Index {},
+    /// Print a key for CI caches that changes whenever the toolchains (or kernel) would be built
+    /// from different sources, versions or flags
+    Key {
+        /// e.g. aarch64-unknown-linux-gnu, the versions are resolved like `toolup cc`
+        #[arg(long = "target", short, value_parser = canonical_target, required_unless_present = "all")]
+        targets: Vec<String>,
+        #[arg(long, conflicts_with = "targets")]
+        /// Every toolchain declared in `toolup.toml` in the current directory
+        all: bool,
+        #[arg(long)]
+        /// Also a kernel and the busybox rootfs of every target
+        kernel: Option<String>,
+    },
+    /// Write the installed toolchains, sysroots, kernel images and downloaded archives to a
+    /// tarball, gzipped if it ends with `.gz`
+    Save {
+        tarball: PathBuf,
+    },
+    /// Unpack a tarball written by `toolup cache save`
+    Restore {
+        tarball: PathBuf,
+    },
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// Accept target aliases such as `aarch64-linux-gnu`, commands use the canonical triple.
+fn canonical_target(s: &str) -> Result<String> {
+    let (target, variant) = parse_name(s)?;
+    let mut canonical = target.to_string();
+    if let Some(variant) = variant {
+        canonical.push_str(&format!("@{variant}"));
+    }
+    if canonical != s {
+        // the logger isn't initialized while parsing arguments
+        eprintln!("using `{canonical}` for `{s}`");
+    }
+    Ok(canonical)
+}
+
+/// Like [`canonical_target`], but an architecture alone selects its glibc linux target.
+fn linux_target(s: &str) -> Result<String> {
+    if s.contains('-') {
+        canonical_target(s)
+    } else {
+        canonical_target(&format!("{}-unknown-linux-gnu", Arch::from_str(s)?))
+    }
+}
+
+/// The commands in an `--exec-list` file, skipping blank lines and `#` comments.
+fn read_exec_list(path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .context(Failure::Usage)
+        .context(format!("failed to read `{}`", path.display()))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// The `--libc` version, or the default for the libc of `target`.
+fn default_libc(target: &str, libc: &Option<String>) -> String {
+    libc.clone().unwrap_or(if target.contains("musl") {
+        "1.2.5".into()
+    } else if target.starts_with("wasm32") {
+        WasiLibcVersion::default().to_string()
+    } else {
+        "2.42".into()
+    })
+}
+
+/// Build `toolchain` with a cross gdb for `--gdb` and `--gdb-python`.
+fn set_gdb(toolchain: &mut Toolchain, gdb: bool, python: bool) {
+    if gdb || python {
+        toolchain.gdb.get_or_insert_default().python |= python;
+    }
+}
+
+/// Build `toolchain` with clang and lld for `--compiler clang`.
+fn set_compiler(toolchain: &mut Toolchain, compiler: Compiler) {
+    if compiler == Compiler::Clang {
+        toolchain.llvm.get_or_insert_default();
+    }
+}
 
-    env_logger::builder()
-        .filter_level(match cli.verbose {
-            0 => log::LevelFilter::Info,
-            1 => log::LevelFilter::Debug,
-            _ => log::LevelFilter::Trace,
+/// `--prefix` or a `prefix` key as an absolute path, GCC is configured with it.
+fn absolute_prefix(prefix: Option<PathBuf>) -> Result<Option<PathBuf>> {
+    prefix
+        .map(|prefix| {
+            std::path::absolute(&prefix).context(format!("invalid prefix `{}`", prefix.display()))
         })
-        .format(|buf, record| {
-            let warn_style = buf.default_level_style(log::Level::Warn);
-            match record.level() {
-                log::Level::Info => {
-                    writeln!(buf, "{}", record.args())
-                }
-                _ => {
-                    writeln!(buf, "{warn_style}{}{warn_style:#}", record.args())
-                }
+        .transpose()
+}
+
+/// The toolchains of `--target`s, or every toolchain in `toolup.toml` with `--all`.
+fn selected_toolchains(targets: Vec<String>, all: bool) -> Result<Vec<(String, Toolchain)>> {
+    if all {
+        Ok(load_local_config()?
+            .context("`--all` requires a `toolup.toml` in the current directory")?
+            .toolchains()?
+            .into_iter()
+            .map(|(toolchain, _)| (toolchain.target.to_string(), toolchain))
+            .collect())
+    } else {
+        targets
+            .into_iter()
+            .map(|target| Ok((target.clone(), resolve_target_toolchain(&target)?.into())))
+            .collect()
+    }
+}
+
+/// The URLs of the source archives needed to install `toolchains`, and with `kernel`, to build
+/// that kernel and the busybox rootfs of every target.
+fn planned_archives(
+    toolchains: Vec<(String, Toolchain)>,
+    kernel: Option<&str>,
+) -> Result<Vec<String>> {
+    vendor::collect_urls(|| {
+        for (target, toolchain) in toolchains {
+            install_toolchain(toolchain, DEFAULT_JOBS, &Force::All)?;
+            if let Some(kernel) = kernel {
+                let target = Target::from_str(&target)?;
+                let (_, toolchain) = toolup::packages::linux::get_image(
+                    &target,
+                    kernel,
+                    DEFAULT_JOBS,
+                    false,
+                    false,
+                    KernelFeatures::default(),
+                    &Force::All,
+                )?;
+                toolup::packages::busybox::build_rootfs(&toolchain, false)?;
             }
-        })
-        .init();
+        }
+        Ok(())
+    })
+}
+
+/// Install `toolchains` in parallel and print a summary, fails if any of them failed.
+fn install_parallel(
+    toolchains: Vec<(Toolchain, ToolchainSettings)>,
+    parallel: Option<usize>,
+    jobs: Option<u64>,
+    force: &Force,
+    plan: bool,
+) -> Result<()> {
+    let parallel = parallel.unwrap_or(DEFAULT_PARALLEL);
+    let results = install_toolchains(
+        toolchains
+            .iter()
+            .map(|(toolchain, settings)| (toolchain.clone(), jobs.or(settings.jobs)))
+            .collect(),
+        parallel,
+        jobs.unwrap_or(DEFAULT_JOBS),
+        force,
+    );
+    if !plan {
+        print_install_summary(&results);
+    }
+    for ((toolchain, result), (_, settings)) in results.iter().zip(&toolchains) {
+        if result.is_ok() {
+            specs::write(toolchain, &settings.default_flags)?;
+        }
+    }
+    let total = results.len();
+    let mut errors = results.into_iter().filter_map(|(_, result)| result.err());
+    match errors.next() {
+        // exit with the failure of the first toolchain that failed
+        Some(err) => Err(err.context(format!(
+            "{} of {total} toolchains failed to install",
+            errors.count() + 1
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Returns the executable gcc links with `options`, or `None` if it doesn't link.
+fn linked_output(options: &[OsString]) -> Option<PathBuf> {
+    if options
+        .iter()
+        .any(|o| o == "-c" || o == "-E" || o == "-S" || o == "-shared")
+    {
+        return None;
+    }
+    let mut output = PathBuf::from("a.out");
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        if option == "-o" {
+            output = options.next()?.into();
+        } else if let Some(path) = option.to_str().and_then(|o| o.strip_prefix("-o")) {
+            output = path.into();
+        }
+    }
+    Some(output)
+}
+
+fn main() -> ExitCode {
+    let result = run();
+    if let Some(summary) = transfer_summary() {
+        log::info!("{summary}");
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let cli = Cli::parse();
+
+    // keep the guard until the end of `run` so the trace is written
+    let _trace = logging::init(cli.verbose, cli.log_format, cli.trace_chrome.as_deref())?;
+
+    set_inherit_env(cli.inherit_env);
+    set_env_file(cli.env_file);
+
+    let workspace = resolve_workspace()?;
+    if let Some(dir) = cli.system.or(workspace.system_dir) {
+        set_system_dir(dir)?;
+    }
+    if let Some(cache_dir) = workspace.cache_dir {
+        set_cache_dir(cache_dir);
+    }
+    set_mirrors(workspace.mirrors);
+    set_source_overrides(resolve_sources()?);
+    set_kernel_flag_rules(workspace.kernel_flags);
+    hooks::set_hooks(workspace.hooks);
+    sandbox::set_sandbox(cli.sandbox || workspace.sandbox.unwrap_or_default());
+    cache::set_clean_after_install(
+        matches!(
+            cli.command,
+            Commands::Install {
+                clean_after_install: true,
+                ..
+            }
+        ) || workspace.clean_after_install.unwrap_or_default(),
+    );
+    if let Some(policy) = workspace.retry {
+        set_retry_policy(policy);
+    }
+    if let Some(rate) = cli.limit_rate.or(workspace.limit_rate) {
+        set_limit_rate(rate);
+    }
+    if let Some(backend) = workspace.downloader {
+        set_backend(backend);
+    }
+    if let Commands::Install {
+        prebuilt: Some(index),
+        ..
+    } = &cli.command
+    {
+        let index = index
+            .clone()
+            .or(workspace.prebuilt_index)
+            .context(Failure::Usage)
+            .context(
+                "`--prebuilt` requires `--prebuilt=INDEX` or `prebuilt_index` in toolup.toml",
+            )?;
+        prebuilt::set_prebuilt_index(index);
+    }
 
     match cli.command {
         Commands::Install {
-            target: toolchain,
+            target: None,
+            all: true,
+            jobs,
+            parallel,
+            force,
+            force_stage,
+            plan,
+            vendor,
+            prefix,
+            ..
+        } => {
+            if let Some(bundle) = vendor {
+                vendor::import_bundle(&bundle)?;
+            }
+            set_plan(plan);
+            let force = Force::new(force, force_stage);
+            let config = load_local_config()?
+                .context("`--all` requires a `toolup.toml` in the current directory")?;
+            let toolchains = config
+                .toolchains()?
+                .into_iter()
+                .map(|(mut toolchain, settings)| {
+                    toolchain.prefix = absolute_prefix(prefix.clone().or(settings.prefix.clone()))?;
+                    Ok((toolchain, settings))
+                })
+                .collect::<Result<_>>()?;
+            install_parallel(toolchains, parallel, jobs, &force, plan)?;
+        }
+        Commands::Install {
+            target: None,
+            targets,
             gcc,
             libc,
             binutils,
             jobs,
+            parallel,
+            force,
+            force_stage,
+            plan,
+            gold,
+            gdb,
+            gdb_python,
+            compiler,
+            profile,
+            vendor,
+            prefix,
+            ..
         } => {
-            let libc = libc.unwrap_or(if toolchain.contains("musl") {
-                "1.2.5".into()
-            } else {
-                "2.42".into()
-            });
-            install_toolchain_str(toolchain, gcc, libc, binutils, None, jobs, false)?;
+            if let Some(bundle) = vendor {
+                vendor::import_bundle(&bundle)?;
+            }
+            set_plan(plan);
+            let force = Force::new(force, force_stage);
+            let toolchains = targets
+                .iter()
+                .map(|target| {
+                    let mut toolchain = parse_toolchain(
+                        target,
+                        &gcc,
+                        &default_libc(target, &libc),
+                        &binutils,
+                        None,
+                    )?;
+                    let settings = resolve_target_settings(target)?;
+                    toolchain.binutils.gold = gold;
+                    set_gdb(&mut toolchain, gdb, gdb_python);
+                    set_compiler(&mut toolchain, compiler);
+                    toolchain.profile = profile;
+                    toolchain.prefix = absolute_prefix(prefix.clone().or(settings.prefix.clone()))?;
+                    Ok((toolchain, settings))
+                })
+                .collect::<Result<_>>()?;
+            install_parallel(toolchains, parallel, jobs, &force, plan)?;
+        }
+        Commands::Install {
+            target,
+            gcc,
+            libc,
+            binutils,
+            jobs,
+            force,
+            force_stage,
+            plan,
+            gold,
+            gdb,
+            gdb_python,
+            compiler,
+            profile,
+            vendor,
+            prefix,
+            ..
+        } => {
+            if let Some(bundle) = vendor {
+                vendor::import_bundle(&bundle)?;
+            }
+            set_plan(plan);
+            let force = Force::new(force, force_stage);
+            let toolchain = target.expect("clap requires a target without `--all`");
+            let settings = resolve_target_settings(&toolchain)?;
+            let jobs = jobs.or(settings.jobs).unwrap_or(DEFAULT_JOBS);
+            let libc = default_libc(&toolchain, &libc);
+            let mut toolchain = parse_toolchain(&toolchain, &gcc, &libc, &binutils, None)?;
+            toolchain.binutils.gold = gold;
+            set_gdb(&mut toolchain, gdb, gdb_python);
+            set_compiler(&mut toolchain, compiler);
+            toolchain.profile = profile;
+            toolchain.prefix = absolute_prefix(prefix.or(settings.prefix))?;
+            let report = install_toolchain(toolchain, jobs, &force)?;
+            specs::write(&report.toolchain, &settings.default_flags)?;
+            if !plan {
+                log::info!("{report}");
+            }
+        }
+        Commands::Vendor {
+            targets,
+            all,
+            kernel,
+            out,
+        } => {
+            let toolchains = selected_toolchains(targets, all)?;
+            let urls = planned_archives(toolchains, kernel.as_deref())?;
+            let manifest = vendor::write_bundle(&urls, &out)?;
+            log::info!(
+                "wrote {} archives to {}",
+                manifest.archives.len(),
+                out.display()
+            );
+        }
+        Commands::CC {
+            target,
+            toolchain,
+            static_musl,
+            linker,
+            print,
+            options,
+        } => {
+            let (target, options) = match toolchain {
+                // without TARGET, clap takes the first option for it
+                Some(name) => (
+                    name,
+                    target
+                        .map(OsString::from)
+                        .into_iter()
+                        .chain(options)
+                        .collect::<Vec<_>>(),
+                ),
+                None => (
+                    canonical_target(&target.expect("clap requires TARGET without --toolchain"))?,
+                    options,
+                ),
+            };
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            let settings = resolve_target_settings(&target)?;
+            if static_musl && !toolchain.target.is_musl() {
+                return Err(Failure::Usage).context(format!(
+                    "`--static-musl` requires a musl target, got {}",
+                    toolchain.target
+                ));
+            }
+            let static_musl = toolchain.target.is_musl() && (static_musl || settings.static_musl);
+
+            install_toolchain(
+                toolchain.clone(),
+                settings.jobs.unwrap_or(DEFAULT_JOBS),
+                &Force::Nothing,
+            )?;
+            gc::record_toolchain_use(&toolchain);
+            if let Some(query) = print {
+                let flags: Vec<OsString> = settings
+                    .cflags
+                    .iter()
+                    .map(OsString::from)
+                    .chain(options)
+                    .collect();
+                for line in inspect::query(&toolchain, query, &flags)? {
+                    println!("{line}");
+                }
+                return Ok(());
+            }
+            let mut gcc = Command::new(toolchain.gcc_bin()?);
+            gcc.env("PATH", toolchain.env_path()?);
+            if let Some(linker) = linker.or(settings.linker) {
+                ensure_linker(&toolchain, linker)?;
+                gcc.arg(format!("-fuse-ld={linker}"));
+            }
+            gcc.args(settings.cflags).args(&options);
+            if static_musl {
+                gcc.args(["-static", "-no-pie"]);
+            }
+            let status = gcc.status()?;
+
+            if static_musl
+                && status.success()
+                && let Some(output) = linked_output(&options)
+                && !inspect::is_fully_static(&toolchain, &output)?
+            {
+                bail!("`{}` is not fully static", output.display());
+            }
+        }
+        Commands::Shell { target, kernel } => {
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            let settings = resolve_target_settings(&target)?;
+            install_toolchain(
+                toolchain.clone(),
+                settings.jobs.unwrap_or(DEFAULT_JOBS),
+                &Force::Nothing,
+            )?;
+            gc::record_toolchain_use(&toolchain);
+            let env = shell::environment(&toolchain, &settings.cflags, kernel)?;
+            let code = shell::spawn(&toolchain, env)?;
+            if code != 0 {
+                std::process::exit(code);
+            }
         }
-        Commands::CC { target, options } => {
+        Commands::ClangConfig { target, out } => {
             let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
-            install_toolchain(toolchain.clone(), 10, false)?;
-            Command::new(toolchain.gcc_bin()?).args(options).status()?;
+            let config = llvm::system_clang_config(&toolchain)?;
+            match out {
+                Some(out) => {
+                    std::fs::write(&out, config)
+                        .context(format!("failed to write `{}`", out.display()))?;
+                    log::info!("=> compile with clang --config={}", out.display());
+                }
+                None => print!("{config}"),
+            }
+        }
+        Commands::CmakeToolchain { target, out } => {
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            let file = cmake::toolchain_file(&toolchain)?;
+            match out {
+                Some(out) => {
+                    std::fs::write(&out, file)
+                        .context(format!("failed to write `{}`", out.display()))?;
+                    log::info!(
+                        "=> configure with cmake -DCMAKE_TOOLCHAIN_FILE={}",
+                        out.display()
+                    );
+                }
+                None => print!("{file}"),
+            }
+        }
+        Commands::RunBaremetal {
+            target,
+            qemu,
+            elf,
+            args,
+        } => {
+            let overrides = qemu.overrides(&target)?;
+            let target = Target::from_str(&target)?;
+            let code = run_baremetal(&target, &elf, &args, &overrides)?;
+            if code != 0 {
+                // mirror the program's exit code when it's a valid process exit code
+                std::process::exit(
+                    u8::try_from(code).map_or(Failure::GuestProgram.exit_code().into(), i32::from),
+                );
+            }
+        }
+        Commands::Runner {
+            target,
+            qemu,
+            elf,
+            args,
+        } => {
+            let target = match target {
+                Some(target) => target,
+                None => runner::infer_target(&elf)?.to_string(),
+            };
+            let overrides = qemu.overrides(&target)?;
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            let code = runner::run(&toolchain, &elf, &args, &overrides)?;
+            if code != 0 {
+                std::process::exit(
+                    u8::try_from(code).map_or(Failure::GuestProgram.exit_code().into(), i32::from),
+                );
+            }
         }
         Commands::Linux {
             version,
-            toolchain,
+            target,
             jobs,
             menuconfig,
             defconfig,
+            mut exec,
+            exec_list,
+            report,
+            detach,
+            kdump,
+            gdbserver,
+            shell,
+            debug,
+            qemu,
+            force_stage,
+            plan,
+        } => {
+            set_plan(plan);
+            let jobs = jobs
+                .or(resolve_target_settings(&target)?.jobs)
+                .unwrap_or(DEFAULT_JOBS);
+            let mut overrides = qemu.overrides(&target)?;
+            if let Some(exec_list) = exec_list {
+                exec.extend(read_exec_list(&exec_list)?);
+            }
+            let target = Target::from_str(&target)?;
+            capability::check(&target, Operation::Linux)?;
+            toolup::packages::busybox::check_exec_programs(&target, &exec)?;
+            if kdump {
+                kdump::check_supported(&target)?;
+                overrides.kernel_args.extend(kdump::kernel_args());
+            }
+            if debug {
+                overrides.args.extend(toolup::debug::qemu_args());
+                overrides.kernel_args.extend(toolup::debug::kernel_args());
+            }
+            let (kernel_image, toolchain) = toolup::packages::linux::get_image(
+                &target,
+                &version,
+                jobs,
+                menuconfig,
+                defconfig,
+                KernelFeatures { kdump, debug },
+                &Force::new(false, force_stage),
+            )?;
+            gc::record_toolchain_use(&toolchain);
+            gc::record_kernel_use(&target, &version);
+            let rootfs = toolup::packages::busybox::build_rootfs(&toolchain, gdbserver)?;
+            if debug {
+                let vmlinux = toolup::packages::linux::vmlinux(&kernel_image);
+                log::info!(
+                    "=> the VM waits for gdb, attach with:\n{}",
+                    toolup::debug::gdb_command(&toolchain, &vmlinux)?
+                );
+            }
+            if detach {
+                if let Some(vm) = detach_vm(&target, kernel_image, rootfs, &overrides)? {
+                    log::info!(
+                        "=> the console is written to {}",
+                        vm.console_log()?.display()
+                    );
+                    println!("{}", vm.id);
+                }
+                return Ok(());
+            }
+            let mut exec = toolup::packages::busybox::exec_programs(&toolchain, exec)?;
+            if shell {
+                exec.sysroot = Some(toolchain.sysroot()?);
+            }
+            let vmcore = std::env::current_dir()?.join("vmcore");
+            if kdump {
+                kdump::prepare(&toolchain, &kernel_image, &rootfs, &mut exec, &vmcore)?;
+            }
+            let run = start_vm(&target, kernel_image, rootfs, &exec, &overrides);
+            let run = if kdump {
+                run.context("the crash kernel didn't take over after the crash")?
+            } else {
+                run?
+            };
+            if kdump && !plan {
+                let size = kdump::check_vmcore(&vmcore)?;
+                log::info!(
+                    "=> the crash kernel captured a vmcore of {} MiB: {}",
+                    size / (1024 * 1024),
+                    vmcore.display()
+                );
+            }
+            if !plan {
+                if run.execs.len() > 1 {
+                    for exec in &run.execs {
+                        println!("{exec}");
+                    }
+                }
+                log::info!("{run}");
+            }
+            if let Some(report) = report {
+                std::fs::write(&report, serde_json::to_string_pretty(&run)?)
+                    .context(format!("failed to write `{}`", report.display()))?;
+            }
+            if run.exit_code != 0 {
+                std::process::exit(
+                    u8::try_from(run.exit_code)
+                        .map_or(Failure::GuestProgram.exit_code().into(), i32::from),
+                );
+            }
+        }
+        Commands::KernelHeadersCompat {
+            kernel,
+            target,
+            jobs,
+            program,
+        } => {
+            let jobs = jobs
+                .or(resolve_target_settings(&target)?.jobs)
+                .unwrap_or(DEFAULT_JOBS);
+            let kernel = KernelVersion::from_str(&kernel)?;
+            let toolchain = kernel_toolchain(&Target::from_str(&target)?, kernel)?;
+            let toolchain = install_toolchain(toolchain, jobs, &Force::Nothing)?.toolchain;
+            let report = compat::check(&toolchain, kernel, &program)?;
+            println!("{report}");
+            if !report.is_compatible() {
+                bail!(
+                    "`{}` probably fails on linux {kernel} with {}",
+                    program.display(),
+                    toolchain.libc
+                );
+            }
+        }
+        Commands::Reproduce {
+            target,
+            against,
+            jobs,
+        } => {
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            let jobs = jobs
+                .or(resolve_target_settings(&target)?.jobs)
+                .unwrap_or(DEFAULT_JOBS);
+            let report = reproduce(&toolchain, jobs, against)?;
+            println!("{report}");
+            if !report.is_reproducible() {
+                bail!("{} is not reproducible", toolchain.id());
+            }
+        }
+        Commands::Check {
+            target,
+            suite,
+            baseline,
+            jobs,
+        } => {
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            let jobs = jobs
+                .or(resolve_target_settings(&target)?.jobs)
+                .unwrap_or(DEFAULT_JOBS);
+            let report = check(&toolchain, suite, jobs, baseline)?;
+            println!("{report}");
+            if !report.regressions().is_empty() {
+                bail!("{suite} has new failures compared to the baseline");
+            }
+        }
+        Commands::LibcTest {
+            target,
+            kernel,
+            libc,
+            against,
+            jobs,
+            qemu,
+            plan,
+        } => {
+            set_plan(plan);
+            let jobs = jobs
+                .or(resolve_target_settings(&target)?.jobs)
+                .unwrap_or(DEFAULT_JOBS);
+            let overrides = qemu.overrides(&target)?;
+            let mut toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            if let Some(libc) = libc {
+                toolchain.libc = parse_toolchain(
+                    &target,
+                    &toolchain.gcc.version.to_string(),
+                    &libc,
+                    &toolchain.binutils.version.to_string(),
+                    None,
+                )?
+                .libc;
+            }
+            install_toolchain(toolchain.clone(), jobs, &Force::new(false, vec![]))?;
+
+            let (kernel_image, kernel_toolchain) = toolup::packages::linux::get_image(
+                &toolchain.target,
+                &kernel,
+                jobs,
+                false,
+                false,
+                KernelFeatures::default(),
+                &Force::new(false, vec![]),
+            )?;
+            gc::record_toolchain_use(&toolchain);
+            gc::record_kernel_use(&toolchain.target, &kernel);
+            let rootfs = toolup::packages::busybox::build_rootfs(&kernel_toolchain, false)?;
+            let report = libc_test::libc_test(
+                &toolchain,
+                &kernel_image,
+                &rootfs,
+                jobs,
+                against.as_deref(),
+                &overrides,
+            )?;
+            if let Some(report) = report {
+                println!("{report}");
+                if !report.check.regressions().is_empty() {
+                    bail!("{} has new failures", report.libc);
+                }
+            }
+        }
+        Commands::Inspect { target, diff } => {
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            let inspection = inspect::inspect(&toolchain)?;
+            match diff {
+                None => print!("{inspection}"),
+                Some(other) => {
+                    let other = match inspect::saved(&other)? {
+                        Some(saved) => saved,
+                        None => inspect::inspect(&resolve_target_toolchain(&other)?.into())?,
+                    };
+                    println!("{}", inspection.diff(&other));
+                }
+            }
+        }
+        Commands::Doctor {} => {
+            let stale = doctor::stale_objdirs()?;
+            if stale.is_empty() {
+                log::info!("no problems found");
+            }
+            for toolchain in &stale {
+                log::warn!("{toolchain}");
+            }
+        }
+        Commands::Describe { target } => {
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            let metadata = metadata::read(&toolchain)?.context(format!(
+                "`{}` has no metadata, it's not installed or was installed by an older toolup",
+                toolchain.id()
+            ))?;
+            metadata.show();
+        }
+        Commands::Pack { target, out } => {
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            let out = out.unwrap_or_else(|| pack::default_archive_name(&toolchain));
+            let metadata = pack::pack(&toolchain, &out)?;
+            log::info!(
+                "packed {} for {} into {}",
+                metadata.id,
+                metadata.host,
+                out.display()
+            );
+        }
+        Commands::Publish { action } => match action {
+            PublishAction::Index { dir, base_url, out } => {
+                let index = prebuilt::index_dir(&dir, base_url.as_deref())?;
+                let out = out.unwrap_or_else(|| dir.join("index.json"));
+                std::fs::write(&out, serde_json::to_string_pretty(&index)?)
+                    .context(format!("failed to write `{}`", out.display()))?;
+                log::info!(
+                    "indexed {} toolchains into {}",
+                    index.toolchains.len(),
+                    out.display()
+                );
+            }
+        },
+        Commands::Provenance { target, verify } => {
+            let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+            if verify {
+                let mismatches = provenance::verify_toolchain(&toolchain)?
+                    .context(format!("`{}` has no provenance", toolchain.id()))?;
+                for mismatch in &mismatches {
+                    println!("{mismatch}");
+                }
+                if !mismatches.is_empty() {
+                    bail!(
+                        "{} doesn't match its provenance, {} files differ",
+                        toolchain.id(),
+                        mismatches.len()
+                    );
+                }
+                log::info!("{} matches its provenance", toolchain.id());
+            } else {
+                let provenance = provenance::read(&toolchain.dir()?)?
+                    .context(format!("`{}` has no provenance", toolchain.id()))?;
+                println!("{}", serde_json::to_string_pretty(&provenance)?);
+            }
+        }
+        Commands::Sysroot { action } => match action {
+            SysrootAction::Clone {
+                target,
+                into,
+                overlay,
+            } => {
+                let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+                clone_sysroot(&toolchain, &into, overlay)?;
+                log::info!("use it with `--sysroot={}`", into.display());
+            }
+            SysrootAction::Add {
+                target,
+                package,
+                sysroot,
+                jobs,
+            } => {
+                let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+                let jobs = jobs
+                    .or(resolve_target_settings(&target)?.jobs)
+                    .unwrap_or(DEFAULT_JOBS);
+                let sysroot = sysroot.map_or_else(|| toolchain.sysroot(), Ok)?;
+                add_package(&toolchain, &sysroot, &package, jobs)?;
+            }
+            SysrootAction::List { target, sysroot } => {
+                let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+                let sysroot = sysroot.map_or_else(|| toolchain.sysroot(), Ok)?;
+                for (name, package) in PackageDb::load(&sysroot)?.packages {
+                    println!("{name} {} ({} files)", package.version, package.files.len());
+                }
+            }
+            SysrootAction::Remove {
+                target,
+                package,
+                sysroot,
+            } => {
+                let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+                let sysroot = sysroot.map_or_else(|| toolchain.sysroot(), Ok)?;
+                remove_package(&sysroot, &package)?;
+            }
+        },
+        Commands::Journal { action } => match action {
+            JournalAction::Show { toolchain } => {
+                let id = if journal::journal_path(&toolchain)?.exists() {
+                    toolchain
+                } else {
+                    Toolchain::from(resolve_target_toolchain(&toolchain)?).id()
+                };
+                journal::show(&journal::read(&id)?);
+            }
+            JournalAction::List {} => {
+                for id in journal::list()? {
+                    println!("{id}");
+                }
+            }
+        },
+        Commands::Outdated { update } => {
+            let upstream = outdated::latest_releases()?;
+            log::info!(
+                "latest: gcc {}, binutils {}, glibc {}, musl {}, longterm kernel {}",
+                upstream.gcc,
+                upstream.binutils,
+                upstream.glibc,
+                upstream.musl,
+                upstream.kernel_lts
+            );
+
+            for config in outdated::config_files()? {
+                let pins = outdated::outdated_pins(&config, &upstream)?;
+                if pins.is_empty() {
+                    log::info!("{}: up to date", config.display());
+                    continue;
+                }
+                println!("{}:", config.display());
+                for pin in &pins {
+                    println!("  {pin}");
+                }
+                if update {
+                    outdated::update_pins(&config, &pins)?;
+                    log::info!("updated {} pins in {}", pins.len(), config.display());
+                }
+            }
+        }
+        Commands::Gc {
+            unused_for,
+            dry_run,
         } => {
-            let target = Target::from_str(toolchain.as_str())?;
-            let (kernel_image, toolchain) =
-                toolup::packages::linux::get_image(&target, &version, jobs, menuconfig, defconfig)?;
-            let rootfs = toolup::packages::busybox::build_rootfs(&toolchain)?;
-            start_vm(&target, kernel_image, rootfs)?;
+            let candidates = gc::candidates(unused_for)?;
+            if candidates.is_empty() {
+                log::info!("nothing to remove");
+            }
+            for candidate in &candidates {
+                println!("{candidate}");
+            }
+            if !dry_run {
+                gc::remove(&candidates)?;
+            }
         }
+        Commands::CleanBuilds { target, dry_run } => {
+            let installed = cache::installed_toolchains()?;
+            let ids = match target {
+                Some(target) => {
+                    let id = Toolchain::from(resolve_target_toolchain(&target)?).id();
+                    if !installed.contains(&id) {
+                        return Err(Failure::Usage).context(format!(
+                            "{id} is not installed, only the build trees of installed \
+                             toolchains are removed"
+                        ));
+                    }
+                    BTreeSet::from([id])
+                }
+                None => installed,
+            };
+            let trees = cache::build_trees(&ids)?;
+            if trees.is_empty() {
+                log::info!("nothing to remove");
+            }
+            if dry_run {
+                for tree in &trees {
+                    println!("{}", tree.display());
+                }
+            } else {
+                let freed = cache::remove_build_trees(&trees)?;
+                log::info!("freed {}", HumanBytes(freed));
+            }
+        }
+        Commands::Vm { action } => match action {
+            VmAction::List {} => {
+                let vms = vm::list()?;
+                if vms.is_empty() {
+                    log::info!("no running VMs");
+                }
+                for vm in vms {
+                    println!("{vm}");
+                }
+            }
+            VmAction::Kill { id } => vm::kill(&id)?,
+            VmAction::Console { id } => vm::console(&id)?,
+        },
         Commands::Cache { action } => match action {
-            CacheAction::Clean { toolchain: _ } => {
-                // TODO: should each build step expose a clean_cache(target) function? what about
-                // different versions? ask to clean the cache for a specific version?
-                unimplemented!()
+            CacheAction::Clean { target } => {
+                let toolchain: Toolchain = resolve_target_toolchain(&target)?.into();
+                clean_toolchain(&toolchain)?;
             }
             CacheAction::Dir {} => {
                 log::info!("{}", cache_dir()?.display());
@@ -146,6 +1612,55 @@ fn main() -> Result<()> {
             CacheAction::Prune {} => {
                 std::fs::remove_dir_all(cache_dir()?).context("failed to prune cache")?;
             }
+            CacheAction::Verify { repair } => {
+                let problems = cache::verify(repair)?;
+                if problems.is_empty() {
+                    log::info!("the cache is intact");
+                }
+                for problem in &problems {
+                    println!("{problem}");
+                }
+                if !problems.is_empty() && !repair {
+                    bail!(
+                        "found {} broken cache entries, run `toolup cache verify --repair`",
+                        problems.len()
+                    );
+                }
+            }
+            CacheAction::Index {} => {
+                println!("{}", serde_json::to_string_pretty(&cache::index()?)?);
+            }
+            CacheAction::Key {
+                targets,
+                all,
+                kernel,
+            } => {
+                let toolchains = selected_toolchains(targets, all)?;
+                let keyed: Vec<Toolchain> = toolchains
+                    .iter()
+                    .map(|(_, toolchain)| toolchain.clone())
+                    .collect();
+                let urls = planned_archives(toolchains, kernel.as_deref())?;
+                println!("{}", snapshot::cache_key(&keyed, kernel.as_deref(), &urls));
+            }
+            CacheAction::Save { tarball } => {
+                snapshot::save(&tarball)?;
+                log::info!("saved the toolup directories to {}", tarball.display());
+            }
+            CacheAction::Restore { tarball } => {
+                snapshot::restore(&tarball)?;
+                log::info!("restored the toolup directories from {}", tarball.display());
+            }
+        },
+        Commands::SelfCmd { action } => match action {
+            SelfAction::Update { check } => match self_update::update(check)? {
+                UpdateStatus::UpToDate(tag) => log::info!("toolup is up to date ({tag})"),
+                UpdateStatus::Available(tag) => log::info!(
+                    "toolup {tag} is available (current: {}), run `toolup self update`",
+                    env!("CARGO_PKG_VERSION")
+                ),
+                UpdateStatus::Updated(tag) => log::info!("updated toolup to {tag}"),
+            },
         },
     };
 
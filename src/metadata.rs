@@ -0,0 +1,243 @@
+//! `toolup.json` in a toolchain directory: the versions a toolchain was built from, the
+//! configure command lines of its packages and the host it was built on, shown by `toolup describe`
+//! to audit or reproduce a build elsewhere.
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::{is_plan, print_command},
+    journal,
+    profile::Toolchain,
+};
+
+const METADATA: &str = "toolup.json";
+
+/// The environment variables that change what configure and make produce.
+const HOST_ENV_VARS: &[&str] = &[
+    "CC",
+    "CXX",
+    "CFLAGS",
+    "CXXFLAGS",
+    "CPPFLAGS",
+    "LDFLAGS",
+    "LD_LIBRARY_PATH",
+    "PKG_CONFIG_PATH",
+    "MAKEFLAGS",
+    "CONFIG_SITE",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// See [`Toolchain::id`]
+    pub id: String,
+    pub target: String,
+    pub gcc: String,
+    pub binutils: String,
+    pub libc: String,
+    pub profile: String,
+    /// In the order they ran
+    pub configure: Vec<ConfigureStep>,
+    /// `None` for toolchains installed before toolup recorded it
+    #[serde(default)]
+    pub host: Option<HostFingerprint>,
+}
+
+/// The host environment a toolchain was built in. Objdirs configured in one environment often
+/// fail to rebuild in another, see `toolup doctor`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostFingerprint {
+    /// `PRETTY_NAME` of `/etc/os-release`
+    pub distro: Option<String>,
+    /// The first line of `gcc --version`
+    pub gcc: Option<String>,
+    /// The first line of `make --version`
+    pub make: Option<String>,
+    /// The first line of `ld --version`
+    pub binutils: Option<String>,
+    /// The variables of [`HOST_ENV_VARS`] that were set
+    pub env: BTreeMap<String, String>,
+}
+
+impl HostFingerprint {
+    pub fn current() -> Self {
+        let distro = std::fs::read_to_string("/etc/os-release")
+            .ok()
+            .and_then(|os_release| {
+                os_release.lines().find_map(|line| {
+                    let name = line.strip_prefix("PRETTY_NAME=")?;
+                    Some(name.trim_matches('"').to_string())
+                })
+            });
+        Self {
+            distro,
+            gcc: first_line_of("gcc", "--version"),
+            make: first_line_of("make", "--version"),
+            binutils: first_line_of("ld", "--version"),
+            env: HOST_ENV_VARS
+                .iter()
+                .filter_map(|var| Some((var.to_string(), std::env::var(var).ok()?)))
+                .collect(),
+        }
+    }
+
+    /// Returns what changed from `self` to `other`, e.g. `gcc: gcc 13.3.0 -> gcc 14.2.0`.
+    pub fn changes(&self, other: &HostFingerprint) -> Vec<String> {
+        fn show(value: Option<&String>) -> &str {
+            value.map_or("<none>", String::as_str)
+        }
+
+        let mut changes = vec![];
+        for (name, before, after) in [
+            ("distro", &self.distro, &other.distro),
+            ("gcc", &self.gcc, &other.gcc),
+            ("make", &self.make, &other.make),
+            ("binutils", &self.binutils, &other.binutils),
+        ] {
+            if before != after {
+                changes.push(format!(
+                    "{name}: {} -> {}",
+                    show(before.as_ref()),
+                    show(after.as_ref())
+                ));
+            }
+        }
+        for var in HOST_ENV_VARS {
+            let (before, after) = (self.env.get(*var), other.env.get(*var));
+            if before != after {
+                changes.push(format!("${var}: {} -> {}", show(before), show(after)));
+            }
+        }
+        changes
+    }
+}
+
+/// The first line `program` prints to stdout with `arg`, `None` if it isn't installed.
+fn first_line_of(program: &str, arg: &str) -> Option<String> {
+    let output = Command::new(program).arg(arg).output().ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(String::from)
+}
+
+/// A configure command run while building the toolchain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigureStep {
+    /// The package and its version, e.g. `final stage gcc 15.2.0`
+    pub package: String,
+    pub cwd: PathBuf,
+    pub argv: Vec<String>,
+    /// Environment variables set on top of the inherited environment
+    pub env: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    /// Print the toolchain versions and its configure commands as shell snippets.
+    pub fn show(&self) {
+        println!("{} {}", "Toolchain:".bold(), self.id.green());
+        for (key, value) in [
+            ("target", &self.target),
+            ("gcc", &self.gcc),
+            ("binutils", &self.binutils),
+            ("libc", &self.libc),
+            ("profile", &self.profile),
+        ] {
+            println!("{:>10}: {value}", key.bold());
+        }
+        if let Some(host) = &self.host {
+            for (key, value) in [
+                ("distro", &host.distro),
+                ("host gcc", &host.gcc),
+                ("host make", &host.make),
+                ("host ld", &host.binutils),
+            ] {
+                if let Some(value) = value {
+                    println!("{:>10}: {value}", key.bold());
+                }
+            }
+        }
+        for step in &self.configure {
+            println!();
+            println!("{}", format!("# {}", step.package).dimmed());
+            let env: Vec<(&String, &String)> = step.env.iter().collect();
+            let (command, args) = step.argv.split_first().expect("argv has a command");
+            print_command(&step.cwd, "configure", OsStr::new(command), args, &env);
+        }
+    }
+}
+
+fn metadata_path(toolchain: &Toolchain) -> Result<PathBuf> {
+    Ok(toolchain.dir()?.join(METADATA))
+}
+
+/// Read the metadata of an installed toolchain, `None` if it was installed before toolup wrote
+/// metadata.
+pub fn read(toolchain: &Toolchain) -> Result<Option<Metadata>> {
+    read_dir(&toolchain.dir()?)
+}
+
+/// Read the metadata of the toolchain directory `dir`, see [`read`].
+pub fn read_dir(dir: &Path) -> Result<Option<Metadata>> {
+    let path = dir.join(METADATA);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content =
+        std::fs::read_to_string(&path).context(format!("failed to read `{}`", path.display()))?;
+    serde_json::from_str(&content)
+        .map(Some)
+        .context(format!("failed to parse `{}`", path.display()))
+}
+
+/// Write the metadata of a toolchain being installed.
+///
+/// The configure commands are taken from the journal of this install, a partial rebuild keeps the
+/// commands of the packages it didn't reconfigure.
+pub fn write(toolchain: &Toolchain) -> Result<()> {
+    if is_plan() {
+        return Ok(());
+    }
+
+    let id = toolchain.id();
+    let steps: Vec<ConfigureStep> = journal::read(&id)?
+        .into_iter()
+        .filter(|entry| entry.title == "configure" && entry.success)
+        .filter_map(|entry| {
+            Some(ConfigureStep {
+                package: entry.package?,
+                cwd: entry.cwd,
+                argv: entry.argv,
+                env: entry.env,
+            })
+        })
+        .collect();
+    let mut configure: Vec<ConfigureStep> = read(toolchain)?
+        .map(|previous| previous.configure)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|previous| !steps.iter().any(|step| step.package == previous.package))
+        .collect();
+    configure.extend(steps);
+
+    let metadata = Metadata {
+        id,
+        target: toolchain.target.to_string(),
+        gcc: toolchain.gcc.version.to_string(),
+        binutils: toolchain.binutils.version.to_string(),
+        libc: toolchain.libc.to_string(),
+        profile: toolchain.profile.to_string(),
+        configure,
+        host: Some(HostFingerprint::current()),
+    };
+    let path = metadata_path(toolchain)?;
+    std::fs::write(&path, serde_json::to_string_pretty(&metadata)?)
+        .context(format!("failed to write `{}`", path.display()))
+}
@@ -0,0 +1,272 @@
+//! Compare the versions pinned in `toolup.toml` with the latest upstream releases.
+//!
+//! GNU releases are read from the ftp.gnu.org directory listings, musl from its release page and
+//! the kernel from `releases.json` on kernel.org. Listings go through [`mirrored_url`], so a
+//! configured GNU mirror is used here as well.
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde::Deserialize;
+use toml_edit::DocumentMut;
+
+use crate::{
+    config::global_config_path,
+    download::mirrored_url,
+    profile::{Abi, Target},
+};
+
+const GCC_URL: &str = "https://ftp.gnu.org/gnu/gcc/";
+const BINUTILS_URL: &str = "https://ftp.gnu.org/gnu/binutils/";
+const GLIBC_URL: &str = "https://ftp.gnu.org/gnu/glibc/";
+const MUSL_URL: &str = "https://musl.libc.org/releases/";
+const KERNEL_URL: &str = "https://www.kernel.org/releases.json";
+
+/// The latest upstream release of every pinned component.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    pub gcc: String,
+    pub binutils: String,
+    pub glibc: String,
+    pub musl: String,
+    /// The newest longterm kernel, kernels are not pinned in `toolup.toml`
+    pub kernel_lts: String,
+}
+
+/// A pin in a configuration file that is behind upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutdatedPin {
+    pub config: PathBuf,
+    pub target: String,
+    /// `gcc`, `binutils` or `libc`
+    pub key: String,
+    pub current: String,
+    pub latest: String,
+}
+
+impl Display for OutdatedPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:<9} {} -> {}",
+            self.target,
+            self.key,
+            self.current.red(),
+            self.latest.green()
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct KernelReleases {
+    releases: Vec<KernelRelease>,
+}
+
+#[derive(Deserialize)]
+struct KernelRelease {
+    moniker: String,
+    version: String,
+}
+
+fn client() -> Result<reqwest::blocking::Client> {
+    Ok(reqwest::blocking::Client::builder()
+        .user_agent(concat!("toolup/", env!("CARGO_PKG_VERSION")))
+        .build()?)
+}
+
+fn get_text(url: &str) -> Result<String> {
+    let url = mirrored_url(url);
+    client()?
+        .get(&url)
+        .send()
+        .context(format!("sending GET request to {url}"))?
+        .error_for_status()
+        .context(format!("non-success status from {url}"))?
+        .text()
+        .context(format!("failed to read the response from {url}"))
+}
+
+/// Parse a dotted numeric version, `None` for anything else (e.g. release candidates).
+fn numeric(version: &str) -> Option<Vec<u64>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Whether `latest` is a newer version than `current`.
+pub fn is_newer(latest: &str, current: &str) -> bool {
+    match (numeric(latest), numeric(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
+
+/// Returns the newest version in a directory listing with entries like `<prefix><version><suffix>`.
+pub fn latest_in_listing(listing: &str, prefix: &str, suffix: &str) -> Option<String> {
+    listing
+        .match_indices(prefix)
+        .filter_map(|(i, _)| {
+            let rest = &listing[i + prefix.len()..];
+            let version = rest.strip_prefix(|c: char| c.is_ascii_digit()).map(|_| {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_digit() && c != '.')
+                    .unwrap_or(rest.len());
+                &rest[..end]
+            })?;
+            let version = version.trim_end_matches('.');
+            rest[version.len()..].starts_with(suffix).then_some(version)
+        })
+        .filter_map(|version| Some((numeric(version)?, version)))
+        .max()
+        .map(|(_, version)| version.to_string())
+}
+
+fn latest_from(url: &str, prefix: &str, suffix: &str) -> Result<String> {
+    latest_in_listing(&get_text(url)?, prefix, suffix)
+        .context(format!("no `{prefix}*{suffix}` release found at {url}"))
+}
+
+fn latest_kernel_lts() -> Result<String> {
+    let releases: KernelReleases = serde_json::from_str(&get_text(KERNEL_URL)?)
+        .context(format!("failed to parse {KERNEL_URL}"))?;
+
+    releases
+        .releases
+        .into_iter()
+        .filter(|release| release.moniker == "longterm")
+        .filter_map(|release| Some((numeric(&release.version)?, release.version)))
+        .max()
+        .map(|(_, version)| version)
+        .context(format!("no longterm kernel found in {KERNEL_URL}"))
+}
+
+/// Fetch the latest upstream releases.
+pub fn latest_releases() -> Result<Upstream> {
+    Ok(Upstream {
+        gcc: latest_from(GCC_URL, "gcc-", "/")?,
+        binutils: latest_from(BINUTILS_URL, "binutils-", ".tar.xz")?,
+        glibc: latest_from(GLIBC_URL, "glibc-", ".tar.xz")?,
+        musl: latest_from(MUSL_URL, "musl-", ".tar.gz")?,
+        kernel_lts: latest_kernel_lts()?,
+    })
+}
+
+/// Returns the configuration files to check: the local `toolup.toml` and the global one.
+pub fn config_files() -> Result<Vec<PathBuf>> {
+    Ok([PathBuf::from("toolup.toml"), global_config_path()?]
+        .into_iter()
+        .filter(|path| path.exists())
+        .collect())
+}
+
+fn read_document(config: &Path) -> Result<DocumentMut> {
+    std::fs::read_to_string(config)
+        .context(format!("failed to read `{}`", config.display()))?
+        .parse()
+        .context(format!("failed to parse TOML in `{}`", config.display()))
+}
+
+/// Returns the pins in `config` that are older than `upstream`.
+pub fn outdated_pins(config: &Path, upstream: &Upstream) -> Result<Vec<OutdatedPin>> {
+    let doc = read_document(config)?;
+    let Some(toolchains) = doc.get("toolchain").and_then(|t| t.as_table_like()) else {
+        return Ok(vec![]);
+    };
+
+    let mut pins = vec![];
+    for (target, table) in toolchains.iter() {
+        let Some(table) = table.as_table_like() else {
+            continue;
+        };
+        let parsed = Target::from_str(target).context(format!(
+            "invalid target `{target}` in `{}`",
+            config.display()
+        ))?;
+        let libc = match parsed.abi {
+            Abi::Musl => Some(&upstream.musl),
+            // freestanding targets don't use the libc pin
+            Abi::Elf | Abi::Eabi | Abi::Eabihf => None,
+            // mingw-w64, FreeBSD, Android and wasi-libc releases aren't tracked
+            Abi::Android | Abi::Wasi => None,
+            _ if parsed.is_windows() || parsed.is_freebsd() => None,
+            _ => Some(&upstream.glibc),
+        };
+
+        let components = [
+            ("gcc", Some(&upstream.gcc)),
+            ("binutils", Some(&upstream.binutils)),
+            ("libc", libc),
+        ];
+        for (key, latest) in components {
+            let Some(latest) = latest else {
+                continue;
+            };
+            let Some(current) = table.get(key).and_then(|v| v.as_str()) else {
+                continue;
+            };
+            if is_newer(latest, current) {
+                pins.push(OutdatedPin {
+                    config: config.to_path_buf(),
+                    target: target.to_string(),
+                    key: key.to_string(),
+                    current: current.to_string(),
+                    latest: latest.clone(),
+                });
+            }
+        }
+    }
+    pins.sort_by(|a, b| (&a.target, &a.key).cmp(&(&b.target, &b.key)));
+    Ok(pins)
+}
+
+/// Set every pin in `pins` to its latest version. Comments and the layout of the file are kept.
+pub fn update_pins(config: &Path, pins: &[OutdatedPin]) -> Result<()> {
+    let mut doc = read_document(config)?;
+    for pin in pins.iter().filter(|pin| pin.config == config) {
+        let value = doc["toolchain"][&pin.target][&pin.key]
+            .as_value_mut()
+            .context(format!("`{}.{}` is not a value", pin.target, pin.key))?;
+        // keep the decor (comments and whitespace) around the old value
+        let decor = value.decor().clone();
+        *value = pin.latest.as_str().into();
+        *value.decor_mut() = decor;
+    }
+
+    std::fs::write(config, doc.to_string())
+        .context(format!("failed to write to `{}`", config.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_newer, latest_in_listing};
+
+    #[test]
+    fn test_latest_in_listing() {
+        let listing = r#"
+            <a href="binutils-2.9.1.tar.gz">binutils-2.9.1.tar.gz</a>
+            <a href="binutils-2.45.tar.xz">binutils-2.45.tar.xz</a>
+            <a href="binutils-2.45.tar.xz.sig">binutils-2.45.tar.xz.sig</a>
+            <a href="binutils-2.44.tar.xz">binutils-2.44.tar.xz</a>
+            <a href="binutils-with-gold-2.46.tar.xz">binutils-with-gold-2.46.tar.xz</a>
+        "#;
+        assert_eq!(
+            latest_in_listing(listing, "binutils-", ".tar.xz").as_deref(),
+            Some("2.45")
+        );
+        assert_eq!(
+            latest_in_listing(r#"<a href="gcc-9.5.0/">gcc-15.2.0/</a>"#, "gcc-", "/").as_deref(),
+            Some("15.2.0")
+        );
+        assert_eq!(latest_in_listing("nothing here", "gcc-", "/"), None);
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("15.2.0", "14.3.0"));
+        assert!(is_newer("2.45", "2.9"));
+        assert!(!is_newer("2.45", "2.45"));
+        assert!(!is_newer("2.46-rc1", "2.45"));
+    }
+}
@@ -0,0 +1,196 @@
+//! `toolup pack`: an installed toolchain and its sysroot in a relocatable `.tar.gz`.
+//!
+//! The archive has the toolchain directory (`<id>/`), its sysroot (`sysroot-<id>/`) and a
+//! `toolup-pack.json` with the versions, the target and the host at its root. GCC finds its own
+//! files relative to its binaries, and toolup points it to a sysroot that moved with a specs file
+//! when the toolchain is installed (see [`crate::specs::write`]). Absolute symlinks inside the
+//! archive are made relative and libtool archives (`*.la`), which only record absolute paths of
+//! the build machine, are left out.
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+use tar::{Archive, Builder, EntryType, Header};
+use walkdir::WalkDir;
+
+use crate::{prebuilt::host, profile::Toolchain};
+
+/// The name of the metadata file of a packed toolchain.
+pub const PACK_METADATA: &str = "toolup-pack.json";
+
+/// What a packed toolchain is, `toolup-pack.json` in the archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackMetadata {
+    /// See [`Toolchain::id`]
+    pub id: String,
+    pub target: String,
+    pub gcc: String,
+    pub binutils: String,
+    pub libc: String,
+    pub profile: String,
+    /// The machine the toolchain runs on, see [`host`]
+    pub host: String,
+    /// The toolup version that built the toolchain
+    pub toolup: String,
+}
+
+impl PackMetadata {
+    pub fn new(toolchain: &Toolchain) -> Self {
+        Self {
+            id: toolchain.id(),
+            target: toolchain.target.to_string(),
+            gcc: toolchain.gcc.version.to_string(),
+            binutils: toolchain.binutils.version.to_string(),
+            libc: toolchain.libc.to_string(),
+            profile: toolchain.profile.to_string(),
+            host: host(),
+            toolup: env!("CARGO_PKG_VERSION").into(),
+        }
+    }
+}
+
+/// The default archive name of `toolchain`, e.g. `<id>-x86_64-linux.tar.gz`.
+pub fn default_archive_name(toolchain: &Toolchain) -> PathBuf {
+    PathBuf::from(format!("{}-{}.tar.gz", toolchain.id(), host()))
+}
+
+/// Pack the installed `toolchain` into `out`.
+pub fn pack(toolchain: &Toolchain, out: &Path) -> Result<PackMetadata> {
+    if !toolchain.gcc_bin()?.exists() {
+        bail!("{} is not installed", toolchain.id());
+    }
+    let id = toolchain.id();
+    let metadata = PackMetadata::new(toolchain);
+
+    let file = File::create(out).context(format!("failed to create `{}`", out.display()))?;
+    let mut builder = Builder::new(GzEncoder::new(file, Compression::default()));
+    builder.follow_symlinks(false);
+
+    log::info!("=> packing {}", toolchain.dir()?.display());
+    append_tree(&mut builder, &toolchain.dir()?, Path::new(&id))?;
+    if !toolchain.is_freestanding() {
+        log::info!("=> packing {}", toolchain.sysroot()?.display());
+        append_tree(
+            &mut builder,
+            &toolchain.sysroot()?,
+            Path::new(&format!("sysroot-{id}")),
+        )?;
+    }
+
+    let json = serde_json::to_string_pretty(&metadata)?;
+    let mut header = Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, PACK_METADATA, json.as_bytes())?;
+
+    builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .and_then(|mut file| file.flush())
+        .context(format!("failed to write `{}`", out.display()))?;
+    Ok(metadata)
+}
+
+/// Read the `toolup-pack.json` of a packed toolchain, `None` if `archive` doesn't have one.
+pub fn read_metadata(archive: &Path) -> Result<Option<PackMetadata>> {
+    let file = File::open(archive).context(format!("failed to open `{}`", archive.display()))?;
+    for entry in Archive::new(GzDecoder::new(file)).entries()? {
+        let mut entry = entry?;
+        if entry.path()? != Path::new(PACK_METADATA) {
+            continue;
+        }
+        let mut json = String::new();
+        entry.read_to_string(&mut json)?;
+        return serde_json::from_str(&json).map(Some).context(format!(
+            "failed to parse the metadata of `{}`",
+            archive.display()
+        ));
+    }
+    Ok(None)
+}
+
+/// Append the tree at `root` to the archive as `name`, making the absolute symlinks that point
+/// inside `root` relative.
+fn append_tree<W: Write>(builder: &mut Builder<W>, root: &Path, name: &Path) -> Result<()> {
+    for entry in WalkDir::new(root) {
+        let entry = entry.context(format!("failed to walk `{}`", root.display()))?;
+        let relative = entry.path().strip_prefix(root)?;
+        let path = name.join(relative);
+        if entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "la") {
+            continue;
+        }
+        if !entry.path_is_symlink() {
+            builder
+                .append_path_with_name(entry.path(), &path)
+                .context(format!("failed to pack `{}`", entry.path().display()))?;
+            continue;
+        }
+
+        let mut target = std::fs::read_link(entry.path())?;
+        if let Ok(inside) = target.strip_prefix(root) {
+            // one `..` for every directory between `root` and the link
+            let depth = entry.depth().saturating_sub(1);
+            target = std::iter::repeat_n(Path::new(".."), depth)
+                .collect::<PathBuf>()
+                .join(inside);
+        }
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder
+            .append_link(&mut header, &path, &target)
+            .context(format!("failed to pack `{}`", entry.path().display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, path::Path};
+
+    use anyhow::Result;
+    use flate2::read::GzDecoder;
+    use tar::{Archive, Builder};
+
+    use super::append_tree;
+
+    #[test]
+    fn test_append_tree() -> Result<()> {
+        let root = tempfile::TempDir::new()?;
+        std::fs::create_dir_all(root.path().join("lib"))?;
+        std::fs::create_dir_all(root.path().join("usr/lib"))?;
+        std::fs::write(root.path().join("lib/libc.so.6"), "")?;
+        std::fs::write(root.path().join("usr/lib/libstdc++.la"), "libdir='/home'")?;
+        std::os::unix::fs::symlink(
+            root.path().join("lib/libc.so.6"),
+            root.path().join("usr/lib/libc.so.6"),
+        )?;
+
+        let out = tempfile::TempDir::new()?;
+        let tarball = out.path().join("sysroot.tar.gz");
+        let mut builder = Builder::new(flate2::write::GzEncoder::new(
+            File::create(&tarball)?,
+            flate2::Compression::fast(),
+        ));
+        builder.follow_symlinks(false);
+        append_tree(&mut builder, root.path(), Path::new("sysroot"))?;
+        builder.into_inner()?.finish()?;
+
+        let unpacked = out.path().join("unpacked");
+        Archive::new(GzDecoder::new(File::open(&tarball)?)).unpack(&unpacked)?;
+        assert_eq!(
+            std::fs::read_link(unpacked.join("sysroot/usr/lib/libc.so.6"))?,
+            Path::new("../../lib/libc.so.6")
+        );
+        assert!(unpacked.join("sysroot/usr/lib/libc.so.6").exists());
+        assert!(!unpacked.join("sysroot/usr/lib/libstdc++.la").exists());
+        Ok(())
+    }
+}
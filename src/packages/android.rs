@@ -0,0 +1,216 @@
+//! Android's bionic for `*-linux-android` toolchains, imported from the NDK.
+//!
+//! Only the NDK's sysroot is used, its clang isn't. The NDK keeps the headers of every
+//! architecture in `usr/include/<triple>` and the libraries of every API level in
+//! `usr/lib/<triple>/<api>`, these are flattened into `usr/include` and `usr/lib` of the
+//! toolchain's sysroot for the architecture and API level of the toolchain, where GCC looks.
+//!
+//! bionic's shared libraries in the NDK are stubs to link against, only static binaries run
+//! outside of a device (e.g. with qemu user-mode).
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    commands::{create_dir_all, is_plan, plan_step, run_command_in},
+    download::{DownloadResult, cache_dir, download_archive},
+    packages::{BuildContext, Package, Source, install_package},
+    profile::{Arch, Libc, Toolchain},
+    provenance,
+    sysroot::copy_tree,
+};
+
+const NDK_VERSION: &str = "r27c";
+
+/// The sysroot inside the NDK.
+const NDK_SYSROOT: &str = "toolchains/llvm/prebuilt/linux-x86_64/sysroot";
+
+/// Import the NDK's sysroot into the toolchain's sysroot.
+pub fn install_ndk_sysroot(toolchain: &Toolchain) -> Result<()> {
+    let Libc::Bionic(api) = toolchain.libc else {
+        return Err(anyhow!("{} isn't an Android toolchain", toolchain.id()));
+    };
+    install_package(&NdkSysrootPackage { toolchain, api }, 1)
+}
+
+/// The bionic headers and libraries of an Android API level, installed into the toolchain's
+/// sysroot.
+pub struct NdkSysrootPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub api: AndroidApi,
+}
+
+impl NdkSysrootPackage<'_> {
+    /// The directory of the target in the NDK's sysroot, e.g. `aarch64-linux-android`.
+    fn triple(&self) -> Result<&'static str> {
+        Ok(match self.toolchain.target.arch {
+            Arch::Aarch64 => "aarch64-linux-android",
+            Arch::X86_64 => "x86_64-linux-android",
+            arch => return Err(anyhow!("Android isn't supported on {arch}")),
+        })
+    }
+}
+
+impl Package for NdkSysrootPackage<'_> {
+    fn name(&self) -> String {
+        "android ndk sysroot".into()
+    }
+
+    fn version(&self) -> String {
+        format!("{NDK_VERSION} api {}", self.api)
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        vec![Source::for_package(
+            "android-ndk",
+            NDK_VERSION,
+            format!("https://dl.google.com/android/repository/android-ndk-{NDK_VERSION}-linux.zip"),
+            format!("android-ndk-{NDK_VERSION}"),
+        )]
+    }
+
+    // the NDK is a zip, only its sysroot is extracted
+    fn fetch(&self) -> Result<PathBuf> {
+        self.triple()?;
+        let source = self.sources().remove(0);
+        let dir = cache_dir()?.join(&source.dirname);
+        provenance::record_source(&source.url, &source.dirname);
+        if dir.join(NDK_SYSROOT).is_dir() {
+            return Ok(dir);
+        }
+        if is_plan() {
+            plan_step(format!("download {}", source.url));
+            return Ok(dir);
+        }
+
+        let archive = match download_archive(&source.url, true)? {
+            DownloadResult::Cached(p)
+            | DownloadResult::Replaced(p)
+            | DownloadResult::Created(p) => p,
+        };
+        // an interrupted extraction never leaves a directory that looks complete
+        let partial = cache_dir()?.join(format!("{}.partial", source.dirname));
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)
+                .context(format!("failed to remove `{}`", partial.display()))?;
+        }
+        create_dir_all(&partial)?;
+        run_command_in(
+            &partial,
+            "extract the ndk",
+            "unzip",
+            &[
+                "-q".to_string(),
+                archive.display().to_string(),
+                format!("*/{NDK_SYSROOT}/*"),
+            ],
+            None::<Vec<(OsString, OsString)>>,
+        )?;
+        // the zip has a top-level `android-ndk-<version>` directory
+        std::fs::rename(partial.join(&source.dirname), &dir)
+            .context(format!("failed to rename `{}`", dir.display()))?;
+        std::fs::remove_dir_all(&partial)?;
+        Ok(dir)
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        if is_plan() {
+            plan_step(format!(
+                "copy the NDK's sysroot for API level {} into the sysroot",
+                self.api
+            ));
+            return Ok(());
+        }
+        let triple = self.triple()?;
+        let ndk = ctx.source_dir.join(NDK_SYSROOT);
+        let libs = ndk.join("usr/lib").join(triple);
+        let api_libs = libs.join(self.api.to_string());
+        if !api_libs.is_dir() {
+            return Err(anyhow!(
+                "NDK {NDK_VERSION} doesn't have API level {} for {triple}",
+                self.api
+            ));
+        }
+
+        let sysroot = self.toolchain.sysroot()?;
+        for dir in ["usr/include", "usr/lib"] {
+            let dest = sysroot.join(dir);
+            // `toolup install --force` copies over a previous import
+            if dest.exists() {
+                std::fs::remove_dir_all(&dest)
+                    .context(format!("failed to remove `{}`", dest.display()))?;
+            }
+            create_dir_all(&dest)?;
+        }
+        copy_tree(&ndk.join("usr/include"), &sysroot.join("usr/include"))?;
+        merge_dir(
+            &ndk.join("usr/include").join(triple),
+            &sysroot.join("usr/include"),
+        )?;
+        // static libraries, then the stubs and crt objects of the API level
+        merge_dir(&libs, &sysroot.join("usr/lib"))?;
+        merge_dir(&api_libs, &sysroot.join("usr/lib"))
+    }
+}
+
+/// Copy the files and directories in `src` into the existing directory `dest`, skipping the
+/// numbered API level directories.
+fn merge_dir(src: &Path, dest: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(src).context(format!("failed to read `{}`", src.display()))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() && name.to_string_lossy().parse::<u64>().is_ok() {
+            continue;
+        }
+        let target = dest.join(&name);
+        if file_type.is_dir() {
+            create_dir_all(&target)?;
+            merge_dir(&entry.path(), &target)?;
+        } else if file_type.is_symlink() {
+            if target.symlink_metadata().is_ok() {
+                std::fs::remove_file(&target)?;
+            }
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)
+                .context(format!("failed to copy `{}`", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// An Android API level, e.g. `35` for Android 15.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AndroidApi(pub u64);
+
+impl Default for AndroidApi {
+    fn default() -> Self {
+        Self(35)
+    }
+}
+
+impl FromStr for AndroidApi {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.parse() {
+            // the oldest level of the NDK
+            Ok(api) if api >= 21 => Ok(AndroidApi(api)),
+            _ => Err(anyhow!("`{}` is an invalid Android API level", s)),
+        }
+    }
+}
+
+impl Display for AndroidApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+serde_string!(AndroidApi);
@@ -1,52 +1,98 @@
-use std::{fmt::Display, str::FromStr};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     commands::{run_configure_in, run_make_in},
-    download::download_and_decompress,
+    packages::{BuildContext, Package, Source, host_tools::find_program, install_package},
     profile::Toolchain,
 };
 
 /// Download and build binutils.
 pub fn install_binutils(toolchain: &Toolchain, jobs: u64) -> Result<()> {
-    log::info!("=> install binutils {}", toolchain.binutils.version);
-
-    let tarball = if toolchain.binutils.version <= BinutilsVersion(2, 28, 1) {
-        format!("{}.tar.gz", toolchain.binutils.version)
-    } else {
-        format!("{}.tar.xz", toolchain.binutils.version)
-    };
-
-    let binutils_dir = download_and_decompress(
-        format!("https://ftp.gnu.org/gnu/binutils/binutils-{tarball}",),
-        format!("binutils-{}", toolchain.binutils.version),
-        true,
-    )
-    .context("failed to download binutils")?;
-
-    let arch_dir = binutils_dir.join(format!("objdir-arch-{}", toolchain.id()));
-
-    std::fs::create_dir_all(&arch_dir).context("failed to create an objdir for the arch")?;
-
-    run_configure_in(
-        &arch_dir,
-        &[
-            "--target",
-            toolchain.target.to_target_string().as_str(),
-            "--prefix",
-            toolchain
-                .dir()?
-                .to_str()
-                .expect("toolchain dir is a valid UTF8 string"),
-            "--disable-nls",
-            "--disable-werror",
-        ],
-    )?;
-    let jobs = jobs.to_string();
-    run_make_in(&arch_dir, &["-j", jobs.as_str()])?;
-    run_make_in(&arch_dir, &["install", "-j", jobs.as_str()])?;
-    Ok(())
+    install_package(&BinutilsPackage { toolchain }, jobs)
+}
+
+/// Cross binutils installed into the toolchain's directory.
+pub struct BinutilsPackage<'a> {
+    pub toolchain: &'a Toolchain,
+}
+
+impl Package for BinutilsPackage<'_> {
+    fn name(&self) -> String {
+        "binutils".into()
+    }
+
+    fn version(&self) -> String {
+        self.toolchain.binutils.version.to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        let version = self.toolchain.binutils.version;
+        // gold was split out of the main tarball in 2.44
+        let name = if self.toolchain.binutils.gold && version >= BinutilsVersion(2, 44, 0) {
+            format!("binutils-with-gold-{version}")
+        } else {
+            format!("binutils-{version}")
+        };
+        let tarball = if version <= BinutilsVersion(2, 28, 1) {
+            format!("{name}.tar.gz")
+        } else {
+            format!("{name}.tar.xz")
+        };
+
+        vec![Source::for_package(
+            "binutils",
+            &version.to_string(),
+            format!("https://ftp.gnu.org/gnu/binutils/{tarball}"),
+            name,
+        )]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-arch-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        let gold = if self.toolchain.binutils.gold {
+            "--enable-gold"
+        } else {
+            "--disable-gold"
+        };
+        run_configure_in(
+            &ctx.objdir,
+            &[
+                gold,
+                "--target",
+                self.toolchain.target.to_target_string().as_str(),
+                "--prefix",
+                self.toolchain
+                    .dir()?
+                    .to_str()
+                    .expect("toolchain dir is a valid UTF8 string"),
+                "--disable-nls",
+                "--disable-werror",
+                // lets ld, ar and nm load GCC's LTO plugin
+                "--enable-plugins",
+            ],
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_make_in(&ctx.objdir, &["-j", ctx.jobs.to_string().as_str()])
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_make_in(
+            &ctx.objdir,
+            &["install", "-j", ctx.jobs.to_string().as_str()],
+        )
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -84,20 +130,92 @@ impl Display for BinutilsVersion {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+serde_string!(BinutilsVersion);
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
 pub struct Binutils {
     pub version: BinutilsVersion,
+    /// Also build the gold linker
+    #[serde(default)]
+    pub gold: bool,
 }
 
 impl Binutils {
     pub fn new(version: BinutilsVersion) -> Self {
-        Self { version }
+        Self {
+            version,
+            gold: false,
+        }
     }
 }
 impl Default for Binutils {
     fn default() -> Self {
-        Self {
-            version: BinutilsVersion(2, 45, 0),
+        Self::new(BinutilsVersion(2, 45, 0))
+    }
+}
+
+/// The linker used by `toolup cc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Linker {
+    Bfd,
+    /// Requires binutils built with [`Binutils::gold`]
+    Gold,
+    /// The host's `ld.lld`, which handles every target
+    Lld,
+}
+
+impl FromStr for Linker {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bfd" => Ok(Linker::Bfd),
+            "gold" => Ok(Linker::Gold),
+            "lld" => Ok(Linker::Lld),
+            _ => Err(anyhow!(
+                "unknown linker `{s}`, expected one of: bfd, gold, lld"
+            )),
+        }
+    }
+}
+
+impl Display for Linker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Linker::Bfd => "bfd",
+            Linker::Gold => "gold",
+            Linker::Lld => "lld",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Make sure `linker` can be used by `toolchain`'s gcc through `-fuse-ld=`.
+///
+/// gcc looks for `<target>-ld.<linker>` next to itself, so for lld a symlink to the host's
+/// `ld.lld` is created in the toolchain's `bin` directory.
+pub fn ensure_linker(toolchain: &Toolchain, linker: Linker) -> Result<()> {
+    let prefixed = toolchain.bin_dir()?.join(format!(
+        "{}-ld.{linker}",
+        toolchain.target.to_target_string()
+    ));
+    if prefixed.exists() {
+        return Ok(());
+    }
+
+    match linker {
+        Linker::Bfd => bail!("`{}` doesn't exist", prefixed.display()),
+        Linker::Gold => bail!(
+            "{} was built without gold, reinstall it with `--gold --force-stage binutils`",
+            toolchain.id()
+        ),
+        Linker::Lld => {
+            let lld = find_program("ld.lld").context("`ld.lld` was not found, install lld")?;
+            log::info!("=> linking {} to {}", prefixed.display(), lld.display());
+            std::os::unix::fs::symlink(&lld, &prefixed)
+                .context(format!("failed to create `{}`", prefixed.display()))?;
+            Ok(())
         }
     }
 }
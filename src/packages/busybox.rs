@@ -1,94 +1,373 @@
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::{fs::OpenOptions, path::PathBuf};
 
-use crate::commands::run_command_in;
+use crate::cache::{self, ArtifactKind};
+use crate::commands::{is_plan, plan_step, run_command_in};
+use crate::compat;
 use crate::cpio::pack_rootfs;
 use crate::download::cache_dir;
-use crate::download::download_and_decompress;
-use crate::profile::Toolchain;
+use crate::elf::Elf;
+use crate::error::Failure;
+use crate::hooks::{self, Hook};
+use crate::packages::gdb::{GDBSERVER, GdbserverPackage};
+use crate::packages::{BuildContext, Package, Source, install_package};
+use crate::profile::{Target, Toolchain};
+use crate::qemu::{EXEC_BIN, Exec};
 
-pub fn download_busybox() -> Result<PathBuf> {
-    log::info!("=> downloading busybox");
+const BUSYBOX_VERSION: &str = "1.36.1";
 
-    // using the github mirror because busybox.net is super slow and times out most of the time.
-    download_and_decompress(
-        "https://github.com/mirror/busybox/archive/refs/tags/1_36_1.tar.gz",
-        "busybox-1_36_1",
-        true,
-    )
+/// The rootfs `/init`. With `toolup_exec` on the kernel command line it runs the commands listed
+/// in that file, one per line, instead of a shell and reports the status of the first one that
+/// failed through `toolup_exit`, see [`crate::qemu::start_vm`]. Booted as a crash kernel it copies
+/// the vmcore to the share instead, see [`crate::kdump`]. The shell gets the toolchain's sysroot with
+/// `toolup_sysroot` and follows the size of the host's terminal.
+const INIT_SCRIPT: &str = r#"#!/bin/sh
+mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+mount -t devtmpfs devtmpfs /dev 2>/dev/null || mount -t tmpfs tmpfs /dev
+[ -c /dev/console ] || mknod -m 600 /dev/console c 5 1
+report_status() {
+    [ -n "$toolup_exit" ] && echo "$1" > "/dev/$toolup_exit"
+}
+power_off() {
+    # x86 stops QEMU through the isa-debug-exit port, QEMU exits with 1
+    [ "$toolup_poweroff" = isa-debug-exit ] && printf '\000' | dd of=/dev/port bs=1 seek=244 2>/dev/null
+    poweroff -f
+}
+if [ -e /proc/vmcore ]; then
+    # booted as the crash kernel of `toolup linux --kdump`
+    echo "toolup: crash kernel booted"
+    mkdir -p /toolup/share
+    mount -t 9p -o trans=virtio toolup /toolup/share && cp /proc/vmcore /toolup/share/vmcore
+    report_status $?
+    power_off
+fi
+if [ -n "$toolup_exec" ]; then
+    mkdir -p /toolup/share
+    # the host directory core dumps are written to, see `collect_cores` in `qemu`
+    if mount -t 9p -o trans=virtio toolup /toolup/share 2>/dev/null; then
+        ulimit -c unlimited
+        echo "/toolup/share/core.%e.%p" > /proc/sys/kernel/core_pattern
+    fi
+    if [ -n "$toolup_kdump" ]; then
+        /toolup/bin/toolup-kdump /toolup/kdump/kernel /toolup/kdump/initrd \
+            "$(sed 's/crashkernel=[^ ]*//' /proc/cmdline) nr_cpus=1 irqpoll reset_devices" ||
+            echo "toolup: failed to load the crash kernel"
+    fi
+    status=0
+    n=0
+    while IFS= read -r cmd <&3; do
+        n=$((n + 1))
+        # `toolup linux` times and reports every command from these lines
+        echo "toolup: exec $n started"
+        sh -c "$cmd" 3<&-
+        code=$?
+        echo "toolup: exec $n finished $code"
+        [ "$status" -eq 0 ] && status=$code
+    done 3< "$toolup_exec"
+    report_status "$status"
+    power_off
+fi
+if [ -n "$toolup_sysroot" ]; then
+    # the toolchain's sysroot, `toolup linux --shell`
+    mkdir -p "$toolup_sysroot"
+    mount -t 9p -o trans=virtio,ro toolup-sysroot "$toolup_sysroot" ||
+        echo "toolup: failed to mount the sysroot"
+fi
+# a serial console has no size, the host writes its terminal's to the `toolup.winch` port
+for port in /sys/class/virtio-ports/*; do
+    [ "$(cat "$port/name" 2>/dev/null)" = toolup.winch ] || continue
+    tty=/dev/$(sed 's/.* //' /sys/class/tty/console/active)
+    while :; do
+        # a new size is sent to the programs running on the console as SIGWINCH
+        while read -r rows cols; do
+            stty -F "$tty" rows "$rows" cols "$cols"
+        done < "/dev/${port##*/}"
+        # the host isn't connected (yet)
+        sleep 1
+    done &
+done
+setsid cttyhack /bin/sh
+# exiting the shell powers off, otherwise init exits and the kernel panics
+poweroff -f
+"#;
+
+/// A static busybox built out of tree for a target and installed into its rootfs directory.
+///
+/// The binary and its list of applet links are kept in `<cache>/busybox`, keyed by the target,
+/// busybox's version and the hash of its `.config`: a configuration that was built once isn't built
+/// again, even after its objdir was removed.
+pub struct BusyboxPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub rootfs_dir: PathBuf,
+}
+
+impl BusyboxPackage<'_> {
+    fn env(&self) -> Result<Vec<(OsString, OsString)>> {
+        Ok(vec![("PATH".into(), self.toolchain.env_path()?)])
+    }
+
+    /// Runs busybox's make from the source tree, building in the objdir.
+    fn make(&self, ctx: &BuildContext, args: &[String]) -> Result<()> {
+        let mut make_args = vec![
+            format!("O={}", ctx.objdir.display()),
+            format!("CROSS_COMPILE={}-", self.toolchain.target),
+        ];
+        make_args.extend_from_slice(args);
+        run_command_in(
+            &ctx.source_dir,
+            "make",
+            "make",
+            &make_args,
+            Some(self.env()?),
+        )
+    }
+
+    /// The directory of the busybox built with the configuration in the objdir.
+    fn cached_build(&self, ctx: &BuildContext) -> Result<PathBuf> {
+        let config = std::fs::read_to_string(ctx.objdir.join(".config"))
+            .context("failed to read the busybox configuration")?;
+        let hash = &blake3::hash(config_settings(&config).as_bytes()).to_hex()[..16];
+        Ok(cache_dir()?.join("busybox").join(format!(
+            "{}-{BUSYBOX_VERSION}-{hash}",
+            self.toolchain.target
+        )))
+    }
+}
+
+impl Package for BusyboxPackage<'_> {
+    fn name(&self) -> String {
+        "busybox".into()
+    }
+
+    fn version(&self) -> String {
+        BUSYBOX_VERSION.into()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        // using the github mirror because busybox.net is super slow and times out most of the time.
+        let tag = BUSYBOX_VERSION.replace('.', "_");
+        vec![Source::for_package(
+            "busybox",
+            BUSYBOX_VERSION,
+            format!("https://github.com/mirror/busybox/archive/refs/tags/{tag}.tar.gz"),
+            format!("busybox-{tag}"),
+        )]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-{}", self.toolchain.target)))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        // out of tree builds refuse a source tree configured by an in-tree build of older toolups
+        if ctx.source_dir.join(".config").exists() {
+            run_command_in(
+                &ctx.source_dir,
+                "make",
+                "make",
+                &["mrproper"],
+                None::<Vec<(OsString, OsString)>>,
+            )?;
+        }
+        self.make(ctx, &["defconfig".into()])?;
+        if is_plan() {
+            plan_step(
+                "set CONFIG_STATIC=y, unset CONFIG_TC and enable the interactive applets in .config",
+            );
+            return Ok(());
+        }
+        fix_busybox_config(ctx.objdir.join(".config"))
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        // `busybox.links` lists the applets `make install` links, they're installed from the cache
+        let args = [
+            format!("-j{}", ctx.jobs),
+            "all".into(),
+            "busybox.links".into(),
+        ];
+        if is_plan() {
+            return self.make(ctx, &args);
+        }
+        let cached = self.cached_build(ctx)?;
+        if cached.join("busybox").exists() {
+            log::info!("=> using the busybox built in {}", cached.display());
+            return Ok(());
+        }
+        self.make(ctx, &args)?;
+
+        std::fs::create_dir_all(&cached)?;
+        // the binary last, it marks a complete entry
+        for file in ["busybox.links", "busybox"] {
+            std::fs::copy(ctx.objdir.join(file), cached.join(file))
+                .context(format!("failed to cache busybox's `{file}`"))?;
+        }
+        Ok(())
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        if is_plan() {
+            plan_step(format!(
+                "install busybox and its applet links into {}",
+                self.rootfs_dir.display()
+            ));
+            return Ok(());
+        }
+        install_busybox(&self.cached_build(ctx)?, &self.rootfs_dir)
+    }
+}
+
+/// The settings of a busybox `.config`, without the comments: its header has the date it was
+/// generated.
+fn config_settings(config: &str) -> String {
+    config
+        .lines()
+        .filter(|line| line.starts_with("CONFIG_") || line.starts_with("# CONFIG_"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Install the busybox cached in `cached` into `rootfs_dir` like `make install`: the binary in
+/// `/bin` and a relative symlink to it for every applet.
+fn install_busybox(cached: &Path, rootfs_dir: &Path) -> Result<()> {
+    let bin_dir = rootfs_dir.join("bin");
+    std::fs::create_dir_all(&bin_dir)?;
+    std::fs::copy(cached.join("busybox"), bin_dir.join("busybox"))
+        .context("failed to install busybox")?;
+
+    let links = std::fs::read_to_string(cached.join("busybox.links"))
+        .context("failed to read busybox's applet links")?;
+    for link in links.lines().filter(|link| !link.is_empty()) {
+        let link = link.trim_start_matches('/');
+        if link == "bin/busybox" {
+            continue;
+        }
+        let path = rootfs_dir.join(link);
+        std::fs::create_dir_all(path.parent().expect("applets are in a directory"))?;
+        if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(&path)
+                .context(format!("failed to remove `{}`", path.display()))?;
+        }
+        std::os::unix::fs::symlink(applet_target(link), &path)
+            .context(format!("failed to link `{}`", path.display()))?;
+    }
+    Ok(())
+}
+
+/// The target of the symlink of the applet at `link`, relative to the rootfs.
+fn applet_target(link: &str) -> PathBuf {
+    let depth = Path::new(link).components().count() - 1;
+    let mut target = PathBuf::new();
+    for _ in 0..depth {
+        target.push("..");
+    }
+    target.join("bin/busybox")
+}
+
+/// What a packed rootfs was built from, stored next to it. It's packed again when one of them
+/// changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RootfsInputs {
+    /// The id of the toolchain whose sysroot is copied into the rootfs
+    toolchain: String,
+    /// The hash of busybox's version and the settings forced over its defconfig
+    busybox: String,
+    /// The hash of [`INIT_SCRIPT`]
+    init: String,
+    gdbserver: bool,
+}
+
+impl RootfsInputs {
+    fn new(toolchain: &Toolchain, gdbserver: bool) -> Self {
+        let busybox = format!("{BUSYBOX_VERSION} {BUSYBOX_CONFIG:?}");
+        Self {
+            toolchain: toolchain.id(),
+            busybox: blake3::hash(busybox.as_bytes()).to_hex().to_string(),
+            init: blake3::hash(INIT_SCRIPT.as_bytes()).to_hex().to_string(),
+            gdbserver,
+        }
+    }
+
+    fn path(target: &Target) -> Result<PathBuf> {
+        Ok(cache_dir()?.join(format!("rootfs-{target}.inputs.json")))
+    }
+
+    fn load(target: &Target) -> Option<Self> {
+        let contents = std::fs::read_to_string(Self::path(target).ok()?).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    fn save(&self, target: &Target) -> Result<()> {
+        let path = Self::path(target)?;
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .context(format!("failed to write `{}`", path.display()))
+    }
+
+    /// Whether a rootfs packed from `self` can be used for `wanted`. Images without gdbserver are
+    /// packed again when it's asked for, it stays in later images.
+    fn satisfies(&self, wanted: &RootfsInputs) -> bool {
+        self.toolchain == wanted.toolchain
+            && self.busybox == wanted.busybox
+            && self.init == wanted.init
+            && (self.gdbserver || !wanted.gdbserver)
+    }
+}
+
+fn rootfs_dir(target: &Target) -> Result<PathBuf> {
+    Ok(cache_dir()?.join(format!("rootfs-{target}")))
 }
 
-/// Returns rootfs image
-pub fn build_rootfs(toolchain: &Toolchain) -> Result<PathBuf> {
-    let busybox_dir = download_busybox()?;
-    let rootfs_dir = cache_dir()?.join(format!("rootfs-{}", toolchain.target));
+/// Returns rootfs image, with `gdbserver` it also has a gdbserver built with `toolchain` (see
+/// [`GDBSERVER`]).
+pub fn build_rootfs(toolchain: &Toolchain, gdbserver: bool) -> Result<PathBuf> {
+    let rootfs_dir = rootfs_dir(&toolchain.target)?;
     let cpio_gz = cache_dir()?.join(format!("rootfs-{}.cpio.gz", toolchain.target));
-    if cpio_gz.exists() {
+    let inputs = RootfsInputs::new(toolchain, gdbserver);
+    let packed = RootfsInputs::load(&toolchain.target);
+    if cpio_gz.exists() && packed.is_some_and(|packed| packed.satisfies(&inputs)) {
+        cache::record_artifact(&cpio_gz, ArtifactKind::Rootfs, Some(toolchain.id()))?;
         return Ok(cpio_gz);
     }
 
-    log::info!("=> busybox");
+    let busybox = BusyboxPackage {
+        toolchain,
+        rootfs_dir: rootfs_dir.clone(),
+    };
+    let gdbserver = gdbserver.then(|| GdbserverPackage {
+        toolchain,
+        rootfs_dir: rootfs_dir.clone(),
+    });
+    if is_plan() {
+        plan_rootfs(toolchain, &busybox, gdbserver.as_ref(), &cpio_gz)?;
+        return Ok(cpio_gz);
+    }
 
     std::fs::create_dir_all(&rootfs_dir)?;
-    std::fs::create_dir_all(&rootfs_dir.join("proc"))?;
-    std::fs::create_dir_all(&rootfs_dir.join("sys"))?;
-    std::fs::create_dir_all(&rootfs_dir.join("dev"))?;
-    std::fs::create_dir_all(&rootfs_dir.join("etc"))?;
+    std::fs::create_dir_all(rootfs_dir.join("proc"))?;
+    std::fs::create_dir_all(rootfs_dir.join("sys"))?;
+    std::fs::create_dir_all(rootfs_dir.join("dev"))?;
+    std::fs::create_dir_all(rootfs_dir.join("etc"))?;
 
-    let init_script = r"#!/bin/sh
-mount -t proc proc /proc
-mount -t sysfs sysfs /sys
-mount -t devtmpfs devtmpfs /dev 2>/dev/null || mount -t tmpfs tmpfs /dev
-[ -c /dev/console ] || mknod -m 600 /dev/console c 5 1
-exec setsid cttyhack /bin/sh
-";
     let mut init = OpenOptions::new()
         .create(true)
-        .append(true)
+        .write(true)
+        .truncate(true)
         .mode(0o755)
         .open(rootfs_dir.join("init"))
         .context("failed to create `init` in rootfs")?;
-    init.write_all(init_script.as_bytes())?;
-
-    let env: Vec<(OsString, OsString)> = vec![("PATH".into(), toolchain.env_path()?)];
-
-    run_command_in(
-        &busybox_dir,
-        "make",
-        "make",
-        &[
-            format!("CROSS_COMPILE={}-", toolchain.target).as_str(),
-            "defconfig",
-        ],
-        Some(env.clone()),
-    )?;
-    fix_busybox_config(busybox_dir.join(".config"))?;
-    let mut f = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(busybox_dir.join(".config"))
-        .context("failed to read busybox's `.config`")?;
-
-    // static build
-    writeln!(f, "CONFIG_STATIC=y")?;
-    // workaround: https://forum.beagleboard.org/t/errors-during-busybox-compilation/38969/6
-    writeln!(f, "# CONFIG_TC is not set")?;
-
-    run_command_in(
-        &busybox_dir,
-        "make",
-        "make",
-        &[
-            format!("CROSS_COMPILE={}-", toolchain.target).as_str(),
-            format!("CONFIG_PREFIX={}", &rootfs_dir.display()).as_str(),
-            "install",
-        ],
-        Some(env.clone()),
-    )?;
+    init.write_all(INIT_SCRIPT.as_bytes())?;
+
+    install_package(&busybox, 1)?;
+    if let Some(gdbserver) = &gdbserver {
+        install_package(gdbserver, 1)?;
+    }
 
     let sysroot = toolchain.sysroot()?;
 
@@ -101,12 +380,191 @@ exec setsid cttyhack /bin/sh
 
     copy_dir_to(&sysroot.join("usr"), &rootfs_dir)?;
 
+    let dirs = [
+        ("rootfs", rootfs_dir.as_path()),
+        ("image", cpio_gz.as_path()),
+    ];
+    hooks::run(Hook::PreRootfsPack, toolchain, &dirs)?;
     log::info!("=> packing");
     pack_rootfs(&rootfs_dir, &cpio_gz)?;
+    RootfsInputs {
+        gdbserver: rootfs_dir.join(GDBSERVER).exists(),
+        ..inputs
+    }
+    .save(&toolchain.target)?;
+    hooks::run(Hook::PostRootfsPack, toolchain, &dirs)?;
+    cache::record_artifact(&cpio_gz, ArtifactKind::Rootfs, Some(toolchain.id()))?;
 
     Ok(cpio_gz)
 }
 
+/// The directories of the sysroot the dynamic loader searches, in order.
+const LIBRARY_DIRS: [&str; 4] = ["lib", "lib64", "usr/lib", "usr/lib64"];
+
+/// Returns the `exec` commands with the host programs they run copied into the guest, along with
+/// the libraries they need from the sysroot.
+///
+/// A command runs a host program when its first word has a `/` and names a host file that isn't in
+/// the rootfs, e.g. `./build/test --verbose`. The command is changed to run the copy.
+pub fn exec_programs(toolchain: &Toolchain, commands: Vec<String>) -> Result<Exec> {
+    let rootfs_dir = rootfs_dir(&toolchain.target)?;
+    let mut exec = Exec::default();
+    for command in commands {
+        let command = command.trim_start();
+        let Some((host, args)) = host_program(&rootfs_dir, command) else {
+            exec.commands.push(command.to_string());
+            continue;
+        };
+        let program = host.display();
+
+        add_libraries(toolchain, host, &mut exec.files)?;
+        warn_incompatible(toolchain, host);
+        let guest = Path::new(EXEC_BIN).join(
+            host.file_name()
+                .context(format!("`{program}` is not a file"))?,
+        );
+        exec.commands.push(format!("{}{args}", guest.display()));
+        exec.files.insert(guest, host.to_path_buf());
+    }
+    Ok(exec)
+}
+
+/// Warn when `program` probably fails on the kernel and libc of the VM, see [`compat`].
+fn warn_incompatible(toolchain: &Toolchain, program: &Path) {
+    let Some(kernel) = toolchain.kernel else {
+        return;
+    };
+    if is_plan() || !Elf::read(program).is_ok_and(|elf| elf.is_some()) {
+        return;
+    }
+    match compat::check(toolchain, kernel, program) {
+        Ok(report) => {
+            for problem in &report.problems {
+                log::warn!("`{}`: {problem}", program.display());
+            }
+        }
+        Err(err) => log::debug!("failed to check `{}`: {err:#}", program.display()),
+    }
+}
+
+/// Splits `command` into the host program it runs and its arguments, `None` if it runs a program
+/// of the guest, see [`exec_programs`].
+fn host_program<'a>(rootfs_dir: &Path, command: &'a str) -> Option<(&'a Path, &'a str)> {
+    let (program, args) =
+        command.split_at(command.find(char::is_whitespace).unwrap_or(command.len()));
+    let host = Path::new(program);
+    let in_rootfs = host
+        .strip_prefix("/")
+        .is_ok_and(|path| rootfs_dir.join(path).exists());
+    if !program.contains('/') || in_rootfs || !host.is_file() {
+        return None;
+    }
+    Some((host, args))
+}
+
+/// Check that the host programs run by the `exec` commands can run on `target`, so a mismatch is
+/// reported before the kernel is built rather than as an `exec format error` in the guest.
+pub fn check_exec_programs(target: &Target, commands: &[String]) -> Result<()> {
+    let rootfs_dir = rootfs_dir(target)?;
+    for command in commands {
+        let Some((host, _)) = host_program(&rootfs_dir, command.trim_start()) else {
+            continue;
+        };
+        let Some(elf) = Elf::read(host)? else {
+            continue;
+        };
+        elf.check_runs_on(target)
+            .context(Failure::Usage)
+            .context(format!("`{}` can't run in the VM", host.display()))?;
+    }
+    Ok(())
+}
+
+/// Check that `program` runs on the target of `toolchain` and add its dynamic loader and the
+/// libraries it needs from the sysroot to `files`.
+pub fn add_libraries(
+    toolchain: &Toolchain,
+    program: &Path,
+    files: &mut BTreeMap<PathBuf, PathBuf>,
+) -> Result<()> {
+    let Some(elf) = Elf::read(program)? else {
+        // e.g. a shell script
+        return Ok(());
+    };
+    elf.check_runs_on(&toolchain.target)
+        .context(Failure::Usage)
+        .context(format!("`{}` can't run in the VM", program.display()))?;
+    if is_plan() {
+        plan_step(format!(
+            "copy `{}` and the libraries it needs into the rootfs",
+            program.display()
+        ));
+        return Ok(());
+    }
+
+    let sysroot = toolchain.sysroot()?;
+    let mut pending = vec![(program.to_path_buf(), elf)];
+    while let Some((path, elf)) = pending.pop() {
+        if let Some(interpreter) = &elf.interpreter {
+            let host = sysroot.join(interpreter.trim_start_matches('/'));
+            if !host.exists() {
+                return Err(Failure::Usage).context(format!(
+                    "the dynamic loader of `{}`, {interpreter}, is not in the sysroot `{}`",
+                    path.display(),
+                    sysroot.display()
+                ));
+            }
+            files.insert(PathBuf::from(interpreter), host);
+        }
+        for name in &elf.needed {
+            let Some(dir) = LIBRARY_DIRS
+                .iter()
+                .find(|dir| sysroot.join(dir).join(name).exists())
+            else {
+                return Err(Failure::Usage).context(format!(
+                    "`{name}`, needed by `{}`, is not in the sysroot `{}`, libraries can be added \
+                     with `toolup sysroot add`",
+                    path.display(),
+                    sysroot.display()
+                ));
+            };
+            let guest = Path::new("/").join(dir).join(name);
+            if files.contains_key(&guest) {
+                continue;
+            }
+            let host = sysroot.join(dir).join(name);
+            if let Some(library) = Elf::read(&host)? {
+                pending.push((host.clone(), library));
+            }
+            files.insert(guest, host);
+        }
+    }
+    Ok(())
+}
+
+/// Print the steps [`build_rootfs`] would run.
+fn plan_rootfs(
+    toolchain: &Toolchain,
+    busybox: &BusyboxPackage,
+    gdbserver: Option<&GdbserverPackage>,
+    cpio_gz: &Path,
+) -> Result<()> {
+    plan_step(format!(
+        "create {} with an `init` script",
+        busybox.rootfs_dir.display()
+    ));
+    install_package(busybox, 1)?;
+    if let Some(gdbserver) = gdbserver {
+        install_package(gdbserver, 1)?;
+    }
+    plan_step(format!(
+        "copy the sysroot {} into the rootfs",
+        toolchain.sysroot()?.display()
+    ));
+    plan_step(format!("pack the rootfs into {}", cpio_gz.display()));
+    Ok(())
+}
+
 /// Copy directory into another one.
 ///
 /// This is a naive implementation that doesn't take cyclic symlinks or other edge cases into
@@ -143,28 +601,96 @@ fn copy_dir_to<P: AsRef<Path>>(src: P, target_root: P) -> Result<()> {
     Ok(())
 }
 
+/// Settings forced over busybox's defconfig, `None` unsets the option.
+const BUSYBOX_CONFIG: [(&str, Option<&str>); 9] = [
+    ("CONFIG_STATIC", Some("y")),
+    // see: https://forum.beagleboard.org/t/errors-during-busybox-compilation/38969/6
+    ("CONFIG_TC", None),
+    // the applets of an interactive `toolup linux --shell`, they follow the terminal size
+    ("CONFIG_VI", Some("y")),
+    ("CONFIG_FEATURE_VI_WIN_RESIZE", Some("y")),
+    ("CONFIG_LESS", Some("y")),
+    ("CONFIG_FEATURE_LESS_WINCH", Some("y")),
+    ("CONFIG_STTY", Some("y")),
+    ("CONFIG_FEATURE_EDITING", Some("y")),
+    ("CONFIG_FEATURE_TAB_COMPLETION", Some("y")),
+];
+
 pub fn fix_busybox_config(path: impl AsRef<Path>) -> Result<()> {
     let path = path.as_ref();
     let contents = std::fs::read_to_string(path)?;
 
     let mut out = String::new();
     for line in contents.lines() {
-        // remove any previous STATIC setting
-        if line.starts_with("CONFIG_STATIC=") || line == "# CONFIG_STATIC is not set" {
-            continue;
-        }
-        // remove any TC setting
-        if line.starts_with("CONFIG_TC=") || line == "# CONFIG_TC is not set" {
+        // remove any previous setting of the forced options
+        let forced = BUSYBOX_CONFIG.iter().any(|(option, _)| {
+            line.strip_prefix(option)
+                .is_some_and(|rest| rest.starts_with('='))
+                || line == format!("# {option} is not set")
+        });
+        if forced {
             continue;
         }
         out.push_str(line);
         out.push('\n');
     }
 
-    out.push_str("CONFIG_STATIC=y\n");
-    out.push_str("# CONFIG_TC is not set\n");
+    for (option, value) in BUSYBOX_CONFIG {
+        match value {
+            Some(value) => out.push_str(&format!("{option}={value}\n")),
+            None => out.push_str(&format!("# {option} is not set\n")),
+        }
+    }
 
     std::fs::write(path, out)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{RootfsInputs, applet_target, config_settings};
+
+    #[test]
+    fn test_config_settings() {
+        let config = "#\n# Automatically generated make config: don't edit\n# Busybox version: 1.36.1\n# Thu Oct 16 10:00:00 2025\n#\nCONFIG_HAVE_DOT_CONFIG=y\n\n# CONFIG_TC is not set\n";
+        assert_eq!(
+            config_settings(config),
+            "CONFIG_HAVE_DOT_CONFIG=y\n# CONFIG_TC is not set"
+        );
+    }
+
+    #[test]
+    fn test_applet_target() {
+        assert_eq!(applet_target("linuxrc"), PathBuf::from("bin/busybox"));
+        assert_eq!(applet_target("bin/sh"), PathBuf::from("../bin/busybox"));
+        assert_eq!(
+            applet_target("usr/sbin/crond"),
+            PathBuf::from("../../bin/busybox")
+        );
+    }
+
+    #[test]
+    fn test_rootfs_inputs() {
+        let inputs = RootfsInputs {
+            toolchain: "aarch64-unknown-linux-gnu-gcc-15.2.0-bin-2.45-glibc-2.42".into(),
+            busybox: "busybox".into(),
+            init: "init".into(),
+            gdbserver: false,
+        };
+        let with_gdbserver = RootfsInputs {
+            gdbserver: true,
+            ..inputs.clone()
+        };
+        assert!(inputs.satisfies(&inputs));
+        assert!(with_gdbserver.satisfies(&inputs));
+        assert!(!inputs.satisfies(&with_gdbserver));
+        let new_init = RootfsInputs {
+            init: "new init".into(),
+            ..inputs.clone()
+        };
+        assert!(!inputs.satisfies(&new_init));
+    }
+}
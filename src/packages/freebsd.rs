@@ -0,0 +1,254 @@
+//! The FreeBSD base system for `*-unknown-freebsd` toolchains.
+//!
+//! Nothing is compiled: the headers and libraries of the sysroot are extracted from the release's
+//! official `base.txz`, then GCC is built against them. FreeBSD installs its libraries in `/lib`
+//! and `/usr/lib` with absolute symlinks between them (e.g. `/usr/lib/libc.so.7`), these are
+//! rewritten to point inside the sysroot.
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow};
+use walkdir::WalkDir;
+
+use crate::{
+    commands::{create_dir_all, is_plan, plan_step, run_command_in},
+    download::{DownloadResult, cache_dir, download_archive},
+    error::Failure,
+    packages::{BuildContext, Package, Source, install_package},
+    profile::{Arch, Libc, Os, Toolchain},
+    provenance,
+    sysroot::copy_tree,
+};
+
+/// The directories of `base.txz` that make up the sysroot.
+const SYSROOT_DIRS: &[&str] = &["./lib", "./usr/include", "./usr/lib"];
+
+/// Import the FreeBSD base system into the toolchain's sysroot.
+pub fn install_freebsd_base(toolchain: &Toolchain) -> Result<()> {
+    let Libc::FreeBsd(version) = toolchain.libc else {
+        return Err(anyhow!("{} isn't a FreeBSD toolchain", toolchain.id()));
+    };
+    // GCC bakes the major version of its target into the compiler
+    if let Os::FreeBsd(major) = toolchain.target.os
+        && major != version.0
+    {
+        return Err(Failure::Usage).context(format!(
+            "{} targets FreeBSD {major}, it can't use the FreeBSD {version} base system",
+            toolchain.target
+        ));
+    }
+    install_package(&FreeBsdBasePackage { toolchain, version }, 1)
+}
+
+/// The headers and libraries of a FreeBSD release, installed into the toolchain's sysroot.
+pub struct FreeBsdBasePackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub version: FreeBsdVersion,
+}
+
+impl FreeBsdBasePackage<'_> {
+    /// FreeBSD's `machine` and `machine_arch` of the target, e.g. `arm64/aarch64`.
+    fn machine(&self) -> Result<&'static str> {
+        Ok(match self.toolchain.target.arch {
+            Arch::X86_64 => "amd64/amd64",
+            Arch::Aarch64 => "arm64/aarch64",
+            Arch::Riscv64 => "riscv/riscv64",
+            arch => return Err(anyhow!("FreeBSD isn't supported on {arch}")),
+        })
+    }
+
+    fn dirname(&self) -> String {
+        format!(
+            "freebsd-base-{}-{}",
+            self.version, self.toolchain.target.arch
+        )
+    }
+}
+
+impl Package for FreeBsdBasePackage<'_> {
+    fn name(&self) -> String {
+        "freebsd base".into()
+    }
+
+    fn version(&self) -> String {
+        self.version.to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        let machine = self.machine().unwrap_or("amd64/amd64");
+        vec![Source::for_package(
+            "freebsd-base",
+            &self.version.to_string(),
+            format!(
+                "https://download.freebsd.org/releases/{machine}/{}-RELEASE/base.txz",
+                self.version
+            ),
+            self.dirname(),
+        )]
+    }
+
+    // base.txz is a whole system rooted at `./`, only the sysroot directories are extracted into
+    // their own directory
+    fn fetch(&self) -> Result<PathBuf> {
+        self.machine()?;
+        let source = self.sources().remove(0);
+        let dir = cache_dir()?.join(&source.dirname);
+        provenance::record_source(&source.url, &source.dirname);
+        if dir.is_dir() {
+            return Ok(dir);
+        }
+        if is_plan() {
+            plan_step(format!("download {}", source.url));
+            return Ok(dir);
+        }
+
+        let archive = match download_archive(&source.url, true)? {
+            DownloadResult::Cached(p)
+            | DownloadResult::Replaced(p)
+            | DownloadResult::Created(p) => p,
+        };
+        // an interrupted extraction never leaves a directory that looks complete
+        let partial = cache_dir()?.join(format!("{}.partial", source.dirname));
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)
+                .context(format!("failed to remove `{}`", partial.display()))?;
+        }
+        create_dir_all(&partial)?;
+        let mut args = vec!["-xf".to_string(), archive.display().to_string()];
+        args.extend(SYSROOT_DIRS.iter().map(|dir| dir.to_string()));
+        run_command_in(
+            &partial,
+            "extract base.txz",
+            "tar",
+            &args,
+            None::<Vec<(OsString, OsString)>>,
+        )?;
+        std::fs::rename(&partial, &dir).context(format!("failed to rename `{}`", dir.display()))?;
+        Ok(dir)
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        if is_plan() {
+            plan_step("copy the FreeBSD base system into the sysroot");
+            return Ok(());
+        }
+        let sysroot = self.toolchain.sysroot()?;
+        for dir in SYSROOT_DIRS {
+            let dir = dir.trim_start_matches("./");
+            let dest = sysroot.join(dir);
+            // `toolup install --force` copies over a previous import
+            if dest.exists() {
+                std::fs::remove_dir_all(&dest)
+                    .context(format!("failed to remove `{}`", dest.display()))?;
+            }
+            copy_tree(&ctx.source_dir.join(dir), &dest)?;
+        }
+        relativize_symlinks(&sysroot)
+    }
+}
+
+/// Rewrite the absolute symlinks under `root` to relative ones, so they resolve inside `root`
+/// instead of on the build machine.
+pub fn relativize_symlinks(root: &Path) -> Result<()> {
+    for entry in WalkDir::new(root) {
+        let entry = entry.context(format!("failed to walk `{}`", root.display()))?;
+        if !entry.path_is_symlink() {
+            continue;
+        }
+        let target = std::fs::read_link(entry.path())?;
+        let Ok(absolute) = target.strip_prefix("/") else {
+            continue;
+        };
+        // one `..` for every directory between `root` and the link
+        let depth = entry.depth().saturating_sub(1);
+        let relative: PathBuf = std::iter::repeat_n(Path::new(".."), depth)
+            .collect::<PathBuf>()
+            .join(absolute);
+        std::fs::remove_file(entry.path())?;
+        std::os::unix::fs::symlink(&relative, entry.path())
+            .context(format!("failed to link `{}`", entry.path().display()))?;
+    }
+    Ok(())
+}
+
+/// A FreeBSD release, e.g. `14.3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FreeBsdVersion(pub u64, pub u64);
+
+impl FreeBsdVersion {
+    /// The latest release of a major version.
+    pub fn latest(major: u64) -> Self {
+        match major {
+            13 => Self(13, 5),
+            14 => Self(14, 3),
+            major => Self(major, 0),
+        }
+    }
+}
+
+impl Default for FreeBsdVersion {
+    fn default() -> Self {
+        Self::latest(14)
+    }
+}
+
+impl FromStr for FreeBsdVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(".").collect();
+
+        fn parse_part(s: &str) -> anyhow::Result<u64> {
+            s.parse().context(format!("`{}` is not a number", s))
+        }
+
+        match parts.as_slice() {
+            [major, minor] => Ok(FreeBsdVersion(parse_part(major)?, parse_part(minor)?)),
+            _ => Err(anyhow!("`{}` is an invalid FreeBSD release", s)),
+        }
+    }
+}
+
+impl Display for FreeBsdVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
+
+serde_string!(FreeBsdVersion);
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use anyhow::Result;
+
+    use super::relativize_symlinks;
+
+    #[test]
+    fn test_relativize_symlinks() -> Result<()> {
+        let root = tempfile::TempDir::new()?;
+        std::fs::create_dir_all(root.path().join("lib"))?;
+        std::fs::create_dir_all(root.path().join("usr/lib"))?;
+        std::fs::write(root.path().join("lib/libc.so.7"), "")?;
+        std::os::unix::fs::symlink("/lib/libc.so.7", root.path().join("usr/lib/libc.so.7"))?;
+        std::os::unix::fs::symlink("libc.so.7", root.path().join("lib/libc.so"))?;
+
+        relativize_symlinks(root.path())?;
+        assert_eq!(
+            std::fs::read_link(root.path().join("usr/lib/libc.so.7"))?,
+            Path::new("../../lib/libc.so.7")
+        );
+        assert!(root.path().join("usr/lib/libc.so.7").exists());
+        // relative links are left alone
+        assert_eq!(
+            std::fs::read_link(root.path().join("lib/libc.so"))?,
+            Path::new("libc.so.7")
+        );
+        Ok(())
+    }
+}
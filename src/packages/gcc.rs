@@ -2,13 +2,20 @@ use std::{
     ffi::OsString,
     fmt::Display,
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process::Command,
     str::FromStr,
 };
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
 
-use crate::{commands::run_command_in, download::download_and_decompress, profile::Toolchain};
+use crate::{
+    commands::{is_plan, plan_step, run_command_in},
+    download::cache_dir,
+    packages::{BuildContext, Package, Source, install_package},
+    profile::{Arch, Libc, Profile, Toolchain},
+};
 
 pub struct Sysroot(pub PathBuf);
 impl Deref for Sysroot {
@@ -30,124 +37,258 @@ pub enum GccStage {
     Stage1,
     /// Build a full compiler using a bootstrap compiler from [`GccStage::Stage1`]
     Final(Option<Sysroot>),
+    /// Build a compiler for a freestanding target against newlib installed in the toolchain's
+    /// directory, with size-optimized target libraries for the nano profile
+    Newlib,
 }
 
 pub fn install_gcc(toolchain: &Toolchain, jobs: u64, stage: GccStage) -> Result<()> {
-    let gcc_name = format!("gcc-{}", toolchain.gcc.version);
-    let tarball = if toolchain.gcc.version <= GCCVersion(10, 1, 0) {
-        format!("{gcc_name}.tar.gz")
-    } else {
-        format!("{gcc_name}.tar.xz")
-    };
-
-    let gcc_dir = download_and_decompress(
-        format!("https://ftp.gnu.org/gnu/gcc/{gcc_name}/{tarball}"),
-        gcc_name,
-        true,
-    )
-    .context("failed to download gcc")?;
-
-    let jobs = jobs.to_string();
-    match stage {
-        GccStage::Stage1 => {
-            log::info!("=> stage1 gcc");
-            let objdir = gcc_dir.join(format!("objdir-stage1-{}", toolchain.id()));
-            std::fs::create_dir_all(&objdir).context("failed to create an objdir for the arch")?;
-
-            let env: Vec<(OsString, OsString)> = vec![("PATH".into(), toolchain.env_path()?)];
-
-            run_command_in(
-                &objdir,
-                "configure",
-                objdir.parent().unwrap().join("configure"),
-                &[
-                    format!("--target={}", toolchain.target).as_str(),
-                    format!("--prefix={}", toolchain.dir()?.display()).as_str(),
-                    "--disable-nls",
-                    "--enable-languages=c,c++".into(),
-                    "--without-headers".into(),
-                    "--disable-threads".into(),
-                    "--disable-shared".into(),
-                    "--disable-libssp".into(),
-                    "--disable-libgomp".into(),
-                    "--disable-libquadmath".into(),
-                    "--disable-multilib".into(),
-                ],
-                Some(env.clone()),
-            )?;
-            run_command_in(
-                &objdir,
-                "make",
-                "make",
-                &["all-gcc", "-j", jobs.as_str()],
-                Some(env.clone()),
-            )?;
-            run_command_in(
-                &objdir,
-                "make",
-                "make",
-                &["install-gcc", "-j", jobs.as_str()],
-                Some(env.clone()),
-            )?;
-            run_command_in(
-                &objdir,
-                "make",
-                "make",
-                &["all-target-libgcc", "-j", jobs.as_str()],
-                Some(env.clone()),
-            )?;
-            run_command_in(
-                &objdir,
-                "make",
-                "make",
-                &["install-target-libgcc", "-j", jobs.as_str()],
-                Some(env.clone()),
-            )?;
+    install_package(&GccPackage { toolchain, stage }, jobs)
+}
+
+/// A GCC compiler stage installed into the toolchain's directory.
+pub struct GccPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub stage: GccStage,
+}
+
+impl GccPackage<'_> {
+    fn env(&self) -> Result<Vec<(OsString, OsString)>> {
+        let mut env = vec![("PATH".into(), self.toolchain.env_path()?)];
+        // GCC 5 and older are C++98 code, newer host compilers default to C++17 (no `register`)
+        if self.toolchain.gcc.version < GCCVersion(6, 0, 0) {
+            env.push(("CXXFLAGS".into(), "-O2 -std=gnu++98".into()));
         }
-        GccStage::Final(maybe_sysroot) => {
-            log::info!("=> final stage gcc");
+        Ok(env)
+    }
 
-            let objdir = gcc_dir.join(format!("objdir-final-{}", toolchain.id()));
-            std::fs::create_dir_all(&objdir).context("failed to create an objdir for the arch")?;
+    fn make(&self, ctx: &BuildContext, target: &str) -> Result<()> {
+        let jobs = ctx.jobs.to_string();
+        let mut args = vec!["-j", jobs.as_str()];
+        if !target.is_empty() {
+            args.insert(0, target);
+        }
+        run_command_in(&ctx.objdir, "make", "make", &args, Some(self.env()?))
+    }
+}
 
-            let env: Vec<(OsString, OsString)> = vec![("PATH".into(), toolchain.env_path()?)];
+impl Package for GccPackage<'_> {
+    fn name(&self) -> String {
+        match self.stage {
+            GccStage::Stage1 => "stage1 gcc".into(),
+            GccStage::Final(_) => "final stage gcc".into(),
+            GccStage::Newlib => "newlib gcc".into(),
+        }
+    }
 
-            let mut args: Vec<String> = vec![
-                format!("--target={}", toolchain.target),
-                format!("--prefix={}", toolchain.dir()?.display()),
-                "--disable-nls".into(),
-                "--enable-languages=c,c++".into(),
+    fn version(&self) -> String {
+        self.toolchain.gcc.version.to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        let gcc_name = format!("gcc-{}", self.toolchain.gcc.version);
+        let tarball = if self.toolchain.gcc.version <= GCCVersion(10, 1, 0) {
+            format!("{gcc_name}.tar.gz")
+        } else {
+            format!("{gcc_name}.tar.xz")
+        };
+
+        vec![Source::for_package(
+            "gcc",
+            &self.toolchain.gcc.version.to_string(),
+            format!("https://ftp.gnu.org/gnu/gcc/{gcc_name}/{tarball}"),
+            gcc_name,
+        )]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        let stage = match self.stage {
+            GccStage::Stage1 => "stage1",
+            GccStage::Final(_) => "final",
+            GccStage::Newlib => "newlib",
+        };
+        Ok(source_dir.join(format!("objdir-{stage}-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        let mut args: Vec<String> = vec![
+            format!("--target={}", self.toolchain.target),
+            format!("--prefix={}", self.toolchain.dir()?.display()),
+            "--disable-nls".into(),
+            "--enable-languages=c,c++".into(),
+        ];
+        // make the baseline explicit so nothing newer than the target cpu is emitted by default,
+        // x32 needs nothing extra since `gnux32` targets default to `-mx32`
+        match self.toolchain.target.arch {
+            Arch::I486 => args.push("--with-arch=i486".into()),
+            Arch::I586 => args.push("--with-arch=pentium".into()),
+            _ => {}
+        }
+        match &self.stage {
+            // mingw-w64's headers are installed before stage1, GCC needs them to build for
+            // Windows even without a CRT
+            GccStage::Stage1 if self.toolchain.target.is_windows() => args.extend([
+                format!("--with-sysroot={}", self.toolchain.sysroot()?.display()),
+                "--disable-shared".into(),
                 "--disable-multilib".into(),
-            ];
-            if let Some(sysroot) = maybe_sysroot {
-                args.push(format!("--with-sysroot={}", sysroot.display()));
+            ]),
+            GccStage::Stage1 => args.extend(
+                [
+                    "--without-headers",
+                    "--disable-threads",
+                    "--disable-shared",
+                    "--disable-libssp",
+                    "--disable-libgomp",
+                    "--disable-libquadmath",
+                    "--disable-multilib",
+                ]
+                .map(String::from),
+            ),
+            GccStage::Newlib => {
+                args.extend(
+                    [
+                        "--with-newlib",
+                        "--disable-shared",
+                        "--disable-threads",
+                        "--disable-libssp",
+                        "--disable-multilib",
+                    ]
+                    .map(String::from),
+                );
+                if self.toolchain.profile == Profile::Nano {
+                    // build libgcc, newlib glue and libstdc++ with -Os
+                    args.push("--enable-target-optspace".into());
+                }
             }
+            GccStage::Final(maybe_sysroot) => {
+                args.push("--disable-multilib".into());
+                args.push("--enable-plugin".into());
+                args.push("--enable-lto".into());
+                if let Some(sysroot) = maybe_sysroot {
+                    args.push(format!("--with-sysroot={}", sysroot.display()));
+                }
+                if let Libc::Bionic(api) = self.toolchain.libc {
+                    // the NDK's headers declare everything up to `__ANDROID_API__`, which clang
+                    // defines and GCC doesn't
+                    args.push(format!(
+                        "--with-specs=%{{!D__ANDROID_API__*:-D__ANDROID_API__={api}}}"
+                    ));
+                    // these need glibc interfaces bionic doesn't have
+                    args.extend(
+                        [
+                            "--disable-libsanitizer",
+                            "--disable-libgomp",
+                            "--disable-libitm",
+                        ]
+                        .map(String::from),
+                    );
+                }
+            }
+        }
 
-            run_command_in(
-                &objdir,
-                "configure",
-                objdir.parent().unwrap().join("configure"),
-                &args,
-                Some(env.clone()),
-            )?;
+        run_command_in(
+            &ctx.objdir,
+            "configure",
+            ctx.source_dir.join("configure"),
+            &args,
+            Some(self.env()?),
+        )
+    }
 
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        match self.stage {
+            GccStage::Stage1 => self.make(ctx, "all-gcc"),
             // hosted/newlib: build everything (gcc, libgcc, libstdc++)
-            run_command_in(
-                &objdir,
-                "make",
-                "make",
-                &["-j", jobs.as_str()],
-                Some(env.clone()),
-            )?;
-            run_command_in(
-                &objdir,
-                "make",
-                "make",
-                &["install", "-j", jobs.as_str()],
-                Some(env.clone()),
-            )?;
+            GccStage::Final(_) | GccStage::Newlib => self.make(ctx, ""),
+        }
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        match self.stage {
+            // libgcc for Windows links against the CRT, the final compiler builds it
+            GccStage::Stage1 if self.toolchain.target.is_windows() => self.make(ctx, "install-gcc"),
+            GccStage::Stage1 => {
+                self.make(ctx, "install-gcc")?;
+                self.make(ctx, "all-target-libgcc")?;
+                self.make(ctx, "install-target-libgcc")
+            }
+            GccStage::Final(_) => {
+                self.make(ctx, "install")?;
+                install_lto_plugin(self.toolchain)?;
+                check_lto(self.toolchain)
+            }
+            GccStage::Newlib => self.make(ctx, "install"),
         }
     }
+}
+
+/// Copy GCC's LTO plugin to `lib/bfd-plugins`, where binutils (`ar`, `nm`, `ranlib`) load it
+/// from automatically.
+fn install_lto_plugin(toolchain: &Toolchain) -> Result<()> {
+    if is_plan() {
+        plan_step("copy liblto_plugin.so into lib/bfd-plugins");
+        return Ok(());
+    }
+
+    let libexec = toolchain
+        .dir()?
+        .join("libexec")
+        .join("gcc")
+        .join(toolchain.target.to_target_string())
+        .join(toolchain.gcc.version.to_string());
+    let plugin = libexec.join("liblto_plugin.so");
+    if !plugin.exists() {
+        bail!(
+            "gcc was built without the LTO plugin, `{}` doesn't exist",
+            plugin.display()
+        );
+    }
+
+    let bfd_plugins = toolchain.dir()?.join("lib").join("bfd-plugins");
+    std::fs::create_dir_all(&bfd_plugins)?;
+    std::fs::copy(&plugin, bfd_plugins.join("liblto_plugin.so"))
+        .context("failed to copy the LTO plugin")?;
+    Ok(())
+}
+
+/// Compile and link a small program with `-flto` through the linker plugin, to catch a broken
+/// LTO setup at install time instead of in users' builds.
+pub fn check_lto(toolchain: &Toolchain) -> Result<()> {
+    if is_plan() {
+        plan_step("compile and link a program with -flto -fuse-linker-plugin");
+        return Ok(());
+    }
+    log::info!("=> check lto");
+
+    let workdir = cache_dir()?.join("smoke").join(toolchain.id());
+    std::fs::create_dir_all(&workdir)?;
+    std::fs::write(
+        workdir.join("a.c"),
+        "int f(int x);\nint main(void) { return f(0); }\n",
+    )?;
+    std::fs::write(workdir.join("b.c"), "int f(int x) { return x; }\n")?;
+
+    let output = Command::new(toolchain.gcc_bin()?)
+        .args([
+            "-O2",
+            "-flto",
+            "-fuse-linker-plugin",
+            "a.c",
+            "b.c",
+            "-o",
+            "lto",
+        ])
+        .current_dir(&workdir)
+        .env("PATH", toolchain.env_path()?)
+        .output()
+        .context("failed to run gcc")?;
+    if !output.status.success() {
+        bail!(
+            "LTO smoke test failed, {} can't link with -flto:\n{}",
+            toolchain.id(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
     Ok(())
 }
 
@@ -181,7 +322,9 @@ impl Display for GCCVersion {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+serde_string!(GCCVersion);
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
 pub struct GCC {
     pub version: GCCVersion,
 }
@@ -0,0 +1,239 @@
+//! A cross GDB for the toolchain's target, installed next to gcc and binutils as
+//! `<target>-gdb`, and a `gdbserver` built with the toolchain for the rootfs of `toolup linux`.
+//!
+//! GDB links against the host's GMP and MPFR (and Python with [`Gdb::python`]), their development
+//! packages have to be installed.
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::{_run_configure_in, run_command_in, run_configure_in, run_make_in},
+    packages::{BuildContext, Package, Source, install_package},
+    profile::Toolchain,
+};
+
+/// Download and build the toolchain's GDB, if it has one.
+pub fn install_gdb(toolchain: &Toolchain, jobs: u64) -> Result<()> {
+    let Some(gdb) = &toolchain.gdb else {
+        return Ok(());
+    };
+    install_package(&GdbPackage { toolchain, gdb }, jobs)
+}
+
+/// Cross GDB installed into the toolchain's directory.
+pub struct GdbPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub gdb: &'a Gdb,
+}
+
+impl Package for GdbPackage<'_> {
+    fn name(&self) -> String {
+        "gdb".into()
+    }
+
+    fn version(&self) -> String {
+        self.gdb.version.to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        let version = self.gdb.version;
+        vec![Source::for_package(
+            "gdb",
+            &version.to_string(),
+            format!("https://ftp.gnu.org/gnu/gdb/gdb-{version}.tar.xz"),
+            format!("gdb-{version}"),
+        )]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        let python = if self.gdb.python {
+            "--with-python=python3"
+        } else {
+            "--without-python"
+        };
+        run_configure_in(
+            &ctx.objdir,
+            &[
+                python,
+                "--target",
+                self.toolchain.target.to_target_string().as_str(),
+                "--prefix",
+                self.toolchain
+                    .dir()?
+                    .to_str()
+                    .expect("toolchain dir is a valid UTF8 string"),
+                "--disable-nls",
+                "--disable-werror",
+                // binutils are built and installed on their own
+                "--disable-binutils",
+                "--disable-ld",
+                "--disable-gas",
+                "--disable-gprof",
+                "--disable-sim",
+                // the sources come with a bundled readline, the host may not have one
+                "--with-system-readline=no",
+            ],
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_make_in(
+            &ctx.objdir,
+            &["all-gdb", "-j", ctx.jobs.to_string().as_str()],
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_make_in(&ctx.objdir, &["install-gdb"])
+    }
+}
+
+/// Where [`GdbserverPackage`] installs `gdbserver` in the rootfs.
+pub const GDBSERVER: &str = "usr/bin/gdbserver";
+
+/// `gdbserver` cross-compiled with the toolchain and installed into a rootfs directory.
+pub struct GdbserverPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub rootfs_dir: PathBuf,
+}
+
+impl GdbserverPackage<'_> {
+    fn gdb_version(&self) -> GdbVersion {
+        self.toolchain
+            .gdb
+            .as_ref()
+            .map(|gdb| gdb.version)
+            .unwrap_or_default()
+    }
+
+    fn make(&self, ctx: &BuildContext, args: &[String]) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            args,
+            Some(vec![(OsString::from("PATH"), self.toolchain.env_path()?)]),
+        )
+    }
+}
+
+impl Package for GdbserverPackage<'_> {
+    fn name(&self) -> String {
+        "gdbserver".into()
+    }
+
+    fn version(&self) -> String {
+        self.gdb_version().to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        GdbPackage {
+            toolchain: self.toolchain,
+            gdb: &Gdb {
+                version: self.gdb_version(),
+                python: false,
+            },
+        }
+        .sources()
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-gdbserver-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        let path = self.toolchain.env_path()?.to_string_lossy().into_owned();
+        _run_configure_in(
+            &ctx.objdir,
+            &[
+                "--host",
+                self.toolchain.target.to_target_string().as_str(),
+                "--prefix",
+                "/usr",
+                "--disable-nls",
+                "--disable-werror",
+                // only gdbserver and the libraries it needs
+                "--disable-gdb",
+                "--disable-binutils",
+                "--disable-ld",
+                "--disable-gas",
+                "--disable-gold",
+                "--disable-gprof",
+                "--disable-gprofng",
+                "--disable-sim",
+            ],
+            Some(vec![
+                ("PATH".into(), path),
+                // libstdc++ is in the toolchain directory, not in the sysroot copied to the rootfs
+                ("LDFLAGS".into(), "-static-libstdc++ -static-libgcc".into()),
+            ]),
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        self.make(ctx, &["all-gdbserver".into(), format!("-j{}", ctx.jobs)])
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        self.make(
+            ctx,
+            &[
+                format!("DESTDIR={}", self.rootfs_dir.display()),
+                "install-strip-gdbserver".into(),
+            ],
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GdbVersion(pub u64, pub u64);
+
+impl Default for GdbVersion {
+    fn default() -> Self {
+        Self(16, 3)
+    }
+}
+
+impl FromStr for GdbVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(".").collect();
+
+        fn parse_part(s: &str) -> anyhow::Result<u64> {
+            s.parse().context(format!("`{}` is not a number", s))
+        }
+
+        match parts.as_slice() {
+            [major, minor] => Ok(GdbVersion(parse_part(major)?, parse_part(minor)?)),
+            _ => Err(anyhow!("`{}` is an invalid gdb version", s)),
+        }
+    }
+}
+
+impl Display for GdbVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
+
+serde_string!(GdbVersion);
+
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+pub struct Gdb {
+    pub version: GdbVersion,
+    /// Enable Python scripting
+    #[serde(default)]
+    pub python: bool,
+}
@@ -1,70 +1,141 @@
-use std::{ffi::OsString, fmt::Display, path::PathBuf, process::Command, str::FromStr};
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
 
 use anyhow::{Context, Result, anyhow};
 
 use crate::{
-    commands::run_command_in,
-    download::download_and_decompress,
-    packages::gnu_make::install_make,
+    commands::{is_plan, run_command_in},
+    packages::{
+        BuildContext, Package, Source, fetch_source,
+        host_tools::{HostTool, missing_tools},
+        install_package,
+    },
     profile::{Libc, Toolchain},
 };
 
 pub fn download_glibc(version: impl AsRef<str>) -> Result<PathBuf> {
     log::info!("=> download glibc");
-    let version = version.as_ref();
-    let tarball = format!("glibc-{version}.tar.xz");
-    let url = format!(
-        "https://ftp.gnu.org/gnu/glibc/{tarball}",
-        tarball = &tarball
-    );
-
-    let glibc_dir = download_and_decompress(&url, format!("glibc-{version}"), true)
-        .context(format!("failed to download {tarball}"))?;
-
-    Ok(glibc_dir)
+    fetch_source(&glibc_source(version.as_ref()))
 }
 
-/// Build glibc and install it in the toolchain's sysroot.
-pub fn install_glibc_sysroot(toolchain: &Toolchain) -> Result<()> {
-    log::info!("=> install glibc");
+fn glibc_source(version: &str) -> Source {
+    Source::for_package(
+        "glibc",
+        version,
+        format!("https://ftp.gnu.org/gnu/glibc/glibc-{version}.tar.xz"),
+        format!("glibc-{version}"),
+    )
+}
 
-    let Libc::Glibc(glibc_version) = toolchain.libc else {
+/// Build glibc with `jobs` threads and install it in the toolchain's sysroot.
+pub fn install_glibc_sysroot(toolchain: &Toolchain, jobs: u64) -> Result<()> {
+    let Libc::Glibc(version) = toolchain.libc else {
         return Err(anyhow!(
             "`install_glibc_sysroot` called with a musl toolchain"
         ));
     };
 
-    // workaround: we need an old Make version to compile this glibc version.
-    // see: https://stackoverflow.com/a/77107152/8701101
-    if glibc_version <= GlibcVersion(2, 30, 0) {
-        install_make("4.3", toolchain)?;
+    install_package(&GlibcPackage { toolchain, version }, jobs)
+}
+
+/// glibc installed into the toolchain's sysroot.
+pub struct GlibcPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub version: GlibcVersion,
+}
+
+impl Package for GlibcPackage<'_> {
+    fn name(&self) -> String {
+        "glibc".into()
     }
 
-    let glibc_dir = download_glibc(glibc_version.to_string())?;
-    let objdir = glibc_dir.join(format!("objdir-arch-{}", toolchain.id()));
-    std::fs::create_dir_all(&objdir)?;
-
-    // Get the target triple for the host.
-    // TODO: write a function for this instead of relying on config.guess.
-    let stdout = Command::new(glibc_dir.join("scripts").join("config.guess"))
-        .output()?
-        .stdout;
-    let guess = String::from_utf8(stdout)?;
-
-    let args = vec![
-        format!("--host={}", toolchain.target),
-        format!("--build={}", guess.trim()),
-        "--prefix=/usr".into(),
-        format!(
-            "--with-headers={}/usr/include",
-            toolchain.sysroot()?.display()
-        ),
-        format!("--with-sysroot={}", toolchain.sysroot()?.display()),
-        "--disable-werror".into(),
-    ];
-    let prefix = toolchain.target;
+    fn version(&self) -> String {
+        self.version.to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        vec![glibc_source(&self.version.to_string())]
+    }
+
+    fn dependencies(&self) -> Result<Vec<Box<dyn Package + '_>>> {
+        // old glibc versions also need an old Make, see `gnu_make::pin_make`
+        Ok(missing_tools(&[HostTool::Bison, HostTool::Gawk]))
+    }
 
-    let env: Vec<(OsString, OsString)> = vec![
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-arch-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        let toolchain = self.toolchain;
+
+        // Get the target triple for the host.
+        // TODO: write a function for this instead of relying on config.guess.
+        let guess = if is_plan() {
+            format!("$({}/scripts/config.guess)", ctx.source_dir.display())
+        } else {
+            let stdout = Command::new(ctx.source_dir.join("scripts").join("config.guess"))
+                .output()?
+                .stdout;
+            String::from_utf8(stdout)?
+        };
+
+        let args = vec![
+            format!("--host={}", toolchain.target),
+            format!("--build={}", guess.trim()),
+            "--prefix=/usr".into(),
+            format!(
+                "--with-headers={}/usr/include",
+                toolchain.sysroot()?.display()
+            ),
+            format!("--with-sysroot={}", toolchain.sysroot()?.display()),
+            "--disable-werror".into(),
+        ];
+
+        run_command_in(
+            &ctx.objdir,
+            "configure",
+            ctx.source_dir.join("configure"),
+            &args,
+            Some(cross_env(toolchain)?),
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &["-j", ctx.jobs.to_string().as_str()],
+            Some(cross_env(self.toolchain)?),
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &[
+                "install",
+                &format!("DESTDIR={}", self.toolchain.sysroot()?.display()),
+                "-j",
+                ctx.jobs.to_string().as_str(),
+            ],
+            Some(cross_env(self.toolchain)?),
+        )
+    }
+}
+
+/// The environment to cross compile a libc with the toolchain's stage1 compiler.
+pub fn cross_env(toolchain: &Toolchain) -> Result<Vec<(OsString, OsString)>> {
+    let prefix = toolchain.target;
+    Ok(vec![
         ("BUILD_CC".into(), "gcc".into()),
         ("BUILD_CXX".into(), "g++".into()),
         ("BUILD_AR".into(), "ar".into()),
@@ -76,30 +147,7 @@ pub fn install_glibc_sysroot(toolchain: &Toolchain) -> Result<()> {
         ("LD".into(), format!("{prefix}-ld").into()),
         ("READELF".into(), format!("{prefix}-readelf").into()),
         ("PATH".into(), toolchain.env_path()?),
-    ];
-    run_command_in(
-        &objdir,
-        "configure",
-        objdir.parent().unwrap().join("configure"),
-        &args,
-        Some(env.clone()),
-    )?;
-
-    run_command_in(&objdir, "make", "make", &["-j", "28"], Some(env.clone()))?;
-    run_command_in(
-        &objdir,
-        "make",
-        "make",
-        &[
-            "install",
-            &format!("DESTDIR={}", toolchain.sysroot()?.display()),
-            "-j",
-            "28",
-        ],
-        Some(env.clone()),
-    )?;
-
-    Ok(())
+    ])
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -143,3 +191,5 @@ impl Display for GlibcVersion {
         }
     }
 }
+
+serde_string!(GlibcVersion);
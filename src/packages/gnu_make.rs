@@ -1,51 +1,209 @@
-use std::{ffi::OsString, path::PathBuf};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Result};
 
-use crate::{commands::run_command_in, download::download_and_decompress, profile::Toolchain};
+use crate::{
+    commands::{is_plan, run_command_in, set_make_dir},
+    download::host_tools_dir,
+    packages::{
+        BuildContext, Package, Source, fetch_source, gcc::GCCVersion, glibc::GlibcVersion,
+        install_package, linux::KernelVersion,
+    },
+    profile::{Libc, Toolchain},
+};
+
+/// Builds that are known to break with newer versions of GNU Make.
+struct MakeRule {
+    reason: &'static str,
+    applies: fn(&Toolchain) -> bool,
+    version: &'static str,
+}
+
+const MAKE_RULES: &[MakeRule] = &[
+    // see: https://stackoverflow.com/a/77107152/8701101
+    MakeRule {
+        reason: "glibc <= 2.30 doesn't build with make 4.4+",
+        applies: |toolchain| matches!(toolchain.libc, Libc::Glibc(v) if v <= GlibcVersion(2, 30, 0)),
+        version: "4.3",
+    },
+    MakeRule {
+        reason: "gcc 7.x and older don't build with make 4.4+",
+        applies: |toolchain| toolchain.gcc.version < GCCVersion(8, 0, 0),
+        version: "4.3",
+    },
+    MakeRule {
+        reason: "linux 5.1 and older don't build with make 4.4+",
+        applies: |toolchain| {
+            toolchain
+                .kernel
+                .is_some_and(|v| v <= KernelVersion::new(5, 1, 0))
+        },
+        version: "4.3",
+    },
+];
 
 pub fn download_make(version: impl AsRef<str>) -> Result<PathBuf> {
     log::info!("=> download make {}", version.as_ref());
 
-    let version = version.as_ref();
-    let tarball = format!("make-{version}.tar.gz");
-    let url = format!("https://ftp.gnu.org/gnu/make/{tarball}", tarball = &tarball);
+    fetch_source(&make_source(version.as_ref()))
+}
 
-    let make_dir = download_and_decompress(&url, format!("make-{version}"), true)
-        .context(format!("failed to download {tarball}"))?;
+fn make_source(version: &str) -> Source {
+    Source::for_package(
+        "make",
+        version,
+        format!("https://ftp.gnu.org/gnu/make/make-{version}.tar.gz"),
+        format!("make-{version}"),
+    )
+}
 
-    Ok(make_dir)
+/// The prefix a GNU Make version is installed into, shared by every toolchain.
+pub fn make_prefix(version: impl AsRef<str>) -> Result<PathBuf> {
+    Ok(host_tools_dir()?.join(format!("make-{}", version.as_ref())))
 }
 
-/// Build GNU Make for `toolchain`.
-pub fn install_make(version: impl AsRef<str>, toolchain: &Toolchain) -> Result<()> {
-    log::info!("=> install make {}", version.as_ref());
+/// The file next to `bin` holding the blake3 hash of the installed `make`.
+const CHECKSUM_FILE: &str = "make.blake3";
 
-    let workdir = download_make(version)?;
+/// Whether `prefix` has a complete install of make: `make` exists and matches the hash recorded
+/// after `make install` finished. An interrupted or corrupted install is rebuilt.
+pub fn is_installed(prefix: &Path) -> Result<bool> {
+    let (Ok(expected), Ok(binary)) = (
+        std::fs::read_to_string(prefix.join(CHECKSUM_FILE)),
+        std::fs::read(prefix.join("bin").join("make")),
+    ) else {
+        return Ok(false);
+    };
+    Ok(blake3::hash(&binary).to_hex().as_str() == expected.trim())
+}
 
-    run_command_in(
-        &workdir,
-        "configure",
-        "./configure",
-        &[format!("--prefix={}", toolchain.dir()?.display())],
-        None::<Vec<(OsString, OsString)>>,
-    )?;
+/// Record the hash of the `make` installed into `prefix`, see [`is_installed`].
+fn record_checksum(prefix: &Path) -> Result<()> {
+    let binary = prefix.join("bin").join("make");
+    let hash = blake3::hash(
+        &std::fs::read(&binary).context(format!("failed to read `{}`", binary.display()))?,
+    );
+    std::fs::write(prefix.join(CHECKSUM_FILE), hash.to_hex().as_str()).context(format!(
+        "failed to write the checksum of `{}`",
+        binary.display()
+    ))
+}
 
-    // we can compile Make using the hosts' Make.
-    run_command_in(
-        &workdir,
-        "make",
-        "make",
-        &["-j10"],
-        None::<Vec<(OsString, OsString)>>,
-    )?;
-    run_command_in(
-        &workdir,
-        "make",
-        "make",
-        &["install"],
-        None::<Vec<(OsString, OsString)>>,
-    )?;
+/// Returns the GNU Make version `toolchain` has to be built with, and why.
+pub fn required_make(toolchain: &Toolchain) -> Option<(&'static str, &'static str)> {
+    MAKE_RULES
+        .iter()
+        .find(|rule| (rule.applies)(toolchain))
+        .map(|rule| (rule.version, rule.reason))
+}
+
+/// Use the GNU Make version required by `toolchain` for the following build commands, building
+/// it if needed. The host's make is used if `toolchain` works with it.
+pub fn pin_make(toolchain: &Toolchain, jobs: u64) -> Result<()> {
+    let Some((version, reason)) = required_make(toolchain) else {
+        set_make_dir(None);
+        return Ok(());
+    };
 
+    log::info!("=> using make {version}: {reason}");
+    set_make_dir(Some(install_make(version, jobs)?));
     Ok(())
 }
+
+/// Build GNU Make into its shared prefix unless it's already installed, with `jobs` threads.
+/// Returns the `bin` directory containing `make`.
+pub fn install_make(version: impl AsRef<str>, jobs: u64) -> Result<PathBuf> {
+    let prefix = make_prefix(&version)?;
+    if !is_installed(&prefix)? || is_plan() {
+        install_package(
+            &MakePackage {
+                version: version.as_ref().into(),
+            },
+            jobs,
+        )?;
+    }
+    Ok(prefix.join("bin"))
+}
+
+/// A GNU Make version installed into [`make_prefix`].
+pub struct MakePackage {
+    pub version: String,
+}
+
+impl Package for MakePackage {
+    fn name(&self) -> String {
+        "make".into()
+    }
+
+    fn version(&self) -> String {
+        self.version.clone()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        vec![make_source(&self.version)]
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "configure",
+            "./configure",
+            &[format!(
+                "--prefix={}",
+                make_prefix(&self.version)?.display()
+            )],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+
+    // we can compile Make using the hosts' Make.
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &[format!("-j{}", ctx.jobs)],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &["install"],
+            None::<Vec<(OsString, OsString)>>,
+        )?;
+        if is_plan() {
+            return Ok(());
+        }
+        record_checksum(&make_prefix(&self.version)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use super::{is_installed, record_checksum};
+
+    #[test]
+    fn test_is_installed() -> Result<()> {
+        let prefix = tempfile::TempDir::new()?;
+        std::fs::create_dir_all(prefix.path().join("bin"))?;
+        std::fs::write(prefix.path().join("bin/make"), "make 4.3")?;
+        // `make install` didn't finish
+        assert!(!is_installed(prefix.path())?);
+
+        record_checksum(prefix.path())?;
+        assert!(is_installed(prefix.path())?);
+
+        std::fs::write(prefix.path().join("bin/make"), "truncat")?;
+        assert!(!is_installed(prefix.path())?);
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+//! Host programs needed by some builds that are commonly missing on CI images.
+//!
+//! Missing tools are built into their own toolup-private prefix, `<name>-<version>` under
+//! [`host_tools_dir`], whose `bin` directories are layered in front of `PATH` for every build
+//! command, see [`layered_path`].
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{
+    commands::run_command_in,
+    download::host_tools_dir,
+    packages::{BuildContext, Package, Source, install_package},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostTool {
+    M4,
+    Bison,
+    Flex,
+    Bc,
+    Gawk,
+}
+
+impl HostTool {
+    /// The program looked up in `PATH` to decide whether the tool is installed.
+    pub fn program(&self) -> &'static str {
+        match self {
+            HostTool::M4 => "m4",
+            HostTool::Bison => "bison",
+            HostTool::Flex => "flex",
+            HostTool::Bc => "bc",
+            HostTool::Gawk => "gawk",
+        }
+    }
+
+    pub fn version(&self) -> &'static str {
+        match self {
+            HostTool::M4 => "1.4.19",
+            HostTool::Bison => "3.8.2",
+            HostTool::Flex => "2.6.4",
+            HostTool::Bc => "1.07.1",
+            HostTool::Gawk => "5.3.1",
+        }
+    }
+
+    fn url(&self) -> String {
+        let version = self.version();
+        match self {
+            HostTool::M4 => format!("https://ftp.gnu.org/gnu/m4/m4-{version}.tar.xz"),
+            HostTool::Bison => format!("https://ftp.gnu.org/gnu/bison/bison-{version}.tar.xz"),
+            HostTool::Flex => format!(
+                "https://github.com/westes/flex/releases/download/v{version}/flex-{version}.tar.gz"
+            ),
+            HostTool::Bc => format!("https://ftp.gnu.org/gnu/bc/bc-{version}.tar.gz"),
+            HostTool::Gawk => format!("https://ftp.gnu.org/gnu/gawk/gawk-{version}.tar.xz"),
+        }
+    }
+
+    /// The prefix the tool is installed into, e.g. `~/.toolup/host-tools/bison-3.8.2`.
+    pub fn prefix(&self) -> Result<PathBuf> {
+        Ok(host_tools_dir()?.join(format!("{}-{}", self.program(), self.version())))
+    }
+
+    /// Whether the tool can be found in `PATH` or the host tools prefix.
+    pub fn is_available(&self) -> bool {
+        find_program(self.program()).is_some()
+    }
+}
+
+const HOST_TOOLS: [HostTool; 5] = [
+    HostTool::M4,
+    HostTool::Bison,
+    HostTool::Flex,
+    HostTool::Bc,
+    HostTool::Gawk,
+];
+
+impl Display for HostTool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.program())
+    }
+}
+
+/// The `bin` directories of the installed host tools.
+fn host_bins() -> Result<Vec<PathBuf>> {
+    let mut bins = vec![];
+    for tool in HOST_TOOLS {
+        let bin = tool.prefix()?.join("bin");
+        if bin.is_dir() {
+            bins.push(bin);
+        }
+    }
+    Ok(bins)
+}
+
+/// Returns the path of `program` in the host tools prefixes or `PATH`.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+
+    host_bins()
+        .ok()?
+        .into_iter()
+        .chain(std::env::split_paths(&path))
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// The `PATH` of child commands, looked up first to last: the host tools built by toolup,
+/// `bin_dirs` (e.g. a toolchain's `bin`), then `base`, usually the system `PATH`. Directories are
+/// only kept at their first position, so layering an already layered `PATH` doesn't change it.
+pub fn layered_path(bin_dirs: &[PathBuf], base: &OsStr) -> Result<OsString> {
+    let mut paths: Vec<PathBuf> = vec![];
+    for dir in host_bins()?
+        .into_iter()
+        .chain(bin_dirs.iter().cloned())
+        .chain(std::env::split_paths(base))
+    {
+        if !paths.contains(&dir) {
+            paths.push(dir);
+        }
+    }
+    Ok(std::env::join_paths(paths)?)
+}
+
+/// Returns the packages for the `tools` that are missing on the host.
+pub fn missing_tools(tools: &[HostTool]) -> Vec<Box<dyn Package>> {
+    tools
+        .iter()
+        .filter(|tool| !tool.is_available())
+        .map(|&tool| Box::new(HostToolPackage { tool }) as Box<dyn Package>)
+        .collect()
+}
+
+/// Build and install the `tools` that are missing on the host.
+pub fn ensure_host_tools(tools: &[HostTool], jobs: u64) -> Result<()> {
+    for package in missing_tools(tools) {
+        install_package(package.as_ref(), jobs)?;
+    }
+    Ok(())
+}
+
+/// A host tool installed into [`host_tools_dir`].
+pub struct HostToolPackage {
+    pub tool: HostTool,
+}
+
+impl Package for HostToolPackage {
+    fn name(&self) -> String {
+        self.tool.program().into()
+    }
+
+    fn version(&self) -> String {
+        self.tool.version().into()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        vec![Source::for_package(
+            self.tool.program(),
+            self.tool.version(),
+            self.tool.url(),
+            format!("{}-{}", self.tool.program(), self.tool.version()),
+        )]
+    }
+
+    fn dependencies(&self) -> Result<Vec<Box<dyn Package + '_>>> {
+        // bison and flex run m4 at build time and at runtime
+        match self.tool {
+            HostTool::Bison | HostTool::Flex => Ok(missing_tools(&[HostTool::M4])),
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join("objdir-host"))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "configure",
+            ctx.source_dir.join("configure"),
+            &[format!("--prefix={}", self.tool.prefix()?.display())],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &[format!("-j{}", ctx.jobs)],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &["install"],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use anyhow::Result;
+
+    use super::layered_path;
+
+    #[test]
+    fn test_layered_path() -> Result<()> {
+        let path = layered_path(
+            &[PathBuf::from("/toolchain/bin")],
+            "/usr/bin:/toolchain/bin:/bin".as_ref(),
+        )?;
+        // host tools installed on the machine come first
+        let paths: Vec<PathBuf> = std::env::split_paths(&path).collect();
+        assert!(paths.ends_with(&[
+            PathBuf::from("/toolchain/bin"),
+            PathBuf::from("/usr/bin"),
+            PathBuf::from("/bin"),
+        ]));
+        // layering twice doesn't change it
+        assert_eq!(
+            layered_path(&[PathBuf::from("/toolchain/bin")], &path)?,
+            path
+        );
+        Ok(())
+    }
+}
@@ -1,38 +1,71 @@
 use std::{
+    collections::BTreeMap,
     ffi::OsString,
+    fmt::Display,
     fs::OpenOptions,
     io::{Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     str::FromStr,
+    sync::OnceLock,
 };
 
 use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    commands::{run_command_in, run_make_in},
-    download::{download_and_decompress, linux_images_dir},
-    install_toolchain_str,
-    profile::{Arch, Target, Toolchain},
+    cache::{self, ArtifactKind},
+    capability::{self, Operation},
+    commands::{is_plan, plan_step, run_command_in, run_make_in},
+    download::linux_images_dir,
+    error::Failure,
+    hooks::{self, Hook},
+    install_toolchain, journal,
+    packages::{
+        BuildContext, Package, Source, fetch_source,
+        gnu_make::pin_make,
+        host_tools::{HostTool, ensure_host_tools},
+        install_package,
+        musl::MuslVersion,
+    },
+    parse_toolchain,
+    profile::{Abi, Arch, Target, Toolchain},
+    provenance,
+    stage::{Force, Stage},
 };
 
+/// The kernel's `ARCH` for `target`.
+pub fn kernel_arch(target: &Target) -> Result<&'static str> {
+    target
+        .arch
+        .to_kernel_arch()
+        .ok_or_else(|| capability::unsupported(target, Operation::Linux))
+}
+
+fn linux_source(version: &str) -> Source {
+    let major = version.split(".").next().unwrap_or_default();
+    // release candidates aren't on the CDN, git.kernel.org generates their tarballs
+    let url = if version.contains("-rc") {
+        format!("https://git.kernel.org/torvalds/t/linux-{version}.tar.gz")
+    } else {
+        format!("https://cdn.kernel.org/pub/linux/kernel/v{major}.x/linux-{version}.tar.xz")
+    };
+    Source::for_package("linux", version, url, format!("linux-{version}"))
+}
+
 pub fn download_linux(version: impl AsRef<str>) -> Result<PathBuf> {
     log::info!("=> download linux");
 
     let version = version.as_ref();
-    let major = version.split(".").next().unwrap();
-    let tarball = format!("linux-{version}.tar.xz");
-    let url = format!(
-        "https://cdn.kernel.org/pub/linux/kernel/v{major}.x/{tarball}",
-        tarball = &tarball
-    );
-
-    let linux_dir = download_and_decompress(&url, format!("linux-{version}"), true)
-        .context(format!("failed to download {tarball}"))?;
+    let linux_dir = fetch_source(&linux_source(version))?;
 
     // TODO: pass parsed version to this function
-    if KernelVersion::from_str(version.as_ref()).unwrap() == KernelVersion(5, 1, 0) {
+    if KernelVersion::from_str(version)? == KernelVersion::new(5, 1, 0) {
         const DTC_LEXER_PATCH: &str = include_str!("../../patches/linux-5.1-dtc-lexer.1.patch");
+        if is_plan() {
+            plan_step("git apply patches/linux-5.1-dtc-lexer.1.patch (in scripts/dtc)");
+            return Ok(linux_dir);
+        }
         let mut cmd = Command::new("git")
             .arg("apply")
             .arg("-")
@@ -45,29 +78,70 @@ pub fn download_linux(version: impl AsRef<str>) -> Result<PathBuf> {
             .context("git apply: failed to open stdin")?;
         stdin.write_all(DTC_LEXER_PATCH.as_bytes())?;
         cmd.wait()?;
+        provenance::record_patch("linux-5.1-dtc-lexer.1.patch", DTC_LEXER_PATCH);
     }
     Ok(linux_dir)
 }
 
 pub fn install_headers(toolchain: &Toolchain) -> Result<()> {
-    log::info!("=> install linux headers");
+    install_package(&HeadersPackage { toolchain }, 1)
+}
 
-    let kernel_src = if let Some(kernel_version) = toolchain.kernel {
-        download_linux(kernel_version.to_string())?
-    } else {
-        download_linux("6.17.7")?
-    };
+/// Linux UAPI headers installed into the toolchain's sysroot.
+pub struct HeadersPackage<'a> {
+    pub toolchain: &'a Toolchain,
+}
 
-    run_make_in(
-        kernel_src,
-        &[
-            format!("ARCH={}", toolchain.target.arch.to_kernel_arch()).as_str(),
-            "headers_install",
-            format!("INSTALL_HDR_PATH={}/usr", toolchain.sysroot()?.display()).as_str(),
-        ],
-    )?;
+impl HeadersPackage<'_> {
+    fn kernel_version(&self) -> String {
+        match self.toolchain.kernel {
+            Some(kernel_version) => kernel_version.to_string(),
+            None => "6.17.7".into(),
+        }
+    }
+}
 
-    Ok(())
+impl Package for HeadersPackage<'_> {
+    fn name(&self) -> String {
+        "linux headers".into()
+    }
+
+    fn version(&self) -> String {
+        self.kernel_version()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        vec![linux_source(&self.kernel_version())]
+    }
+
+    // the 5.1 tree needs to be patched after extracting
+    fn fetch(&self) -> Result<PathBuf> {
+        download_linux(self.kernel_version())
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_make_in(
+            &ctx.source_dir,
+            &[
+                format!("ARCH={}", kernel_arch(&self.toolchain.target)?).as_str(),
+                "headers_install",
+                format!(
+                    "INSTALL_HDR_PATH={}/usr",
+                    self.toolchain.sysroot()?.display()
+                )
+                .as_str(),
+            ],
+        )
+    }
+}
+
+/// Options added to the kernel config for the features of `toolup linux`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KernelFeatures {
+    /// `--kdump`, see [`crate::kdump`]
+    pub kdump: bool,
+    /// `--debug`, see [`crate::debug`]
+    pub debug: bool,
 }
 
 pub fn config(
@@ -76,6 +150,7 @@ pub fn config(
     out: PathBuf,
     menuconfig: bool,
     use_defconfig: bool,
+    features: KernelFeatures,
 ) -> Result<()> {
     log::info!("=> kernel defconfig");
 
@@ -93,15 +168,11 @@ pub fn config(
     //    "defconfig"
     //};
     let defconfig = match toolchain.target.arch {
-        Arch::I686 => "i386_defconfig",
+        Arch::I486 | Arch::I586 | Arch::I686 => "i386_defconfig",
         _ => "defconfig",
     };
 
-    let force_defconfig = if out.join(".config").exists() {
-        false
-    } else {
-        true
-    };
+    let force_defconfig = !out.join(".config").exists();
 
     if use_defconfig || force_defconfig {
         run_command_in(
@@ -109,7 +180,7 @@ pub fn config(
             "make",
             "make",
             &[
-                format!("ARCH={}", toolchain.target.arch.to_kernel_arch()).as_str(),
+                format!("ARCH={}", kernel_arch(&toolchain.target)?).as_str(),
                 "mrproper",
             ],
             Some(env.clone()),
@@ -120,18 +191,31 @@ pub fn config(
             "make",
             "make",
             &[
-                format!("ARCH={}", toolchain.target.arch.to_kernel_arch()).as_str(),
+                format!("ARCH={}", kernel_arch(&toolchain.target)?).as_str(),
                 format!("O={}", out.display()).as_str(),
                 format!("CROSS_COMPILE={}-", toolchain.target).as_str(),
                 defconfig,
             ],
             Some(env.clone()),
         )?;
+
+        let mut options = SHARE_CONFIG_OPTIONS.to_vec();
+        options.extend(target_config_options(&toolchain.target));
+        set_config_options(toolchain, &workdir, &out, &options)?;
+    }
+    // added to an existing config too, the image is cached by the hash of the config
+    if features.kdump {
+        set_config_options(toolchain, &workdir, &out, &KDUMP_CONFIG_OPTIONS)?;
+    }
+    if features.debug {
+        set_config_options(toolchain, &workdir, &out, &DEBUG_CONFIG_OPTIONS)?;
     }
-    if menuconfig {
+    if menuconfig && is_plan() {
+        plan_step(format!("make menuconfig (in {})", workdir.display()));
+    } else if menuconfig {
         Command::new("make")
-            .args(&[
-                format!("ARCH={}", toolchain.target.arch.to_kernel_arch()).as_str(),
+            .args([
+                format!("ARCH={}", kernel_arch(&toolchain.target)?).as_str(),
                 format!("O={}", out.display()).as_str(),
                 format!("CROSS_COMPILE={}-", toolchain.target).as_str(),
                 "menuconfig",
@@ -146,40 +230,382 @@ pub fn config(
     Ok(())
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct KernelVersion(u64, u64, u64);
+/// Change the kernel config in `out` with `scripts/config` arguments, then resolve the
+/// dependencies of the changed options.
+fn set_config_options(
+    toolchain: &Toolchain,
+    workdir: &Path,
+    out: &Path,
+    options: &[&str],
+) -> Result<()> {
+    if options.is_empty() {
+        return Ok(());
+    }
+    let env: Vec<(OsString, OsString)> = vec![("PATH".into(), toolchain.env_path()?)];
+    let mut args = vec![
+        "--file".to_string(),
+        out.join(".config").display().to_string(),
+    ];
+    args.extend(options.iter().map(|o| o.to_string()));
+    run_command_in(
+        workdir,
+        "scripts/config",
+        workdir.join("scripts").join("config"),
+        &args,
+        Some(env.clone()),
+    )?;
+    run_command_in(
+        workdir,
+        "make",
+        "make",
+        &[
+            format!("ARCH={}", kernel_arch(&toolchain.target)?).as_str(),
+            format!("O={}", out.display()).as_str(),
+            format!("CROSS_COMPILE={}-", toolchain.target).as_str(),
+            "olddefconfig",
+        ],
+        Some(env),
+    )
+}
+
+/// Loading a crash kernel with `kexec_file_load` and reading the vmcore from it, see
+/// [`crate::kdump`]. `panic_on_oops` needs the magic sysrq key to crash on purpose.
+const KDUMP_CONFIG_OPTIONS: [&str; 12] = [
+    "--enable",
+    "KEXEC",
+    "--enable",
+    "KEXEC_FILE",
+    "--enable",
+    "CRASH_DUMP",
+    "--enable",
+    "PROC_VMCORE",
+    "--enable",
+    "RELOCATABLE",
+    "--enable",
+    "MAGIC_SYSRQ",
+];
+
+/// A `vmlinux` with the DWARF debug info gdb needs to step through the kernel. Kernels before 5.18
+/// only know `DEBUG_INFO`, later ones pick the DWARF version with a choice.
+const DEBUG_CONFIG_OPTIONS: [&str; 10] = [
+    "--enable",
+    "DEBUG_KERNEL",
+    "--enable",
+    "DEBUG_INFO",
+    "--disable",
+    "DEBUG_INFO_NONE",
+    "--enable",
+    "DEBUG_INFO_DWARF_TOOLCHAIN_DEFAULT",
+    "--disable",
+    "DEBUG_INFO_REDUCED",
+];
+
+/// Core dumps and the 9p share core dumps are written to with `toolup linux --exec`, the sysroot
+/// share and the virtio port of the terminal size with `--shell`, see `qemu::start_vm`.
+const SHARE_CONFIG_OPTIONS: [&str; 18] = [
+    "--enable",
+    "COREDUMP",
+    "--enable",
+    "VIRTIO",
+    "--enable",
+    "VIRTIO_PCI",
+    "--enable",
+    "VIRTIO_MMIO",
+    "--enable",
+    "NET_9P",
+    "--enable",
+    "NET_9P_VIRTIO",
+    "--enable",
+    "9P_FS",
+    "--enable",
+    "ELF_CORE",
+    "--enable",
+    "VIRTIO_CONSOLE",
+];
+
+/// Options that the defconfig doesn't set for `target`, as `scripts/config` arguments.
+fn target_config_options(target: &Target) -> Vec<&'static str> {
+    match (target.arch, target.abi) {
+        // i386_defconfig builds for a 686
+        (Arch::I486, _) => vec!["--disable", "M686", "--enable", "M486"],
+        (Arch::I586, _) => vec!["--disable", "M686", "--enable", "M586"],
+        // `X86_X32` was renamed to `X86_X32_ABI` in 5.18
+        (_, Abi::GnuX32) => vec!["--enable", "X86_X32", "--enable", "X86_X32_ABI"],
+        _ => vec![],
+    }
+}
+
+/// A kernel release, e.g. `6.6`, `6.6.87` or the release candidate `6.13-rc3`.
+///
+/// Versions are ordered the way they're released: `6.13-rc1` < `6.13-rc3` < `6.13` < `6.13.1`.
+/// `Display` is the name kernel.org uses and `FromStr` parses it back, the patch is left out when
+/// it's 0 (`6.6`, not `6.6.0`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KernelVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// The release candidate of `major.minor`, rc versions have no patch
+    pub rc: Option<u64>,
+}
+
+impl KernelVersion {
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            rc: None,
+        }
+    }
+
+    /// The release candidate `major.minor-rc<rc>`.
+    pub const fn rc(major: u64, minor: u64, rc: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch: 0,
+            rc: Some(rc),
+        }
+    }
+
+    pub fn is_rc(&self) -> bool {
+        self.rc.is_some()
+    }
+}
+
+impl Ord for KernelVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // a release candidate comes before the release
+        let rank = |v: &Self| (v.major, v.minor, v.patch, v.rc.is_none(), v.rc);
+        rank(self).cmp(&rank(other))
+    }
+}
+
+impl PartialOrd for KernelVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
 impl FromStr for KernelVersion {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let parts: Vec<&str> = s.split(".").collect();
-
-        match parts.as_slice() {
-            [major, minor] => Ok(KernelVersion(
-                major.parse().context("invalid version")?,
-                minor.parse().context("invalid version")?,
-                0,
-            )),
-            [major, minor, patch] => Ok(KernelVersion(
-                major.parse().context("invalid version")?,
-                minor.parse().context("invalid version")?,
-                patch.parse().context("invalid version")?,
-            )),
-            _ => Err(anyhow!("")),
-        }
+        let invalid =
+            || anyhow!("`{s}` is an invalid kernel version, expected e.g. 6.6, 6.6.87 or 6.13-rc3");
+        let number = |part: &str| -> Result<u64> {
+            // no signs, spaces or leading zeros, so the version is displayed the way it's written
+            if part.is_empty()
+                || !part.bytes().all(|b| b.is_ascii_digit())
+                || (part.len() > 1 && part.starts_with('0'))
+            {
+                return Err(invalid());
+            }
+            part.parse().map_err(|_| invalid())
+        };
+
+        let (release, rc) = match s.split_once("-rc") {
+            Some((release, rc)) => (release, Some(number(rc)?)),
+            None => (s, None),
+        };
+        let parts: Vec<&str> = release.split('.').collect();
+        let version = match parts.as_slice() {
+            [major, minor] => KernelVersion::new(number(major)?, number(minor)?, 0),
+            [major, minor, patch] if rc.is_none() => {
+                KernelVersion::new(number(major)?, number(minor)?, number(patch)?)
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(KernelVersion { rc, ..version })
     }
 }
-impl ToString for KernelVersion {
-    fn to_string(&self) -> String {
-        if self.2 == 0 {
-            format!("{}.{}", self.0, self.1)
+
+impl Display for KernelVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if let Some(rc) = self.rc {
+            write!(f, "-rc{rc}")
+        } else if self.patch != 0 {
+            write!(f, ".{}", self.patch)
         } else {
-            format!("{}.{}.{}", self.0, self.1, self.2)
+            Ok(())
         }
     }
 }
 
+serde_string!(KernelVersion);
+
+/// Flags that make a kernel build with a newer toolchain than it was released with, appended to
+/// make variables for the kernel versions in `from..=until`. The rules in [`KERNEL_FLAG_RULES`]
+/// can be extended with `[[workspace.kernel_flags]]` in `toolup.toml`:
+///
+/// ```toml
+/// [[workspace.kernel_flags]]
+/// reason = "gcc 15 warns about ..."
+/// from = "5.4"
+/// until = "5.10"
+/// flags = { KCFLAGS = ["-Wno-error=foo"] }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KernelFlagRule {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub reason: String,
+    /// The first kernel version that needs the flags [default: every version until `until`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<KernelVersion>,
+    /// The last kernel version that needs the flags [default: every version from `from`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<KernelVersion>,
+    /// Make variable to flags, e.g. `KCFLAGS = ["-Wno-error=format"]`
+    pub flags: BTreeMap<String, Vec<String>>,
+}
+
+impl KernelFlagRule {
+    pub fn applies(&self, version: KernelVersion) -> bool {
+        self.from.is_none_or(|from| version >= from)
+            && self.until.is_none_or(|until| version <= until)
+    }
+}
+
+/// A built-in [`KernelFlagRule`].
+struct BuiltinFlagRule {
+    reason: &'static str,
+    from: Option<KernelVersion>,
+    until: Option<KernelVersion>,
+    flags: &'static [(&'static str, &'static [&'static str])],
+}
+
+impl From<&BuiltinFlagRule> for KernelFlagRule {
+    fn from(rule: &BuiltinFlagRule) -> Self {
+        Self {
+            reason: rule.reason.into(),
+            from: rule.from,
+            until: rule.until,
+            flags: rule
+                .flags
+                .iter()
+                .map(|(var, flags)| {
+                    (
+                        var.to_string(),
+                        flags.iter().map(|f| f.to_string()).collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Workarounds for building old kernels with the toolchains picked by [`get_image`].
+const KERNEL_FLAG_RULES: &[BuiltinFlagRule] = &[
+    BuiltinFlagRule {
+        // https://gcc.gnu.org/bugzilla/show_bug.cgi?id=117178
+        reason: "gcc 15 warns about char arrays without room for the NUL",
+        from: None,
+        until: Some(KernelVersion::new(6, 14, 0)),
+        flags: &[("KCFLAGS", &["-Wno-unterminated-string-initialization"])],
+    },
+    BuiltinFlagRule {
+        reason: "`bool` is a keyword with gcc 15's default -std=c23",
+        from: None,
+        until: Some(KernelVersion::new(6, 13, 0)),
+        flags: &[
+            ("KCFLAGS", &["-std=gnu11"]),
+            ("CFLAGS_KERNEL", &["-std=gnu11"]),
+            ("CFLAGS_MODULE", &["-std=gnu11"]),
+        ],
+    },
+    BuiltinFlagRule {
+        // https://lists.linaro.org/archives/list/linux-stable-mirror%40lists.linaro.org/message/7X43AVMPEXUTTYJFHQLJAV5AMZO7PFB3/
+        reason: "newer gcc reports false array bounds errors",
+        from: None,
+        until: Some(KernelVersion::new(6, 2, 0)),
+        flags: &[("KCFLAGS", &["-Wno-array-bounds"])],
+    },
+    BuiltinFlagRule {
+        reason: "newer gcc reports format errors",
+        from: None,
+        until: Some(KernelVersion::new(6, 0, 0)),
+        flags: &[("KCFLAGS", &["-Wno-error=format"])],
+    },
+    BuiltinFlagRule {
+        reason: "gcc 12+ reports use-after-free errors",
+        from: Some(KernelVersion::new(5, 1, 1)),
+        until: Some(KernelVersion::new(5, 15, 0)),
+        flags: &[
+            (
+                "KCFLAGS",
+                &["-Wno-use-after-free", "-Wno-error=use-after-free"],
+            ),
+            (
+                "CFLAGS_KERNEL",
+                &["-Wno-error=use-after-free", "-Wno-use-after-free"],
+            ),
+            (
+                "CFLAGS_MODULE",
+                &["-Wno-error=use-after-free", "-Wno-use-after-free"],
+            ),
+            (
+                "CFLAGS",
+                &["-Wno-error=use-after-free", "-Wno-use-after-free"],
+            ),
+            (
+                "EXTRA_CFLAGS",
+                &["-Wno-error=use-after-free", "-Wno-use-after-free"],
+            ),
+        ],
+    },
+    BuiltinFlagRule {
+        reason: "the host tools of 5.1 and older don't build with newer gcc",
+        from: None,
+        until: Some(KernelVersion::new(5, 1, 0)),
+        flags: &[
+            ("HOSTCFLAGS", &["-Wno-error=redundant-decls", "-fno-common"]),
+            ("KBUILD_HOSTCFLAGS", &["-Wno-error", "-fno-common"]),
+            // the old build system's errors are hard to read without the commands
+            ("V", &["1"]),
+        ],
+    },
+];
+
+static USER_FLAG_RULES: OnceLock<Vec<KernelFlagRule>> = OnceLock::new();
+
+/// Set the `[[workspace.kernel_flags]]` rules, applied after the built-in ones.
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_kernel_flag_rules(rules: Vec<KernelFlagRule>) {
+    let _ = USER_FLAG_RULES.set(rules);
+}
+
+/// The built-in rules followed by the user's.
+pub fn kernel_flag_rules() -> Vec<KernelFlagRule> {
+    KERNEL_FLAG_RULES
+        .iter()
+        .map(KernelFlagRule::from)
+        .chain(USER_FLAG_RULES.get().into_iter().flatten().cloned())
+        .collect()
+}
+
+/// The flags of every rule in `rules` that applies to `version`, per make variable. A flag added
+/// by several rules is only added once, in the position of the first.
+pub fn kernel_flags(
+    version: KernelVersion,
+    rules: &[KernelFlagRule],
+) -> BTreeMap<String, Vec<String>> {
+    let mut merged: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for rule in rules.iter().filter(|rule| rule.applies(version)) {
+        for (var, flags) in &rule.flags {
+            let merged = merged.entry(var.clone()).or_default();
+            for flag in flags {
+                if !merged.contains(flag) {
+                    merged.push(flag.clone());
+                }
+            }
+        }
+    }
+    merged
+}
+
 pub fn build(
     version: impl AsRef<str>,
     toolchain: &Toolchain,
@@ -192,120 +618,173 @@ pub fn build(
     let mut env: Vec<(OsString, OsString)> = vec![("PATH".into(), toolchain.env_path()?)];
     let mut args: Vec<String> = vec![
         format!("O={}", out.display()),
-        format!("ARCH={}", toolchain.target.arch.to_kernel_arch()),
+        format!("ARCH={}", kernel_arch(&toolchain.target)?),
         format!("CROSS_COMPILE={}-", toolchain.target.to_string()),
         format!("-j{}", jobs),
     ];
 
-    let mut kcflags: Vec<&str> = vec![];
-    let kernel_version = KernelVersion::from_str(version.as_ref())?;
-
     // modify compiler flags to compile old kernels with a newer GCC version.
-    if kernel_version <= KernelVersion(6, 14, 0) {
-        // https://gcc.gnu.org/bugzilla/show_bug.cgi?id=117178
-        kcflags.push("-Wno-unterminated-string-initialization");
+    let kernel_version = KernelVersion::from_str(version.as_ref())?;
+    for (var, flags) in kernel_flags(kernel_version, &kernel_flag_rules()) {
+        // the other variables are overridden on the command line, KCFLAGS is meant to be set in
+        // the environment
+        if var == "KCFLAGS" {
+            env.push((var.into(), flags.join(" ").into()));
+        } else {
+            args.push(format!("{var}={}", flags.join(" ")));
+        }
     }
 
-    // 'bool' is a keyword with '-std=c23' onwards
-    if kernel_version <= KernelVersion(6, 13, 0) {
-        kcflags.push("-std=gnu11");
+    run_command_in(&workdir, "make", "make", &args, Some(env))?;
+    Ok(())
+}
 
-        args.push("CFLAGS_KERNEL=-std=gnu11".into());
-        args.push("CFLAGS_MODULE=-std=gnu11".into());
-    }
+pub fn build_out(version: impl AsRef<str>, target: &Target) -> Result<PathBuf> {
+    Ok(linux_images_dir()?.join(format!("{}-{}", target, version.as_ref())))
+}
 
-    if kernel_version <= KernelVersion(6, 2, 0) {
-        // https://lists.linaro.org/archives/list/linux-stable-mirror%40lists.linaro.org/message/7X43AVMPEXUTTYJFHQLJAV5AMZO7PFB3/
-        kcflags.push("-Wno-array-bounds");
+/// The toolchain of a kernel era: the kernels up to the `until` series (including their stable
+/// updates, e.g. `4.9.337` for `4.9`) are built with these versions.
+pub struct KernelToolchain {
+    pub until: Option<(u64, u64)>,
+    pub gcc: &'static str,
+    pub binutils: &'static str,
+    /// musl builds with every gcc, the default musl version is used for musl targets
+    pub glibc: &'static str,
+    pub reason: &'static str,
+}
 
-        args.push("CFLAGS_KERNEL=-std=gnu11".into());
-        args.push("CFLAGS_MODULE=-std=gnu11".into());
-    }
+/// Period-correct toolchains for old kernels, from the oldest era. Each row is built in CI by
+/// booting the newest kernel of its era.
+pub const KERNEL_TOOLCHAINS: &[KernelToolchain] = &[
+    KernelToolchain {
+        until: Some((3, 19)),
+        gcc: "4.9.4",
+        binutils: "2.25.1",
+        glibc: "2.23",
+        reason: "3.x kernels predate gcc 5",
+    },
+    KernelToolchain {
+        until: Some((4, 9)),
+        gcc: "6.5.0",
+        binutils: "2.28.1",
+        glibc: "2.26",
+        reason: "4.9 and older predate gcc 7",
+    },
+    KernelToolchain {
+        until: Some((5, 1)),
+        gcc: "7.5.0",
+        binutils: "2.33.1",
+        glibc: "2.30",
+        reason: "5.1 and older (4.19 included) don't build with gcc 10+",
+    },
+    KernelToolchain {
+        until: Some((5, 10)),
+        gcc: "15.2.0",
+        binutils: "2.34",
+        glibc: "2.35",
+        reason: "5.10 builds with binutils 2.34",
+    },
+    KernelToolchain {
+        until: None,
+        gcc: "15.2.0",
+        binutils: "2.45",
+        glibc: "2.42",
+        reason: "current kernels build with the latest toolchain",
+    },
+];
 
-    if kernel_version <= KernelVersion(6, 0, 0) {
-        kcflags.push("-Wno-error=format");
-    }
+/// The first kernel with each architecture, older ones don't have a port.
+const KERNEL_ARCH_SINCE: &[(Arch, KernelVersion)] = &[
+    (Arch::Aarch64, KernelVersion::new(3, 7, 0)),
+    (Arch::Riscv64, KernelVersion::new(4, 15, 0)),
+];
 
-    if kernel_version <= KernelVersion(5, 15, 0) && kernel_version > KernelVersion(5, 1, 0) {
-        kcflags.push("-Wno-use-after-free");
-        //kcflags.push("-fno-analyzer");
-        kcflags.push("-Wno-error=use-after-free");
-        args.push("CFLAGS_KERNEL=-std=gnu11 -Wno-error=use-after-free -Wno-use-after-free".into());
-        args.push("CFLAGS_MODULE=-std=gnu11 -Wno-error=use-after-free -Wno-use-after-free".into());
-        args.push("CFLAGS=-Wno-error=use-after-free -Wno-use-after-free".into());
-        args.push("EXTRA_CFLAGS=-Wno-error=use-after-free -Wno-use-after-free".into());
+/// Returns the toolchain that builds `version` for `target`, see [`KERNEL_TOOLCHAINS`].
+pub fn kernel_toolchain(target: &Target, version: KernelVersion) -> Result<Toolchain> {
+    if version.major < 3 {
+        return Err(Failure::Usage).context(format!(
+            "linux {version} is too old, toolup builds 3.0 and newer"
+        ));
     }
-
-    if kernel_version <= KernelVersion(5, 1, 0) {
-        args.push("HOSTCFLAGS=-Wno-error=redundant-decls -fno-common".into());
-        args.push("KBUILD_HOSTCFLAGS=-Wno-error -fno-common".into());
-        args.push("V=1".into());
+    if let Some((_, since)) = KERNEL_ARCH_SINCE
+        .iter()
+        .find(|(arch, since)| *arch == target.arch && version < *since)
+    {
+        return Err(Failure::Usage).context(format!(
+            "linux {version} has no {} port, it was added in {since}",
+            target.arch
+        ));
     }
 
-    if !kcflags.is_empty() {
-        env.push(("KCFLAGS".into(), kcflags.join(" ").into()));
-    }
-    run_command_in(&workdir, "make", "make", &args, Some(env))?;
-    Ok(())
+    let era = KERNEL_TOOLCHAINS
+        .iter()
+        .find(|era| {
+            era.until
+                .is_none_or(|until| (version.major, version.minor) <= until)
+        })
+        .expect("the last era has no upper bound");
+    log::info!(
+        "=> building linux {version} with gcc {} and binutils {}: {}",
+        era.gcc,
+        era.binutils,
+        era.reason
+    );
+    let libc = if target.is_musl() {
+        MuslVersion::default().to_string()
+    } else {
+        era.glibc.to_string()
+    };
+    parse_toolchain(
+        &target.to_string(),
+        era.gcc,
+        &libc,
+        era.binutils,
+        Some(&version),
+    )
 }
 
-pub fn build_out(version: impl AsRef<str>, target: &Target) -> Result<PathBuf> {
-    Ok(linux_images_dir()?.join(format!("{}-{}", target.to_string(), version.as_ref())))
+/// The uncompressed kernel with its symbols kept next to `image` with `--debug`, see
+/// [`get_image`].
+pub fn vmlinux(image: &Path) -> PathBuf {
+    match image.extension() {
+        // the config hash
+        Some(hash) => image.with_file_name(format!("vmlinux.{}", hash.to_string_lossy())),
+        None => image.with_file_name("vmlinux"),
+    }
 }
 
 /// Returns a tuple consisting of a kernel image and the toolchain used to compile it.
 ///
-/// The toolchain will be selected based on the kernel version.
+/// The toolchain will be selected based on the kernel version. With [`KernelFeatures::debug`] the
+/// `vmlinux` is kept as well, see [`vmlinux`].
 pub fn get_image(
     target: &Target,
     version: impl AsRef<str>,
     jobs: u64,
     menuconfig: bool,
     defconfig: bool,
+    features: KernelFeatures,
+    force: &Force,
 ) -> Result<(PathBuf, Toolchain)> {
+    let _span =
+        tracing::info_span!("kernel_image", target = %target, version = version.as_ref()).entered();
     log::info!("=> kernel image");
 
+    capability::check(target, Operation::Linux)?;
+
     let kernel_version = KernelVersion::from_str(version.as_ref())?;
-    let toolchain = if kernel_version <= KernelVersion(5, 1, 0) {
-        install_toolchain_str(
-            target.to_string(),
-            "7.5.0".into(),
-            "2.30".into(),
-            "2.33.1".into(),
-            Some(&kernel_version),
-            jobs,
-            false,
-        )?
-    } else if kernel_version <= KernelVersion(5, 10, 0) {
-        install_toolchain_str(
-            target.to_string(),
-            "15.2.0".into(),
-            "2.35".into(),
-            "2.34".into(), // the 5.10 kernel will compile with this binutils version
-            Some(&kernel_version),
-            jobs,
-            false,
-        )?
-    } else {
-        install_toolchain_str(
-            target.to_string(),
-            "15.2.0".into(),
-            "2.42".into(),
-            "2.45".into(),
-            Some(&kernel_version),
-            jobs,
-            false,
-        )?
-    };
+    let toolchain = kernel_toolchain(target, kernel_version)?;
+    let toolchain = install_toolchain(toolchain, jobs, force)?.toolchain;
 
     let out = build_out(&version, &toolchain.target)?;
     let boot_dir = out
         .join("arch")
-        .join(toolchain.target.arch.to_kernel_arch())
+        .join(kernel_arch(&toolchain.target)?)
         .join("boot");
 
     let out_image = match toolchain.target.arch {
-        Arch::X86_64 | Arch::I686 => boot_dir.join("bzImage"),
+        Arch::X86_64 | Arch::I486 | Arch::I586 | Arch::I686 => boot_dir.join("bzImage"),
         Arch::Armv7 => boot_dir.join("zImage"),
         Arch::Aarch64 => boot_dir.join("Image"),
         // for mips and ppc, the image is at the top level
@@ -320,6 +799,14 @@ pub fn get_image(
         _ => boot_dir.join("Image"),
     };
 
+    journal::start(&format!("linux-{}-{}", toolchain.target, version.as_ref()))?;
+
+    ensure_host_tools(&[HostTool::Flex, HostTool::Bison, HostTool::Bc], jobs)?;
+    // the make of the kernel being built, installing other toolchains may have pinned another one
+    let mut building = toolchain.clone();
+    building.kernel = Some(kernel_version);
+    pin_make(&building, jobs)?;
+
     let workdir = download_linux(&version)?;
     config(
         &toolchain,
@@ -327,8 +814,28 @@ pub fn get_image(
         out.clone(),
         menuconfig,
         defconfig,
+        features,
     )?;
 
+    let dirs = [("source", workdir.as_path()), ("out", out.as_path())];
+    if is_plan() {
+        hooks::run(Hook::PreKernelBuild, &toolchain, &dirs)?;
+        build(&version, &toolchain, workdir.clone(), jobs, out.clone())?;
+        plan_step(format!(
+            "copy {} to {}.<config hash>",
+            out_image.display(),
+            out_image.display()
+        ));
+        if features.debug {
+            plan_step(format!(
+                "copy {} to {}",
+                out.join("vmlinux").display(),
+                vmlinux(&out_image.with_extension("<config hash>")).display()
+            ));
+        }
+        return Ok((out_image, toolchain));
+    }
+
     let mut config_file = OpenOptions::new()
         .read(true)
         .open(out.join(".config"))
@@ -341,13 +848,278 @@ pub fn get_image(
     let mut toolup_image = out_image.clone();
     toolup_image.add_extension(config_hash.to_string());
 
-    if toolup_image.exists() {
+    let vmlinux = vmlinux(&toolup_image);
+    // `out` only has the vmlinux of the last build
+    let has_vmlinux = !features.debug || vmlinux.exists();
+    if toolup_image.exists() && has_vmlinux && !force.includes(Stage::KernelImage) {
+        cache::record_artifact(
+            &toolup_image,
+            ArtifactKind::KernelImage,
+            Some(toolchain.id()),
+        )?;
         return Ok((toolup_image, toolchain));
     }
 
-    build(&version, &toolchain, workdir.clone(), jobs, out)?;
+    hooks::run(Hook::PreKernelBuild, &toolchain, &dirs)?;
+    build(&version, &toolchain, workdir.clone(), jobs, out.clone())?;
 
     std::fs::copy(out_image, &toolup_image).context("failed to copy kernel image")?;
+    // on ppc64 the image is the vmlinux
+    if features.debug && vmlinux != toolup_image {
+        std::fs::copy(out.join("vmlinux"), &vmlinux).context("failed to copy vmlinux")?;
+    }
+    hooks::run(
+        Hook::PostKernelBuild,
+        &toolchain,
+        &[("out", &out), ("image", &toolup_image)],
+    )?;
+    cache::record_artifact(
+        &toolup_image,
+        ArtifactKind::KernelImage,
+        Some(toolchain.id()),
+    )?;
 
     Ok((toolup_image, toolchain))
 }
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::{
+        KERNEL_TOOLCHAINS, KernelFlagRule, KernelVersion, kernel_flag_rules, kernel_flags,
+        kernel_toolchain,
+    };
+    use crate::profile::Target;
+
+    #[test]
+    fn test_kernel_version_roundtrip() -> anyhow::Result<()> {
+        for (s, version) in [
+            ("6.6", KernelVersion::new(6, 6, 0)),
+            ("6.6.87", KernelVersion::new(6, 6, 87)),
+            ("5.1", KernelVersion::new(5, 1, 0)),
+            ("6.0", KernelVersion::new(6, 0, 0)),
+            ("6.13-rc3", KernelVersion::rc(6, 13, 3)),
+            ("7.0-rc1", KernelVersion::rc(7, 0, 1)),
+        ] {
+            assert_eq!(KernelVersion::from_str(s)?, version, "{s}");
+            assert_eq!(version.to_string(), s);
+        }
+        // a 0 patch is accepted but left out
+        assert_eq!(KernelVersion::from_str("6.6.0")?.to_string(), "6.6");
+        Ok(())
+    }
+
+    #[test]
+    fn test_kernel_version_invalid() {
+        for s in [
+            "",
+            "6",
+            "6.",
+            ".6",
+            "6.6.",
+            "6.6.6.6",
+            "v6.6",
+            "6.6-rc",
+            "6.6-rc1.2",
+            "6.6.1-rc1",
+            "6.6-rc-1",
+            "6.06",
+            "6.6 ",
+            "+6.6",
+            "6.6-next",
+            "6.x",
+        ] {
+            assert!(
+                KernelVersion::from_str(s).is_err(),
+                "{s:?} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn test_kernel_version_order() -> anyhow::Result<()> {
+        let ordered = [
+            "4.19",
+            "4.19.325",
+            "5.1",
+            "5.10",
+            "5.15",
+            "6.0",
+            "6.2",
+            "6.6",
+            "6.6.87",
+            "6.13-rc1",
+            "6.13-rc3",
+            "6.13-rc10",
+            "6.13",
+            "6.13.1",
+            "6.14-rc1",
+            "6.14",
+        ]
+        .map(KernelVersion::from_str)
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(a.cmp(b), i.cmp(&j), "{a} vs {b}");
+            }
+        }
+        // rc kernels get the workarounds of their release
+        assert!(KernelVersion::rc(6, 13, 3) <= KernelVersion::new(6, 13, 0));
+        assert!(KernelVersion::rc(6, 14, 1) > KernelVersion::new(6, 13, 0));
+        Ok(())
+    }
+
+    fn flags(version: &str, rules: &[KernelFlagRule]) -> Vec<(String, String)> {
+        kernel_flags(KernelVersion::from_str(version).unwrap(), rules)
+            .into_iter()
+            .map(|(var, flags)| (var, flags.join(" ")))
+            .collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(var, flags)| (var.to_string(), flags.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_kernel_flags() {
+        let rules = kernel_flag_rules();
+        assert_eq!(flags("6.15", &rules), pairs(&[]));
+        assert_eq!(
+            flags("6.14", &rules),
+            pairs(&[("KCFLAGS", "-Wno-unterminated-string-initialization")])
+        );
+        // the rc kernels of 6.14 get the workarounds of 6.14
+        assert_eq!(flags("6.14-rc2", &rules), flags("6.14", &rules));
+        assert_eq!(
+            flags("6.6", &rules),
+            pairs(&[
+                ("CFLAGS_KERNEL", "-std=gnu11"),
+                ("CFLAGS_MODULE", "-std=gnu11"),
+                (
+                    "KCFLAGS",
+                    "-Wno-unterminated-string-initialization -std=gnu11"
+                ),
+            ])
+        );
+        // several rules add to the same variables, none of them overwrites the others
+        assert_eq!(
+            flags("5.10", &rules),
+            pairs(&[
+                ("CFLAGS", "-Wno-error=use-after-free -Wno-use-after-free"),
+                (
+                    "CFLAGS_KERNEL",
+                    "-std=gnu11 -Wno-error=use-after-free -Wno-use-after-free"
+                ),
+                (
+                    "CFLAGS_MODULE",
+                    "-std=gnu11 -Wno-error=use-after-free -Wno-use-after-free"
+                ),
+                (
+                    "EXTRA_CFLAGS",
+                    "-Wno-error=use-after-free -Wno-use-after-free"
+                ),
+                (
+                    "KCFLAGS",
+                    "-Wno-unterminated-string-initialization -std=gnu11 -Wno-array-bounds \
+                     -Wno-error=format -Wno-use-after-free -Wno-error=use-after-free"
+                ),
+            ])
+        );
+        let old = flags("5.1", &rules);
+        assert!(old.contains(&(
+            "HOSTCFLAGS".into(),
+            "-Wno-error=redundant-decls -fno-common".into()
+        )));
+        assert!(!old.iter().any(|(var, _)| var == "EXTRA_CFLAGS"));
+    }
+
+    #[test]
+    fn test_user_kernel_flags() -> anyhow::Result<()> {
+        let rule: KernelFlagRule = toml::from_str(
+            r#"
+            from = "5.4"
+            until = "5.10"
+            flags = { KCFLAGS = ["-Wno-error=format", "-Wno-missing-prototypes"] }
+            "#,
+        )?;
+        let mut rules = kernel_flag_rules();
+        rules.push(rule);
+        let kcflags = |version| {
+            flags(version, &rules)
+                .into_iter()
+                .find(|(var, _)| var == "KCFLAGS")
+                .map(|(_, flags)| flags)
+        };
+        // appended once, after the built-in flags
+        assert!(
+            kcflags("5.4")
+                .unwrap()
+                .ends_with("-Wno-error=use-after-free -Wno-missing-prototypes")
+        );
+        assert_eq!(
+            kcflags("5.4").unwrap().matches("-Wno-error=format").count(),
+            1
+        );
+        assert!(!kcflags("5.15").unwrap().contains("-Wno-missing-prototypes"));
+        assert!(!kcflags("5.3").unwrap().contains("-Wno-missing-prototypes"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_kernel_toolchain() -> anyhow::Result<()> {
+        let target = Target::from_str("x86_64-unknown-linux-gnu")?;
+        let picked = |version: &str| -> anyhow::Result<(String, String, String)> {
+            let toolchain = kernel_toolchain(&target, KernelVersion::from_str(version)?)?;
+            Ok((
+                toolchain.gcc.version.to_string(),
+                toolchain.binutils.version.to_string(),
+                toolchain.libc.to_string(),
+            ))
+        };
+        let expected = |gcc: &str, binutils: &str, libc: &str| {
+            (gcc.to_string(), binutils.to_string(), libc.to_string())
+        };
+        assert_eq!(picked("3.18")?, expected("4.9.4", "2.25.1", "glibc-2.23"));
+        assert_eq!(
+            picked("4.9.337")?,
+            expected("6.5.0", "2.28.1", "glibc-2.26")
+        );
+        assert_eq!(picked("4.19")?, expected("7.5.0", "2.33.1", "glibc-2.30"));
+        // stable updates are built like their series
+        assert_eq!(
+            picked("5.10.240")?,
+            expected("15.2.0", "2.34", "glibc-2.35")
+        );
+        assert_eq!(
+            picked("6.13-rc3")?,
+            expected("15.2.0", "2.45", "glibc-2.42")
+        );
+
+        let musl = Target::from_str("aarch64-unknown-linux-musl")?;
+        let toolchain = kernel_toolchain(&musl, KernelVersion::new(4, 19, 0))?;
+        assert_eq!(toolchain.libc.to_string(), "musl-1.2.5");
+        assert_eq!(toolchain.kernel, Some(KernelVersion::new(4, 19, 0)));
+
+        assert!(kernel_toolchain(&target, KernelVersion::new(2, 6, 32)).is_err());
+        let riscv = Target::from_str("riscv64-unknown-linux-gnu")?;
+        assert!(kernel_toolchain(&riscv, KernelVersion::new(4, 14, 0)).is_err());
+        assert!(kernel_toolchain(&riscv, KernelVersion::new(4, 19, 0)).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_kernel_toolchains_are_ordered() {
+        let bounds: Vec<_> = KERNEL_TOOLCHAINS.iter().map(|era| era.until).collect();
+        assert!(bounds.is_sorted_by(|a, b| match (a, b) {
+            (Some(a), Some(b)) => a < b,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }));
+        assert_eq!(bounds.last(), Some(&None));
+    }
+}
@@ -0,0 +1,333 @@
+//! Clang and lld for the toolchain's target, installed next to GCC with `toolup install --compiler
+//! clang`.
+//!
+//! GCC is still built: glibc only builds with GCC, and clang links programs with the toolchain's
+//! crt files, libgcc and libstdc++ (`--gcc-toolchain`). LLVM is built with the backend of the
+//! target only. `<target>-clang` and `<target>-clang++` in the toolchain's `bin` run clang with
+//! `<target>.cfg` (see [`clang_config`]), which points it to the sysroot and links with lld.
+//!
+//! `wasm32-wasi` toolchains are only clang and lld, see [`crate::packages::wasi`].
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capability::{self, Operation},
+    commands::{is_plan, plan_step, run_command_in},
+    error::Failure,
+    packages::{BuildContext, Package, Source, host_tools::find_program, install_package},
+    profile::{Arch, Toolchain},
+};
+
+/// The compiler `toolup install --compiler` builds the toolchain with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compiler {
+    #[default]
+    Gcc,
+    /// GCC, then clang and lld using GCC's sysroot and runtime libraries
+    Clang,
+}
+
+impl FromStr for Compiler {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gcc" => Ok(Compiler::Gcc),
+            "clang" => Ok(Compiler::Clang),
+            _ => Err(anyhow!(
+                "unknown compiler `{s}`, expected one of: gcc, clang"
+            )),
+        }
+    }
+}
+
+impl Display for Compiler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Compiler::Gcc => "gcc",
+            Compiler::Clang => "clang",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// The LLVM backend generating code for `arch`, `None` if LLVM has no stable one.
+pub fn llvm_backend(arch: Arch) -> Option<&'static str> {
+    match arch {
+        Arch::X86_64 | Arch::I486 | Arch::I586 | Arch::I686 => Some("X86"),
+        Arch::Aarch64 => Some("AArch64"),
+        Arch::Armv7 => Some("ARM"),
+        Arch::Riscv64 => Some("RISCV"),
+        Arch::Ppc64 | Arch::Ppc64Le => Some("PowerPC"),
+        Arch::Avr => Some("AVR"),
+        Arch::Bpf => Some("BPF"),
+        Arch::Wasm32 => Some("WebAssembly"),
+        // experimental in LLVM
+        Arch::Xtensa => None,
+    }
+}
+
+/// Download and build clang and lld for the toolchain, if it's built with them.
+pub fn install_llvm(toolchain: &Toolchain, jobs: u64) -> Result<()> {
+    let Some(llvm) = &toolchain.llvm else {
+        return Ok(());
+    };
+    install_package(&LlvmPackage { toolchain, llvm }, jobs)?;
+    install_clang_wrappers(toolchain)
+}
+
+/// Clang and lld installed into the toolchain's directory.
+pub struct LlvmPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub llvm: &'a Llvm,
+}
+
+impl Package for LlvmPackage<'_> {
+    fn name(&self) -> String {
+        "llvm".into()
+    }
+
+    fn version(&self) -> String {
+        self.llvm.version.to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        let version = self.llvm.version;
+        vec![Source::for_package(
+            "llvm",
+            &version.to_string(),
+            format!(
+                "https://github.com/llvm/llvm-project/releases/download/llvmorg-{version}/llvm-project-{version}.src.tar.xz"
+            ),
+            format!("llvm-project-{version}.src"),
+        )]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        if !is_plan() {
+            find_program("cmake").context("`cmake` was not found, install it to build clang")?;
+        }
+        let backend = llvm_backend(self.toolchain.target.arch)
+            .ok_or_else(|| capability::unsupported(&self.toolchain.target, Operation::Clang))?;
+        // ninja builds LLVM noticeably faster, make is the fallback
+        let generator = if find_program("ninja").is_some() {
+            "Ninja"
+        } else {
+            "Unix Makefiles"
+        };
+        run_command_in(
+            &ctx.objdir,
+            "cmake",
+            "cmake",
+            &[
+                "-S".to_string(),
+                ctx.source_dir.join("llvm").display().to_string(),
+                "-B".to_string(),
+                ctx.objdir.display().to_string(),
+                "-G".to_string(),
+                generator.to_string(),
+                "-DCMAKE_BUILD_TYPE=Release".to_string(),
+                format!("-DCMAKE_INSTALL_PREFIX={}", self.toolchain.dir()?.display()),
+                "-DLLVM_ENABLE_PROJECTS=clang;lld".to_string(),
+                format!("-DLLVM_TARGETS_TO_BUILD={backend}"),
+                format!(
+                    "-DLLVM_DEFAULT_TARGET_TRIPLE={}",
+                    self.toolchain.target.to_target_string()
+                ),
+                // clang, lld and the llvm-* tools, not the libraries and headers of LLVM
+                "-DLLVM_INSTALL_TOOLCHAIN_ONLY=ON".to_string(),
+                "-DLLVM_INCLUDE_TESTS=OFF".to_string(),
+                "-DLLVM_INCLUDE_EXAMPLES=OFF".to_string(),
+                "-DLLVM_INCLUDE_BENCHMARKS=OFF".to_string(),
+                "-DLLVM_INCLUDE_DOCS=OFF".to_string(),
+            ],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "cmake",
+            "cmake",
+            &["--build", ".", "-j", ctx.jobs.to_string().as_str()],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "cmake",
+            "cmake",
+            &["--install", ".", "--strip"],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+}
+
+/// The sysroot of an installed toolchain clang compiles against: newlib's directory for
+/// freestanding targets.
+fn clang_sysroot(toolchain: &Toolchain) -> Result<PathBuf> {
+    Ok(if toolchain.is_freestanding() {
+        toolchain.dir()?.join(toolchain.target.to_target_string())
+    } else {
+        toolchain.sysroot()?
+    })
+}
+
+/// The clang configuration file of `toolchain`: the target, the sysroot and the GCC installation
+/// holding the crt files, libgcc, libstdc++ and binutils. Programs are linked with lld.
+///
+/// The file is written while the toolchain is in its staging directory, so paths inside the
+/// toolchain are relative to the `bin` directory the file is in (`<CFGDIR>`).
+///
+/// WASI toolchains have no GCC, the sysroot has wasi-libc and clang links with `wasm-ld`.
+pub fn clang_config(toolchain: &Toolchain) -> Result<String> {
+    let target = toolchain.target.to_target_string();
+    if toolchain.target.is_wasi() {
+        return Ok(format!(
+            "--target={target}\n--sysroot={}\n",
+            toolchain.sysroot()?.display()
+        ));
+    }
+    let sysroot = if toolchain.is_freestanding() {
+        format!("<CFGDIR>/../{target}")
+    } else {
+        toolchain.sysroot()?.display().to_string()
+    };
+    Ok(format!(
+        "--target={target}\n--sysroot={sysroot}\n--gcc-toolchain=<CFGDIR>/..\n-fuse-ld=lld\n"
+    ))
+}
+
+/// The clang configuration file compiling against an installed `toolchain` with a clang that
+/// toolup didn't build, `toolup clang-config`. The host's clang may not have lld, so programs are
+/// linked with the toolchain's binutils (`-B`), and GCC's runtime libraries are added to the
+/// library search paths.
+pub fn system_clang_config(toolchain: &Toolchain) -> Result<String> {
+    if !toolchain.gcc_bin()?.exists() {
+        return Err(Failure::Usage).context(format!(
+            "{} is not installed, install it with `toolup install {}`",
+            toolchain.id(),
+            toolchain.name()
+        ));
+    }
+    // the builtins are in the resource directory of the toolchain's clang
+    if toolchain.target.is_wasi() {
+        return Err(Failure::Usage).context(format!(
+            "{} has its own clang, compile with `{}`",
+            toolchain.id(),
+            toolchain.gcc_bin()?.display()
+        ));
+    }
+    let target = toolchain.target.to_target_string();
+    let dir = toolchain.dir()?;
+    let mut config = format!(
+        "--target={target}\n--sysroot={}\n--gcc-toolchain={}\n-B{}\n",
+        clang_sysroot(toolchain)?.display(),
+        dir.display(),
+        toolchain.bin_dir()?.display()
+    );
+    // clang finds crtbegin.o through `-B`, libgcc and libstdc++ through `-L`
+    let gcc_lib = dir
+        .join("lib/gcc")
+        .join(&target)
+        .join(toolchain.gcc.version.to_string());
+    config.push_str(&format!("-B{}\n", gcc_lib.display()));
+    for lib_dir in [
+        gcc_lib.clone(),
+        dir.join(&target).join("lib64"),
+        dir.join(&target).join("lib"),
+    ] {
+        if lib_dir.is_dir() {
+            config.push_str(&format!("-L{}\n", lib_dir.display()));
+        }
+    }
+    Ok(config)
+}
+
+/// Write `<target>.cfg` and the `<target>-clang` and `<target>-clang++` scripts running clang with
+/// it. The scripts find clang and the file next to them, like the paths in [`clang_config`].
+fn install_clang_wrappers(toolchain: &Toolchain) -> Result<()> {
+    let target = toolchain.target.to_target_string();
+    if is_plan() {
+        plan_step(format!(
+            "write {target}.cfg and the {target}-clang and {target}-clang++ wrappers"
+        ));
+        return Ok(());
+    }
+
+    let bin_dir = toolchain.bin_dir()?;
+    let config = bin_dir.join(format!("{target}.cfg"));
+    std::fs::write(&config, clang_config(toolchain)?)
+        .context(format!("failed to write `{}`", config.display()))?;
+    for driver in ["clang", "clang++"] {
+        let wrapper = bin_dir.join(format!("{target}-{driver}"));
+        std::fs::write(
+            &wrapper,
+            format!(
+                "#!/bin/sh\nbin=\"$(dirname \"$0\")\"\nexec \"$bin/{driver}\" --config=\"$bin/{target}.cfg\" \"$@\"\n"
+            ),
+        )
+        .context(format!("failed to write `{}`", wrapper.display()))?;
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+    }
+    log::info!("=> compile with {target}-clang and {target}-clang++");
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LlvmVersion(pub u64, pub u64, pub u64);
+
+impl Default for LlvmVersion {
+    fn default() -> Self {
+        Self(20, 1, 8)
+    }
+}
+
+impl FromStr for LlvmVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(".").collect();
+
+        fn parse_part(s: &str) -> anyhow::Result<u64> {
+            s.parse().context(format!("`{}` is not a number", s))
+        }
+
+        match parts.as_slice() {
+            [major, minor, patch] => Ok(LlvmVersion(
+                parse_part(major)?,
+                parse_part(minor)?,
+                parse_part(patch)?,
+            )),
+            _ => Err(anyhow!("`{}` is an invalid llvm version", s)),
+        }
+    }
+}
+
+impl Display for LlvmVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+serde_string!(LlvmVersion);
+
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+pub struct Llvm {
+    pub version: LlvmVersion,
+}
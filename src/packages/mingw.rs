@@ -0,0 +1,186 @@
+//! mingw-w64 for `x86_64-pc-windows-gnu` toolchains.
+//!
+//! The headers and the CRT are installed under `<sysroot>/mingw`, where GCC configured for a
+//! `*-w64-mingw32` target with `--with-sysroot` looks for them (`/mingw/include` and
+//! `/mingw/lib`). The headers are installed before the stage1 compiler, which builds the CRT.
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    commands::run_command_in,
+    packages::{BuildContext, Package, Source, glibc::cross_env, install_package},
+    profile::{Libc, Toolchain},
+};
+
+fn mingw_source(version: &MingwVersion) -> Source {
+    Source::for_package(
+        "mingw-w64",
+        &version.to_string(),
+        format!(
+            "https://downloads.sourceforge.net/project/mingw-w64/mingw-w64/mingw-w64-release/mingw-w64-v{version}.tar.bz2"
+        ),
+        format!("mingw-w64-v{version}"),
+    )
+}
+
+fn mingw_version(toolchain: &Toolchain) -> Result<MingwVersion> {
+    let Libc::Mingw(version) = toolchain.libc else {
+        return Err(anyhow!("{} doesn't use mingw-w64", toolchain.id()));
+    };
+    Ok(version)
+}
+
+/// Install the mingw-w64 headers in the toolchain's sysroot.
+pub fn install_mingw_headers(toolchain: &Toolchain) -> Result<()> {
+    let version = mingw_version(toolchain)?;
+    install_package(&MingwPackage::headers(toolchain, version), 1)
+}
+
+/// Build the mingw-w64 CRT with the stage1 compiler and install it in the toolchain's sysroot.
+pub fn install_mingw_crt(toolchain: &Toolchain, jobs: u64) -> Result<()> {
+    let version = mingw_version(toolchain)?;
+    install_package(&MingwPackage::crt(toolchain, version), jobs)
+}
+
+/// A directory of the mingw-w64 source tree installed into the toolchain's sysroot.
+pub struct MingwPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub version: MingwVersion,
+    /// `mingw-w64-headers` or `mingw-w64-crt`
+    pub component: &'static str,
+}
+
+impl<'a> MingwPackage<'a> {
+    pub fn headers(toolchain: &'a Toolchain, version: MingwVersion) -> Self {
+        Self {
+            toolchain,
+            version,
+            component: "mingw-w64-headers",
+        }
+    }
+
+    pub fn crt(toolchain: &'a Toolchain, version: MingwVersion) -> Self {
+        Self {
+            toolchain,
+            version,
+            component: "mingw-w64-crt",
+        }
+    }
+
+    fn env(&self) -> Result<Vec<(OsString, OsString)>> {
+        if self.component == "mingw-w64-headers" {
+            // only copies headers, the cross compiler doesn't exist yet
+            return Ok(vec![("PATH".into(), self.toolchain.env_path()?)]);
+        }
+        cross_env(self.toolchain)
+    }
+}
+
+impl Package for MingwPackage<'_> {
+    fn name(&self) -> String {
+        self.component.into()
+    }
+
+    fn version(&self) -> String {
+        self.version.to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        vec![mingw_source(&self.version)]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-{}-{}", self.component, self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        let mut args = vec![
+            format!("--host={}", self.toolchain.target),
+            format!(
+                "--prefix={}",
+                self.toolchain.sysroot()?.join("mingw").display()
+            ),
+        ];
+        if self.component == "mingw-w64-crt" {
+            args.push(format!(
+                "--with-sysroot={}",
+                self.toolchain.sysroot()?.display()
+            ));
+            // GCC is built with --disable-multilib, there's no 32-bit compiler for lib32
+            args.push("--disable-lib32".into());
+            args.push("--enable-lib64".into());
+        }
+
+        run_command_in(
+            &ctx.objdir,
+            "configure",
+            ctx.source_dir.join(self.component).join("configure"),
+            &args,
+            Some(self.env()?),
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &["-j", ctx.jobs.to_string().as_str()],
+            Some(self.env()?),
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &["install", "-j", ctx.jobs.to_string().as_str()],
+            Some(self.env()?),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MingwVersion(pub u64, pub u64, pub u64);
+
+impl Default for MingwVersion {
+    fn default() -> Self {
+        Self(13, 0, 0)
+    }
+}
+
+impl FromStr for MingwVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(".").collect();
+
+        fn parse_part(s: &str) -> anyhow::Result<u64> {
+            s.parse().context(format!("`{}` is not a number", s))
+        }
+
+        match parts.as_slice() {
+            [major, minor, patch] => Ok(MingwVersion(
+                parse_part(major)?,
+                parse_part(minor)?,
+                parse_part(patch)?,
+            )),
+            _ => Err(anyhow!("`{}` is an invalid mingw-w64 version", s)),
+        }
+    }
+}
+
+impl Display for MingwVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+serde_string!(MingwVersion);
@@ -1,9 +1,299 @@
 //! A collection of packages that can be installed and built from source.
+//!
+//! Every package implements [`Package`] and is installed by [`install_package`], which fetches the
+//! sources, installs dependencies and runs the configure/build/install steps in order.
 
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{self, ArtifactKind},
+    commands::{create_dir_all, is_plan, plan_step, staging},
+    download::{cache_dir, download_and_decompress},
+    journal, locks,
+    packages::gcc::GccStage,
+    profile::{Libc, Toolchain},
+    provenance,
+};
+
+pub mod android;
 pub mod binutils;
 pub mod busybox;
+pub mod freebsd;
 pub mod gcc;
+pub mod gdb;
 pub mod glibc;
 pub mod gnu_make;
+pub mod host_tools;
 pub mod linux;
+pub mod llvm;
+pub mod mingw;
 pub mod musl;
+pub mod newlib;
+pub mod sysroot_libs;
+pub mod wasi;
+
+/// A source archive of a package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub url: String,
+    /// The directory the archive extracts to, relative to the cache directory.
+    pub dirname: String,
+}
+
+impl Source {
+    pub fn new(url: impl Into<String>, dirname: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            dirname: dirname.into(),
+        }
+    }
+
+    /// The source archive of `package` at `version`, `url` and `dirname` are the defaults used
+    /// unless `[sources]` overrides them.
+    pub fn for_package(
+        package: &str,
+        version: &str,
+        url: impl Into<String>,
+        dirname: impl Into<String>,
+    ) -> Self {
+        let Some(source) = SOURCE_OVERRIDES.get().and_then(|o| o.get(package)) else {
+            return Self::new(url, dirname);
+        };
+        match source {
+            SourceOverride::Url(template) => Self::new(expand(template, version), dirname),
+            SourceOverride::Full {
+                url: template,
+                dirname,
+            } => Self::new(expand(template, version), expand(dirname, version)),
+        }
+    }
+}
+
+static SOURCE_OVERRIDES: OnceLock<HashMap<String, SourceOverride>> = OnceLock::new();
+
+/// A `[sources]` entry, replacing where a package's source archive is downloaded from.
+///
+/// URLs and directory names may use `{version}`, `{major}` and `{minor}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SourceOverride {
+    /// The archive URL, for archives that extract to the same directory as the default one
+    Url(String),
+    /// The archive URL and the directory it extracts to
+    Full { url: String, dirname: String },
+}
+
+/// Set the `[sources]` overrides, keyed by package name (e.g. `gcc`, `linux` or `busybox`).
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_source_overrides(overrides: HashMap<String, SourceOverride>) {
+    let _ = SOURCE_OVERRIDES.set(overrides);
+}
+
+/// Expand the version variables in a `[sources]` template.
+pub fn expand(template: &str, version: &str) -> String {
+    let mut parts = version.split('.');
+    let major = parts.next().unwrap_or_default();
+    let minor = parts.next().unwrap_or_default();
+    template
+        .replace("{version}", version)
+        .replace("{major}", major)
+        .replace("{minor}", minor)
+}
+
+/// The directories a package is built in.
+pub struct BuildContext {
+    /// The extracted source tree of the first [`Package::sources`] entry.
+    pub source_dir: PathBuf,
+    /// The directory configure and make run in.
+    pub objdir: PathBuf,
+    pub jobs: u64,
+}
+
+/// A package built from source.
+///
+/// Steps that a package doesn't need can be left to their default implementation.
+pub trait Package {
+    /// e.g. `gcc`
+    fn name(&self) -> String;
+
+    fn version(&self) -> String;
+
+    /// The source archives of the package. The first archive is the package's source tree.
+    fn sources(&self) -> Vec<Source>;
+
+    /// Packages that must be installed before this one.
+    fn dependencies(&self) -> Result<Vec<Box<dyn Package + '_>>> {
+        Ok(vec![])
+    }
+
+    /// Download and extract the sources, returning the source tree.
+    fn fetch(&self) -> Result<PathBuf> {
+        let sources = self.sources();
+        let Some((main, extra)) = sources.split_first() else {
+            bail!("package `{}` has no sources", self.name());
+        };
+        for source in extra {
+            fetch_source(source)?;
+        }
+        fetch_source(main)
+    }
+
+    /// Returns the directory to build in. Defaults to the source tree.
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.to_path_buf())
+    }
+
+    fn configure(&self, _ctx: &BuildContext) -> Result<()> {
+        Ok(())
+    }
+
+    fn build(&self, _ctx: &BuildContext) -> Result<()> {
+        Ok(())
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()>;
+
+    /// Remove build artifacts. Defaults to removing the objdir if it's not the source tree.
+    fn clean(&self, ctx: &BuildContext) -> Result<()> {
+        if ctx.objdir != ctx.source_dir && ctx.objdir.exists() {
+            std::fs::remove_dir_all(&ctx.objdir)
+                .context(format!("failed to remove `{}`", ctx.objdir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Download and extract `source`. The hash of the archive is recorded in the cache manifest, see
+/// `toolup cache verify`.
+pub fn fetch_source(source: &Source) -> Result<PathBuf> {
+    let _span = tracing::info_span!("fetch_source", url = source.url).entered();
+    provenance::record_source(&source.url, &source.dirname);
+    download_and_decompress(&source.url, &source.dirname, true)
+        .context(format!("failed to download {}", source.url))
+}
+
+/// Install `package` and its dependencies.
+pub fn install_package(package: &dyn Package, jobs: u64) -> Result<()> {
+    for dependency in package.dependencies()? {
+        install_package(dependency.as_ref(), jobs)?;
+    }
+
+    let _span = tracing::info_span!(
+        "install_package",
+        name = package.name(),
+        version = package.version()
+    )
+    .entered();
+
+    log::info!("=> install {} {}", package.name(), package.version());
+
+    let ctx = prepare(package, jobs)?;
+    // objdirs shared by toolchains installed in parallel, e.g. a source tree, are built in turn
+    let _lock = locks::lock(format!("objdir:{}", ctx.objdir.display()));
+    journal::set_package(Some(format!("{} {}", package.name(), package.version())));
+    let result = package
+        .configure(&ctx)
+        .and_then(|_| package.build(&ctx))
+        .and_then(|_| package.install(&ctx));
+    journal::set_package(None);
+    result?;
+    // packages building in their source tree have no objdir of their own
+    if ctx.objdir != ctx.source_dir {
+        cache::record_artifact(&ctx.objdir, ArtifactKind::Objdir, staging())?;
+    }
+    Ok(())
+}
+
+/// Remove the build artifacts of `package`, without downloading its sources.
+pub fn clean_package(package: &dyn Package) -> Result<()> {
+    let Some(main) = package.sources().into_iter().next() else {
+        return Ok(());
+    };
+    let source_dir = cache_dir()?.join(main.dirname);
+    let objdir = package.objdir(&source_dir)?;
+
+    package.clean(&BuildContext {
+        source_dir,
+        objdir,
+        jobs: 1,
+    })
+}
+
+/// The packages built from source for `toolchain`, whether or not they are installed.
+pub fn toolchain_packages(toolchain: &Toolchain) -> Vec<Box<dyn Package + '_>> {
+    let mut packages: Vec<Box<dyn Package + '_>> = vec![];
+    if !toolchain.target.is_wasi() {
+        packages.push(Box::new(binutils::BinutilsPackage { toolchain }));
+        for stage in [GccStage::Stage1, GccStage::Final(None), GccStage::Newlib] {
+            packages.push(Box::new(gcc::GccPackage { toolchain, stage }));
+        }
+    }
+    match toolchain.libc {
+        Libc::Glibc(version) => {
+            packages.push(Box::new(linux::HeadersPackage { toolchain }));
+            packages.push(Box::new(glibc::GlibcPackage { toolchain, version }));
+        }
+        Libc::Musl(version) => {
+            packages.push(Box::new(linux::HeadersPackage { toolchain }));
+            packages.push(Box::new(musl::MuslPackage { toolchain, version }));
+        }
+        Libc::Mingw(version) => {
+            packages.push(Box::new(mingw::MingwPackage::headers(toolchain, version)));
+            packages.push(Box::new(mingw::MingwPackage::crt(toolchain, version)));
+        }
+        Libc::FreeBsd(version) => {
+            packages.push(Box::new(freebsd::FreeBsdBasePackage { toolchain, version }))
+        }
+        Libc::Bionic(api) => packages.push(Box::new(android::NdkSysrootPackage { toolchain, api })),
+        Libc::Newlib(_) => packages.push(Box::new(newlib::NewlibPackage { toolchain })),
+        Libc::WasiLibc(version) => {
+            packages.push(Box::new(wasi::WasiLibcPackage { toolchain, version }))
+        }
+    }
+    if let Some(gdb) = &toolchain.gdb {
+        packages.push(Box::new(gdb::GdbPackage { toolchain, gdb }));
+    }
+    if let Some(llvm) = &toolchain.llvm {
+        packages.push(Box::new(llvm::LlvmPackage { toolchain, llvm }));
+        if toolchain.target.is_wasi() {
+            packages.push(Box::new(wasi::BuiltinsPackage { toolchain, llvm }));
+        }
+    }
+    packages
+}
+
+/// Remove the build artifacts of every package of `toolchain`, `toolup cache clean`. Sources and
+/// the installed toolchain are kept.
+pub fn clean_toolchain(toolchain: &Toolchain) -> Result<()> {
+    for package in toolchain_packages(toolchain) {
+        if is_plan() {
+            plan_step(format!("clean {} {}", package.name(), package.version()));
+            continue;
+        }
+        log::info!("=> clean {} {}", package.name(), package.version());
+        clean_package(package.as_ref()).context(format!("failed to clean {}", package.name()))?;
+    }
+    Ok(())
+}
+
+fn prepare(package: &dyn Package, jobs: u64) -> Result<BuildContext> {
+    let source_dir = package
+        .fetch()
+        .context(format!("failed to fetch {}", package.name()))?;
+    let objdir = package.objdir(&source_dir)?;
+    create_dir_all(&objdir)?;
+
+    Ok(BuildContext {
+        source_dir,
+        objdir,
+        jobs,
+    })
+}
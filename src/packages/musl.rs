@@ -1,86 +1,106 @@
-use std::{ffi::OsString, fmt::Display, path::PathBuf, str::FromStr};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use anyhow::{Context, Result, anyhow};
 
 use crate::{
     commands::run_command_in,
-    download::download_and_decompress,
+    packages::{BuildContext, Package, Source, fetch_source, glibc::cross_env, install_package},
     profile::{Libc, Toolchain},
 };
 
 pub fn download_musl(version: impl AsRef<str>) -> Result<PathBuf> {
     log::info!("=> download musl");
-    let version = version.as_ref();
-    let tarball = format!("musl-{version}.tar.gz");
-    let url = format!(
-        "https://musl.libc.org/releases/{tarball}",
-        tarball = &tarball
-    );
-
-    let musl_dir = download_and_decompress(&url, format!("musl-{version}"), true)
-        .context(format!("failed to download {tarball}"))?;
-
-    Ok(musl_dir)
+    fetch_source(&musl_source(version.as_ref()))
 }
 
-/// Build musl and install it in the toolchain's sysroot.
-pub fn install_musl_sysroot(toolchain: &Toolchain) -> Result<()> {
-    log::info!("=> install musl");
+fn musl_source(version: &str) -> Source {
+    Source::for_package(
+        "musl",
+        version,
+        format!("https://musl.libc.org/releases/musl-{version}.tar.gz"),
+        format!("musl-{version}"),
+    )
+}
 
-    let Libc::Musl(musl_version) = toolchain.libc else {
+/// Build musl with `jobs` threads and install it in the toolchain's sysroot.
+pub fn install_musl_sysroot(toolchain: &Toolchain, jobs: u64) -> Result<()> {
+    let Libc::Musl(version) = toolchain.libc else {
         return Err(anyhow!(
             "`install_musl_sysroot` called with a glibc toolchain"
         ));
     };
 
-    let musl_dir = download_musl(musl_version.to_string())?;
-    let objdir = musl_dir.join(format!("objdir-arch-{}", toolchain.id()));
-    std::fs::create_dir_all(&objdir)?;
-
-    let args = vec![
-        format!("--host={}", toolchain.target),
-        "--prefix=/usr".into(),
-        "--syslibdir=/lib".into(),
-        "--disable-werror".into(),
-    ];
-    let prefix = toolchain.target;
-
-    let env: Vec<(OsString, OsString)> = vec![
-        ("BUILD_CC".into(), "gcc".into()),
-        ("BUILD_CXX".into(), "g++".into()),
-        ("BUILD_AR".into(), "ar".into()),
-        ("BUILD_RANLIB".into(), "ranlib".into()),
-        ("CC".into(), format!("{prefix}-gcc").into()),
-        ("CXX".into(), format!("{prefix}-g++").into()),
-        ("AR".into(), format!("{prefix}-ar").into()),
-        ("RANLIB".into(), format!("{prefix}-ranlib").into()),
-        ("LD".into(), format!("{prefix}-ld").into()),
-        ("READELF".into(), format!("{prefix}-readelf").into()),
-        ("PATH".into(), toolchain.env_path()?),
-    ];
-    run_command_in(
-        &objdir,
-        "configure",
-        objdir.parent().unwrap().join("configure"),
-        &args,
-        Some(env.clone()),
-    )?;
-
-    run_command_in(&objdir, "make", "make", &["-j", "28"], Some(env.clone()))?;
-    run_command_in(
-        &objdir,
-        "make",
-        "make",
-        &[
-            "install",
-            &format!("DESTDIR={}", toolchain.sysroot()?.display()),
-            "-j",
-            "28",
-        ],
-        Some(env.clone()),
-    )?;
-
-    Ok(())
+    install_package(&MuslPackage { toolchain, version }, jobs)
+}
+
+/// musl installed into the toolchain's sysroot.
+pub struct MuslPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub version: MuslVersion,
+}
+
+impl Package for MuslPackage<'_> {
+    fn name(&self) -> String {
+        "musl".into()
+    }
+
+    fn version(&self) -> String {
+        self.version.to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        vec![musl_source(&self.version.to_string())]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-arch-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        let args = vec![
+            format!("--host={}", self.toolchain.target),
+            "--prefix=/usr".into(),
+            "--syslibdir=/lib".into(),
+            "--disable-werror".into(),
+        ];
+
+        run_command_in(
+            &ctx.objdir,
+            "configure",
+            ctx.source_dir.join("configure"),
+            &args,
+            Some(cross_env(self.toolchain)?),
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &["-j", ctx.jobs.to_string().as_str()],
+            Some(cross_env(self.toolchain)?),
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &[
+                "install",
+                &format!("DESTDIR={}", self.toolchain.sysroot()?.display()),
+                "-j",
+                ctx.jobs.to_string().as_str(),
+            ],
+            Some(cross_env(self.toolchain)?),
+        )
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -118,3 +138,5 @@ impl Display for MuslVersion {
         write!(f, "{}.{}.{}", self.0, self.1, self.2)
     }
 }
+
+serde_string!(MuslVersion);
@@ -0,0 +1,294 @@
+//! newlib for freestanding toolchains with [`Libc::Newlib`] or built with [`Profile::Nano`].
+//!
+//! newlib and libgloss are installed into the toolchain's directory and GCC is rebuilt against
+//! them `--with-newlib`, which adds libstdc++ to the stage1 compiler.
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow};
+use walkdir::WalkDir;
+
+use crate::{
+    commands::{is_plan, plan_step, run_command_in},
+    download::cache_dir,
+    packages::{BuildContext, Package, Source, install_package},
+    profile::{Libc, Profile, Toolchain},
+};
+
+/// Libraries that `nano.specs` links as `-l<name>_nano`.
+const NANO_LIBS: &[&str] = &["c", "g", "m", "rdimon", "stdc++", "supc++"];
+
+/// A `nano.specs` for targets whose libgloss doesn't install one, it's the same as ARM's.
+const NANO_SPECS: &str = "%rename link                nano_link
+%rename link_gcc_c_sequence                nano_link_gcc_c_sequence
+
+*nano_libc:
+-lc_nano
+
+*nano_libgloss:
+%{specs=rdimon.specs:-lrdimon_nano} %{specs=nosys.specs:-lnosys}
+
+*link_gcc_c_sequence:
+%(nano_link_gcc_c_sequence) --start-group %G %(nano_libc) %(nano_libgloss) --end-group
+
+*link:
+%(nano_link) %:replace-outfile(-lc -lc_nano) %:replace-outfile(-lg -lg_nano) %:replace-outfile(-lrdimon -lrdimon_nano) %:replace-outfile(-lstdc++ -lstdc++_nano) %:replace-outfile(-lsupc++ -lsupc++_nano)
+
+*lib:
+%{!shared:%{g*:-lg_nano} %{!p:%{!pg:-lc_nano}}%{p:-lc_p}%{pg:-lc_p}}
+";
+
+pub fn install_newlib(toolchain: &Toolchain, jobs: u64) -> Result<()> {
+    install_package(&NewlibPackage { toolchain }, jobs)
+}
+
+/// newlib installed into the toolchain's directory.
+pub struct NewlibPackage<'a> {
+    pub toolchain: &'a Toolchain,
+}
+
+impl NewlibPackage<'_> {
+    /// The version of [`Libc::Newlib`], nano toolchains without it use the default one.
+    fn newlib_version(&self) -> NewlibVersion {
+        match self.toolchain.libc {
+            Libc::Newlib(version) => version,
+            _ => NewlibVersion::default(),
+        }
+    }
+}
+
+impl Package for NewlibPackage<'_> {
+    fn name(&self) -> String {
+        match self.toolchain.profile {
+            Profile::Nano => "newlib-nano".into(),
+            Profile::Default => "newlib".into(),
+        }
+    }
+
+    fn version(&self) -> String {
+        self.newlib_version().to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        let version = self.newlib_version();
+        vec![Source::for_package(
+            "newlib",
+            &version.to_string(),
+            format!("https://sourceware.org/pub/newlib/newlib-{version}.tar.gz"),
+            format!("newlib-{version}"),
+        )]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        let mut args: Vec<String> = vec![
+            format!("--target={}", self.toolchain.target.to_target_string()),
+            format!("--prefix={}", self.toolchain.dir()?.display()),
+            "--disable-nls".into(),
+            "--disable-multilib".into(),
+        ];
+        if self.toolchain.profile == Profile::Nano {
+            args.extend(
+                [
+                    "--enable-newlib-reent-small",
+                    "--disable-newlib-fvwrite-in-streamio",
+                    "--disable-newlib-fseek-optimization",
+                    "--disable-newlib-wide-orient",
+                    "--enable-newlib-nano-malloc",
+                    "--disable-newlib-unbuf-stream-opt",
+                    "--enable-lite-exit",
+                    "--enable-newlib-global-atexit",
+                    "--enable-newlib-nano-formatted-io",
+                ]
+                .map(String::from),
+            );
+        }
+
+        run_command_in(
+            &ctx.objdir,
+            "configure",
+            ctx.source_dir.join("configure"),
+            &args,
+            Some(self.env()?),
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &["-j", ctx.jobs.to_string().as_str()],
+            Some(self.env()?),
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(&ctx.objdir, "make", "make", &["install"], Some(self.env()?))
+    }
+}
+
+impl NewlibPackage<'_> {
+    fn env(&self) -> Result<Vec<(OsString, OsString)>> {
+        let cflags = match self.toolchain.profile {
+            Profile::Nano => "-Os -ffunction-sections -fdata-sections",
+            Profile::Default => "-O2 -ffunction-sections -fdata-sections",
+        };
+        Ok(vec![
+            ("PATH".into(), self.toolchain.env_path()?),
+            ("CFLAGS_FOR_TARGET".into(), cflags.into()),
+        ])
+    }
+}
+
+/// A newlib release, e.g. `4.5.0.20241231`. Releases since 4.2 end with the date of the snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NewlibVersion(pub u64, pub u64, pub u64, pub Option<u64>);
+
+impl Default for NewlibVersion {
+    fn default() -> Self {
+        Self(4, 5, 0, Some(20241231))
+    }
+}
+
+impl FromStr for NewlibVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(".").collect();
+
+        fn parse_part(s: &str) -> anyhow::Result<u64> {
+            s.parse().context(format!("`{}` is not a number", s))
+        }
+
+        match parts.as_slice() {
+            [major, minor, patch] => Ok(NewlibVersion(
+                parse_part(major)?,
+                parse_part(minor)?,
+                parse_part(patch)?,
+                None,
+            )),
+            [major, minor, patch, date] => Ok(NewlibVersion(
+                parse_part(major)?,
+                parse_part(minor)?,
+                parse_part(patch)?,
+                Some(parse_part(date)?),
+            )),
+            _ => Err(anyhow!("`{}` is an invalid newlib version", s)),
+        }
+    }
+}
+
+impl Display for NewlibVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)?;
+        if let Some(date) = self.3 {
+            write!(f, ".{date}")?;
+        }
+        Ok(())
+    }
+}
+
+serde_string!(NewlibVersion);
+
+/// The newlib selected by the libc of a freestanding toolchain, `newlib` for the default version
+/// or `newlib-<version>`. `None` for anything else, freestanding toolchains have no libc then.
+pub fn parse_libc(libc: &str) -> Option<Result<NewlibVersion>> {
+    match libc {
+        "newlib" => Some(Ok(NewlibVersion::default())),
+        _ => libc.strip_prefix("newlib-").map(NewlibVersion::from_str),
+    }
+}
+
+/// Make `--specs=nano.specs` work: the libraries it links are aliased to the nano build, and a
+/// `nano.specs` is installed if libgloss doesn't provide one for the target.
+pub fn install_nano_specs(toolchain: &Toolchain) -> Result<()> {
+    if is_plan() {
+        plan_step("alias the newlib libraries as lib*_nano.a and install nano.specs");
+        return Ok(());
+    }
+
+    let lib_dir = toolchain
+        .dir()?
+        .join(toolchain.target.to_target_string())
+        .join("lib");
+    for entry in WalkDir::new(&lib_dir) {
+        let entry = entry.context(format!("failed to walk `{}`", lib_dir.display()))?;
+        let name = entry.file_name().to_string_lossy();
+        let Some(lib) = name
+            .strip_prefix("lib")
+            .and_then(|name| name.strip_suffix(".a"))
+        else {
+            continue;
+        };
+        if NANO_LIBS.contains(&lib) {
+            let nano = entry.path().with_file_name(format!("lib{lib}_nano.a"));
+            std::fs::copy(entry.path(), &nano)
+                .context(format!("failed to create `{}`", nano.display()))?;
+        }
+    }
+
+    let specs = lib_dir.join("nano.specs");
+    if !specs.exists() {
+        std::fs::write(&specs, NANO_SPECS)
+            .context(format!("failed to write `{}`", specs.display()))?;
+    }
+    Ok(())
+}
+
+/// Link a hello world with the nano profile and log its size.
+pub fn report_size(toolchain: &Toolchain) -> Result<()> {
+    if is_plan() {
+        plan_step("build a hello world with --specs=nano.specs and report its size");
+        return Ok(());
+    }
+
+    let workdir = cache_dir()?.join("smoke").join(toolchain.id());
+    std::fs::create_dir_all(&workdir)?;
+    std::fs::write(
+        workdir.join("hello.c"),
+        "#include <stdio.h>\nint main(void) { printf(\"hello world\\n\"); return 0; }\n",
+    )?;
+
+    let target = toolchain.target.to_target_string();
+    let output = Command::new(toolchain.gcc_bin()?)
+        .args([
+            "-Os",
+            "-ffunction-sections",
+            "-fdata-sections",
+            "-Wl,--gc-sections",
+            "--specs=nano.specs",
+            "--specs=nosys.specs",
+            "hello.c",
+            "-o",
+            "hello",
+        ])
+        .current_dir(&workdir)
+        .env("PATH", toolchain.env_path()?)
+        .output()
+        .context("failed to run gcc")?;
+    if !output.status.success() {
+        log::warn!(
+            "failed to link a hello world with nano.specs:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Ok(());
+    }
+
+    let size = Command::new(toolchain.bin_dir()?.join(format!("{target}-size")))
+        .arg("hello")
+        .current_dir(&workdir)
+        .output()
+        .context("failed to run size")?;
+    log::info!("=> hello world size (nano.specs, -Os)");
+    log::info!("{}", String::from_utf8_lossy(&size.stdout).trim_end());
+    Ok(())
+}
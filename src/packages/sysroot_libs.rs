@@ -0,0 +1,184 @@
+//! Libraries that can be added to a sysroot with `toolup sysroot add`.
+//!
+//! Each library is installed into a staging directory with `DESTDIR`, then copied into the
+//! sysroot by [`crate::sysroot::add_package`] which records the installed files.
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::{
+    commands::run_command_in,
+    packages::{BuildContext, Package, Source, glibc::cross_env},
+    profile::{Abi, Arch, Target, Toolchain},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysrootLibKind {
+    Zlib,
+    Openssl,
+}
+
+/// A library and its version, parsed from `name[@version]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysrootLib {
+    pub kind: SysrootLibKind,
+    pub version: String,
+}
+
+impl FromStr for SysrootLib {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, version) = match s.split_once('@') {
+            Some((name, version)) => (name, Some(version)),
+            None => (s, None),
+        };
+        let (kind, default) = match name {
+            "zlib" => (SysrootLibKind::Zlib, "1.3.1"),
+            "openssl" => (SysrootLibKind::Openssl, "3.5.4"),
+            _ => {
+                return Err(anyhow!(
+                    "unknown sysroot package `{name}`, expected one of: zlib, openssl"
+                ));
+            }
+        };
+        Ok(SysrootLib {
+            kind,
+            version: version.unwrap_or(default).to_string(),
+        })
+    }
+}
+
+impl Display for SysrootLib {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.name(), self.version)
+    }
+}
+
+impl SysrootLib {
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            SysrootLibKind::Zlib => "zlib",
+            SysrootLibKind::Openssl => "openssl",
+        }
+    }
+}
+
+/// A [`SysrootLib`] built for `toolchain` against `sysroot` and installed into `destdir`.
+pub struct SysrootLibPackage<'a> {
+    pub lib: SysrootLib,
+    pub toolchain: &'a Toolchain,
+    pub sysroot: PathBuf,
+    pub destdir: PathBuf,
+}
+
+impl SysrootLibPackage<'_> {
+    fn env(&self) -> Result<Vec<(OsString, OsString)>> {
+        let mut env = cross_env(self.toolchain)?;
+        let sysroot = format!("--sysroot={}", self.sysroot.display());
+        env.push(("CFLAGS".into(), format!("-O2 {sysroot}").into()));
+        env.push(("LDFLAGS".into(), sysroot.into()));
+        Ok(env)
+    }
+
+    fn make(&self, ctx: &BuildContext, args: &[&str]) -> Result<()> {
+        let jobs = ctx.jobs.to_string();
+        let mut args = args.to_vec();
+        args.extend(["-j", jobs.as_str()]);
+        run_command_in(&ctx.objdir, "make", "make", &args, Some(self.env()?))
+    }
+}
+
+impl Package for SysrootLibPackage<'_> {
+    fn name(&self) -> String {
+        self.lib.name().into()
+    }
+
+    fn version(&self) -> String {
+        self.lib.version.clone()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        let version = &self.lib.version;
+        let url = match self.lib.kind {
+            SysrootLibKind::Zlib => format!("https://zlib.net/fossils/zlib-{version}.tar.gz"),
+            SysrootLibKind::Openssl => format!(
+                "https://github.com/openssl/openssl/releases/download/openssl-{version}/openssl-{version}.tar.gz"
+            ),
+        };
+        vec![Source::for_package(
+            self.lib.name(),
+            version,
+            url,
+            format!("{}-{version}", self.lib.name()),
+        )]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-sysroot-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        let (configure, args): (PathBuf, Vec<String>) = match self.lib.kind {
+            SysrootLibKind::Zlib => (
+                ctx.source_dir.join("configure"),
+                vec!["--prefix=/usr".into()],
+            ),
+            SysrootLibKind::Openssl => {
+                let Some(platform) = openssl_platform(&self.toolchain.target) else {
+                    bail!("openssl doesn't support {}", self.toolchain.target);
+                };
+                (
+                    ctx.source_dir.join("Configure"),
+                    vec![
+                        platform.into(),
+                        "--prefix=/usr".into(),
+                        "--libdir=lib".into(),
+                        "no-tests".into(),
+                    ],
+                )
+            }
+        };
+
+        run_command_in(
+            &ctx.objdir,
+            "configure",
+            configure,
+            &args,
+            Some(self.env()?),
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        self.make(ctx, &[])
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        let destdir = format!("DESTDIR={}", self.destdir.display());
+        match self.lib.kind {
+            SysrootLibKind::Zlib => self.make(ctx, &["install", &destdir]),
+            // skip the documentation
+            SysrootLibKind::Openssl => self.make(ctx, &["install_sw", &destdir]),
+        }
+    }
+}
+
+/// The OpenSSL `Configure` target for `target`.
+fn openssl_platform(target: &Target) -> Option<&'static str> {
+    match target.arch {
+        Arch::X86_64 if target.abi == Abi::GnuX32 => Some("linux-x32"),
+        Arch::X86_64 => Some("linux-x86_64"),
+        Arch::I486 | Arch::I586 | Arch::I686 => Some("linux-x86"),
+        Arch::Aarch64 => Some("linux-aarch64"),
+        Arch::Armv7 => Some("linux-armv4"),
+        Arch::Riscv64 => Some("linux64-riscv64"),
+        Arch::Ppc64Le => Some("linux-ppc64le"),
+        Arch::Ppc64 => Some("linux-ppc64"),
+        Arch::Avr | Arch::Bpf | Arch::Xtensa | Arch::Wasm32 => None,
+    }
+}
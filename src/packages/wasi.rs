@@ -0,0 +1,244 @@
+//! wasi-libc and the compiler-rt builtins for `wasm32-wasi` toolchains.
+//!
+//! GCC has no WebAssembly backend, so WASI toolchains are only clang and lld (see
+//! [`crate::packages::llvm`]), without GCC or binutils. clang links programs with
+//! `libclang_rt.builtins-wasm32.a` from its resource directory, which is built first with the new
+//! clang, then wasi-libc is built into the toolchain's sysroot. `<target>-clang` runs clang with
+//! `--target=wasm32-wasi --sysroot=<sysroot>`.
+//!
+//! libc++ isn't built, `wasm32-wasi-clang++` compiles C++ without the standard library.
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    commands::{is_plan, run_command_in},
+    packages::{
+        BuildContext, Package, Source,
+        host_tools::find_program,
+        install_package,
+        llvm::{Llvm, LlvmPackage},
+    },
+    profile::{Libc, Toolchain},
+};
+
+/// Build the compiler-rt builtins into clang's resource directory, then wasi-libc into the
+/// sysroot.
+pub fn install_wasi_libc(toolchain: &Toolchain, jobs: u64) -> Result<()> {
+    let Libc::WasiLibc(version) = toolchain.libc else {
+        return Err(anyhow!("{} isn't a WASI toolchain", toolchain.id()));
+    };
+    let Some(llvm) = &toolchain.llvm else {
+        return Err(anyhow!("{} isn't built with clang", toolchain.id()));
+    };
+    install_package(&BuiltinsPackage { toolchain, llvm }, jobs)?;
+    install_package(&WasiLibcPackage { toolchain, version }, jobs)
+}
+
+/// compiler-rt's builtins for wasm32, installed into clang's resource directory.
+pub struct BuiltinsPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub llvm: &'a Llvm,
+}
+
+impl Package for BuiltinsPackage<'_> {
+    fn name(&self) -> String {
+        "compiler-rt builtins".into()
+    }
+
+    fn version(&self) -> String {
+        self.llvm.version.to_string()
+    }
+
+    // the llvm-project source clang was built from
+    fn sources(&self) -> Vec<Source> {
+        LlvmPackage {
+            toolchain: self.toolchain,
+            llvm: self.llvm,
+        }
+        .sources()
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-builtins-{}", self.toolchain.id())))
+    }
+
+    fn configure(&self, ctx: &BuildContext) -> Result<()> {
+        if !is_plan() {
+            find_program("cmake").context("`cmake` was not found, install it to build clang")?;
+        }
+        let dir = self.toolchain.dir()?;
+        let clang = dir.join("bin/clang");
+        run_command_in(
+            &ctx.objdir,
+            "cmake",
+            "cmake",
+            &[
+                "-S".to_string(),
+                ctx.source_dir
+                    .join("compiler-rt/lib/builtins")
+                    .display()
+                    .to_string(),
+                "-B".to_string(),
+                ctx.objdir.display().to_string(),
+                "-DCMAKE_BUILD_TYPE=Release".to_string(),
+                "-DCMAKE_SYSTEM_NAME=Generic".to_string(),
+                format!("-DCMAKE_C_COMPILER={}", clang.display()),
+                format!("-DCMAKE_ASM_COMPILER={}", clang.display()),
+                format!(
+                    "-DCMAKE_C_COMPILER_TARGET={}",
+                    self.toolchain.target.to_target_string()
+                ),
+                format!("-DCMAKE_AR={}", dir.join("bin/llvm-ar").display()),
+                format!("-DCMAKE_RANLIB={}", dir.join("bin/llvm-ranlib").display()),
+                // there's no libc to link a test program against yet
+                "-DCMAKE_C_COMPILER_WORKS=ON".to_string(),
+                // clang's resource directory, where it looks for `lib/wasi/libclang_rt.*`
+                format!(
+                    "-DCMAKE_INSTALL_PREFIX={}",
+                    dir.join("lib/clang")
+                        .join(self.llvm.version.0.to_string())
+                        .display()
+                ),
+                "-DCOMPILER_RT_BAREMETAL_BUILD=ON".to_string(),
+                "-DCOMPILER_RT_DEFAULT_TARGET_ONLY=ON".to_string(),
+                "-DCOMPILER_RT_HAS_FPIC_FLAG=OFF".to_string(),
+                "-DCOMPILER_RT_INCLUDE_TESTS=OFF".to_string(),
+                "-DCOMPILER_RT_OS_DIR=wasi".to_string(),
+                "-DLLVM_ENABLE_PER_TARGET_RUNTIME_DIR=OFF".to_string(),
+            ],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "cmake",
+            "cmake",
+            &["--build", ".", "-j", ctx.jobs.to_string().as_str()],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        run_command_in(
+            &ctx.objdir,
+            "cmake",
+            "cmake",
+            &["--install", "."],
+            None::<Vec<(OsString, OsString)>>,
+        )
+    }
+}
+
+/// wasi-libc installed into the toolchain's sysroot.
+pub struct WasiLibcPackage<'a> {
+    pub toolchain: &'a Toolchain,
+    pub version: WasiLibcVersion,
+}
+
+impl WasiLibcPackage<'_> {
+    /// The variables of wasi-libc's Makefile, it builds out of tree in `objdir`.
+    fn make_args(&self, ctx: &BuildContext) -> Result<Vec<String>> {
+        let bin_dir = self.toolchain.bin_dir()?;
+        Ok(vec![
+            "-C".to_string(),
+            ctx.source_dir.display().to_string(),
+            format!("CC={}", bin_dir.join("clang").display()),
+            format!("AR={}", bin_dir.join("llvm-ar").display()),
+            format!("NM={}", bin_dir.join("llvm-nm").display()),
+            format!("TARGET_TRIPLE={}", self.toolchain.target.to_target_string()),
+            format!("OBJDIR={}", ctx.objdir.join("obj").display()),
+            format!("SYSROOT={}", ctx.objdir.join("sysroot").display()),
+        ])
+    }
+}
+
+impl Package for WasiLibcPackage<'_> {
+    fn name(&self) -> String {
+        "wasi-libc".into()
+    }
+
+    fn version(&self) -> String {
+        self.version.to_string()
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        let version = self.version;
+        vec![Source::for_package(
+            "wasi-libc",
+            &version.to_string(),
+            format!(
+                "https://github.com/WebAssembly/wasi-libc/archive/refs/tags/wasi-sdk-{version}.tar.gz"
+            ),
+            format!("wasi-libc-wasi-sdk-{version}"),
+        )]
+    }
+
+    fn objdir(&self, source_dir: &Path) -> Result<PathBuf> {
+        Ok(source_dir.join(format!("objdir-{}", self.toolchain.id())))
+    }
+
+    fn build(&self, ctx: &BuildContext) -> Result<()> {
+        let mut args = self.make_args(ctx)?;
+        args.push(format!("-j{}", ctx.jobs));
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &args,
+            Some(vec![("PATH".into(), self.toolchain.env_path()?)]),
+        )
+    }
+
+    fn install(&self, ctx: &BuildContext) -> Result<()> {
+        let mut args = self.make_args(ctx)?;
+        args.push("install".to_string());
+        args.push(format!(
+            "INSTALL_DIR={}",
+            self.toolchain.sysroot()?.display()
+        ));
+        run_command_in(
+            &ctx.objdir,
+            "make",
+            "make",
+            &args,
+            Some(vec![("PATH".into(), self.toolchain.env_path()?)]),
+        )
+    }
+}
+
+/// A wasi-libc release, named after the wasi-sdk release it's tagged with, e.g. `25` for
+/// `wasi-sdk-25`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WasiLibcVersion(pub u64);
+
+impl Default for WasiLibcVersion {
+    fn default() -> Self {
+        Self(25)
+    }
+}
+
+impl FromStr for WasiLibcVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.parse()
+            .map(WasiLibcVersion)
+            .map_err(|_| anyhow!("`{}` is an invalid wasi-libc version", s))
+    }
+}
+
+impl Display for WasiLibcVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+serde_string!(WasiLibcVersion);
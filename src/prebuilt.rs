@@ -0,0 +1,330 @@
+//! Installing prebuilt toolchains from an index, `toolup install --prebuilt`.
+//!
+//! An index is a JSON file listing packed toolchains, written by `toolup publish index`:
+//!
+//! ```json
+//! {
+//!   "toolchains": [
+//!     {
+//!       "id": "aarch64-unknown-linux-gnu-gcc-15.2.0-bin-2.45-glibc-2.42",
+//!       "target": "aarch64-unknown-linux-gnu",
+//!       "gcc": "15.2.0",
+//!       "binutils": "2.45",
+//!       "libc": "glibc-2.42",
+//!       "profile": "default",
+//!       "host": "x86_64-linux",
+//!       "toolup": "0.1.0",
+//!       "url": "aarch64-unknown-linux-gnu-gcc-15.2.0-bin-2.45-glibc-2.42-x86_64-linux.tar.gz",
+//!       "size": 187695104,
+//!       "blake3": "…"
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! The archives are written by `toolup pack`, see [`crate::pack`]. Their URLs are relative to the
+//! index unless they're absolute. Toolchains missing from the index are built from source.
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::{create_dir_all, is_plan, plan_step},
+    download::{DownloadResult, decompress_tar, download_archive},
+    error::Failure,
+    pack::{PACK_METADATA, PackMetadata, read_metadata},
+    profile::Toolchain,
+    provenance, registry, specs,
+};
+
+/// The toolchains of an index.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Index {
+    pub toolchains: Vec<Entry>,
+}
+
+/// A packed toolchain in an [`Index`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The `toolup-pack.json` of the archive
+    #[serde(flatten)]
+    pub pack: PackMetadata,
+    /// Where the archive is downloaded from, relative to the index unless it's a URL
+    pub url: String,
+    /// The size of the archive in bytes
+    pub size: u64,
+    /// The blake3 hash of the archive
+    pub blake3: String,
+}
+
+impl Index {
+    /// The entry of `toolchain` that can be installed on this machine.
+    pub fn find(&self, toolchain: &Toolchain) -> Option<&Entry> {
+        self.toolchains
+            .iter()
+            .find(|entry| entry.pack.id == toolchain.id() && entry.pack.host == host())
+    }
+}
+
+/// Returns the index of the packed toolchains (`*.tar.gz`) in `dir`. Their URLs are `base_url`
+/// followed by the file name, or only the file name to host the index next to them.
+pub fn index_dir(dir: &Path, base_url: Option<&str>) -> Result<Index> {
+    let mut archives = vec![];
+    for entry in std::fs::read_dir(dir).context(format!("failed to read `{}`", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file() && path.to_string_lossy().ends_with(".tar.gz") {
+            archives.push(path);
+        }
+    }
+    archives.sort();
+
+    let mut index = Index::default();
+    for archive in archives {
+        let Some(pack) = read_metadata(&archive)? else {
+            log::warn!(
+                "`{}` has no {PACK_METADATA}, it wasn't written by `toolup pack`",
+                archive.display()
+            );
+            continue;
+        };
+        let name = archive
+            .file_name()
+            .context("archives have a file name")?
+            .to_string_lossy()
+            .into_owned();
+        let content =
+            std::fs::read(&archive).context(format!("failed to read `{}`", archive.display()))?;
+        index.toolchains.push(Entry {
+            pack,
+            url: match base_url {
+                Some(base) => format!("{}/{name}", base.trim_end_matches('/')),
+                None => name,
+            },
+            size: content.len() as u64,
+            blake3: blake3::hash(&content).to_hex().to_string(),
+        });
+    }
+    Ok(index)
+}
+
+/// Where the archive at `url` of the index at `index` is, a URL or a path.
+fn archive_location(index: &str, url: &str) -> String {
+    if url.contains("://") || url.starts_with('/') {
+        return url.to_string();
+    }
+    if index.contains("://") {
+        let base = index.rsplit_once('/').map_or(index, |(base, _)| base);
+        return format!("{base}/{url}");
+    }
+    Path::new(index)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(url)
+        .display()
+        .to_string()
+}
+
+/// The machine toolup runs on, e.g. `x86_64-linux`.
+pub fn host() -> String {
+    format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS)
+}
+
+static PREBUILT_INDEX: OnceLock<String> = OnceLock::new();
+
+/// Look for toolchains in the index at `index` (a URL or a path) before building them.
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_prebuilt_index(index: String) {
+    let _ = PREBUILT_INDEX.set(index);
+}
+
+/// Read the index at `location`, a URL or a path.
+pub fn read_index(location: &str) -> Result<Index> {
+    let path = if Path::new(location).is_file() {
+        PathBuf::from(location)
+    } else {
+        // indexes change whenever a toolchain is published
+        match download_archive(location, false).context(Failure::Download)? {
+            DownloadResult::Cached(p)
+            | DownloadResult::Replaced(p)
+            | DownloadResult::Created(p) => p,
+        }
+    };
+    let content =
+        std::fs::read_to_string(&path).context(format!("failed to read `{}`", path.display()))?;
+    serde_json::from_str(&content).context(format!("`{location}` is not a toolchain index"))
+}
+
+/// Install `toolchain` from the index set with [`set_prebuilt_index`]. Returns `false` when there's
+/// no index or it doesn't have the toolchain, it has to be built then.
+pub fn install(toolchain: &Toolchain) -> Result<bool> {
+    let Some(location) = PREBUILT_INDEX.get() else {
+        return Ok(false);
+    };
+    if is_plan() {
+        plan_step(format!(
+            "install {} from {location} if it's there, otherwise build it",
+            toolchain.id()
+        ));
+        return Ok(false);
+    }
+    let index = read_index(location)?;
+    let Some(entry) = index.find(toolchain) else {
+        log::info!(
+            "=> {location} has no prebuilt {} for {}, building it",
+            toolchain.id(),
+            host()
+        );
+        return Ok(false);
+    };
+
+    let location = archive_location(location, &entry.url);
+    log::info!("=> install prebuilt {location}");
+    let local = Path::new(&location).is_file();
+    let archive = if local {
+        PathBuf::from(&location)
+    } else {
+        match download_archive(&location, true)? {
+            DownloadResult::Cached(p)
+            | DownloadResult::Replaced(p)
+            | DownloadResult::Created(p) => p,
+        }
+    };
+    let actual = blake3::hash(&std::fs::read(&archive)?).to_hex();
+    if actual.as_str() != entry.blake3 {
+        // a corrupted download is fetched again next time
+        if !local {
+            std::fs::remove_file(&archive)?;
+        }
+        return Err(Failure::Download).context(format!(
+            "blake3 mismatch for {location}: expected {}, got {actual}",
+            entry.blake3
+        ));
+    }
+
+    let id = toolchain.id();
+    let partial = toolchain
+        .install_prefix()?
+        .join(format!(".{id}.prebuilt.partial"));
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)
+            .context(format!("failed to remove `{}`", partial.display()))?;
+    }
+    create_dir_all(&partial)?;
+    decompress_tar(&archive, &partial)?;
+
+    let dir = partial.join(&id);
+    let sysroot = partial.join(format!("sysroot-{id}"));
+    if !dir.is_dir() {
+        bail!("`{location}` doesn't have a `{id}` directory");
+    }
+    if let Some(provenance) = provenance::read(&dir)? {
+        let mismatches = provenance::verify(
+            &provenance,
+            &dir,
+            Some(sysroot.as_path()).filter(|s| s.is_dir()),
+        )?;
+        if !mismatches.is_empty() {
+            bail!(
+                "{location} doesn't match its provenance, {} files differ",
+                mismatches.len()
+            );
+        }
+    }
+
+    // the sysroot first, the toolchain directory marks the toolchain as installed
+    if !toolchain.is_freestanding() {
+        replace(&sysroot, &toolchain.sysroot()?)?;
+    }
+    replace(&dir, &toolchain.dir()?)?;
+    std::fs::remove_dir_all(&partial)?;
+    if let Some(prefix) = &toolchain.prefix {
+        registry::record(&id, prefix)?;
+    }
+    // the toolchain was most likely packed with another sysroot path
+    specs::write(toolchain, &[])?;
+    Ok(true)
+}
+
+/// Move `src` to `dest`, replacing it.
+fn replace(src: &Path, dest: &Path) -> Result<()> {
+    if !src.is_dir() {
+        bail!("`{}` is missing from the archive", src.display());
+    }
+    if dest.exists() {
+        std::fs::remove_dir_all(dest).context(format!("failed to remove `{}`", dest.display()))?;
+    }
+    if let Some(parent) = dest.parent() {
+        create_dir_all(parent)?;
+    }
+    std::fs::rename(src, dest).context(format!("failed to move `{}`", dest.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+
+    use anyhow::Result;
+    use flate2::{Compression, write::GzEncoder};
+    use tar::{Builder, Header};
+
+    use super::{archive_location, index_dir};
+    use crate::pack::PACK_METADATA;
+
+    #[test]
+    fn test_archive_location() {
+        let url = "x86_64-elf.tar.gz";
+        assert_eq!(
+            archive_location("https://example.com/toolchains/index.json", url),
+            "https://example.com/toolchains/x86_64-elf.tar.gz"
+        );
+        assert_eq!(
+            archive_location("/srv/toolchains/index.json", url),
+            "/srv/toolchains/x86_64-elf.tar.gz"
+        );
+        assert_eq!(
+            archive_location(
+                "/srv/index.json",
+                "https://cdn.example.com/x86_64-elf.tar.gz"
+            ),
+            "https://cdn.example.com/x86_64-elf.tar.gz"
+        );
+    }
+
+    #[test]
+    fn test_index_dir() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let metadata = r#"{"id": "x86_64-elf-gcc-15.2.0-bin-2.45-none", "target": "x86_64-elf",
+            "gcc": "15.2.0", "binutils": "2.45", "libc": "none", "profile": "default",
+            "host": "x86_64-linux", "toolup": "0.1.0"}"#;
+        let mut builder = Builder::new(GzEncoder::new(
+            File::create(dir.path().join("x86_64-elf.tar.gz"))?,
+            Compression::fast(),
+        ));
+        let mut header = Header::new_gnu();
+        header.set_size(metadata.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, PACK_METADATA, metadata.as_bytes())?;
+        builder.into_inner()?.finish()?;
+        // not a packed toolchain
+        std::fs::write(dir.path().join("notes.txt"), "")?;
+
+        let index = index_dir(dir.path(), Some("https://example.com/toolchains/"))?;
+        assert_eq!(index.toolchains.len(), 1);
+        let entry = &index.toolchains[0];
+        assert_eq!(entry.pack.target, "x86_64-elf");
+        assert_eq!(
+            entry.url,
+            "https://example.com/toolchains/x86_64-elf.tar.gz"
+        );
+        let archive = std::fs::read(dir.path().join("x86_64-elf.tar.gz"))?;
+        assert_eq!(entry.size, archive.len() as u64);
+        assert_eq!(entry.blake3, blake3::hash(&archive).to_hex().as_str());
+        Ok(())
+    }
+}
@@ -2,19 +2,32 @@ use std::{ffi::OsString, fmt::Display, path::PathBuf, str::FromStr};
 
 use anyhow::{Context, Result, anyhow};
 use colored::Colorize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    commands::staging,
     download::{self, sysroots_dir},
+    packages::android::AndroidApi,
     packages::binutils::Binutils,
+    packages::freebsd::FreeBsdVersion,
     packages::gcc::GCC,
+    packages::gdb::Gdb,
     packages::glibc::GlibcVersion,
+    packages::host_tools::layered_path,
     packages::linux::KernelVersion,
+    packages::llvm::Llvm,
+    packages::mingw::MingwVersion,
     packages::musl::MuslVersion,
+    packages::newlib::NewlibVersion,
+    packages::wasi::WasiLibcVersion,
+    registry,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Arch {
     X86_64,
+    I486,
+    I586,
     I686,
     Aarch64,
     Armv7,
@@ -24,39 +37,71 @@ pub enum Arch {
     Avr,
     Bpf,
     Xtensa,
+    /// WebAssembly, only built with clang, see [`crate::packages::wasi`]
+    Wasm32,
 }
 
-impl ToString for Arch {
-    fn to_string(&self) -> String {
+impl Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Arch::X86_64 => "x86_64",
+            Arch::I486 => "i486",
+            Arch::I586 => "i586",
+            Arch::I686 => "i686",
+            Arch::Aarch64 => "aarch64",
+            Arch::Armv7 => "armv7",
+            Arch::Riscv64 => "riscv64",
+            Arch::Ppc64Le => "ppc64le",
+            Arch::Ppc64 => "ppc64",
+            Arch::Avr => "avr",
+            Arch::Bpf => "bpf",
+            Arch::Xtensa => "xtensa",
+            Arch::Wasm32 => "wasm32",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl Arch {
+    /// Return an architecture string to be used the `ARCH` parameter when building the kernel,
+    /// `None` if the kernel has no port for this architecture.
+    pub fn to_kernel_arch(self) -> Option<&'static str> {
         match self {
-            Arch::X86_64 => "x86_64".into(),
-            Arch::I686 => "i686".into(),
-            Arch::Aarch64 => "aarch64".into(),
-            Arch::Armv7 => "armv7".into(),
-            Arch::Riscv64 => "riscv64".into(),
-            Arch::Ppc64Le => "ppc64le".into(),
-            Arch::Ppc64 => "ppc64".into(),
-            Arch::Avr => "avr".into(),
-            Arch::Bpf => "bpf".into(),
-            Arch::Xtensa => "xtensa".into(),
+            Arch::X86_64 => Some("x86"),
+            Arch::I486 | Arch::I586 | Arch::I686 => Some("x86"),
+            Arch::Aarch64 => Some("arm64"),
+            Arch::Armv7 => Some("arm"),
+            Arch::Riscv64 => Some("riscv"),
+            Arch::Ppc64Le => Some("powerpc"),
+            Arch::Ppc64 => Some("powerpc"),
+            Arch::Xtensa => Some("xtensa"),
+            Arch::Avr | Arch::Bpf | Arch::Wasm32 => None,
         }
     }
 }
 
 impl Arch {
-    /// Return an architecture string to be used the `ARCH` parameter when building the kernel.
-    pub fn to_kernel_arch(self) -> &'static str {
+    /// Return the qemu user-mode emulator that runs binaries of this architecture.
+    pub fn to_qemu_user(self) -> Option<&'static str> {
         match self {
-            Arch::X86_64 => "x86",
-            Arch::I686 => "x86",
-            Arch::Aarch64 => "arm64",
-            Arch::Armv7 => "arm",
-            Arch::Riscv64 => "riscv",
-            Arch::Ppc64Le => "powerpc",
-            Arch::Ppc64 => "powerpc",
-            Arch::Xtensa => "xtensa",
-            Arch::Avr => unreachable!(),
-            Arch::Bpf => unreachable!(),
+            Arch::X86_64 => Some("qemu-x86_64"),
+            Arch::I486 | Arch::I586 | Arch::I686 => Some("qemu-i386"),
+            Arch::Aarch64 => Some("qemu-aarch64"),
+            Arch::Armv7 => Some("qemu-arm"),
+            Arch::Riscv64 => Some("qemu-riscv64"),
+            Arch::Ppc64Le => Some("qemu-ppc64le"),
+            Arch::Ppc64 => Some("qemu-ppc64"),
+            Arch::Xtensa | Arch::Avr | Arch::Bpf | Arch::Wasm32 => None,
+        }
+    }
+
+    /// Return the QEMU `-cpu` model for architectures that QEMU would otherwise emulate with
+    /// instructions the target doesn't have (e.g. `cmov` on i586).
+    pub fn to_qemu_cpu(self) -> Option<&'static str> {
+        match self {
+            Arch::I486 => Some("486"),
+            Arch::I586 => Some("pentium"),
+            _ => None,
         }
     }
 }
@@ -65,14 +110,21 @@ impl Arch {
 pub enum Os {
     None, // bare-metal
     Linux,
+    /// mingw-w64, the GNU triple is `<arch>-w64-mingw32`
+    Windows,
+    /// FreeBSD of a major version, the GNU triple is `<arch>-unknown-freebsd<major>`
+    FreeBsd(u64),
 }
 
-impl ToString for Os {
-    fn to_string(&self) -> String {
-        match self {
-            Os::None => "none".into(),
-            Os::Linux => "linux".into(),
-        }
+impl Display for Os {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Os::None => "none",
+            Os::Linux => "linux",
+            Os::Windows => "windows",
+            Os::FreeBsd(major) => return write!(f, "freebsd{major}"),
+        };
+        write!(f, "{s}")
     }
 }
 
@@ -85,21 +137,31 @@ pub enum Abi {
     Eabihf,
     GnuEabi,
     GnuEabihf,
+    /// The x32 ABI: x86_64 instructions with 32-bit pointers
+    GnuX32,
     Elf,
+    /// Android's bionic, the GNU triple is `<arch>-linux-android`
+    Android,
+    /// The WebAssembly System Interface, the triple is `wasm32-wasi`
+    Wasi,
 }
 
-impl ToString for Abi {
-    fn to_string(&self) -> String {
-        match self {
-            Abi::Gnu => "gnu".into(),
-            Abi::Musl => "musl".into(),
-            Abi::Msvc => "msvc".into(),
-            Abi::Eabi => "eabi".into(),
-            Abi::Eabihf => "eabihf".into(),
-            Abi::GnuEabi => "gnueabi".into(),
-            Abi::GnuEabihf => "gnueabihf".into(),
-            Abi::Elf => "elf".into(),
-        }
+impl Display for Abi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Abi::Gnu => "gnu",
+            Abi::Musl => "musl",
+            Abi::Msvc => "msvc",
+            Abi::Eabi => "eabi",
+            Abi::Eabihf => "eabihf",
+            Abi::GnuEabi => "gnueabi",
+            Abi::GnuEabihf => "gnueabihf",
+            Abi::GnuX32 => "gnux32",
+            Abi::Elf => "elf",
+            Abi::Android => "android",
+            Abi::Wasi => "wasi",
+        };
+        write!(f, "{s}")
     }
 }
 
@@ -113,15 +175,16 @@ pub enum Vendor {
     //Apple,
 }
 
-impl ToString for Vendor {
-    fn to_string(&self) -> String {
-        match self {
-            Vendor::Unknown => "unknown".into(),
-            Vendor::Pc => "pc".into(),
-            Vendor::Esp32 => "esp32".into(),
-            Vendor::Esp32S2 => "esp32s2".into(),
-            Vendor::Esp32S3 => "esp32s3".into(),
-        }
+impl Display for Vendor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Vendor::Unknown => "unknown",
+            Vendor::Pc => "pc",
+            Vendor::Esp32 => "esp32",
+            Vendor::Esp32S2 => "esp32s2",
+            Vendor::Esp32S3 => "esp32s3",
+        };
+        write!(f, "{s}")
     }
 }
 
@@ -130,6 +193,8 @@ impl FromStr for Arch {
     fn from_str(s: &str) -> Result<Self> {
         match s {
             "x86_64" => Ok(Arch::X86_64),
+            "i486" => Ok(Arch::I486),
+            "i586" => Ok(Arch::I586),
             "i686" => Ok(Arch::I686),
             "aarch64" => Ok(Arch::Aarch64),
             "armv7" => Ok(Arch::Armv7),
@@ -139,6 +204,7 @@ impl FromStr for Arch {
             "avr" => Ok(Arch::Avr),
             "bpf" => Ok(Arch::Bpf),
             "xtensa" => Ok(Arch::Xtensa),
+            "wasm32" => Ok(Arch::Wasm32),
             _ => Err(anyhow!("unsupported architecture")),
         }
     }
@@ -155,6 +221,9 @@ impl FromStr for Abi {
             "gnueabi" => Ok(Abi::GnuEabi),
             "eabihf" => Ok(Abi::Eabihf),
             "gnueabihf" => Ok(Abi::GnuEabihf),
+            "gnux32" => Ok(Abi::GnuX32),
+            "android" => Ok(Abi::Android),
+            "wasi" => Ok(Abi::Wasi),
             _ => Err(anyhow!("unsupported abi")),
         }
     }
@@ -180,9 +249,17 @@ impl FromStr for Os {
         match s {
             "none" => Ok(Os::None),
             "linux" => Ok(Os::Linux),
-            //"windows" => Ok(Os::Windows),
+            "windows" => Ok(Os::Windows),
             //"darwin" => Ok(Os::Darwin),
-            //"freebsd" => Ok(Os::FreeBsd),
+            "freebsd" => Ok(Os::FreeBsd(FreeBsdVersion::default().0)),
+            os if os.starts_with("freebsd") => {
+                // `freebsd14.3` is accepted, only the major version matters to GCC
+                let version = &os["freebsd".len()..];
+                let major = version.split('.').next().unwrap_or(version);
+                Ok(Os::FreeBsd(major.parse().context(format!(
+                    "`{os}` is an invalid FreeBSD version"
+                ))?))
+            }
             //"netbsd" => Ok(Os::NetBsd),
             //"openbsd" => Ok(Os::OpenBsd),
             _ => Err(anyhow!("unsupported os")),
@@ -203,6 +280,42 @@ impl Target {
         matches!(self.abi, Abi::Musl)
     }
 
+    pub fn is_windows(&self) -> bool {
+        self.os == Os::Windows
+    }
+
+    pub fn is_freebsd(&self) -> bool {
+        matches!(self.os, Os::FreeBsd(_))
+    }
+
+    pub fn is_android(&self) -> bool {
+        self.abi == Abi::Android
+    }
+
+    /// Whether the target is WebAssembly with WASI, built with clang and wasi-libc instead of GCC.
+    pub fn is_wasi(&self) -> bool {
+        self.abi == Abi::Wasi
+    }
+
+    /// Whether the target has no operating system.
+    pub fn is_freestanding(&self) -> bool {
+        matches!(self.abi, Abi::Elf | Abi::Eabi | Abi::Eabihf)
+    }
+
+    /// Returns the qemu user-mode command that runs binaries of this target, including the cpu
+    /// model if one is needed.
+    pub fn qemu_user_command(&self) -> Option<String> {
+        // qemu user-mode doesn't implement the x32 syscall ABI, and only runs Linux binaries
+        if self.abi == Abi::GnuX32 || self.is_windows() || self.is_freebsd() {
+            return None;
+        }
+        let qemu = self.arch.to_qemu_user()?;
+        Some(match self.arch.to_qemu_cpu() {
+            Some(cpu) => format!("{qemu} -cpu {cpu}"),
+            None => qemu.to_string(),
+        })
+    }
+
     pub fn to_target_string(&self) -> String {
         match self {
             Target {
@@ -213,7 +326,7 @@ impl Target {
                 vendor,
                 ..
             } => {
-                format!("xtensa-{}-elf", vendor.to_string())
+                format!("xtensa-{}-elf", vendor)
             }
             // GNU tools will not understand the full format for freestanding targets.
             Target {
@@ -222,7 +335,33 @@ impl Target {
                 os: Os::None,
                 abi: Abi::Elf,
             } => {
-                format!("{}-elf", arch.to_string())
+                format!("{}-elf", arch)
+            }
+            // and need the major version of FreeBSD
+            Target {
+                arch,
+                os: Os::FreeBsd(major),
+                ..
+            } => {
+                format!("{}-unknown-freebsd{}", arch, major)
+            }
+            // and Android without a vendor
+            Target {
+                arch,
+                abi: Abi::Android,
+                ..
+            } => {
+                format!("{}-linux-android", arch)
+            }
+            // clang and wasi-libc know WASI without a vendor or os
+            Target { abi: Abi::Wasi, .. } => "wasm32-wasi".into(),
+            // and only know mingw-w64 by its own triple
+            Target {
+                arch,
+                os: Os::Windows,
+                ..
+            } => {
+                format!("{}-w64-mingw32", arch)
             }
             Target {
                 arch,
@@ -232,20 +371,65 @@ impl Target {
             } => {
                 format!(
                     "{arch}-{vendor}-{os}-{abi}",
-                    arch = arch.to_string(),
-                    vendor = vendor.to_string(),
-                    os = os.to_string(),
-                    abi = abi.to_string()
+                    arch = arch,
+                    vendor = vendor,
+                    os = os,
+                    abi = abi
                 )
             }
         }
     }
 }
 
+/// Architecture names used by Debian, Rust and GNU triples, with the toolup architecture they
+/// map to.
+const ARCH_ALIASES: &[(&str, &str)] = &[
+    ("amd64", "x86_64"),
+    // Debian's `i386` multiarch name targets a 686
+    ("i386", "i686"),
+    ("arm64", "aarch64"),
+    ("arm", "armv7"),
+    ("armhf", "armv7"),
+    ("armv7a", "armv7"),
+    ("armv7l", "armv7"),
+    ("riscv64gc", "riscv64"),
+    ("powerpc64le", "ppc64le"),
+    ("powerpc64", "ppc64"),
+];
+
+/// Rewrite common GNU, Debian and Rust spellings of a triple into toolup's format, e.g.
+/// `aarch64-linux-gnu` and `arm64-linux-gnu` are `aarch64-unknown-linux-gnu`.
+pub fn normalize_triple(s: &str) -> String {
+    let mut parts: Vec<&str> = s.split('-').collect();
+    if let Some((_, arch)) = ARCH_ALIASES
+        .iter()
+        .find(|(alias, _)| Some(alias) == parts.first())
+    {
+        parts[0] = arch;
+    }
+
+    match parts.as_slice() {
+        [arch, "linux", abi] => format!("{arch}-unknown-linux-{abi}"),
+        // GNU tools will not understand the full format for freestanding targets.
+        [arch, "unknown", "none"] | [arch, "unknown", "none", "elf"] if *arch != "bpf" => {
+            format!("{arch}-elf")
+        }
+        [arch, "none", abi] => format!("{arch}-unknown-none-{abi}"),
+        // Rust's name for mingw-w64
+        [arch, "pc", "windows", "gnu"] => format!("{arch}-w64-mingw32"),
+        // and for WASI preview 1
+        ["wasm32", "unknown", "wasi"] | ["wasm32", "wasip1"] => "wasm32-wasi".into(),
+        _ => parts.join("-"),
+    }
+}
+
 impl FromStr for Target {
     type Err = anyhow::Error;
 
+    /// Parse a target, aliases are accepted (see [`normalize_triple`]).
     fn from_str(s: &str) -> Result<Self> {
+        let normalized = normalize_triple(s);
+        let s = normalized.as_str();
         let parts: Vec<&str> = s.split('-').collect();
 
         match parts.as_slice() {
@@ -261,6 +445,16 @@ impl FromStr for Target {
                     })
                 }
             }
+            // GCC has no WebAssembly backend, wasm32-wasi is built with clang and wasi-libc
+            ["wasm32", "wasi"] => Ok(Target {
+                arch: Arch::Wasm32,
+                vendor: Vendor::Unknown,
+                os: Os::None,
+                abi: Abi::Wasi,
+            }),
+            ["wasm32", ..] => Err(anyhow!(
+                "use `wasm32-wasi`, only WASI is supported on wasm32"
+            )),
             [arch, "elf"] => Ok(Target {
                 arch: Arch::from_str(arch)?,
                 vendor: Vendor::Unknown,
@@ -274,20 +468,12 @@ impl FromStr for Target {
                 abi: Abi::Elf,
             }),
             ["xtensa", ..] => Err(anyhow!("unknown xtensa toolchain",)),
-            // GNU tools will not understand the full format for freestanding targets.
-            [arch, "unknown", "none", "elf"] => Err(anyhow!(
-                "use <arch>-elf for freestanding targets. use: {}-elf",
-                arch
-            )),
             [arch, vendor, "none", abi] => {
                 let abi = Abi::from_str(abi)?;
                 match abi {
                     Abi::Eabi | Abi::Eabihf | Abi::Elf => {}
                     _ => {
-                        return Err(anyhow!(
-                            "unsupported abi `{}` for os `none`",
-                            abi.to_string(),
-                        ));
+                        return Err(anyhow!("unsupported abi `{}` for os `none`", abi,));
                     }
                 };
                 Ok(Target {
@@ -297,6 +483,36 @@ impl FromStr for Target {
                     abi,
                 })
             }
+            ["x86_64", "w64", "mingw32"] => Ok(Target {
+                arch: Arch::X86_64,
+                vendor: Vendor::Pc,
+                os: Os::Windows,
+                abi: Abi::Gnu,
+            }),
+            [_, "w64", "mingw32"] => Err(anyhow!("mingw-w64 is only supported on x86_64")),
+            [arch @ ("x86_64" | "aarch64" | "riscv64"), "unknown", os]
+                if os.starts_with("freebsd") =>
+            {
+                Ok(Target {
+                    arch: Arch::from_str(arch)?,
+                    vendor: Vendor::Unknown,
+                    os: Os::from_str(os)?,
+                    abi: Abi::Gnu,
+                })
+            }
+            [.., os] | [_, _, os, _] if os.starts_with("freebsd") => Err(anyhow!(
+                "use `<arch>-unknown-freebsd`, FreeBSD is supported on x86_64, aarch64 and riscv64"
+            )),
+            [_, _, "windows", _] => Err(anyhow!(
+                "use `x86_64-pc-windows-gnu`, only mingw-w64 Windows targets are supported"
+            )),
+            [arch, _, _, "android"] if !matches!(*arch, "aarch64" | "x86_64") => {
+                Err(anyhow!("Android is supported on aarch64 and x86_64"))
+            }
+            [_, _, os, "android"] if *os != "linux" => Err(anyhow!("use `<arch>-linux-android`")),
+            [arch, _, _, "gnux32"] if *arch != "x86_64" => {
+                Err(anyhow!("the x32 ABI is only supported on x86_64"))
+            }
             // 4 parts: arch-vendor-os-abi
             [arch, vendor, os, abi] => {
                 //
@@ -320,10 +536,22 @@ impl Display for Target {
     }
 }
 
+serde_string!(Target, Libc);
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub enum Libc {
     Glibc(GlibcVersion),
     Musl(MuslVersion),
+    /// The mingw-w64 headers and CRT of Windows targets
+    Mingw(MingwVersion),
+    /// The base system of a FreeBSD release
+    FreeBsd(FreeBsdVersion),
+    /// bionic from the Android NDK, at an API level
+    Bionic(AndroidApi),
+    /// newlib and libgloss of freestanding targets, see [`crate::packages::newlib`]
+    Newlib(NewlibVersion),
+    /// wasi-libc of `wasm32-wasi`, at the wasi-sdk release it's tagged with
+    WasiLibc(WasiLibcVersion),
 }
 
 impl Display for Libc {
@@ -335,11 +563,114 @@ impl Display for Libc {
             Libc::Musl(musl_version) => {
                 write!(f, "musl-{}", musl_version)
             }
+            Libc::Mingw(mingw_version) => {
+                write!(f, "mingw-w64-{}", mingw_version)
+            }
+            Libc::FreeBsd(freebsd_version) => {
+                write!(f, "freebsd-{}", freebsd_version)
+            }
+            Libc::Bionic(api) => {
+                write!(f, "bionic-{}", api)
+            }
+            Libc::Newlib(newlib_version) => {
+                write!(f, "newlib-{}", newlib_version)
+            }
+            Libc::WasiLibc(wasi_libc_version) => {
+                write!(f, "wasi-libc-{}", wasi_libc_version)
+            }
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+impl FromStr for Libc {
+    type Err = anyhow::Error;
+
+    /// Parse a libc and its version as displayed, e.g. `glibc-2.42`, `musl-1.2.5`,
+    /// `mingw-w64-13.0.0`, `freebsd-14.3`, `bionic-35`, `newlib-4.5.0.20241231` or `wasi-libc-25`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('-') {
+            Some(("glibc", version)) => Ok(Libc::Glibc(GlibcVersion::from_str(version)?)),
+            Some(("musl", version)) => Ok(Libc::Musl(MuslVersion::from_str(version)?)),
+            Some(("mingw", version)) if version.starts_with("w64-") => Ok(Libc::Mingw(
+                MingwVersion::from_str(&version["w64-".len()..])?,
+            )),
+            Some(("freebsd", version)) => Ok(Libc::FreeBsd(FreeBsdVersion::from_str(version)?)),
+            Some(("bionic", api)) => Ok(Libc::Bionic(AndroidApi::from_str(api)?)),
+            Some(("newlib", version)) => Ok(Libc::Newlib(NewlibVersion::from_str(version)?)),
+            Some(("wasi", version)) if version.starts_with("libc-") => Ok(Libc::WasiLibc(
+                WasiLibcVersion::from_str(&version["libc-".len()..])?,
+            )),
+            _ => Err(anyhow!(
+                "`{s}` is an invalid libc, expected glibc-<version>, musl-<version>, \
+                 mingw-w64-<version>, freebsd-<version>, bionic-<api level>, newlib-<version> \
+                 or wasi-libc-<version>"
+            )),
+        }
+    }
+}
+
+/// How a freestanding toolchain is built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// Only a stage1 compiler, without a C library unless it's [`Libc::Newlib`]
+    #[default]
+    Default,
+    /// newlib-nano and a GCC with size-optimized target libraries
+    Nano,
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "default" => Ok(Profile::Default),
+            "nano" => Ok(Profile::Nano),
+            _ => Err(anyhow!(
+                "unknown profile `{s}`, expected one of: default, nano"
+            )),
+        }
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Profile::Default => "default",
+            Profile::Nano => "nano",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Where a toolchain is installed, returned by [`Toolchain::paths`].
+///
+/// The layout is stable and only changes in a major release, relative to `$HOME`:
+/// - `prefix`: `.toolup/toolchains/<id>`
+/// - `bin_dir`: `<prefix>/bin`
+/// - `gcc`: `<bin_dir>/<target>-gcc`, `<bin_dir>/<target>-clang` for WASI toolchains
+/// - `sysroot`: `.toolup/sysroot/sysroot-<id>`
+///
+/// A toolchain installed with `--prefix <dir>` is in `<dir>/<id>` and its sysroot in
+/// `<dir>/sysroot-<id>`.
+///
+/// The paths are returned whether or not the toolchain is installed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolchainPaths {
+    /// See [`Toolchain::id`]
+    pub id: String,
+    /// Where GCC and binutils are installed
+    pub prefix: PathBuf,
+    pub bin_dir: PathBuf,
+    pub gcc: PathBuf,
+    /// The kernel headers and C library, only populated for hosted targets
+    pub sysroot: PathBuf,
+}
+
+/// Serialized with the string forms of its versions, e.g. `{"target":
+/// "aarch64-unknown-linux-gnu", "gcc": {"version": "15.2.0"}, "libc": "glibc-2.42", ...}`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
 pub struct Toolchain {
     pub target: Target,
     pub binutils: Binutils,
@@ -348,6 +679,38 @@ pub struct Toolchain {
     /// The kernel version to install headers from into the sysroot, only use this when installing
     /// a toolchain to build the kernel itself.
     pub kernel: Option<KernelVersion>,
+    /// Only used by freestanding targets
+    #[serde(default)]
+    pub profile: Profile,
+    /// Where to install the toolchain instead of `~/.toolup/toolchains`, see
+    /// [`Toolchain::install_prefix`]
+    #[serde(default)]
+    pub prefix: Option<PathBuf>,
+    /// Also build a cross GDB, see [`crate::packages::gdb`]
+    #[serde(default)]
+    pub gdb: Option<Gdb>,
+    /// Also build clang and lld, see [`crate::packages::llvm`]
+    #[serde(default)]
+    pub llvm: Option<Llvm>,
+    /// A user-chosen name to install several toolchains for the same target side by side, see
+    /// [`Toolchain::name`]
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+/// Parse a toolchain name, a target optionally followed by `@<variant>`, e.g.
+/// `aarch64-unknown-linux-gnu@hardened`. Target aliases are accepted.
+pub fn parse_name(name: &str) -> Result<(Target, Option<String>)> {
+    let Some((target, variant)) = name.split_once('@') else {
+        return Ok((Target::from_str(name)?, None));
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if variant.is_empty() || !variant.chars().all(valid) {
+        return Err(anyhow!(
+            "`{variant}` is an invalid toolchain variant, only letters, digits, `-`, `_` and `.` are allowed"
+        ));
+    }
+    Ok((Target::from_str(target)?, Some(variant.to_string())))
 }
 
 impl Toolchain {
@@ -358,6 +721,11 @@ impl Toolchain {
             gcc,
             libc,
             kernel: None,
+            profile: Profile::Default,
+            prefix: None,
+            gdb: None,
+            llvm: target.is_wasi().then(Llvm::default),
+            variant: None,
         }
     }
 
@@ -374,6 +742,11 @@ impl Toolchain {
             gcc,
             libc,
             kernel: Some(kernel_version),
+            profile: Profile::Default,
+            prefix: None,
+            gdb: None,
+            llvm: target.is_wasi().then(Llvm::default),
+            variant: None,
         }
     }
 
@@ -383,34 +756,131 @@ impl Toolchain {
         let binutils = Binutils::default();
         let libc = if target.is_musl() {
             Libc::Musl(MuslVersion::default())
+        } else if target.is_windows() {
+            Libc::Mingw(MingwVersion::default())
+        } else if let Os::FreeBsd(major) = target.os {
+            Libc::FreeBsd(FreeBsdVersion::latest(major))
+        } else if target.is_android() {
+            Libc::Bionic(AndroidApi::default())
+        } else if target.is_wasi() {
+            Libc::WasiLibc(WasiLibcVersion::default())
         } else {
             Libc::Glibc(GlibcVersion::default())
         };
 
-        Self::new(target.clone(), binutils, gcc, libc)
+        Self::new(*target, binutils, gcc, libc)
     }
 
-    /// Returns the location of the `gcc` binary for this toolchain.
+    /// Returns the location of the `gcc` binary for this toolchain, the `clang` wrapper for WASI
+    /// toolchains which have no GCC.
     ///
     /// # Notes
     /// The binary may not exist, this method returns the location where the binary should be.
     pub fn gcc_bin(&self) -> Result<PathBuf> {
+        let compiler = if self.target.is_wasi() {
+            "clang"
+        } else {
+            "gcc"
+        };
         Ok(self
             .bin_dir()?
-            .join(format!("{}-gcc", self.target.to_target_string())))
+            .join(format!("{}-{compiler}", self.target.to_target_string())))
     }
 
     /// Returns the directory path for the toolchain. This is where GCC and binutils will be
     /// installed.
+    ///
+    /// While the toolchain is being installed this is [`Toolchain::staging_dir`] instead.
     pub fn dir(&self) -> Result<PathBuf> {
-        Ok(download::cross_prefix()?.join(self.id()))
+        let id = self.id();
+        if staging().as_deref() == Some(id.as_str()) {
+            return self.staging_dir();
+        }
+        Ok(self.install_prefix()?.join(id))
+    }
+
+    /// Returns the directory the toolchain is built in, it's renamed to [`Toolchain::dir`] once
+    /// every stage succeeded.
+    pub fn staging_dir(&self) -> Result<PathBuf> {
+        Ok(self
+            .install_prefix()?
+            .join(format!(".{}.partial", self.id())))
+    }
+
+    /// Returns the prefix given with `--prefix` or a `prefix` key, `None` if the toolchain is (to
+    /// be) installed in `~/.toolup/toolchains`.
+    ///
+    /// A toolchain without [`Toolchain::prefix`] is looked up in the [`registry`], where the
+    /// prefix of an installed toolchain is recorded.
+    pub fn custom_prefix(&self) -> Result<Option<PathBuf>> {
+        match &self.prefix {
+            Some(prefix) => Ok(Some(prefix.clone())),
+            None => registry::prefix(&self.id()),
+        }
     }
 
+    /// Returns the directory the toolchain directory is in.
+    pub fn install_prefix(&self) -> Result<PathBuf> {
+        match self.custom_prefix()? {
+            Some(prefix) => Ok(prefix),
+            None => download::cross_prefix(),
+        }
+    }
+
+    /// Returns a unique id for the toolchain, used to name its directories.
+    ///
+    /// The format is stable: `<target>-gcc-<gcc>-bin-<binutils>-<libc>-<libc version>`, followed
+    /// by `-<profile>` for non-default profiles and `@<variant>` for variants. WASI toolchains are
+    /// built without GCC and binutils, their id is `<target>-llvm-<llvm>-<libc>-<libc version>`.
     pub fn id(&self) -> String {
-        format!(
-            "{}-gcc-{}-bin-{}-{}",
-            self.target, self.gcc.version, self.binutils.version, self.libc
-        )
+        let mut id = match &self.llvm {
+            Some(llvm) if self.target.is_wasi() => {
+                format!("{}-llvm-{}-{}", self.target, llvm.version, self.libc)
+            }
+            _ => format!(
+                "{}-gcc-{}-bin-{}-{}",
+                self.target, self.gcc.version, self.binutils.version, self.libc
+            ),
+        };
+        if self.profile != Profile::Default {
+            id.push_str(&format!("-{}", self.profile));
+        }
+        if let Some(variant) = &self.variant {
+            id.push_str(&format!("@{variant}"));
+        }
+        id
+    }
+
+    /// The name the toolchain is declared with in `toolup.toml` and selected with on the command
+    /// line: the target, followed by `@<variant>` for variants.
+    pub fn name(&self) -> String {
+        match &self.variant {
+            Some(variant) => format!("{}@{variant}", self.target),
+            None => self.target.to_string(),
+        }
+    }
+
+    /// Returns every path derived from the toolchain id.
+    pub fn paths(&self) -> Result<ToolchainPaths> {
+        Ok(ToolchainPaths {
+            id: self.id(),
+            prefix: self.dir()?,
+            bin_dir: self.bin_dir()?,
+            gcc: self.gcc_bin()?,
+            sysroot: self.sysroot()?,
+        })
+    }
+
+    /// Whether the target has no operating system.
+    pub fn is_freestanding(&self) -> bool {
+        self.target.is_freestanding()
+    }
+
+    /// Whether newlib is built for the freestanding target, with [`Libc::Newlib`] or the nano
+    /// profile.
+    pub fn has_newlib(&self) -> bool {
+        self.is_freestanding()
+            && (self.profile == Profile::Nano || matches!(self.libc, Libc::Newlib(_)))
     }
 
     /// Returns the location of the `bin` directory. May be used to inside the `PATH` environment
@@ -421,39 +891,62 @@ impl Toolchain {
 
     /// Returns the sysroot path.
     ///
-    /// The sysroot has the kerenl headers and a C library.
+    /// The sysroot has the kerenl headers and a C library. It's next to the toolchain directory
+    /// for toolchains installed with a [`Toolchain::custom_prefix`].
     pub fn sysroot(&self) -> Result<PathBuf> {
-        Ok(sysroots_dir()?.join(format!("sysroot-{}", self.id())))
+        let name = format!("sysroot-{}", self.id());
+        Ok(match self.custom_prefix()? {
+            Some(prefix) => prefix.join(name),
+            None => sysroots_dir()?.join(name),
+        })
     }
 
     /// Returns a modified PATH environment variable that should be used when building any package
-    /// within the toolchain.
+    /// within the toolchain, see [`layered_path`].
     pub fn env_path(&self) -> Result<OsString> {
         let base =
-            std::env::var("PATH").context("failed to get the `PATH` environment variable")?;
-        let mut paths = std::env::split_paths(&base).collect::<Vec<_>>();
-        paths.insert(0, self.bin_dir()?);
-
-        Ok(std::env::join_paths(paths)?)
+            std::env::var_os("PATH").context("failed to get the `PATH` environment variable")?;
+        layered_path(&[self.bin_dir()?], &base)
     }
 }
 
 impl Display for Toolchain {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", "Toolchain: ".bold())?;
-        writeln!(f, "{}", self.target.to_string().green())?;
+        writeln!(f, "{}", self.name().green())?;
 
-        write!(f, "{}", "├─ ".yellow())?;
-        write!(f, "{}", "GCC: ".bold())?;
-        writeln!(f, "{}", self.gcc.version)?;
+        if !self.target.is_wasi() {
+            write!(f, "{}", "├─ ".yellow())?;
+            write!(f, "{}", "GCC: ".bold())?;
+            writeln!(f, "{}", self.gcc.version)?;
 
-        write!(f, "{}", "├─ ".yellow())?;
-        write!(f, "{}", "Binutils: ".bold())?;
-        writeln!(f, "{}", self.binutils.version)?;
+            write!(f, "{}", "├─ ".yellow())?;
+            write!(f, "{}", "Binutils: ".bold())?;
+            writeln!(f, "{}", self.binutils.version)?;
+        }
 
         write!(f, "{}", "├─ ".yellow())?;
         write!(f, "{}", "Libc: ".bold())?;
-        writeln!(f, "{}", self.libc)
+        writeln!(f, "{}", self.libc)?;
+
+        if self.profile != Profile::Default {
+            write!(f, "{}", "├─ ".yellow())?;
+            write!(f, "{}", "Profile: ".bold())?;
+            writeln!(f, "{}", self.profile)?;
+        }
+
+        if let Some(gdb) = &self.gdb {
+            write!(f, "{}", "├─ ".yellow())?;
+            write!(f, "{}", "GDB: ".bold())?;
+            writeln!(f, "{}", gdb.version)?;
+        }
+
+        if let Some(llvm) = &self.llvm {
+            write!(f, "{}", "├─ ".yellow())?;
+            write!(f, "{}", "LLVM: ".bold())?;
+            writeln!(f, "{}", llvm.version)?;
+        }
+        Ok(())
     }
 }
 
@@ -461,7 +954,7 @@ impl Display for Toolchain {
 mod test {
     use std::str::FromStr;
 
-    use super::{Abi, Arch, Os, Target, Vendor};
+    use super::{Abi, Arch, Libc, Os, Target, Toolchain, Vendor};
     use anyhow::Result;
 
     #[test]
@@ -511,6 +1004,92 @@ mod test {
                 abi: Abi::Gnu
             }
         );
+        assert_eq!(
+            Target::from_str("i586-unknown-linux-musl")?,
+            Target {
+                arch: Arch::I586,
+                vendor: Vendor::Unknown,
+                os: Os::Linux,
+                abi: Abi::Musl
+            }
+        );
+        assert_eq!(
+            Target::from_str("x86_64-unknown-linux-gnux32")?,
+            Target {
+                arch: Arch::X86_64,
+                vendor: Vendor::Unknown,
+                os: Os::Linux,
+                abi: Abi::GnuX32
+            }
+        );
+        assert!(Target::from_str("i686-unknown-linux-gnux32").is_err());
+        assert_eq!(
+            Target::from_str("wasm32-wasi")?,
+            Target {
+                arch: Arch::Wasm32,
+                vendor: Vendor::Unknown,
+                os: Os::None,
+                abi: Abi::Wasi
+            }
+        );
+        assert!(Target::from_str("wasm32-unknown-emscripten").is_err());
+        assert!(Target::from_str("wasm32-elf").is_err());
+        assert!(Target::from_str("wasm32-unknown-linux-gnu").is_err());
+        assert_eq!(
+            Target::from_str("x86_64-w64-mingw32")?,
+            Target {
+                arch: Arch::X86_64,
+                vendor: Vendor::Pc,
+                os: Os::Windows,
+                abi: Abi::Gnu
+            }
+        );
+        assert!(Target::from_str("aarch64-w64-mingw32").is_err());
+        assert!(Target::from_str("x86_64-pc-windows-msvc").is_err());
+        assert_eq!(
+            Target::from_str("aarch64-unknown-freebsd13")?,
+            Target {
+                arch: Arch::Aarch64,
+                vendor: Vendor::Unknown,
+                os: Os::FreeBsd(13),
+                abi: Abi::Gnu
+            }
+        );
+        assert!(Target::from_str("ppc64-unknown-freebsd").is_err());
+        assert!(Target::from_str("x86_64-unknown-freebsd-gnu").is_err());
+        assert_eq!(
+            Target::from_str("aarch64-linux-android")?,
+            Target {
+                arch: Arch::Aarch64,
+                vendor: Vendor::Unknown,
+                os: Os::Linux,
+                abi: Abi::Android
+            }
+        );
+        assert!(Target::from_str("riscv64-linux-android").is_err());
+
+        // aliases
+        for (alias, canonical) in [
+            ("aarch64-linux-gnu", "aarch64-unknown-linux-gnu"),
+            ("arm64-linux-gnu", "aarch64-unknown-linux-gnu"),
+            ("arm-linux-gnueabihf", "armv7-unknown-linux-gnueabihf"),
+            ("amd64-linux-musl", "x86_64-unknown-linux-musl"),
+            ("riscv64gc-unknown-linux-gnu", "riscv64-unknown-linux-gnu"),
+            ("powerpc64le-linux-gnu", "ppc64le-unknown-linux-gnu"),
+            ("x86_64-unknown-none", "x86_64-elf"),
+            ("riscv64-unknown-none-elf", "riscv64-elf"),
+            ("armv7a-none-eabihf", "armv7-unknown-none-eabihf"),
+            ("bpf-unknown-none", "bpf-unknown-none"),
+            ("x86_64-pc-windows-gnu", "x86_64-w64-mingw32"),
+            ("x86_64-unknown-freebsd", "x86_64-unknown-freebsd14"),
+            ("amd64-unknown-freebsd14.3", "x86_64-unknown-freebsd14"),
+            ("aarch64-linux-android", "aarch64-linux-android"),
+            ("x86_64-unknown-linux-android", "x86_64-linux-android"),
+            ("wasm32-unknown-wasi", "wasm32-wasi"),
+            ("wasm32-wasip1", "wasm32-wasi"),
+        ] {
+            assert_eq!(Target::from_str(alias)?.to_string(), canonical, "{alias}");
+        }
         assert_eq!(
             Target::from_str("ppc64-unknown-linux-gnu")?,
             Target {
@@ -532,4 +1111,15 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_wasi_toolchain() -> Result<()> {
+        let toolchain = Toolchain::target_default(&Target::from_str("wasm32-wasi")?);
+        assert!(toolchain.llvm.is_some());
+        assert_eq!(toolchain.libc, Libc::from_str("wasi-libc-25")?);
+        assert_eq!(toolchain.libc.to_string(), "wasi-libc-25");
+        assert_eq!(toolchain.id(), "wasm32-wasi-llvm-20.1.8-wasi-libc-25");
+        assert!(Libc::from_str("wasi-25").is_err());
+        Ok(())
+    }
 }
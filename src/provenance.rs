@@ -0,0 +1,318 @@
+//! `provenance.json` in a toolchain directory: how a toolchain was built, for consumers of
+//! prebuilt toolchains that need to audit where a compiler came from.
+//!
+//! Loosely modelled on SLSA provenance: the subject is the blake3 hash of every file of the
+//! toolchain and its sysroot, the materials are the source archives (with their hashes) and the
+//! patches applied to them, and the build is described by the configure commands (see
+//! [`crate::metadata`]), the toolup version and the build machine. `toolup provenance --verify`
+//! and `toolup cache restore` check a toolchain against its subject.
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::{Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    cache::Manifest,
+    commands::is_plan,
+    download::{archive_filename, cache_dir},
+    metadata::{self, ConfigureStep},
+    profile::Toolchain,
+};
+
+pub const PROVENANCE: &str = "provenance.json";
+
+thread_local! {
+    // the materials of the toolchain being installed by this thread, see `start`
+    static SOURCES: RefCell<BTreeMap<String, String>> = const { RefCell::new(BTreeMap::new()) };
+    static PATCHES: RefCell<BTreeMap<String, String>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// See [`Toolchain::id`]
+    pub id: String,
+    /// The blake3 hash of every file, relative to the toolchain directory. Sysroot files are
+    /// under `sysroot/`.
+    pub subject: BTreeMap<String, String>,
+    pub sources: Vec<SourceMaterial>,
+    /// The blake3 hash of every patch applied to the sources, by name
+    pub patches: BTreeMap<String, String>,
+    pub configure: Vec<ConfigureStep>,
+    pub builder: BuildMachine,
+}
+
+/// A source archive the toolchain was built from.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SourceMaterial {
+    pub url: String,
+    /// `None` for archives cached before toolup recorded hashes
+    pub blake3: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildMachine {
+    pub toolup_version: String,
+    /// e.g. `x86_64-linux`
+    pub host: String,
+    /// The kernel release of the build machine
+    pub kernel: Option<String>,
+    /// The first line of `cc --version`
+    pub host_cc: Option<String>,
+    /// RFC 3339
+    pub finished: String,
+}
+
+impl BuildMachine {
+    fn current() -> Self {
+        let host_cc = std::process::Command::new("cc")
+            .arg("--version")
+            .output()
+            .ok()
+            .and_then(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .next()
+                    .map(String::from)
+            });
+        Self {
+            toolup_version: env!("CARGO_PKG_VERSION").to_string(),
+            host: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            kernel: std::fs::read_to_string("/proc/sys/kernel/osrelease")
+                .ok()
+                .map(|release| release.trim().to_string()),
+            host_cc,
+            finished: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+        }
+    }
+}
+
+/// A file that doesn't match the subject of a provenance document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Modified(String),
+    Missing(String),
+    Added(String),
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mismatch::Modified(path) => write!(f, "modified: {path}"),
+            Mismatch::Missing(path) => write!(f, "missing: {path}"),
+            Mismatch::Added(path) => write!(f, "added: {path}"),
+        }
+    }
+}
+
+/// Start recording the materials of a toolchain install on this thread.
+pub fn start() {
+    SOURCES.with_borrow_mut(|sources| sources.clear());
+    PATCHES.with_borrow_mut(|patches| patches.clear());
+}
+
+/// Record that the source archive of `url`, extracted to `dirname`, is used by the install.
+pub fn record_source(url: &str, dirname: &str) {
+    SOURCES.with_borrow_mut(|sources| sources.insert(url.to_string(), dirname.to_string()));
+}
+
+/// Record that a patch was applied to the sources.
+pub fn record_patch(name: &str, content: &str) {
+    let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+    PATCHES.with_borrow_mut(|patches| patches.insert(name.to_string(), hash));
+}
+
+/// Returns the blake3 hash of every file under `dir`, by path relative to `dir`. Symlinks are
+/// hashed by their target.
+pub fn hash_tree(dir: &Path) -> Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry
+            .path()
+            .strip_prefix(dir)?
+            .to_string_lossy()
+            .into_owned();
+        let hash = if entry.path_is_symlink() {
+            let target = std::fs::read_link(entry.path())?;
+            blake3::hash(target.as_os_str().as_encoded_bytes())
+        } else if entry.file_type().is_file() {
+            blake3::hash(
+                &std::fs::read(entry.path())
+                    .context(format!("failed to read `{}`", entry.path().display()))?,
+            )
+        } else {
+            continue;
+        };
+        hashes.insert(relative, hash.to_hex().to_string());
+    }
+    Ok(hashes)
+}
+
+/// The files of a toolchain directory and its sysroot (under `sysroot/`), hashed.
+fn subject(dir: &Path, sysroot: Option<&Path>) -> Result<BTreeMap<String, String>> {
+    let mut subject = hash_tree(dir)?;
+    subject.remove(PROVENANCE);
+    if let Some(sysroot) = sysroot.filter(|sysroot| sysroot.is_dir()) {
+        for (path, hash) in hash_tree(sysroot)? {
+            subject.insert(format!("sysroot/{path}"), hash);
+        }
+    }
+    Ok(subject)
+}
+
+fn sysroot(toolchain: &Toolchain) -> Result<Option<PathBuf>> {
+    Ok(if toolchain.is_freestanding() {
+        None
+    } else {
+        Some(toolchain.sysroot()?)
+    })
+}
+
+/// Write the provenance of a toolchain being installed, after [`metadata::write`].
+///
+/// A partial rebuild keeps the materials of the stages it didn't rebuild.
+pub fn write(toolchain: &Toolchain) -> Result<()> {
+    if is_plan() {
+        return Ok(());
+    }
+
+    let manifest = Manifest::load(&cache_dir()?)?;
+    let mut sources: Vec<SourceMaterial> = SOURCES
+        .with_borrow(Clone::clone)
+        .into_iter()
+        .map(|(url, dirname)| {
+            // the hash of the archive the source directory was extracted from
+            let blake3 = match manifest.sources.get(&dirname) {
+                Some(source) => Some(source.blake3.clone()),
+                None => archive_filename(&url)
+                    .ok()
+                    .and_then(|file| manifest.archives.get(&file))
+                    .map(|archive| archive.blake3.clone()),
+            };
+            SourceMaterial { url, blake3 }
+        })
+        .collect();
+    let mut patches = PATCHES.with_borrow(Clone::clone);
+    if let Some(previous) = read(&toolchain.dir()?)? {
+        for source in previous.sources {
+            if !sources.iter().any(|s| s.url == source.url) {
+                sources.push(source);
+            }
+        }
+        for (name, hash) in previous.patches {
+            patches.entry(name).or_insert(hash);
+        }
+    }
+    sources.sort();
+
+    let dir = toolchain.dir()?;
+    let provenance = Provenance {
+        id: toolchain.id(),
+        subject: subject(&dir, sysroot(toolchain)?.as_deref())?,
+        sources,
+        patches,
+        configure: metadata::read(toolchain)?
+            .map(|metadata| metadata.configure)
+            .unwrap_or_default(),
+        builder: BuildMachine::current(),
+    };
+    let path = dir.join(PROVENANCE);
+    std::fs::write(&path, serde_json::to_string_pretty(&provenance)?)
+        .context(format!("failed to write `{}`", path.display()))
+}
+
+/// Read the provenance in the toolchain directory `dir`, `None` if it has none.
+pub fn read(dir: &Path) -> Result<Option<Provenance>> {
+    let path = dir.join(PROVENANCE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content =
+        std::fs::read_to_string(&path).context(format!("failed to read `{}`", path.display()))?;
+    serde_json::from_str(&content)
+        .map(Some)
+        .context(format!("failed to parse `{}`", path.display()))
+}
+
+/// Compare the toolchain directory `dir` and its `sysroot` with the subject of `provenance`.
+pub fn verify(
+    provenance: &Provenance,
+    dir: &Path,
+    sysroot: Option<&Path>,
+) -> Result<Vec<Mismatch>> {
+    let actual = subject(dir, sysroot)?;
+    let mut mismatches = vec![];
+    for (path, hash) in &provenance.subject {
+        match actual.get(path) {
+            None => mismatches.push(Mismatch::Missing(path.clone())),
+            Some(actual) if actual != hash => mismatches.push(Mismatch::Modified(path.clone())),
+            Some(_) => {}
+        }
+    }
+    for path in actual.keys() {
+        if !provenance.subject.contains_key(path) {
+            mismatches.push(Mismatch::Added(path.clone()));
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Verify an installed toolchain against its provenance, `None` if it has none.
+pub fn verify_toolchain(toolchain: &Toolchain) -> Result<Option<Vec<Mismatch>>> {
+    let dir = toolchain.dir()?;
+    let Some(provenance) = read(&dir)? else {
+        return Ok(None);
+    };
+    verify(&provenance, &dir, sysroot(toolchain)?.as_deref()).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{BuildMachine, Mismatch, Provenance, subject, verify};
+
+    #[test]
+    fn test_verify() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let sysroot = tempfile::TempDir::new()?;
+        std::fs::create_dir(dir.path().join("bin"))?;
+        std::fs::write(dir.path().join("bin/gcc"), "gcc")?;
+        std::fs::write(sysroot.path().join("libc.so"), "libc")?;
+        std::os::unix::fs::symlink("gcc", dir.path().join("bin/cc"))?;
+
+        let provenance = Provenance {
+            id: "test".into(),
+            subject: subject(dir.path(), Some(sysroot.path()))?,
+            sources: vec![],
+            patches: BTreeMap::new(),
+            configure: vec![],
+            builder: BuildMachine::current(),
+        };
+        assert_eq!(
+            provenance.subject.keys().collect::<Vec<_>>(),
+            ["bin/cc", "bin/gcc", "sysroot/libc.so"]
+        );
+        assert!(verify(&provenance, dir.path(), Some(sysroot.path()))?.is_empty());
+
+        std::fs::write(dir.path().join("bin/gcc"), "not gcc")?;
+        std::fs::remove_file(sysroot.path().join("libc.so"))?;
+        std::fs::write(dir.path().join("bin/extra"), "")?;
+        assert_eq!(
+            verify(&provenance, dir.path(), Some(sysroot.path()))?,
+            [
+                Mismatch::Modified("bin/gcc".into()),
+                Mismatch::Missing("sysroot/libc.so".into()),
+                Mismatch::Added("bin/extra".into()),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1,24 +1,183 @@
 use std::{
-    path::Path,
-    process::{Command, Stdio},
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    os::unix::{net::UnixStream, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
 
-use crate::profile::{Arch, Target};
+use crate::{
+    capability::{self, Operation},
+    commands::{is_plan, log_filename},
+    cpio::pack_rootfs,
+    download::logs_dir,
+    error::Failure,
+    firmware,
+    profile::{Abi, Arch, Target},
+    vm::{VmDir, VmInfo},
+};
 
-pub fn start_vm(target: &Target, kernel: impl AsRef<Path>, initrd: impl AsRef<Path>) -> Result<()> {
-    let kernel = kernel.as_ref();
-    let initrd = initrd.as_ref();
+/// Changes to the QEMU command line, from `--qemu-binary` and `--qemu-arg` or the configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QemuOverrides {
+    /// Used instead of the `qemu-system-*` binary for the target
+    pub binary: Option<PathBuf>,
+    /// Appended after the arguments toolup passes
+    pub args: Vec<String>,
+    /// Appended to the kernel command line, e.g. `crashkernel=256M`
+    pub kernel_args: Vec<String>,
+}
+
+impl QemuOverrides {
+    fn command(&self, qemu: &str) -> Command {
+        match &self.binary {
+            Some(binary) => Command::new(binary),
+            None => Command::new(qemu),
+        }
+    }
+}
+
+/// How the exit status of an `exec` command gets from the guest to the host. The guest writes the
+/// status to a virtio console backed by a file on the `device` bus, then stops QEMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitChannel {
+    /// QEMU is stopped by writing 0 to the `isa-debug-exit` port, it exits with `(0 << 1) | 1`.
+    /// The port can't carry the status itself, QEMU's exit code only has room for 0-127.
+    IsaDebugExit { device: &'static str },
+    /// The guest powers off with PSCI (or SBI on RISC-V) and QEMU exits with 0
+    Virtio { device: &'static str },
+}
+
+impl ExitChannel {
+    fn for_arch(arch: Arch) -> Self {
+        let device = serial_device(arch);
+        match arch {
+            Arch::X86_64 | Arch::I486 | Arch::I586 | Arch::I686 => {
+                ExitChannel::IsaDebugExit { device }
+            }
+            _ => ExitChannel::Virtio { device },
+        }
+    }
+
+    /// Whether QEMU exited the way it does after the guest reported its status.
+    fn stopped_by_guest(self, status: ExitStatus) -> bool {
+        match self {
+            ExitChannel::IsaDebugExit { .. } => status.code() == Some(1),
+            ExitChannel::Virtio { .. } => status.success(),
+        }
+    }
+}
+
+/// The virtio-serial bus of the virtio consoles and ports.
+fn serial_device(arch: Arch) -> &'static str {
+    match arch {
+        Arch::Aarch64 | Arch::Armv7 | Arch::Riscv64 => "virtio-serial-device",
+        // pseries has no virtio-mmio
+        _ => "virtio-serial-pci",
+    }
+}
+
+/// The 9p device sharing a host directory with the guest, mounted at `/toolup/share` by the rootfs
+/// `/init`.
+fn share_device(arch: Arch) -> &'static str {
+    match arch {
+        Arch::Aarch64 | Arch::Armv7 | Arch::Riscv64 => "virtio-9p-device",
+        _ => "virtio-9p-pci",
+    }
+}
+
+/// The `exec` commands of a VM run and the host files they need in the guest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Exec {
+    /// Shell commands run one after the other, an empty list boots into a shell
+    pub commands: Vec<String>,
+    /// Host files copied into the rootfs, keyed by their absolute path in the guest
+    pub files: BTreeMap<PathBuf, PathBuf>,
+    /// Files the guest writes to `/toolup/share`, copied to the host after the run, keyed by their
+    /// name in the share
+    pub outputs: BTreeMap<String, PathBuf>,
+    /// A host directory mounted read-only at [`SYSROOT_MOUNT`] in the shell, `toolup linux --shell`
+    pub sysroot: Option<PathBuf>,
+}
 
+/// Where the shell finds [`Exec::sysroot`].
+pub const SYSROOT_MOUNT: &str = "/toolup/sysroot";
+
+/// One of the `exec` commands of a VM run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExecRun {
+    pub command: String,
+    /// `None` if the command didn't finish, e.g. the kernel panicked
+    pub exit_code: Option<i32>,
+    pub secs: Option<f64>,
+}
+
+impl Display for ExecRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.exit_code, self.secs) {
+            (Some(code), Some(secs)) => write!(f, "{code:>4} {secs:>7.2}s {}", self.command),
+            _ => write!(f, "   - {:>8} {}", "-", self.command),
+        }
+    }
+}
+
+/// The outcome and timings of a VM run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct VmRun {
+    /// The exit status of the first `exec` command that failed, 0 if they all succeeded or for an
+    /// interactive shell
+    pub exit_code: i32,
+    /// From starting QEMU until the kernel executed `/init`
+    pub boot_secs: Option<f64>,
+    /// From the start of the first `exec` command until the last one finished
+    pub exec_secs: Option<f64>,
+    /// From starting QEMU until it exited
+    pub wall_secs: f64,
+    /// Every `exec` command, in the order they ran
+    pub execs: Vec<ExecRun>,
+    /// The core dumps of the `exec` commands that crashed, copied to the current directory
+    pub cores: Vec<PathBuf>,
+}
+
+impl Display for VmRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = |s: Option<f64>| s.map_or("-".to_string(), |s| format!("{s:.2}s"));
+        write!(
+            f,
+            "boot {}, exec {}, total {:.2}s",
+            secs(self.boot_secs),
+            secs(self.exec_secs),
+            self.wall_secs
+        )
+    }
+}
+
+/// The QEMU command booting `kernel` with `initrd`, with the monitor sockets of `vm`. Returns the
+/// command and the kernel command line, the console and the extra arguments are added by the
+/// caller.
+fn vm_command(
+    target: &Target,
+    kernel: &Path,
+    initrd: &Path,
+    vm: &VmDir,
+    overrides: &QemuOverrides,
+) -> Result<(Command, String)> {
     let (qemu, extra, console) = match target.arch {
         Arch::X86_64 => ("qemu-system-x86_64", vec![], "ttyS0"),
-        Arch::I686 => ("qemu-system-i386", vec![], "ttyS0"),
-        Arch::Riscv64 => (
-            "qemu-system-riscv64",
-            vec!["-machine", "virt", "-bios", "default"],
-            "ttyS0",
-        ),
+        Arch::I486 | Arch::I586 | Arch::I686 => ("qemu-system-i386", vec![], "ttyS0"),
+        Arch::Riscv64 => ("qemu-system-riscv64", vec!["-machine", "virt"], "ttyS0"),
         Arch::Aarch64 => (
             "qemu-system-aarch64",
             vec!["-M", "virt", "-cpu", "cortex-a57"],
@@ -31,15 +190,23 @@ pub fn start_vm(target: &Target, kernel: impl AsRef<Path>, initrd: impl AsRef<Pa
             vec!["-M", "virt", "-cpu", "cortex-a15"],
             "ttyAMA0",
         ),
-
-        _ => unreachable!(),
+        _ => return Err(capability::unsupported(target, Operation::Linux)),
     };
 
-    let append = format!("console={console},115200 rdinit=/init earlycon");
+    // reboot on panic, with `-no-reboot` QEMU exits instead
+    let mut append = format!("console={console},115200 rdinit=/init earlycon panic=-1");
+    for arg in &overrides.kernel_args {
+        append.push(' ');
+        append.push_str(arg);
+    }
 
-    let mut cmd = Command::new(qemu);
+    let mut cmd = overrides.command(qemu);
+    if let Some(cpu) = target.arch.to_qemu_cpu() {
+        cmd.args(["-cpu", cpu]);
+    }
     cmd.args(&extra)
-        .args(["-m", "1G", "-smp", "2", "-nographic"])
+        .args(firmware::qemu_args(target.arch)?)
+        .args(["-m", "1G", "-smp", "2", "-no-reboot"])
         .args([
             "-kernel",
             kernel
@@ -52,19 +219,855 @@ pub fn start_vm(target: &Target, kernel: impl AsRef<Path>, initrd: impl AsRef<Pa
                 .to_str()
                 .ok_or_else(|| anyhow::anyhow!("bad initrd path"))?,
         ])
+        .arg("-qmp")
+        .arg(format!(
+            "unix:{},server=on,wait=off",
+            vm.qmp_socket().display()
+        ))
+        .arg("-chardev")
+        .arg(format!(
+            "socket,id=toolup-monitor,path={},server=on,wait=off",
+            vm.monitor_socket().display()
+        ))
+        .args(["-mon", "chardev=toolup-monitor,mode=readline"]);
+    Ok((cmd, append))
+}
+
+/// Print the command line of `cmd`.
+fn print_command(cmd: &Command) {
+    print!("{} ", cmd.get_program().to_string_lossy());
+    for arg in cmd.get_args() {
+        print!("{} ", arg.to_string_lossy());
+    }
+}
+
+/// Where the rootfs `/init` reads the `exec` commands from.
+const EXEC_LIST: &str = "toolup/exec";
+/// Where the host programs run by `exec` commands are copied in the guest.
+pub const EXEC_BIN: &str = "/toolup/bin";
+
+/// Returns `initrd` followed by an archive holding the `exec` commands and files, written to
+/// `dir`. The kernel unpacks both archives into the same rootfs, so the cached rootfs doesn't have
+/// to be repacked for every command.
+fn exec_initrd(initrd: &Path, exec: &Exec, dir: &Path) -> Result<PathBuf> {
+    let overlay = tempfile::TempDir::new()?;
+    let list = overlay.path().join(EXEC_LIST);
+    std::fs::create_dir_all(list.parent().expect("the list is in a directory"))?;
+    std::fs::write(
+        &list,
+        exec.commands
+            .iter()
+            .map(|cmd| format!("{cmd}\n"))
+            .collect::<String>(),
+    )?;
+    for (guest, host) in &exec.files {
+        let path = overlay
+            .path()
+            .join(guest.strip_prefix("/").unwrap_or(guest));
+        std::fs::create_dir_all(path.parent().expect("the file is in a directory"))?;
+        std::fs::copy(host, &path).context(format!(
+            "failed to copy `{}` into the rootfs",
+            host.display()
+        ))?;
+    }
+    let overlay_cpio = dir.join("exec.cpio.gz");
+    pack_rootfs(overlay.path(), &overlay_cpio).context("failed to pack the exec commands")?;
+
+    let combined = dir.join("initrd.cpio.gz");
+    let mut out = File::create(&combined)?;
+    for part in [initrd, overlay_cpio.as_path()] {
+        std::io::copy(&mut File::open(part)?, &mut out)
+            .context(format!("failed to copy `{}`", part.display()))?;
+    }
+    Ok(combined)
+}
+
+/// Boot `kernel` with `initrd`, into a shell or running the `exec` commands one after the other
+/// instead.
+pub fn start_vm(
+    target: &Target,
+    kernel: impl AsRef<Path>,
+    initrd: impl AsRef<Path>,
+    exec: &Exec,
+    overrides: &QemuOverrides,
+) -> Result<VmRun> {
+    let _span = tracing::info_span!("start_vm", target = %target).entered();
+    let kernel = kernel.as_ref();
+
+    // holds the monitor sockets, QMP is also used to shut the VM down when toolup is interrupted
+    let vm_dir = VmDir::create()?;
+    let qmp_socket = vm_dir.qmp_socket();
+    if let Some(cmd) = exec.commands.iter().find(|cmd| cmd.contains('\n')) {
+        return Err(Failure::Usage).context(format!("`{cmd}` spans multiple lines"));
+    }
+    let initrd = if exec.commands.is_empty() || is_plan() {
+        initrd.as_ref().to_path_buf()
+    } else {
+        exec_initrd(initrd.as_ref(), exec, &vm_dir.path)?
+    };
+    let (mut cmd, mut append) = vm_command(target, kernel, &initrd, &vm_dir, overrides)?;
+
+    let channel = ExitChannel::for_arch(target.arch);
+    let status_file = tempfile::NamedTempFile::new()?;
+    // the guest writes core dumps here
+    let share = vm_dir.path.join("share");
+    if !exec.commands.is_empty() {
+        // the kernel passes unknown parameters to init as environment variables
+        append.push_str(&format!(" toolup_exec=/{EXEC_LIST}"));
+        std::fs::create_dir_all(&share)?;
+        cmd.arg("-fsdev")
+            .arg(format!(
+                "local,id=toolup-share,path={},security_model=none",
+                share.display()
+            ))
+            .arg("-device")
+            .arg(format!(
+                "{},fsdev=toolup-share,mount_tag=toolup",
+                share_device(target.arch)
+            ));
+        let (ExitChannel::IsaDebugExit { device } | ExitChannel::Virtio { device }) = channel;
+        // the first virtio console after the boot console
+        append.push_str(" toolup_exit=hvc1");
+        cmd.arg("-chardev")
+            .arg(format!(
+                "file,id=toolup-exit,path={}",
+                status_file.path().display()
+            ))
+            .args(["-device", device])
+            .args(["-device", "virtconsole,chardev=toolup-exit"]);
+        if let ExitChannel::IsaDebugExit { .. } = channel {
+            append.push_str(" toolup_poweroff=isa-debug-exit");
+            cmd.args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x01"]);
+        }
+    }
+    // the guest follows the size of the terminal, see `ForwardResize`
+    let resize = exec.commands.is_empty() && terminal_size().is_some();
+    if exec.commands.is_empty() {
+        if let Some(sysroot) = &exec.sysroot {
+            append.push_str(&format!(" toolup_sysroot={SYSROOT_MOUNT}"));
+            cmd.arg("-fsdev")
+                .arg(format!(
+                    "local,id=toolup-sysroot,path={},security_model=none,readonly=on",
+                    sysroot.display()
+                ))
+                .arg("-device")
+                .arg(format!(
+                    "{},fsdev=toolup-sysroot,mount_tag=toolup-sysroot",
+                    share_device(target.arch)
+                ));
+        }
+        if resize {
+            cmd.arg("-chardev")
+                .arg(format!(
+                    "socket,id=toolup-winch,path={},server=on,wait=off",
+                    vm_dir.winch_socket().display()
+                ))
+                .args(["-device", serial_device(target.arch)])
+                .args([
+                    "-device",
+                    "virtserialport,chardev=toolup-winch,name=toolup.winch",
+                ]);
+        }
+    }
+    cmd.arg("-nographic")
         .args(["-append", &append])
+        .args(&overrides.args)
         .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
+        // scanned for kernel panics
+        .stdout(Stdio::piped())
         .stderr(Stdio::inherit());
+    let qemu = cmd.get_program().to_string_lossy().to_string();
 
-    print!("{} ", qemu);
-    for arg in cmd.get_args() {
-        print!("{} ", arg.to_str().unwrap());
+    print_command(&cmd);
+    if is_plan() {
+        println!();
+        return Ok(VmRun::default());
+    }
+
+    // the whole console, including an interactive session
+    let transcript_path = logs_dir()?.join(log_filename(format!("console-{target}")));
+    let mut transcript = File::create(&transcript_path)
+        .context(format!("failed to create `{}`", transcript_path.display()))?;
+    let _terminal = TerminalGuard::save();
+    let started = Instant::now();
+    let mut child = cmd
+        .spawn()
+        .context(Failure::VmBoot)
+        .context(format!("failed to run {qemu}"))?;
+    vm_dir.register(child.id(), target, kernel)?;
+    log::debug!("=> started VM {}", vm_dir.id);
+    let shutdown = ShutdownOnSignal::install(qmp_socket, child.id());
+    let resize = resize.then(|| ForwardResize::install(vm_dir.winch_socket()));
+    let console = forward_console(
+        child.stdout.take().expect("stdout is piped"),
+        Some(&mut transcript),
+    )?;
+    let status = child.wait().context(Failure::VmBoot)?;
+    drop(resize);
+    log::info!(
+        "=> the console transcript is in {}",
+        transcript_path.display()
+    );
+
+    if shutdown.finish() {
+        bail!("the VM was shut down after toolup was interrupted");
+    }
+    if console.panicked {
+        return Err(Failure::KernelPanic).context("the kernel panicked, see the console above");
+    }
+    let since_start = |at: Option<Instant>| at.map(|at| (at - started).as_secs_f64());
+    let exec_secs = console
+        .execs
+        .first()
+        .zip(console.execs.iter().rev().find_map(|e| e.finished))
+        .map(|(first, (end, _))| (end - first.started).as_secs_f64());
+    let execs = exec
+        .commands
+        .iter()
+        .enumerate()
+        .map(|(i, command)| {
+            let finished = console
+                .execs
+                .get(i)
+                .and_then(|e| e.finished.map(|f| (e.started, f)));
+            ExecRun {
+                command: command.clone(),
+                exit_code: finished.map(|(_, (_, code))| code),
+                secs: finished.map(|(start, (end, _))| (end - start).as_secs_f64()),
+            }
+        })
+        .collect();
+    let mut run = VmRun {
+        exit_code: 0,
+        boot_secs: since_start(console.init),
+        exec_secs,
+        wall_secs: started.elapsed().as_secs_f64(),
+        execs,
+        cores: vec![],
+    };
+    if exec.commands.is_empty() {
+        if !status.success() {
+            return Err(Failure::VmBoot).context(format!("QEMU exited with status {status}"));
+        }
+        return Ok(run);
     }
 
-    let status = cmd.status()?;
-    if !status.success() {
-        bail!("QEMU exited with status {status}");
+    run.cores = collect_cores(&share, target, exec)?;
+    for (name, host) in &exec.outputs {
+        let guest = share.join(name);
+        if guest.exists() {
+            std::fs::copy(&guest, host)
+                .context(format!("failed to copy `{name}` to `{}`", host.display()))?;
+        }
+    }
+
+    let code = if channel.stopped_by_guest(status) {
+        std::fs::read_to_string(status_file.path())?
+            .trim()
+            .parse()
+            .ok()
+    } else {
+        None
+    };
+    run.exit_code = code.context(Failure::VmBoot).context(format!(
+        "the guest didn't report an exit status, QEMU exited with {status}"
+    ))?;
+    Ok(run)
+}
+
+/// Copy the core dumps the guest wrote to `share` to the current directory, printing how to debug
+/// them.
+fn collect_cores(share: &Path, target: &Target, exec: &Exec) -> Result<Vec<PathBuf>> {
+    let mut cores = vec![];
+    for entry in std::fs::read_dir(share)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // `core.%e.%p`, see `packages::busybox`
+        let Some(program) = name
+            .strip_prefix("core.")
+            .and_then(|rest| rest.rsplit_once('.'))
+            .map(|(program, _pid)| program)
+        else {
+            continue;
+        };
+        let core = std::env::current_dir()?.join(&name);
+        std::fs::copy(entry.path(), &core).context(format!(
+            "failed to copy the core dump to `{}`",
+            core.display()
+        ))?;
+
+        // `%e` is truncated to 15 characters
+        let binary = exec
+            .files
+            .iter()
+            .find(|(guest, _)| {
+                guest.starts_with(EXEC_BIN)
+                    && guest
+                        .file_name()
+                        .is_some_and(|file| file.to_string_lossy().starts_with(program))
+            })
+            .map_or(PathBuf::from(program), |(_, host)| host.clone());
+        log::warn!(
+            "=> {program} dumped core, debug it with:\n{target}-gdb {} {}",
+            binary.display(),
+            core.display()
+        );
+        cores.push(core);
+    }
+    cores.sort();
+    Ok(cores)
+}
+
+/// Boot `kernel` with `initrd` in the background, with the guest console written to
+/// [`VmDir::console_log`]. Returns the VM once QEMU is up, or `None` with `--plan`.
+///
+/// The VM keeps running after toolup exits, it's managed with `toolup vm`.
+pub fn detach_vm(
+    target: &Target,
+    kernel: impl AsRef<Path>,
+    initrd: impl AsRef<Path>,
+    overrides: &QemuOverrides,
+) -> Result<Option<VmInfo>> {
+    let _span = tracing::info_span!("detach_vm", target = %target).entered();
+    let kernel = kernel.as_ref();
+    let vm_dir = VmDir::create()?;
+    let (mut cmd, append) = vm_command(target, kernel, initrd.as_ref(), &vm_dir, overrides)?;
+    let qemu_log = vm_dir.path.join("qemu.log");
+    cmd.args(["-display", "none"])
+        .arg("-serial")
+        .arg(format!("file:{}", vm_dir.console_log().display()))
+        .args(["-append", &append])
+        .args(&overrides.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(File::create(&qemu_log)?)
+        // not interrupted by a Ctrl-C in the terminal toolup was started from
+        .process_group(0);
+    let qemu = cmd.get_program().to_string_lossy().to_string();
+
+    print_command(&cmd);
+    println!();
+    if is_plan() {
+        return Ok(None);
+    }
+
+    let mut child = cmd
+        .spawn()
+        .context(Failure::VmBoot)
+        .context(format!("failed to run {qemu}"))?;
+    // QEMU creates the sockets once it parsed its arguments and started the machine
+    for _ in 0..50 {
+        if let Some(status) = child.try_wait()? {
+            let log = std::fs::read_to_string(&qemu_log).unwrap_or_default();
+            return Err(Failure::VmBoot)
+                .context(format!("QEMU exited with status {status}: {}", log.trim()));
+        }
+        if vm_dir.qmp_socket().exists() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    let info = vm_dir.register(child.id(), target, kernel)?;
+    vm_dir.keep();
+    Ok(Some(info))
+}
+
+/// Restores the terminal settings on drop, `-nographic` leaves the terminal raw when QEMU doesn't
+/// exit cleanly.
+struct TerminalGuard(Option<libc::termios>);
+
+impl TerminalGuard {
+    fn save() -> Self {
+        // SAFETY: `termios` is plain data and `tcgetattr` only writes to it
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return Self(None);
+            }
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Self(None);
+            }
+            Self(Some(termios))
+        }
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        if let Some(termios) = &self.0 {
+            // SAFETY: `termios` was filled by `tcgetattr`
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+            }
+        }
+    }
+}
+
+/// The rows and columns of the terminal toolup runs in, `None` if stdin isn't a terminal.
+fn terminal_size() -> Option<(u16, u16)> {
+    // SAFETY: `winsize` is plain data and `TIOCGWINSZ` only writes to it
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut size) != 0 || size.ws_row == 0 {
+            return None;
+        }
+        Some((size.ws_row, size.ws_col))
+    }
+}
+
+/// Set by the SIGWINCH handler of [`ForwardResize`].
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_resize(_: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Writes the size of the host's terminal to the `toolup.winch` port of the guest, at first and
+/// on every SIGWINCH. A serial console has no size of its own, the rootfs `/init` sets it on the
+/// console with `stty`, which sends SIGWINCH to the programs running there (see
+/// `packages::busybox`).
+struct ForwardResize {
+    previous: libc::sighandler_t,
+    done: Arc<AtomicBool>,
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl ForwardResize {
+    fn install(socket: PathBuf) -> Self {
+        // the initial size
+        RESIZED.store(true, Ordering::SeqCst);
+        let handler = on_resize as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        let previous = unsafe { libc::signal(libc::SIGWINCH, handler) };
+
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut port = None;
+                while !done.load(Ordering::SeqCst) {
+                    // QEMU creates the socket once the machine started
+                    if port.is_none() {
+                        port = UnixStream::connect(&socket).ok();
+                    }
+                    if let Some(stream) = port.as_mut()
+                        && RESIZED.swap(false, Ordering::SeqCst)
+                        && let Some((rows, cols)) = terminal_size()
+                        && let Err(err) = writeln!(stream, "{rows} {cols}")
+                    {
+                        log::debug!("failed to send the terminal size: {err}");
+                        port = None;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+            })
+        };
+        Self {
+            previous,
+            done,
+            watcher: Some(watcher),
+        }
+    }
+}
+
+impl Drop for ForwardResize {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+        // SAFETY: restores the handler that was installed before
+        unsafe {
+            libc::signal(libc::SIGWINCH, self.previous);
+        }
+    }
+}
+
+/// Set by the signal handler of [`ShutdownOnSignal`].
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+const SHUTDOWN_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Catches SIGINT, SIGTERM and SIGHUP while a VM runs and shuts the VM down through its QMP
+/// socket instead of leaving QEMU running.
+struct ShutdownOnSignal {
+    previous: Vec<(libc::c_int, libc::sighandler_t)>,
+    done: Arc<AtomicBool>,
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl ShutdownOnSignal {
+    fn install(qmp_socket: PathBuf, pid: u32) -> Self {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let previous = SHUTDOWN_SIGNALS
+            .iter()
+            // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+            .map(|&signal| (signal, unsafe { libc::signal(signal, handler) }))
+            .collect();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    if INTERRUPTED.load(Ordering::SeqCst) {
+                        shutdown_vm(&qmp_socket, pid, &done);
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+            })
+        };
+        Self {
+            previous,
+            done,
+            watcher: Some(watcher),
+        }
+    }
+
+    /// Stop watching for signals once QEMU exited, returns whether a signal was caught.
+    fn finish(mut self) -> bool {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for ShutdownOnSignal {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        for &(signal, handler) in &self.previous {
+            // SAFETY: restores the handler that was installed before
+            unsafe {
+                libc::signal(signal, handler);
+            }
+        }
+    }
+}
+
+/// Ask the guest to power down, then quit QEMU and finally kill it if it's still running after a
+/// few seconds. The busybox rootfs has no ACPI daemon, so most guests only stop with `quit`.
+fn shutdown_vm(qmp_socket: &Path, pid: u32, exited: &AtomicBool) {
+    let wait_for_exit = || {
+        (0..30).any(|_| {
+            std::thread::sleep(Duration::from_millis(100));
+            exited.load(Ordering::SeqCst)
+        })
+    };
+
+    log::warn!("=> interrupted, shutting down the VM");
+    if let Err(err) = qmp(qmp_socket, "system_powerdown") {
+        log::debug!("system_powerdown failed: {err:#}");
+    }
+    if wait_for_exit() {
+        return;
+    }
+    if let Err(err) = qmp(qmp_socket, "quit") {
+        log::debug!("quit failed: {err:#}");
+    }
+    if wait_for_exit() {
+        return;
+    }
+    log::warn!("=> QEMU didn't quit, killing it");
+    // SAFETY: a plain syscall, `exited` is set right after QEMU is reaped so the pid is still QEMU's
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+/// Run a QMP command without arguments.
+pub fn qmp(socket: &Path, command: &str) -> Result<()> {
+    let mut stream = UnixStream::connect(socket)
+        .context(format!("failed to connect to `{}`", socket.display()))?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    // the greeting
+    reader.read_line(&mut line)?;
+    for command in ["qmp_capabilities", command] {
+        writeln!(stream, r#"{{"execute": "{command}"}}"#)?;
+        line.clear();
+        reader.read_line(&mut line)?;
+        if line.contains(r#""error""#) {
+            bail!("`{command}` failed: {}", line.trim());
+        }
     }
     Ok(())
 }
+
+/// An `exec` command seen by [`forward_console`].
+#[derive(Debug)]
+struct ExecEvent {
+    started: Instant,
+    /// When it finished and its exit status
+    finished: Option<(Instant, i32)>,
+}
+
+/// What [`forward_console`] saw on the guest console.
+#[derive(Debug, Default)]
+struct ConsoleEvents {
+    panicked: bool,
+    /// When the kernel executed `/init`
+    init: Option<Instant>,
+    /// The `exec` commands, in order
+    execs: Vec<ExecEvent>,
+}
+
+impl ConsoleEvents {
+    /// Note the `toolup: exec <n> started` and `toolup: exec <n> finished <status>` lines printed
+    /// by the rootfs `/init`, see `packages::busybox`.
+    fn exec_line(&mut self, line: &str, at: Instant) {
+        let Some(rest) = line.trim().strip_prefix("toolup: exec ") else {
+            return;
+        };
+        let mut words = rest.split(' ');
+        let (Some(n), Some(event)) = (words.next(), words.next()) else {
+            return;
+        };
+        let Ok(n) = n.parse::<usize>() else {
+            return;
+        };
+        match event {
+            "started" if n == self.execs.len() + 1 => self.execs.push(ExecEvent {
+                started: at,
+                finished: None,
+            }),
+            "finished" => {
+                let code = words.next().and_then(|code| code.parse().ok());
+                let exec = n.checked_sub(1).and_then(|i| self.execs.get_mut(i));
+                if let (Some(exec), Some(code)) = (exec, code) {
+                    exec.finished = Some((at, code));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Copy the guest console to stdout and `transcript`, noting when the kernel panicked and the boot
+/// milestones.
+fn forward_console(
+    mut console: impl Read,
+    mut transcript: Option<&mut dyn Write>,
+) -> Result<ConsoleEvents> {
+    const PANIC: &[u8] = b"Kernel panic - not syncing";
+    const INIT: &[u8] = b"Run /init as init process";
+    // the longest pattern
+    const LONGEST: usize = PANIC.len();
+
+    let mut stdout = std::io::stdout();
+    let mut buf = [0u8; 4096];
+    // keep the end of the previous read in case the message is split between reads
+    let mut window: Vec<u8> = vec![];
+    let mut line: Vec<u8> = vec![];
+    let mut events = ConsoleEvents::default();
+    loop {
+        let n = match console.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).context("failed to read the guest console"),
+        };
+        stdout.write_all(&buf[..n])?;
+        stdout.flush()?;
+        // the transcript is best effort, the console keeps going without it
+        if let Some(out) = transcript.as_mut()
+            && let Err(err) = out.write_all(&buf[..n])
+        {
+            log::warn!("failed to write the console transcript: {err}");
+            transcript = None;
+        }
+
+        window.extend_from_slice(&buf[..n]);
+        let seen = |pattern: &[u8]| window.windows(pattern.len()).any(|w| w == pattern);
+        events.panicked |= seen(PANIC);
+        if events.init.is_none() && seen(INIT) {
+            events.init = Some(Instant::now());
+        }
+        window.drain(..window.len().saturating_sub(LONGEST));
+
+        for &byte in &buf[..n] {
+            if byte == b'\n' {
+                events.exec_line(&String::from_utf8_lossy(&line), Instant::now());
+                line.clear();
+            } else if line.len() < 256 {
+                // the lines toolup looks for are short
+                line.push(byte);
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Run a bare-metal `elf` for a freestanding `target` with QEMU semihosting, returning its exit
+/// code.
+///
+/// The program has to be linked with a semihosting runtime (e.g. libgloss' `rdimon` for ARM),
+/// which forwards stdio and the exit code to the host through QEMU. x86 targets are rejected,
+/// QEMU has no semihosting for them.
+pub fn run_baremetal(
+    target: &Target,
+    elf: impl AsRef<Path>,
+    args: &[OsString],
+    overrides: &QemuOverrides,
+) -> Result<i32> {
+    let load = [OsString::from("-kernel"), elf.as_ref().into()];
+    baremetal(target, elf.as_ref(), &load, args, overrides)
+}
+
+/// Like [`run_baremetal`], but loads `binary`, the flat binary of `elf` (`objcopy -O binary`), at
+/// `address` and starts it at `entry`, instead of letting QEMU load the ELF.
+pub fn run_baremetal_binary(
+    target: &Target,
+    elf: &Path,
+    binary: &Path,
+    address: u64,
+    entry: u64,
+    args: &[OsString],
+    overrides: &QemuOverrides,
+) -> Result<i32> {
+    let mut file = OsString::from(format!("loader,addr={address:#x},force-raw=on,file="));
+    file.push(binary);
+    let load = [
+        "-device".into(),
+        file,
+        "-device".into(),
+        format!("loader,addr={entry:#x},cpu-num=0").into(),
+    ];
+    baremetal(target, elf, &load, args, overrides)
+}
+
+/// Run a bare-metal program with semihosting, `load` are the QEMU arguments loading it.
+fn baremetal(
+    target: &Target,
+    elf: &Path,
+    load: &[OsString],
+    args: &[OsString],
+    overrides: &QemuOverrides,
+) -> Result<i32> {
+    if !matches!(target.abi, Abi::Elf | Abi::Eabi | Abi::Eabihf) {
+        bail!("{target} is not a freestanding target, run it with qemu user-mode instead");
+    }
+
+    let (qemu, extra): (&str, &[&str]) = match target.arch {
+        Arch::Armv7 => ("qemu-system-arm", &["-M", "virt", "-cpu", "cortex-a15"]),
+        Arch::Aarch64 => ("qemu-system-aarch64", &["-M", "virt", "-cpu", "cortex-a57"]),
+        Arch::Riscv64 => ("qemu-system-riscv64", &["-M", "virt", "-bios", "none"]),
+        // QEMU only implements semihosting for ARM, RISC-V and a few embedded architectures
+        Arch::X86_64 | Arch::I486 | Arch::I586 | Arch::I686 => {
+            return Err(Failure::Usage).context(format!(
+                "bare-metal run is not supported for x86, QEMU has no semihosting for {target}"
+            ));
+        }
+        _ => bail!("QEMU can't run {target} binaries"),
+    };
+
+    // semihosting passes the program name and arguments as a single command line
+    let mut cmdline = elf.as_os_str().to_owned();
+    for arg in args {
+        cmdline.push(" ");
+        cmdline.push(arg);
+    }
+
+    let mut cmd = overrides.command(qemu);
+    if let Some(cpu) = target.arch.to_qemu_cpu() {
+        cmd.args(["-cpu", cpu]);
+    }
+    cmd.args(extra)
+        .args(["-nographic", "-monitor", "none"])
+        .args(["-semihosting-config", "enable=on,target=native"])
+        .arg("-semihosting-cmdline")
+        .arg(cmdline)
+        .args(load)
+        .args(&overrides.args)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+    let qemu = cmd.get_program().to_string_lossy().to_string();
+
+    if is_plan() {
+        print!("{qemu} ");
+        for arg in cmd.get_args() {
+            print!("{} ", arg.to_string_lossy());
+        }
+        println!();
+        return Ok(0);
+    }
+
+    let _terminal = TerminalGuard::save();
+    let status = cmd
+        .status()
+        .context(Failure::VmBoot)
+        .context(format!("failed to run {qemu}"))?;
+    status
+        .code()
+        .context(Failure::GuestProgram)
+        .context(format!("{qemu} was killed by a signal"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{os::unix::process::ExitStatusExt, process::ExitStatus, str::FromStr};
+
+    use super::{ExitChannel, QemuOverrides, forward_console, run_baremetal};
+    use crate::profile::{Arch, Target};
+
+    #[test]
+    fn test_exit_channel_stopped_by_guest() {
+        // wait statuses, the exit code is in the second byte
+        let exited = |code: i32| ExitStatus::from_raw(code << 8);
+        let x86 = ExitChannel::for_arch(Arch::X86_64);
+        assert!(x86.stopped_by_guest(exited(1)));
+        // QEMU failed before the guest stopped it
+        assert!(!x86.stopped_by_guest(exited(0)));
+        assert!(!x86.stopped_by_guest(exited(255)));
+        let aarch64 = ExitChannel::for_arch(Arch::Aarch64);
+        assert!(aarch64.stopped_by_guest(exited(0)));
+        assert!(!aarch64.stopped_by_guest(exited(1)));
+    }
+
+    #[test]
+    fn test_baremetal_rejects_x86() -> anyhow::Result<()> {
+        for target in ["x86_64-elf", "i686-elf"] {
+            let error = run_baremetal(
+                &Target::from_str(target)?,
+                "a.out",
+                &[],
+                &QemuOverrides::default(),
+            )
+            .unwrap_err();
+            assert!(
+                format!("{error:#}").contains("not supported for x86"),
+                "{target}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_reports_every_exec() -> anyhow::Result<()> {
+        let console = b"[    0.5] Run /init as init process\r\n\
+            toolup: exec 1 started\r\nok\r\ntoolup: exec 1 finished 0\r\n\
+            toolup: exec 2 started\r\ntoolup: exec 2 finished 3\r\n\
+            toolup: exec 3 started\r\n";
+        let mut transcript = vec![];
+        let events = forward_console(&console[..], Some(&mut transcript))?;
+        assert_eq!(transcript, console);
+        assert!(events.init.is_some());
+        assert!(!events.panicked);
+        let finished = events
+            .execs
+            .iter()
+            .map(|e| e.finished.map(|(_, code)| code))
+            .collect::<Vec<_>>();
+        assert_eq!(finished, vec![Some(0), Some(3), None]);
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! `~/.toolup/prefixes.json`: toolchains installed with `--prefix` (or a `prefix` key) outside
+//! `~/.toolup/toolchains`, keyed by toolchain id. Commands that only know a toolchain's versions,
+//! e.g. `toolup cc`, find it through this registry.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{commands::is_plan, download::toolup_dir, locks};
+
+fn registry_path() -> Result<PathBuf> {
+    Ok(toolup_dir()?.join("prefixes.json"))
+}
+
+/// Returns the prefix of every toolchain installed outside `~/.toolup/toolchains`.
+pub fn load() -> Result<BTreeMap<String, PathBuf>> {
+    let path = registry_path()?;
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content =
+        std::fs::read_to_string(&path).context(format!("failed to read `{}`", path.display()))?;
+    serde_json::from_str(&content).context(format!("failed to parse `{}`", path.display()))
+}
+
+/// Returns the prefix toolchain `id` was installed into, `None` for the default location.
+pub fn prefix(id: &str) -> Result<Option<PathBuf>> {
+    Ok(load()?.remove(id))
+}
+
+/// Record that toolchain `id` is installed in `prefix`.
+pub fn record(id: &str, prefix: &Path) -> Result<()> {
+    if is_plan() {
+        return Ok(());
+    }
+
+    // toolchains installed in parallel update the registry in turn
+    let _lock = locks::lock("registry");
+    let mut prefixes = load()?;
+    prefixes.insert(id.to_string(), prefix.to_path_buf());
+
+    let path = registry_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(&prefixes)?)
+        .context(format!("failed to write `{}`", path.display()))
+}
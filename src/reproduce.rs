@@ -0,0 +1,316 @@
+//! Check whether a toolchain build is reproducible.
+//!
+//! A toolchain is built from clean objdirs, the installed toolchain and sysroot are copied into a
+//! snapshot, then it's built again (or compared to an existing snapshot) and both trees are
+//! compared file by file. File metadata is ignored, and archive member timestamps/owners are
+//! normalized before comparing.
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use walkdir::WalkDir;
+
+use crate::{
+    download::cache_dir, install_toolchain, profile::Toolchain, stage::Force, sysroot::copy_tree,
+};
+
+/// A file in each snapshot listing the toolchain and sysroot paths the build was installed to.
+const PREFIXES_FILE: &str = "prefixes";
+
+/// What a tree entry is, compared between the two builds.
+#[derive(Debug, PartialEq, Eq)]
+enum Entry {
+    File(blake3::Hash),
+    Symlink(PathBuf),
+    Dir,
+}
+
+#[derive(Debug, Default)]
+pub struct ReproduceReport {
+    /// Paths that only exist in the first build
+    pub only_first: Vec<PathBuf>,
+    /// Paths that only exist in the second build
+    pub only_second: Vec<PathBuf>,
+    /// Paths that exist in both builds with different contents
+    pub differ: Vec<PathBuf>,
+    /// The number of compared paths
+    pub compared: usize,
+}
+
+impl ReproduceReport {
+    pub fn is_reproducible(&self) -> bool {
+        self.only_first.is_empty() && self.only_second.is_empty() && self.differ.is_empty()
+    }
+}
+
+impl Display for ReproduceReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for path in &self.only_first {
+            writeln!(f, "{} {}", "-".red(), path.display())?;
+        }
+        for path in &self.only_second {
+            writeln!(f, "{} {}", "+".green(), path.display())?;
+        }
+        for path in &self.differ {
+            writeln!(f, "{} {}", "~".yellow(), path.display())?;
+        }
+        write!(
+            f,
+            "{} paths compared, {} differ, {} only in first, {} only in second",
+            self.compared,
+            self.differ.len(),
+            self.only_first.len(),
+            self.only_second.len()
+        )
+    }
+}
+
+/// Returns the directory where snapshots of `toolchain` builds are kept.
+pub fn snapshots_dir(toolchain: &Toolchain) -> Result<PathBuf> {
+    Ok(cache_dir()?.join("reproduce").join(toolchain.id()))
+}
+
+/// Build `toolchain` from scratch and compare it with another build.
+///
+/// If `against` is `None` the toolchain is built twice, otherwise the build is compared with the
+/// snapshot at `against` (e.g. one kept from a previous run on another machine).
+///
+/// The installed toolchain and sysroot are replaced by the last build.
+pub fn reproduce(
+    toolchain: &Toolchain,
+    jobs: u64,
+    against: Option<PathBuf>,
+) -> Result<ReproduceReport> {
+    let snapshots = snapshots_dir(toolchain)?;
+
+    let first = match against {
+        Some(dir) => dir,
+        None => {
+            log::info!("=> first build");
+            let first = snapshots.join("first");
+            clean_build(toolchain, jobs)?;
+            snapshot(toolchain, &first)?;
+            first
+        }
+    };
+
+    log::info!("=> second build");
+    let second = snapshots.join("second");
+    clean_build(toolchain, jobs)?;
+    snapshot(toolchain, &second)?;
+
+    log::info!("=> comparing {} and {}", first.display(), second.display());
+    diff_trees(&first, &second)
+}
+
+/// Remove previous build trees and installed files, then install `toolchain`.
+fn clean_build(toolchain: &Toolchain, jobs: u64) -> Result<()> {
+    for objdir in toolchain_objdirs(toolchain)? {
+        log::debug!("removing {}", objdir.display());
+        std::fs::remove_dir_all(&objdir)
+            .context(format!("failed to remove `{}`", objdir.display()))?;
+    }
+    for dir in [toolchain.dir()?, toolchain.sysroot()?] {
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .context(format!("failed to remove `{}`", dir.display()))?;
+        }
+    }
+
+    install_toolchain(toolchain.clone(), jobs, &Force::All)?;
+    Ok(())
+}
+
+/// Returns the objdirs created in the cache while building `toolchain`.
+pub fn toolchain_objdirs(toolchain: &Toolchain) -> Result<Vec<PathBuf>> {
+    let suffix = format!("-{}", toolchain.id());
+    let mut objdirs = vec![];
+
+    for source in std::fs::read_dir(cache_dir()?)? {
+        let source = source?.path();
+        if !source.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(&source)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if path.is_dir() && name.starts_with("objdir-") && name.ends_with(&suffix) {
+                objdirs.push(path);
+            }
+        }
+    }
+
+    Ok(objdirs)
+}
+
+/// Copy the installed toolchain and its sysroot into `dest`.
+fn snapshot(toolchain: &Toolchain, dest: &Path) -> Result<()> {
+    if dest.exists() {
+        std::fs::remove_dir_all(dest).context(format!("failed to remove `{}`", dest.display()))?;
+    }
+    copy_tree(&toolchain.dir()?, &dest.join("toolchain"))?;
+    if toolchain.sysroot()?.exists() {
+        copy_tree(&toolchain.sysroot()?, &dest.join("sysroot"))?;
+    }
+
+    // record where the build was installed, so snapshots from other machines can be normalized.
+    // binaries embed the staging directory they were built in.
+    let prefixes = format!(
+        "{}\n{}\n{}\n",
+        toolchain.dir()?.display(),
+        toolchain.sysroot()?.display(),
+        toolchain.staging_dir()?.display()
+    );
+    std::fs::write(dest.join(PREFIXES_FILE), prefixes)?;
+    Ok(())
+}
+
+fn index_tree(root: &Path) -> Result<BTreeMap<PathBuf, Entry>> {
+    let prefixes: Vec<String> = std::fs::read_to_string(root.join(PREFIXES_FILE))
+        .unwrap_or_default()
+        .lines()
+        .map(String::from)
+        .collect();
+
+    let mut index = BTreeMap::new();
+    for entry in WalkDir::new(root).min_depth(1) {
+        let entry = entry.context(format!("failed to walk `{}`", root.display()))?;
+        let relative = entry.path().strip_prefix(root)?.to_path_buf();
+        if relative == Path::new(PREFIXES_FILE) {
+            continue;
+        }
+
+        let value = if entry.path_is_symlink() {
+            Entry::Symlink(std::fs::read_link(entry.path())?)
+        } else if entry.file_type().is_dir() {
+            Entry::Dir
+        } else {
+            let mut bytes = std::fs::read(entry.path())?;
+            normalize(&mut bytes, &prefixes);
+            Entry::File(blake3::hash(&bytes))
+        };
+        index.insert(relative, value);
+    }
+    Ok(index)
+}
+
+/// Compare two trees by content.
+pub fn diff_trees(first: &Path, second: &Path) -> Result<ReproduceReport> {
+    let first = index_tree(first)?;
+    let mut second = index_tree(second)?;
+    let mut report = ReproduceReport::default();
+
+    for (path, entry) in first {
+        report.compared += 1;
+        match second.remove(&path) {
+            None => report.only_first.push(path),
+            Some(other) if other != entry => report.differ.push(path),
+            Some(_) => {}
+        }
+    }
+    report.compared += second.len();
+    report.only_second.extend(second.into_keys());
+
+    Ok(report)
+}
+
+/// Remove content that is expected to change between identical builds.
+fn normalize(bytes: &mut Vec<u8>, prefixes: &[String]) {
+    // before the prefixes are replaced, which changes the size of the members
+    normalize_ar(bytes);
+
+    // install locations are embedded in text files (e.g. libtool `.la` files) and binaries
+    for prefix in prefixes.iter().filter(|p| !p.is_empty()) {
+        let prefix = prefix.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i..].starts_with(prefix) {
+                out.extend_from_slice(b"@PREFIX@");
+                i += prefix.len();
+            } else {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+        *bytes = out;
+    }
+}
+
+/// Zero the mtime, uid and gid of every member in a `ar` archive (static libraries).
+fn normalize_ar(bytes: &mut [u8]) {
+    const MAGIC: &[u8] = b"!<arch>\n";
+    const HEADER: usize = 60;
+
+    if !bytes.starts_with(MAGIC) {
+        return;
+    }
+
+    let mut offset = MAGIC.len();
+    while offset + HEADER <= bytes.len() {
+        let header = &mut bytes[offset..offset + HEADER];
+        if &header[58..60] != b"`\n" {
+            return;
+        }
+        let Some(size) = std::str::from_utf8(&header[48..58])
+            .ok()
+            .and_then(|s| s.trim().parse::<usize>().ok())
+        else {
+            return;
+        };
+        // mtime (12), uid (6), gid (6)
+        header[16..40].fill(b' ');
+        header[16] = b'0';
+        header[28] = b'0';
+        header[34] = b'0';
+
+        // members are aligned to 2 bytes
+        offset += HEADER + size + (size % 2);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::normalize;
+
+    fn ar(members: &[(&str, u64, &str)]) -> Vec<u8> {
+        let mut bytes = b"!<arch>\n".to_vec();
+        for (name, mtime, content) in members {
+            let header = format!(
+                "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                format!("{name}/"),
+                mtime,
+                1000,
+                1000,
+                "100644",
+                content.len()
+            );
+            bytes.extend_from_slice(header.as_bytes());
+            bytes.extend_from_slice(content.as_bytes());
+            if content.len() % 2 == 1 {
+                bytes.push(b'\n');
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_normalize_ar_with_prefix() {
+        // the first member shrinks once the prefix is replaced, the second one must still be found
+        let mut first = ar(&[
+            ("a.o", 1700000000, "built in /tmp/build-a/toolchain"),
+            ("b.o", 1700000000, "code"),
+        ]);
+        let mut second = ar(&[
+            ("a.o", 1800000000, "built in /tmp/build-b/toolchain"),
+            ("b.o", 1800000000, "code"),
+        ]);
+        normalize(&mut first, &["/tmp/build-a".to_string()]);
+        normalize(&mut second, &["/tmp/build-b".to_string()]);
+        assert_eq!(first, second);
+    }
+}
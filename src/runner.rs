@@ -0,0 +1,117 @@
+//! `toolup runner`: run the bare-metal programs cargo builds for a freestanding target, e.g. Rust
+//! `no_std` firmware, with QEMU semihosting. It's meant to be a cargo `runner`:
+//!
+//! ```toml
+//! [target.riscv64gc-unknown-none-elf]
+//! runner = "toolup runner"
+//! ```
+//!
+//! The target is read from the ELF unless given. The program is converted to a flat binary with
+//! the objcopy of the target's toolchain and loaded where the ELF wants it, the way it's flashed
+//! on a board. x86 isn't supported, QEMU has no semihosting for it.
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    elf::Elf,
+    error::Failure,
+    profile::{Arch, Target, Toolchain},
+    qemu::{QemuOverrides, run_baremetal, run_baremetal_binary},
+};
+
+fn read_elf(elf: &Path) -> Result<Elf> {
+    Elf::read(elf)?
+        .context(Failure::Usage)
+        .context(format!("`{}` is not an ELF file", elf.display()))
+}
+
+/// The freestanding target `elf` was built for.
+pub fn infer_target(elf: &Path) -> Result<Target> {
+    let program = read_elf(elf)?;
+    program
+        .freestanding_target()
+        .context(Failure::Usage)
+        .context(format!(
+            "`{}` is a {} program, toolup has no freestanding toolchain for it",
+            elf.display(),
+            program.describe()
+        ))
+}
+
+/// Convert `elf` to a flat binary next to it with the objcopy of `toolchain`.
+fn flat_binary(toolchain: &Toolchain, elf: &Path) -> Result<PathBuf> {
+    let objcopy = toolchain
+        .bin_dir()?
+        .join(format!("{}-objcopy", toolchain.target));
+    if !objcopy.exists() {
+        return Err(Failure::Usage).context(format!(
+            "`{}` doesn't exist, install the toolchain with `toolup install {}`",
+            objcopy.display(),
+            toolchain.target
+        ));
+    }
+    let binary = elf.with_added_extension("bin");
+    let output = Command::new(&objcopy)
+        .args(["-O", "binary"])
+        .arg(elf)
+        .arg(&binary)
+        .output()
+        .context(format!("failed to run `{}`", objcopy.display()))?;
+    if !output.status.success() {
+        return Err(Failure::Build).context(format!(
+            "objcopy failed to convert `{}`:\n{}",
+            elf.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(binary)
+}
+
+/// Run `elf`, built for the freestanding target of `toolchain`, returning its exit code.
+pub fn run(
+    toolchain: &Toolchain,
+    elf: &Path,
+    args: &[OsString],
+    overrides: &QemuOverrides,
+) -> Result<i32> {
+    let target = &toolchain.target;
+    let program = read_elf(elf)?;
+    if program
+        .freestanding_target()
+        .map(|elf_target| elf_target.arch)
+        != Some(target.arch)
+    {
+        return Err(Failure::Usage).context(format!(
+            "`{}` is a {} program, not a {target} one",
+            elf.display(),
+            program.describe()
+        ));
+    }
+    // rejected by `run_baremetal` before the objcopy of a toolchain that may not be installed
+    if matches!(
+        target.arch,
+        Arch::X86_64 | Arch::I486 | Arch::I586 | Arch::I686
+    ) {
+        return run_baremetal(target, elf, args, overrides);
+    }
+
+    let address = program
+        .load_address
+        .context(Failure::Usage)
+        .context(format!("`{}` has no loadable segments", elf.display()))?;
+    let binary = flat_binary(toolchain, elf)?;
+    run_baremetal_binary(
+        target,
+        elf,
+        &binary,
+        address,
+        program.entry,
+        args,
+        overrides,
+    )
+}
@@ -0,0 +1,94 @@
+//! Running build commands in a bubblewrap sandbox, `--sandbox` or `sandbox = true` under
+//! `[workspace]`.
+//!
+//! configure and make only see the host's system directories (read-only), the toolup directories
+//! (the cache, toolchains, sysroots and host tools), the directory they run in and the
+//! directories of the toolchain being installed (see [`set_sandbox_dirs`]). Everything else, e.g.
+//! `$HOME` or `/opt`, doesn't exist for them, so a build script can't write outside of its build
+//! and no undeclared host path ends up in a toolchain. The network is unshared as well.
+use std::{
+    cell::RefCell,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    download::{cache_dir, ro_cache_dirs, toolup_dir},
+    error::Failure,
+    packages::host_tools::find_program,
+};
+
+/// The host's system directories, mounted read-only when they exist.
+const SYSTEM_DIRS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc"];
+
+static SANDBOX: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static SANDBOX_DIRS: RefCell<Vec<PathBuf>> = const { RefCell::new(vec![]) };
+}
+
+/// Run build commands in a bubblewrap sandbox.
+pub fn set_sandbox(sandbox: bool) {
+    SANDBOX.store(sandbox, Ordering::Relaxed);
+}
+
+/// Whether build commands run in a sandbox.
+pub fn is_sandboxed() -> bool {
+    SANDBOX.load(Ordering::Relaxed)
+}
+
+/// Make `dirs` writable in the sandbox for the build commands of this thread, e.g. the `--prefix`
+/// of the toolchain being installed.
+pub fn set_sandbox_dirs(dirs: Vec<PathBuf>) {
+    SANDBOX_DIRS.set(dirs);
+}
+
+/// Returns the `bwrap` command line running `command` with `args` in `workdir`.
+pub fn wrap(
+    workdir: &Path,
+    command: &OsStr,
+    args: &[impl AsRef<OsStr>],
+) -> Result<(PathBuf, Vec<OsString>)> {
+    let bwrap = find_program("bwrap")
+        .context(Failure::Usage)
+        .context("`--sandbox` requires bubblewrap (`bwrap`) to be installed")?;
+
+    let mut bwrap_args: Vec<OsString> = vec![
+        "--unshare-all".into(),
+        "--die-with-parent".into(),
+        "--dev".into(),
+        "/dev".into(),
+        "--proc".into(),
+        "/proc".into(),
+        "--tmpfs".into(),
+        "/tmp".into(),
+        // TMPDIR may point to a directory that isn't mounted
+        "--setenv".into(),
+        "TMPDIR".into(),
+        "/tmp".into(),
+    ];
+    for dir in SYSTEM_DIRS {
+        bwrap_args.extend(["--ro-bind-try".into(), dir.into(), dir.into()]);
+    }
+    for dir in ro_cache_dirs() {
+        bwrap_args.extend(["--ro-bind-try".into(), dir.clone().into(), dir.into()]);
+    }
+    let writable = [toolup_dir()?, cache_dir()?, workdir.to_path_buf()]
+        .into_iter()
+        .chain(SANDBOX_DIRS.with_borrow(Clone::clone));
+    for dir in writable {
+        std::fs::create_dir_all(&dir).context(format!("failed to create `{}`", dir.display()))?;
+        bwrap_args.extend(["--bind".into(), dir.clone().into(), dir.into()]);
+    }
+    bwrap_args.extend([
+        "--chdir".into(),
+        workdir.into(),
+        "--".into(),
+        command.into(),
+    ]);
+    bwrap_args.extend(args.iter().map(|arg| arg.as_ref().to_os_string()));
+    Ok((bwrap, bwrap_args))
+}
@@ -0,0 +1,195 @@
+//! Updating the `toolup` binary from GitHub releases.
+//!
+//! Releases publish a single `toolup` binary (see `.github/workflows/release.yaml`). The update
+//! downloads it next to the current executable, verifies it and then renames it over the current
+//! executable.
+use std::{
+    fs::{self, File},
+    io,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::ui;
+
+const RELEASES_URL: &str = "https://api.github.com/repos/mohammedgqudah/toolup/releases/latest";
+const ASSET_NAME: &str = "toolup";
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+    pub size: u64,
+    /// e.g. `sha256:<hex>`, only present for assets uploaded after GitHub started computing digests.
+    pub digest: Option<String>,
+}
+
+pub enum UpdateStatus {
+    /// The running binary is the latest release
+    UpToDate(String),
+    /// A newer release exists but `check_only` was requested
+    Available(String),
+    /// The binary was replaced with the given release
+    Updated(String),
+}
+
+fn client() -> Result<reqwest::blocking::Client> {
+    Ok(reqwest::blocking::Client::builder()
+        .user_agent(concat!("toolup/", env!("CARGO_PKG_VERSION")))
+        .build()?)
+}
+
+/// Fetch the latest published release.
+pub fn latest_release() -> Result<Release> {
+    client()?
+        .get(RELEASES_URL)
+        .header("Accept", "application/vnd.github+json")
+        .send()
+        .context(format!("sending GET request to {RELEASES_URL}"))?
+        .error_for_status()
+        .context(format!("non-success status from {RELEASES_URL}"))?
+        .json()
+        .context("failed to parse the GitHub release")
+}
+
+/// Parse a release tag such as `v0.2.0` or `0.2.0`.
+pub fn parse_version(tag: &str) -> Result<(u64, u64, u64)> {
+    let version = tag.trim_start_matches('v');
+    let parts = version
+        .split('.')
+        .map(|part| part.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .context(format!("`{tag}` is an invalid release version"))?;
+
+    match parts.as_slice() {
+        [major, minor, patch] => Ok((*major, *minor, *patch)),
+        [major, minor] => Ok((*major, *minor, 0)),
+        _ => Err(anyhow!("`{tag}` is an invalid release version")),
+    }
+}
+
+/// Check for a newer release and replace the current executable with it.
+///
+/// use `check_only` to only report whether an update is available.
+pub fn update(check_only: bool) -> Result<UpdateStatus> {
+    let current = parse_version(env!("CARGO_PKG_VERSION"))?;
+    let release = latest_release()?;
+    let latest = parse_version(&release.tag_name)?;
+
+    if latest <= current {
+        return Ok(UpdateStatus::UpToDate(release.tag_name));
+    }
+    if check_only {
+        return Ok(UpdateStatus::Available(release.tag_name));
+    }
+
+    let asset = release
+        .assets
+        .iter()
+        .find(|asset| asset.name == ASSET_NAME)
+        .context(format!(
+            "release {} doesn't have a `{ASSET_NAME}` binary",
+            release.tag_name
+        ))?;
+
+    let exe = std::env::current_exe().context("failed to locate the current executable")?;
+    // download next to the executable so the final rename doesn't cross filesystems
+    let mut download_path = exe.clone();
+    download_path.add_extension("download");
+
+    if let Err(err) = download_and_verify(asset, &download_path) {
+        let _ = fs::remove_file(&download_path);
+        return Err(err);
+    }
+
+    fs::rename(&download_path, &exe).context(format!(
+        "failed to replace `{}`, do you have write access?",
+        exe.display()
+    ))?;
+
+    Ok(UpdateStatus::Updated(release.tag_name))
+}
+
+fn download_and_verify(asset: &Asset, dest: &Path) -> Result<()> {
+    let response = client()?
+        .get(&asset.browser_download_url)
+        .send()
+        .context(format!(
+            "sending GET request to {}",
+            asset.browser_download_url
+        ))?
+        .error_for_status()
+        .context(format!(
+            "non-success status from {}",
+            asset.browser_download_url
+        ))?;
+
+    let pb = ui::transfer_bar(Some(asset.size), asset.name.clone());
+
+    let mut file = File::create(dest).context(format!("creating {}", dest.display()))?;
+    io::copy(&mut pb.wrap_read(response), &mut file)
+        .context(format!("writing {}", dest.display()))?;
+    pb.finish();
+    drop(file);
+
+    let bytes = fs::read(dest).context(format!("reading {}", dest.display()))?;
+    if bytes.len() as u64 != asset.size {
+        bail!(
+            "downloaded {} bytes but the release asset is {} bytes",
+            bytes.len(),
+            asset.size
+        );
+    }
+
+    match asset
+        .digest
+        .as_deref()
+        .and_then(|d| d.strip_prefix("sha256:"))
+    {
+        Some(expected) => {
+            let actual = format!("{:x}", Sha256::digest(&bytes));
+            if actual != expected {
+                bail!("checksum mismatch: expected sha256 {expected}, got {actual}");
+            }
+        }
+        None => log::warn!("the release doesn't publish a checksum, skipping verification"),
+    }
+
+    fs::set_permissions(dest, fs::Permissions::from_mode(0o755))?;
+
+    // make sure the new binary runs on this host before replacing the working one
+    let status = Command::new(dest)
+        .arg("--version")
+        .output()
+        .context("failed to run the downloaded binary")?
+        .status;
+    if !status.success() {
+        bail!("the downloaded binary exited with {status} when running `--version`");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_version;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("v0.2.0").unwrap(), (0, 2, 0));
+        assert_eq!(parse_version("1.10").unwrap(), (1, 10, 0));
+        assert!(parse_version("v1.2.3-rc1").is_err());
+        assert!(parse_version("v0.3.0").unwrap() > parse_version("0.2.9").unwrap());
+    }
+}
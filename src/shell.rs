@@ -0,0 +1,150 @@
+//! `toolup shell`: an interactive subshell set up to cross-compile for a toolchain.
+//!
+//! The shell gets the toolchain's tools first in `PATH`, `CC`/`CXX` and friends, the sysroot for
+//! pkg-config, and `TOOLUP_TOOLCHAIN` with the toolchain id. The prompt starts with
+//! `(toolup <target>)` so it's clear which toolchain `cc` builds with. bash reads the user's
+//! `~/.bashrc` first, which usually sets `PS1`, so it's started with an rcfile that sources it and
+//! adds the prefix after; other shells only get the prefix when they use `PS1` from the
+//! environment.
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{download::cache_dir, packages::linux::kernel_arch, profile::Toolchain};
+
+/// Set in the shell to the id of its toolchain.
+pub const TOOLCHAIN_VAR: &str = "TOOLUP_TOOLCHAIN";
+
+/// The tools of the toolchain exported as `<VAR>=<target>-<tool>`.
+const TOOLS: &[(&str, &str)] = &[
+    ("CC", "gcc"),
+    ("CXX", "g++"),
+    ("CPP", "cpp"),
+    ("AR", "ar"),
+    ("AS", "as"),
+    ("LD", "ld"),
+    ("NM", "nm"),
+    ("OBJCOPY", "objcopy"),
+    ("OBJDUMP", "objdump"),
+    ("RANLIB", "ranlib"),
+    ("READELF", "readelf"),
+    ("STRIP", "strip"),
+];
+
+/// The tools of WASI toolchains, which have clang and the LLVM tools instead of GCC and binutils.
+const WASI_TOOLS: &[(&str, &str)] = &[
+    ("CC", "wasm32-wasi-clang"),
+    ("CXX", "wasm32-wasi-clang++"),
+    ("AR", "llvm-ar"),
+    ("LD", "wasm-ld"),
+    ("NM", "llvm-nm"),
+    ("OBJCOPY", "llvm-objcopy"),
+    ("OBJDUMP", "llvm-objdump"),
+    ("RANLIB", "llvm-ranlib"),
+    ("STRIP", "llvm-strip"),
+];
+
+/// The environment of a shell for `toolchain`. `kernel` adds `ARCH` and `CROSS_COMPILE` for
+/// building Linux, `cflags` (the toolchain's `cflags` setting) is exported as `CFLAGS`.
+pub fn environment(
+    toolchain: &Toolchain,
+    cflags: &[String],
+    kernel: bool,
+) -> Result<Vec<(String, OsString)>> {
+    let target = &toolchain.target;
+    let mut env = vec![
+        ("PATH".to_string(), toolchain.env_path()?),
+        (TOOLCHAIN_VAR.to_string(), toolchain.id().into()),
+        ("TARGET".to_string(), target.to_string().into()),
+    ];
+    if target.is_wasi() {
+        env.extend(
+            WASI_TOOLS
+                .iter()
+                .map(|(var, tool)| (var.to_string(), (*tool).into())),
+        );
+    } else {
+        env.extend(
+            TOOLS
+                .iter()
+                .map(|(var, tool)| (var.to_string(), format!("{target}-{tool}").into())),
+        );
+    }
+    if !cflags.is_empty() {
+        env.push(("CFLAGS".to_string(), cflags.join(" ").into()));
+    }
+
+    if !toolchain.is_freestanding() {
+        let sysroot = toolchain.sysroot()?;
+        let libdir = std::env::join_paths([
+            sysroot.join("usr/lib/pkgconfig"),
+            sysroot.join("usr/share/pkgconfig"),
+        ])?;
+        env.push(("SYSROOT".to_string(), sysroot.clone().into()));
+        env.push(("PKG_CONFIG_SYSROOT_DIR".to_string(), sysroot.into()));
+        // only the sysroot's packages, never the build machine's
+        env.push(("PKG_CONFIG_LIBDIR".to_string(), libdir));
+        env.push(("PKG_CONFIG_PATH".to_string(), OsString::new()));
+    }
+
+    if kernel {
+        env.push(("ARCH".to_string(), kernel_arch(target)?.into()));
+        env.push(("CROSS_COMPILE".to_string(), format!("{target}-").into()));
+    }
+    Ok(env)
+}
+
+/// The prefix of the prompt of a shell for `toolchain`.
+fn prompt_prefix(toolchain: &Toolchain) -> String {
+    format!("(toolup {}) ", toolchain.target)
+}
+
+/// The bash rcfile of a shell for `toolchain`, sourcing `~/.bashrc` before changing the prompt.
+fn bash_rcfile(toolchain: &Toolchain) -> Result<PathBuf> {
+    let dir = cache_dir()?.join("shell");
+    std::fs::create_dir_all(&dir).context("creating toolup shell dir")?;
+    let rcfile = dir.join(format!("{}.bashrc", toolchain.id()));
+    let rc = format!(
+        "[ -f ~/.bashrc ] && . ~/.bashrc\nPS1='{}'\"$PS1\"\n",
+        prompt_prefix(toolchain)
+    );
+    std::fs::write(&rcfile, rc).context(format!("failed to write `{}`", rcfile.display()))?;
+    Ok(rcfile)
+}
+
+/// Start `$SHELL` (or `/bin/sh`) with `env` for `toolchain` and wait for it, returns its exit
+/// code.
+pub fn spawn(toolchain: &Toolchain, env: Vec<(String, OsString)>) -> Result<i32> {
+    if let Some(active) = std::env::var_os(TOOLCHAIN_VAR) {
+        log::warn!(
+            "already in a toolup shell for {}, starting a nested one",
+            active.to_string_lossy()
+        );
+    }
+
+    let shell = std::env::var_os("SHELL")
+        .filter(|shell| !shell.is_empty())
+        .map_or_else(|| PathBuf::from("/bin/sh"), PathBuf::from);
+    let mut command = Command::new(&shell);
+    command.envs(env);
+    if shell.file_name() == Some(Path::new("bash").as_os_str()) {
+        command.arg("--rcfile").arg(bash_rcfile(toolchain)?);
+    } else {
+        let ps1 = std::env::var("PS1").unwrap_or_else(|_| "$ ".to_string());
+        command.env("PS1", format!("{}{ps1}", prompt_prefix(toolchain)));
+    }
+
+    log::info!(
+        "=> starting {} for {}, `exit` to leave it",
+        shell.display(),
+        toolchain.id()
+    );
+    let status = command
+        .status()
+        .context(format!("failed to start `{}`", shell.display()))?;
+    Ok(status.code().unwrap_or(1))
+}
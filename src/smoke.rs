@@ -0,0 +1,159 @@
+//! A hello world built with a freshly installed hosted toolchain, catching a broken libc or
+//! libstdc++ right after the install instead of at first use.
+use std::{ffi::OsString, path::PathBuf, process::Command};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    commands::{is_plan, plan_step},
+    error::Failure,
+    packages::host_tools::find_program,
+    profile::Toolchain,
+};
+
+const GREETING: &str = "hello from toolup";
+
+const HELLO_C: &str = r#"#include <stdio.h>
+
+int main(void) {
+    puts("hello from toolup");
+    return 0;
+}
+"#;
+
+const HELLO_CXX: &str = r#"#include <iostream>
+#include <string>
+
+int main() {
+    std::string greeting = "hello from toolup";
+    std::cout << greeting << std::endl;
+    return 0;
+}
+"#;
+
+/// A program that runs binaries of a target on the build machine, with its arguments and
+/// environment.
+struct Runner {
+    program: PathBuf,
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+}
+
+/// Returns qemu user-mode for Linux targets, wine for Windows targets or wasmtime for WASI, `None`
+/// if it isn't installed.
+fn runner(toolchain: &Toolchain) -> Result<Option<Runner>> {
+    if toolchain.target.is_wasi() {
+        return Ok(find_program("wasmtime").map(|program| Runner {
+            program,
+            args: vec![],
+            env: vec![],
+        }));
+    }
+    if toolchain.target.is_windows() {
+        let Some(program) = find_program("wine").or_else(|| find_program("wine64")) else {
+            return Ok(None);
+        };
+        // libgcc and libstdc++ are DLLs, wine looks them up in WINEPATH
+        let target_dir = toolchain.dir()?.join(toolchain.target.to_target_string());
+        let winepath = [target_dir.join("lib"), target_dir.join("bin")]
+            .map(|dir| dir.display().to_string())
+            .join(";");
+        return Ok(Some(Runner {
+            program,
+            args: vec![],
+            env: vec![
+                ("WINEPATH".into(), winepath.into()),
+                ("WINEDEBUG".into(), "-all".into()),
+            ],
+        }));
+    }
+
+    // e.g. `qemu-aarch64 -cpu max`
+    let Some(qemu) = toolchain.target.qemu_user_command() else {
+        return Ok(None);
+    };
+    let mut args = qemu.split_whitespace();
+    let Some(program) = args.next().and_then(find_program) else {
+        return Ok(None);
+    };
+    let mut args: Vec<OsString> = args.map(OsString::from).collect();
+    args.push("-L".into());
+    args.push(toolchain.sysroot()?.into());
+    Ok(Some(Runner {
+        program,
+        args,
+        env: vec![],
+    }))
+}
+
+/// Compile and link a C and a C++ hello world against the sysroot of `toolchain`, then run them
+/// with qemu user-mode (or wine for Windows targets) if it's installed. WASI toolchains only build
+/// the C one, they have no libc++, and run it with wasmtime.
+pub fn hello_world(toolchain: &Toolchain) -> Result<()> {
+    if is_plan() {
+        plan_step("build a C and a C++ hello world and run them with qemu user-mode or wine");
+        return Ok(());
+    }
+    log::info!("=> building a hello world");
+
+    let workdir = tempfile::TempDir::new()?;
+    let target = toolchain.target.to_target_string();
+    let exe = if toolchain.target.is_windows() {
+        "hello.exe"
+    } else if toolchain.target.is_wasi() {
+        "hello.wasm"
+    } else {
+        "./hello"
+    };
+    let runner = runner(toolchain)?;
+    if runner.is_none() {
+        log::info!(
+            "=> qemu user-mode (or wine, or wasmtime) isn't installed or can't run {target} \
+             binaries, the hello world is only linked"
+        );
+    }
+
+    let programs = if toolchain.target.is_wasi() {
+        vec![("clang", HELLO_C, "hello.c")]
+    } else {
+        vec![("gcc", HELLO_C, "hello.c"), ("g++", HELLO_CXX, "hello.cc")]
+    };
+    for (compiler, source, file) in programs {
+        let compiler = format!("{target}-{compiler}");
+        std::fs::write(workdir.path().join(file), source)?;
+        let output = Command::new(toolchain.bin_dir()?.join(&compiler))
+            .args([file, "-o", exe])
+            // the NDK's shared libraries are stubs, only a static binary runs outside a device
+            .args(toolchain.target.is_android().then_some("-static"))
+            .current_dir(workdir.path())
+            .env("PATH", toolchain.env_path()?)
+            .output()
+            .context(format!("failed to run `{compiler}`"))?;
+        if !output.status.success() {
+            return Err(Failure::Build).context(format!(
+                "`{compiler}` can't build a hello world:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        let Some(runner) = &runner else {
+            continue;
+        };
+        let output = Command::new(&runner.program)
+            .args(&runner.args)
+            .arg(exe)
+            .envs(runner.env.clone())
+            .current_dir(workdir.path())
+            .output()
+            .context(format!("failed to run `{}`", runner.program.display()))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() || stdout.trim() != GREETING {
+            return Err(Failure::Build).context(format!(
+                "the hello world built by `{compiler}` doesn't run ({}):\n{stdout}{}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+    }
+    Ok(())
+}
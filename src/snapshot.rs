@@ -0,0 +1,195 @@
+//! Caching the toolup directories in CI.
+//!
+//! `toolup cache key` prints a hash of everything an install would download and build, to be used
+//! as the key of a CI cache. `toolup cache save` writes the installed toolchains, sysroots, kernel
+//! images and downloaded archives to a tarball that `toolup cache restore` unpacks on the next run.
+//! Toolchains have absolute paths built in, a tarball must be restored to the same `$HOME` (or
+//! `--system` directory) it was saved from.
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use tar::{Archive, Builder};
+
+use crate::{
+    download::{cache_dir, toolup_dir},
+    profile::Toolchain,
+    provenance,
+};
+
+/// The directories of [`toolup_dir`] left out of a tarball, e.g. the sockets of running VMs.
+const SKIPPED: &[&str] = &["vms"];
+
+/// Returns a key that changes whenever installing `toolchains` (and `kernel`) would download or
+/// build something different: the toolup version (its patches and configure flags), the host, the
+/// toolchains and the archive URLs in `urls`.
+pub fn cache_key(toolchains: &[Toolchain], kernel: Option<&str>, urls: &[String]) -> String {
+    let mut lines = vec![
+        format!("toolup {}", env!("CARGO_PKG_VERSION")),
+        format!("host {}-{}", std::env::consts::ARCH, std::env::consts::OS),
+    ];
+    for toolchain in toolchains {
+        let mut line = format!(
+            "toolchain {} gold={} prefix={:?}",
+            toolchain.id(),
+            toolchain.binutils.gold,
+            toolchain.prefix
+        );
+        if let Some(gdb) = &toolchain.gdb {
+            line.push_str(&format!(" gdb={} python={}", gdb.version, gdb.python));
+        }
+        lines.push(line);
+    }
+    if let Some(kernel) = kernel {
+        lines.push(format!("kernel {kernel}"));
+    }
+    let mut urls = urls.to_vec();
+    urls.sort();
+    urls.dedup();
+    lines.extend(urls.into_iter().map(|url| format!("url {url}")));
+
+    let hash = blake3::hash(lines.join("\n").as_bytes());
+    format!("toolup-{}", &hash.to_hex()[..16])
+}
+
+fn is_gzip(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(".gz") || path.extension().is_some_and(|ext| ext == "tgz")
+}
+
+/// Write the toolup directory (`toolup/`) and the archive cache (`cache/archives/`) to `tarball`,
+/// gzipped if it ends with `.gz` or `.tgz`.
+pub fn save(tarball: &Path) -> Result<()> {
+    let file =
+        File::create(tarball).context(format!("failed to create `{}`", tarball.display()))?;
+    let writer: Box<dyn Write> = if is_gzip(tarball) {
+        Box::new(GzEncoder::new(file, Compression::fast()))
+    } else {
+        Box::new(file)
+    };
+    let mut builder = Builder::new(writer);
+    // toolchains have relative symlinks, e.g. `bin/cc -> gcc`
+    builder.follow_symlinks(false);
+
+    let toolup = toolup_dir()?;
+    if toolup.is_dir() {
+        for entry in std::fs::read_dir(&toolup)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if SKIPPED.iter().any(|skipped| name == *skipped) {
+                continue;
+            }
+            log::info!("=> saving {}", entry.path().display());
+            let path = Path::new("toolup").join(&name);
+            if entry.file_type()?.is_dir() {
+                builder.append_dir_all(&path, entry.path())?;
+            } else {
+                builder.append_path_with_name(entry.path(), &path)?;
+            }
+        }
+    }
+    let archives = cache_dir()?.join("archives");
+    if archives.is_dir() {
+        log::info!("=> saving {}", archives.display());
+        builder.append_dir_all("cache/archives", &archives)?;
+        // the hashes `toolup cache verify` checks the archives against
+        let manifest = cache_dir()?.join("manifest.json");
+        if manifest.is_file() {
+            builder.append_path_with_name(&manifest, "cache/manifest.json")?;
+        }
+    }
+
+    builder
+        .into_inner()
+        .and_then(|mut writer| writer.flush())
+        .context(format!("failed to write `{}`", tarball.display()))
+}
+
+/// Unpack a tarball written by [`save`], replacing files that already exist. Restored toolchains
+/// are verified against their [`provenance`].
+pub fn restore(tarball: &Path) -> Result<()> {
+    let file = File::open(tarball).context(format!("failed to open `{}`", tarball.display()))?;
+    let reader: Box<dyn Read> = if is_gzip(tarball) {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let toolup = toolup_dir()?;
+    let cache = cache_dir()?;
+
+    let mut restored = BTreeSet::new();
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let mut components = path.components();
+        let root = match components.next() {
+            Some(Component::Normal(root)) if root == "toolup" => &toolup,
+            Some(Component::Normal(root)) if root == "cache" => &cache,
+            _ => bail!(
+                "unexpected entry `{}` in `{}`",
+                path.display(),
+                tarball.display()
+            ),
+        };
+        let rest: PathBuf = components.collect();
+        if !rest
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!(
+                "unsafe entry `{}` in `{}`",
+                path.display(),
+                tarball.display()
+            );
+        }
+
+        let dest = root.join(rest);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+            // a symlink unpacked earlier must not lead outside the root
+            if !parent.canonicalize()?.starts_with(root.canonicalize()?) {
+                bail!(
+                    "unsafe entry `{}` in `{}`",
+                    path.display(),
+                    tarball.display()
+                );
+            }
+        }
+        entry
+            .unpack(&dest)
+            .context(format!("failed to unpack `{}`", dest.display()))?;
+        if let Ok(toolchain) = path.strip_prefix("toolup/toolchains")
+            && let Some(Component::Normal(id)) = toolchain.components().next()
+        {
+            restored.insert(id.to_string_lossy().into_owned());
+        }
+    }
+
+    // check the restored toolchains weren't modified since they were built
+    for id in restored {
+        let dir = toolup.join("toolchains").join(&id);
+        let Some(provenance) = provenance::read(&dir)? else {
+            continue;
+        };
+        let sysroot = toolup.join("sysroot").join(format!("sysroot-{id}"));
+        let mismatches = provenance::verify(&provenance, &dir, Some(&sysroot))?;
+        if !mismatches.is_empty() {
+            for mismatch in &mismatches {
+                log::error!("{id}: {mismatch}");
+            }
+            bail!(
+                "{id} doesn't match its provenance, {} files differ",
+                mismatches.len()
+            );
+        }
+        log::info!("=> verified {id} against its provenance");
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! Default compiler flags from `default_flags` in `toolup.toml`, baked into a GCC specs file.
+//!
+//! GCC reads a file named `specs` from its library directory (`lib/gcc/<target>/<version>`) on
+//! every invocation, so `<target>-gcc` picks up the flags without a wrapper script. The flags are
+//! appended to the driver's `self_spec`, the way `--with-specs` would, and only apply when the
+//! command line doesn't choose a value itself: `-march=armv8.2-a` becomes
+//! `%{!march=*:-march=armv8.2-a}` and `-Os` becomes `%{!O*:-Os}`.
+//!
+//! The specs file also points GCC to the sysroot of a toolchain that was built somewhere else, e.g.
+//! one packed by `toolup pack` on another machine: GCC was configured with the sysroot path of the
+//! build machine.
+use std::{path::PathBuf, process::Command};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    commands::{is_plan, plan_step},
+    profile::Toolchain,
+};
+
+/// Returns the spec adding `flags` to a command line that doesn't set them.
+pub fn self_spec(flags: &[String]) -> String {
+    flags
+        .iter()
+        .map(|flag| {
+            let flag = flag.replace('%', "%%");
+            if flag.starts_with("-O") {
+                format!("%{{!O*:{flag}}}")
+            } else if let Some((name, _)) = flag.split_once('=')
+                && let Some(name) = name.strip_prefix('-')
+            {
+                format!("%{{!{name}=*:{flag}}}")
+            } else {
+                flag
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns the specs file of an installed `toolchain`, in its GCC library directory.
+fn specs_path(toolchain: &Toolchain) -> Result<PathBuf> {
+    let gcc = toolchain.gcc_bin()?;
+    let output = Command::new(&gcc)
+        .arg("-print-libgcc-file-name")
+        .output()
+        .context(format!("failed to run `{}`", gcc.display()))?;
+    let libgcc = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let dir = libgcc
+        .parent()
+        .filter(|dir| output.status.success() && dir.is_dir())
+        .context(format!(
+            "`{}` didn't print its library directory",
+            gcc.display()
+        ))?;
+    Ok(dir.join("specs"))
+}
+
+/// Returns the sysroot `toolchain`'s GCC was configured with, from `gcc -v`.
+fn configured_sysroot(toolchain: &Toolchain) -> Result<Option<PathBuf>> {
+    let gcc = toolchain.gcc_bin()?;
+    let output = Command::new(&gcc)
+        .arg("-v")
+        .output()
+        .context(format!("failed to run `{}`", gcc.display()))?;
+    Ok(
+        configured_option(&String::from_utf8_lossy(&output.stderr), "--with-sysroot=")
+            .map(PathBuf::from),
+    )
+}
+
+/// The value of `option` in the `Configured with:` line of `gcc -v`.
+fn configured_option(verbose: &str, option: &str) -> Option<String> {
+    verbose
+        .lines()
+        .find_map(|line| line.strip_prefix("Configured with: "))?
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix(option))
+        .map(String::from)
+}
+
+/// Write the specs file of `toolchain` with `flags`, or remove it when there are none.
+///
+/// `--sysroot` is added when the toolchain's sysroot isn't the one GCC was configured with.
+pub fn write(toolchain: &Toolchain, flags: &[String]) -> Result<()> {
+    if is_plan() {
+        if !flags.is_empty() {
+            plan_step(format!(
+                "write a specs file defaulting to {}",
+                flags.join(" ")
+            ));
+        }
+        return Ok(());
+    }
+
+    let mut flags = flags.to_vec();
+    if !toolchain.is_freestanding()
+        && let Some(configured) = configured_sysroot(toolchain)?
+        && configured != toolchain.sysroot()?
+    {
+        log::info!(
+            "=> the sysroot moved from {}, using {}",
+            configured.display(),
+            toolchain.sysroot()?.display()
+        );
+        flags.push(format!("--sysroot={}", toolchain.sysroot()?.display()));
+    }
+
+    let path = specs_path(toolchain)?;
+    if flags.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path)
+                .context(format!("failed to remove `{}`", path.display()))?;
+        }
+        return Ok(());
+    }
+    log::info!("=> defaulting to {}", flags.join(" "));
+    // `+` appends to the built-in spec
+    let specs = format!("*self_spec:\n+ {}\n\n", self_spec(&flags));
+    std::fs::write(&path, specs).context(format!("failed to write `{}`", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{configured_option, self_spec};
+
+    #[test]
+    fn test_self_spec() {
+        let flags = ["-march=armv8.2-a", "-mtune=cortex-a76", "-Os", "-fno-plt"];
+        assert_eq!(
+            self_spec(&flags.map(String::from)),
+            "%{!march=*:-march=armv8.2-a} %{!mtune=*:-mtune=cortex-a76} %{!O*:-Os} -fno-plt"
+        );
+    }
+
+    #[test]
+    fn test_configured_option() {
+        let verbose = "Using built-in specs.
+Target: aarch64-unknown-linux-gnu
+Configured with: ../configure --target=aarch64-unknown-linux-gnu --with-sysroot=/home/ci/.toolup/sysroot/sysroot-x --disable-multilib
+gcc version 15.2.0 (GCC)
+";
+        assert_eq!(
+            configured_option(verbose, "--with-sysroot="),
+            Some("/home/ci/.toolup/sysroot/sysroot-x".into())
+        );
+        assert_eq!(configured_option(verbose, "--with-build-sysroot="), None);
+    }
+}
@@ -0,0 +1,305 @@
+//! Toolchain build stages, used to select what gets rebuilt and to report what an install did.
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::is_plan,
+    error::{Failure, exit_code},
+};
+
+/// A stage of the build pipeline that can be forced to run again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    Binutils,
+    /// Linux (or mingw-w64) headers installed into the sysroot of a toolchain
+    KernelHeaders,
+    /// The kernel image built by `toolup linux`
+    KernelImage,
+    Libc,
+    /// The final GCC compiler. For freestanding targets this is the only GCC stage.
+    GccFinal,
+    /// The cross GDB of toolchains built with one
+    Gdb,
+    /// Clang and lld of toolchains built with `--compiler clang` and of WASI toolchains
+    Llvm,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 7] = [
+        Stage::Binutils,
+        Stage::KernelHeaders,
+        Stage::KernelImage,
+        Stage::Libc,
+        Stage::GccFinal,
+        Stage::Gdb,
+        Stage::Llvm,
+    ];
+}
+
+impl FromStr for Stage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "binutils" => Ok(Stage::Binutils),
+            "kernel-headers" => Ok(Stage::KernelHeaders),
+            "kernel-image" => Ok(Stage::KernelImage),
+            "libc" => Ok(Stage::Libc),
+            "gcc-final" => Ok(Stage::GccFinal),
+            "gdb" => Ok(Stage::Gdb),
+            "llvm" => Ok(Stage::Llvm),
+            _ => Err(anyhow!(
+                "unknown stage `{s}`, expected one of: {}",
+                Stage::ALL.map(|s| s.to_string()).join(", ")
+            )),
+        }
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Stage::Binutils => "binutils",
+            Stage::KernelHeaders => "kernel-headers",
+            Stage::KernelImage => "kernel-image",
+            Stage::Libc => "libc",
+            Stage::GccFinal => "gcc-final",
+            Stage::Gdb => "gdb",
+            Stage::Llvm => "llvm",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// What to rebuild when a toolchain is already installed.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Force {
+    /// Reuse the installed toolchain
+    #[default]
+    Nothing,
+    /// Rebuild everything
+    All,
+    /// Only rebuild the given stages
+    Stages(Vec<Stage>),
+}
+
+impl Force {
+    pub fn new(all: bool, stages: Vec<Stage>) -> Self {
+        if all {
+            Force::All
+        } else if stages.is_empty() {
+            Force::Nothing
+        } else {
+            Force::Stages(stages)
+        }
+    }
+
+    /// Whether `stage` has to run. Every stage runs if the toolchain isn't `installed` yet, or
+    /// with [`Force::All`].
+    pub fn should_run(&self, stage: Stage, installed: bool) -> bool {
+        self.from_scratch(installed) || self.includes(stage)
+    }
+
+    /// Whether the toolchain is built from scratch, like one that isn't `installed`. Otherwise the
+    /// forced stages are rebuilt on a copy of the installed toolchain, with its compiler.
+    pub fn from_scratch(&self, installed: bool) -> bool {
+        !installed || *self == Force::All
+    }
+
+    /// Whether `stage` was explicitly forced.
+    pub fn includes(&self, stage: Stage) -> bool {
+        match self {
+            Force::Nothing => false,
+            Force::All => true,
+            Force::Stages(stages) => stages.contains(&stage),
+        }
+    }
+}
+
+/// How stages that failed are run again, `[workspace.retry]` in toolup.toml. Only download and
+/// build failures are retried, e.g. a mirror timing out in the middle of configure or a flaky NFS
+/// cache directory, never configuration errors.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// How many times a failed stage runs again
+    #[serde(default)]
+    pub retries: u32,
+    /// Seconds to wait before the first retry, doubled after every retry
+    #[serde(default = "default_backoff_secs")]
+    pub backoff_secs: u64,
+    /// The stages that are retried, every stage by default. Leave out the stages that can't run
+    /// twice in the same build tree, e.g. because of local patches to the sources.
+    #[serde(default = "all_stages")]
+    pub stages: Vec<Stage>,
+}
+
+fn default_backoff_secs() -> u64 {
+    30
+}
+
+fn all_stages() -> Vec<Stage> {
+    Stage::ALL.to_vec()
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            backoff_secs: default_backoff_secs(),
+            stages: all_stages(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry` (starting at 1) of `stage` after it failed with
+    /// `err`, `None` if it shouldn't be retried.
+    pub fn delay(&self, stage: Stage, retry: u32, err: &anyhow::Error) -> Option<Duration> {
+        let transient = [Failure::Download, Failure::Build]
+            .iter()
+            .any(|failure| failure.exit_code() == exit_code(err));
+        if retry > self.retries || !transient || !self.stages.contains(&stage) {
+            return None;
+        }
+        let factor = 2u64.saturating_pow(retry - 1);
+        Some(Duration::from_secs(
+            self.backoff_secs.saturating_mul(factor),
+        ))
+    }
+}
+
+static RETRY_POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+/// Set the `[workspace.retry]` policy.
+///
+/// Only the first call has an effect, this is meant to be called once at startup.
+pub fn set_retry_policy(policy: RetryPolicy) {
+    let _ = RETRY_POLICY.set(policy);
+}
+
+/// Whether a stage was built or reused from the installed toolchain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StageOutcome {
+    Built,
+    Cached,
+}
+
+/// A stage of an install.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StageRun {
+    pub stage: Stage,
+    pub outcome: StageOutcome,
+    /// How long the stage took, 0 when it was cached
+    pub secs: f64,
+}
+
+impl Display for StageRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.outcome {
+            StageOutcome::Built => write!(f, "{:<10} built  {:.1}s", self.stage, self.secs),
+            StageOutcome::Cached => write!(f, "{:<10} cached", self.stage),
+        }
+    }
+}
+
+/// Records the stages run by an install, in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StageRuns(pub Vec<StageRun>);
+
+impl StageRuns {
+    /// Run `stage` with `f` if `run` is set, otherwise record that it was cached. A failed stage is
+    /// run again as set by [`set_retry_policy`].
+    pub fn run(
+        &mut self,
+        stage: Stage,
+        run: bool,
+        mut f: impl FnMut() -> Result<()>,
+    ) -> Result<()> {
+        if !run {
+            self.0.push(StageRun {
+                stage,
+                outcome: StageOutcome::Cached,
+                secs: 0.0,
+            });
+            return Ok(());
+        }
+        let started = Instant::now();
+        let policy = RETRY_POLICY.get().cloned().unwrap_or_default();
+        let mut retry = 0;
+        while let Err(err) = f() {
+            retry += 1;
+            let Some(delay) = policy.delay(stage, retry, &err).filter(|_| !is_plan()) else {
+                return Err(err);
+            };
+            log::warn!(
+                "{stage} failed: {err:#}, retrying in {}s ({retry}/{})",
+                delay.as_secs(),
+                policy.retries
+            );
+            std::thread::sleep(delay);
+        }
+        self.0.push(StageRun {
+            stage,
+            outcome: StageOutcome::Built,
+            secs: started.elapsed().as_secs_f64(),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::{Force, Stage};
+
+    #[test]
+    fn test_should_run() {
+        for stage in Stage::ALL {
+            for installed in [false, true] {
+                assert_eq!(
+                    Force::Nothing.should_run(stage, installed),
+                    !installed,
+                    "{stage} {installed}"
+                );
+                // even an installed toolchain is rebuilt from scratch
+                assert!(
+                    Force::All.should_run(stage, installed),
+                    "{stage} {installed}"
+                );
+                assert!(
+                    Force::Stages(vec![stage]).should_run(stage, installed),
+                    "{stage} {installed}"
+                );
+                for other in Stage::ALL.into_iter().filter(|other| *other != stage) {
+                    assert_eq!(
+                        Force::Stages(vec![other]).should_run(stage, installed),
+                        !installed,
+                        "{stage} with {other} forced, {installed}"
+                    );
+                }
+            }
+        }
+        assert!(Force::All.from_scratch(true));
+        assert!(!Force::Stages(vec![Stage::GccFinal]).from_scratch(true));
+    }
+
+    #[test]
+    fn test_stage_names() -> anyhow::Result<()> {
+        for stage in Stage::ALL {
+            assert_eq!(Stage::from_str(&stage.to_string())?, stage);
+        }
+        // the headers and the image are forced on their own
+        assert!(Stage::from_str("kernel").is_err());
+        Ok(())
+    }
+}
@@ -1,44 +1,310 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
 
 use crate::{
+    commands::{create_dir_all, run_command_in},
+    download::cache_dir,
+    hooks::{self, Hook},
+    packages::android::install_ndk_sysroot,
+    packages::freebsd::install_freebsd_base,
     packages::gcc::{GccStage, install_gcc},
     packages::glibc::install_glibc_sysroot,
+    packages::host_tools::find_program,
+    packages::install_package,
     packages::linux,
+    packages::mingw::{install_mingw_crt, install_mingw_headers},
     packages::musl::install_musl_sysroot,
+    packages::sysroot_libs::{SysrootLib, SysrootLibPackage},
     profile::{Libc, Toolchain},
+    stage::{Force, Stage, StageRuns},
 };
 
 /// Create and populate a sysroot for a target.
 ///
 /// This:
 ///   1. Creates the sysroot directory
-///   2. Installs Linux kernel headers into the sysroot
-///   3. Builds a stage1 cross-compiler to configure and build glibc into the sysroot
+///   2. Installs Linux kernel headers into the sysroot, or the mingw-w64 headers for Windows
+///   3. Builds a stage1 cross-compiler to configure and build the libc (glibc, musl or the
+///      mingw-w64 CRT) into the sysroot, or imports FreeBSD's base system or the Android NDK's
+///      sysroot
 ///
-/// The caller must already have installed binutils.
-pub fn setup_sysroot(toolchain: &Toolchain, jobs: u64) -> Result<PathBuf> {
+/// The caller must already have installed binutils. If the toolchain is already `installed`, only
+/// the stages selected by `force` run and the installed compiler is used to build the libc, unless
+/// it's [`Force::All`].
+pub fn setup_sysroot(
+    toolchain: &Toolchain,
+    jobs: u64,
+    force: &Force,
+    installed: bool,
+    stages: &mut StageRuns,
+) -> Result<PathBuf> {
     log::info!("=> setup sysroot");
 
     let sysroot = toolchain.sysroot()?;
-    std::fs::create_dir_all(&sysroot)?;
-    std::fs::create_dir_all(sysroot.join("usr").join("include"))?;
-    std::fs::create_dir_all(sysroot.join("usr").join("lib"))?;
+    create_dir_all(&sysroot)?;
+    create_dir_all(sysroot.join("usr").join("include"))?;
+    create_dir_all(sysroot.join("usr").join("lib"))?;
+
+    // 1. install linux headers, or the Windows API headers of mingw-w64
+    stages.run(
+        Stage::KernelHeaders,
+        force.should_run(Stage::KernelHeaders, installed),
+        || match toolchain.libc {
+            Libc::Mingw(_) => install_mingw_headers(toolchain),
+            // the kernel headers of FreeBSD and Android are imported with the libc
+            Libc::FreeBsd(_) | Libc::Bionic(_) => Ok(()),
+            _ => linux::install_headers(toolchain),
+        },
+    )?;
+
+    stages.run(
+        Stage::Libc,
+        force.should_run(Stage::Libc, installed),
+        || {
+            // prebuilt, nothing to compile it with
+            match toolchain.libc {
+                Libc::FreeBsd(_) => return install_freebsd_base(toolchain),
+                Libc::Bionic(_) => return install_ndk_sysroot(toolchain),
+                _ => {}
+            }
+            // an installed final compiler can build the libc, building stage1 would overwrite it.
+            if force.from_scratch(installed) {
+                install_gcc(toolchain, jobs, GccStage::Stage1)?;
+            }
+
+            match toolchain.libc {
+                Libc::Musl(_) => install_musl_sysroot(toolchain, jobs),
+                Libc::Mingw(_) => install_mingw_crt(toolchain, jobs),
+                _ => install_glibc_sysroot(toolchain, jobs),
+            }
+        },
+    )?;
+    hooks::run(Hook::PostSysroot, toolchain, &[("sysroot", &sysroot)])?;
+
+    Ok(sysroot)
+}
+
+/// Create a project-local copy of the toolchain's sysroot at `dest`, that packages can be
+/// installed into without modifying the shared sysroot.
+///
+/// With `overlay`, `dest` is an overlayfs mount on top of the shared sysroot instead of a copy.
+/// Writes go to `<dest>.upper`. `fuse-overlayfs` is used if it's installed, otherwise the
+/// kernel's overlayfs which requires root.
+pub fn clone_sysroot(toolchain: &Toolchain, dest: &Path, overlay: bool) -> Result<()> {
+    let sysroot = toolchain.sysroot()?;
+    if !sysroot.exists() {
+        bail!(
+            "{} doesn't have a sysroot, install it first",
+            toolchain.id()
+        );
+    }
+    if dest.exists() && dest.read_dir()?.next().is_some() {
+        bail!("`{}` already exists and is not empty", dest.display());
+    }
+
+    if !overlay {
+        log::info!("=> copy {} to {}", sysroot.display(), dest.display());
+        return copy_tree(&sysroot, dest);
+    }
+
+    let mut upper = dest.as_os_str().to_owned();
+    upper.push(".upper");
+    let mut work = dest.as_os_str().to_owned();
+    work.push(".work");
+    for dir in [dest.as_os_str(), &upper, &work] {
+        std::fs::create_dir_all(dir)
+            .context(format!("failed to create `{}`", dir.to_string_lossy()))?;
+    }
+
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        sysroot.display(),
+        upper.to_string_lossy(),
+        work.to_string_lossy()
+    );
+    let (command, mut args): (PathBuf, Vec<OsString>) = match find_program("fuse-overlayfs") {
+        Some(fuse) => (fuse, vec!["-o".into(), options.into()]),
+        None => (
+            "mount".into(),
+            vec![
+                "-t".into(),
+                "overlay".into(),
+                "overlay".into(),
+                "-o".into(),
+                options.into(),
+            ],
+        ),
+    };
+    args.push(dest.into());
+
+    log::info!(
+        "=> mount an overlay of {} on {}",
+        sysroot.display(),
+        dest.display()
+    );
+    run_command_in(
+        std::env::current_dir()?,
+        "mount overlay",
+        command,
+        &args,
+        None::<Vec<(OsString, OsString)>>,
+    )?;
+    log::info!("unmount with `umount {}`", dest.display());
+    Ok(())
+}
+
+/// Copy a directory preserving symlinks.
+pub fn copy_tree(src: &Path, dest: &Path) -> Result<()> {
+    for entry in WalkDir::new(src) {
+        let entry = entry.context(format!("failed to walk `{}`", src.display()))?;
+        let target = dest.join(entry.path().strip_prefix(src)?);
+
+        if entry.path_is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)?;
+        } else if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)
+                .context(format!("failed to copy `{}`", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
 
-    // 1. install linux headers
-    linux::install_headers(&toolchain)?;
+/// The database of packages added to a sysroot, relative to the sysroot.
+const PACKAGES_DB: &str = "var/lib/toolup/packages.json";
 
-    install_gcc(&toolchain, jobs, GccStage::Stage1)?;
+/// A package added to a sysroot with [`add_package`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledPackage {
+    pub version: String,
+    /// Installed files and symlinks, relative to the sysroot
+    pub files: Vec<PathBuf>,
+}
+
+/// The packages added to a sysroot, by name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PackageDb {
+    pub packages: BTreeMap<String, InstalledPackage>,
+}
+
+impl PackageDb {
+    pub fn load(sysroot: &Path) -> Result<PackageDb> {
+        let path = sysroot.join(PACKAGES_DB);
+        if !path.exists() {
+            return Ok(PackageDb::default());
+        }
+        let content = std::fs::read_to_string(&path)
+            .context(format!("failed to read `{}`", path.display()))?;
+        serde_json::from_str(&content)
+            .context(format!("invalid package database `{}`", path.display()))
+    }
+
+    pub fn save(&self, sysroot: &Path) -> Result<()> {
+        let path = sysroot.join(PACKAGES_DB);
+        std::fs::create_dir_all(path.parent().expect("the database is in a directory"))?;
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .context(format!("failed to write `{}`", path.display()))
+    }
+}
+
+/// Build `lib` with `toolchain` and install it into `sysroot`, recording its files.
+pub fn add_package(
+    toolchain: &Toolchain,
+    sysroot: &Path,
+    lib: &SysrootLib,
+    jobs: u64,
+) -> Result<()> {
+    let mut db = PackageDb::load(sysroot)?;
+    if let Some(installed) = db.packages.get(lib.name()) {
+        bail!(
+            "{} {} is already installed in `{}`, remove it first",
+            lib.name(),
+            installed.version,
+            sysroot.display()
+        );
+    }
+
+    let staging = cache_dir()?
+        .join("staging")
+        .join(format!("{}-{}", lib.name(), toolchain.id()));
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)
+            .context(format!("failed to remove `{}`", staging.display()))?;
+    }
+
+    install_package(
+        &SysrootLibPackage {
+            lib: lib.clone(),
+            toolchain,
+            sysroot: sysroot.to_path_buf(),
+            destdir: staging.clone(),
+        },
+        jobs,
+    )?;
+
+    let mut files = vec![];
+    for entry in WalkDir::new(&staging).min_depth(1) {
+        let entry = entry.context(format!("failed to walk `{}`", staging.display()))?;
+        let relative = entry.path().strip_prefix(&staging)?.to_path_buf();
+        let target = sysroot.join(&relative);
 
-    match toolchain.libc {
-        Libc::Musl(_) => {
-            install_musl_sysroot(&toolchain)?;
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
         }
-        _ => {
-            install_glibc_sysroot(&toolchain)?;
+        if target.symlink_metadata().is_ok() {
+            std::fs::remove_file(&target)?;
         }
+        if entry.path_is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)
+                .context(format!("failed to copy `{}`", entry.path().display()))?;
+        }
+        files.push(relative);
     }
+    std::fs::remove_dir_all(&staging)?;
 
-    Ok(sysroot)
+    log::info!("=> added {lib} ({} files)", files.len());
+    db.packages.insert(
+        lib.name().into(),
+        InstalledPackage {
+            version: lib.version.clone(),
+            files,
+        },
+    );
+    db.save(sysroot)
+}
+
+/// Remove a package added with [`add_package`] from `sysroot`.
+pub fn remove_package(sysroot: &Path, name: &str) -> Result<()> {
+    let mut db = PackageDb::load(sysroot)?;
+    let Some(package) = db.packages.remove(name) else {
+        bail!("`{name}` is not installed in `{}`", sysroot.display());
+    };
+
+    for file in &package.files {
+        let path = sysroot.join(file);
+        if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(&path)
+                .context(format!("failed to remove `{}`", path.display()))?;
+        }
+        // remove directories left empty by the package
+        for dir in path.ancestors().skip(1) {
+            if dir == sysroot || std::fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+
+    log::info!("=> removed {name} {}", package.version);
+    db.save(sysroot)
 }
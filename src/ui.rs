@@ -0,0 +1,70 @@
+//! Progress bars.
+//!
+//! Every bar is added to a single shared [`MultiProgress`], so bars drawn at the same time (e.g. a
+//! download while an archive is extracted, or downloads running in parallel) get a line each
+//! instead of overwriting each other. Log records are written through [`log_writer`], which hides
+//! the bars while a line is printed.
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+static PROGRESS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// The progress bars currently drawn.
+pub fn progress() -> &'static MultiProgress {
+    &PROGRESS
+}
+
+/// A spinner showing `message`, e.g. the last line printed by a command.
+pub fn spinner(message: impl Into<Cow<'static, str>>) -> ProgressBar {
+    let pb = progress().add(ProgressBar::new_spinner());
+    pb.set_style(
+        ProgressStyle::with_template("{spinner:.dim} {msg:.dim}")
+            .expect("this should be a valid template"),
+    );
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb.set_message(message);
+    pb
+}
+
+/// A bar counting the bytes of `name` transferred out of `size`, or a spinner if the size is
+/// unknown.
+pub fn transfer_bar(size: Option<u64>, name: impl Into<Cow<'static, str>>) -> ProgressBar {
+    let pb = match size {
+        Some(size) => ProgressBar::new(size),
+        None => ProgressBar::new_spinner(),
+    };
+    let pb = progress().add(pb);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{msg:.dim} {bar:30.green/dim} {binary_bytes:>7}/{binary_total_bytes:7}",
+        )
+        .expect("this should be a valid template")
+        .progress_chars("--"),
+    );
+    pb.set_message(name);
+    pb
+}
+
+/// Writes to stderr without tearing the progress bars.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        progress().suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// The writer log records are printed with, see [`LogWriter`].
+pub fn log_writer() -> LogWriter {
+    LogWriter
+}
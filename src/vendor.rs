@@ -0,0 +1,166 @@
+//! Source bundles for building toolchains without network access.
+//!
+//! `toolup vendor` plans the requested installs (see [`crate::commands::set_plan`]) to find every
+//! source archive they need, downloads them and writes a tar bundle:
+//!
+//! ```text
+//! sources.tar
+//! ├─ manifest.json            # the URL, cache filename and blake3 hash of every archive
+//! └─ archives/<hash>-<name>   # the archives, named like in the archive cache
+//! ```
+//!
+//! `toolup install --vendor sources.tar` verifies the archives and imports them into the archive
+//! cache, so the install finds everything cached and never downloads.
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tar::{Archive, Builder};
+
+use crate::{
+    cache,
+    commands::{set_plan, set_plan_quiet},
+    download::{DownloadResult, archive_filename, archives_dir, download_archive, planned_urls},
+    error::Failure,
+};
+
+const MANIFEST: &str = "manifest.json";
+
+/// An archive in a bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VendoredArchive {
+    pub url: String,
+    /// The path of the archive in the bundle, relative to `archives/`
+    pub file: String,
+    pub blake3: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub archives: Vec<VendoredArchive>,
+}
+
+/// Returns the URLs of every archive that `plan` would download.
+///
+/// `plan` runs in quiet plan mode, e.g. a closure installing toolchains with [`crate::Force::All`]
+/// so installed toolchains are planned as well.
+pub fn collect_urls(plan: impl FnOnce() -> Result<()>) -> Result<Vec<String>> {
+    set_plan(true);
+    set_plan_quiet(true);
+    let result = plan();
+    set_plan_quiet(false);
+    set_plan(false);
+    result?;
+    Ok(planned_urls())
+}
+
+/// Download `urls` and write them into a bundle at `out`.
+pub fn write_bundle(urls: &[String], out: &Path) -> Result<Manifest> {
+    let file = File::create(out).context(format!("failed to create `{}`", out.display()))?;
+    let mut bundle = Builder::new(file);
+    let mut manifest = Manifest::default();
+
+    for url in urls {
+        let path = match download_archive(url, true)? {
+            DownloadResult::Cached(p)
+            | DownloadResult::Replaced(p)
+            | DownloadResult::Created(p) => p,
+        };
+        let file = archive_filename(url)?;
+        log::info!("=> vendoring {file}");
+        bundle
+            .append_path_with_name(&path, Path::new("archives").join(&file))
+            .context(format!("failed to add `{}` to the bundle", path.display()))?;
+        manifest.archives.push(VendoredArchive {
+            url: url.clone(),
+            file,
+            blake3: blake3::hash(&std::fs::read(&path)?).to_hex().to_string(),
+        });
+    }
+
+    let json = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    bundle.append_data(&mut header, MANIFEST, json.as_slice())?;
+    bundle
+        .finish()
+        .context(format!("failed to write `{}`", out.display()))?;
+    Ok(manifest)
+}
+
+/// Verify the archives in the bundle at `bundle` and copy them into the archive cache.
+pub fn import_bundle(bundle: &Path) -> Result<Manifest> {
+    let open = || -> Result<Archive<File>> {
+        Ok(Archive::new(File::open(bundle).context(format!(
+            "failed to open `{}`",
+            bundle.display()
+        ))?))
+    };
+
+    let mut manifest = None;
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == Path::new(MANIFEST) {
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            manifest = Some(serde_json::from_str::<Manifest>(&json).context(format!(
+                "failed to parse the manifest of `{}`",
+                bundle.display()
+            ))?);
+            break;
+        }
+    }
+    let manifest = manifest.context(format!("`{}` has no {MANIFEST}", bundle.display()))?;
+
+    let archives = archives_dir()?;
+    let mut imported = vec![];
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        let Some(archive) = manifest
+            .archives
+            .iter()
+            .find(|archive| path == Path::new("archives").join(&archive.file))
+        else {
+            continue;
+        };
+
+        let mut bytes = vec![];
+        entry.read_to_end(&mut bytes)?;
+        let actual = blake3::hash(&bytes).to_hex();
+        if actual.as_str() != archive.blake3 {
+            return Err(Failure::Download).context(format!(
+                "blake3 mismatch for {} in `{}`: expected {}, got {actual}",
+                archive.file,
+                bundle.display(),
+                archive.blake3
+            ));
+        }
+
+        // the cache is keyed by the URL, not by the file name in the bundle
+        let dest: PathBuf = archives.join(archive_filename(&archive.url)?);
+        std::fs::write(&dest, bytes).context(format!("failed to write `{}`", dest.display()))?;
+        cache::record_archive_hash(&archive.url, archive.blake3.clone())?;
+        log::debug!("=> imported {}", archive.file);
+        imported.push(&archive.file);
+    }
+
+    if let Some(missing) = manifest
+        .archives
+        .iter()
+        .find(|archive| !imported.contains(&&archive.file))
+    {
+        return Err(Failure::Download).context(format!(
+            "`{}` is missing {} listed in its manifest",
+            bundle.display(),
+            missing.file
+        ));
+    }
+
+    Ok(manifest)
+}
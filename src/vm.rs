@@ -0,0 +1,218 @@
+//! VMs started by `toolup linux`.
+//!
+//! Every running VM has a directory in `~/.toolup/vms/<id>` holding `vm.json` and the sockets of
+//! its QEMU monitors: `qmp.sock` (QMP, used to shut the VM down) and `monitor.sock` (the human
+//! monitor, opened by `toolup vm console`). The directory is removed when the VM exits, entries
+//! left by a QEMU that died without cleaning up, or by a VM started with `--detach`, are skipped
+//! and removed by [`list`].
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::{profile::Target, qemu::qmp};
+
+const INFO: &str = "vm.json";
+const CONSOLE_LOG: &str = "console.log";
+
+/// `vm.json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmInfo {
+    pub id: String,
+    pub pid: u32,
+    pub target: String,
+    pub kernel: PathBuf,
+    /// RFC 3339
+    pub started: String,
+}
+
+impl VmInfo {
+    /// The `toolup.winch` port the size of the host's terminal is written to, see
+    /// `qemu::start_vm`.
+    pub fn winch_socket(&self) -> PathBuf {
+        self.path.join("winch.sock")
+    }
+
+    /// The guest console of a VM started with `--detach`.
+    pub fn console_log(&self) -> Result<PathBuf> {
+        Ok(vms_dir()?.join(&self.id).join(CONSOLE_LOG))
+    }
+}
+
+impl Display for VmInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<8} {:>7} {:<28} {} {}",
+            self.id,
+            self.pid,
+            self.target,
+            self.started,
+            self.kernel.display()
+        )
+    }
+}
+
+pub fn vms_dir() -> Result<PathBuf> {
+    let dir = PathBuf::from(std::env::var("HOME").context("reading $HOME")?)
+        .join(".toolup")
+        .join("vms");
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// The directory of a VM, removed on drop.
+pub struct VmDir {
+    pub id: String,
+    pub path: PathBuf,
+}
+
+impl VmDir {
+    /// Create the directory of a new VM.
+    pub fn create() -> Result<Self> {
+        let dir = vms_dir()?;
+        let seed = format!("{:?}-{}", std::time::SystemTime::now(), std::process::id());
+        let id = blake3::hash(seed.as_bytes()).to_hex()[..8].to_string();
+        let path = dir.join(&id);
+        std::fs::create_dir_all(&path).context(format!("failed to create `{}`", path.display()))?;
+        Ok(Self { id, path })
+    }
+
+    pub fn qmp_socket(&self) -> PathBuf {
+        self.path.join("qmp.sock")
+    }
+
+    pub fn monitor_socket(&self) -> PathBuf {
+        self.path.join("monitor.sock")
+    }
+
+    /// The `toolup.winch` port the size of the host's terminal is written to, see
+    /// `qemu::start_vm`.
+    pub fn winch_socket(&self) -> PathBuf {
+        self.path.join("winch.sock")
+    }
+
+    /// The guest console of a VM started with `--detach`.
+    pub fn console_log(&self) -> PathBuf {
+        self.path.join(CONSOLE_LOG)
+    }
+
+    /// Record the QEMU process running the VM, after which it shows up in [`list`].
+    pub fn register(&self, pid: u32, target: &Target, kernel: &Path) -> Result<VmInfo> {
+        let info = VmInfo {
+            id: self.id.clone(),
+            pid,
+            target: target.to_string(),
+            kernel: kernel.to_path_buf(),
+            started: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+        };
+        let path = self.path.join(INFO);
+        std::fs::write(&path, serde_json::to_string_pretty(&info)?)
+            .context(format!("failed to write `{}`", path.display()))?;
+        Ok(info)
+    }
+
+    /// Leave the directory behind for a VM that outlives toolup, [`list`] removes it once the VM
+    /// exits.
+    pub fn keep(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for VmDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn is_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+/// Returns the running VMs, sorted by start time.
+pub fn list() -> Result<Vec<VmInfo>> {
+    let mut vms = vec![];
+    for entry in std::fs::read_dir(vms_dir()?)? {
+        let path = entry?.path();
+        let Ok(content) = std::fs::read_to_string(path.join(INFO)) else {
+            // still starting
+            continue;
+        };
+        let info: VmInfo = serde_json::from_str(&content)
+            .context(format!("failed to parse `{}`", path.join(INFO).display()))?;
+        if is_alive(info.pid) {
+            vms.push(info);
+        } else {
+            log::debug!("=> removing {}, its QEMU is gone", path.display());
+            let _ = std::fs::remove_dir_all(&path);
+        }
+    }
+    vms.sort_by(|a, b| a.started.cmp(&b.started));
+    Ok(vms)
+}
+
+fn find(id: &str) -> Result<(VmInfo, PathBuf)> {
+    let vm = list()?.into_iter().find(|vm| vm.id == id).context(format!(
+        "no running VM with id `{id}`, see `toolup vm list`"
+    ))?;
+    let path = vms_dir()?.join(&vm.id);
+    Ok((vm, path))
+}
+
+/// Quit the QEMU of VM `id`, killing it if it doesn't exit.
+pub fn kill(id: &str) -> Result<()> {
+    let (vm, path) = find(id)?;
+    if let Err(err) = qmp(&path.join("qmp.sock"), "quit") {
+        log::debug!("quit failed: {err:#}");
+    }
+    for _ in 0..30 {
+        if !is_alive(vm.pid) {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    log::warn!("=> QEMU didn't quit, killing it");
+    // SAFETY: a plain syscall, the pid was checked to be alive above
+    if unsafe { libc::kill(vm.pid as libc::pid_t, libc::SIGKILL) } != 0 {
+        bail!("failed to kill {}: {}", vm.pid, io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Attach stdin and stdout to the QEMU monitor of VM `id` until stdin is closed.
+pub fn console(id: &str) -> Result<()> {
+    let (_, path) = find(id)?;
+    let socket = path.join("monitor.sock");
+    let stream = UnixStream::connect(&socket)
+        .context(format!("failed to connect to `{}`", socket.display()))?;
+    eprintln!("connected to the QEMU monitor of {id}, Ctrl-D to detach");
+
+    let mut reader = stream.try_clone()?;
+    std::thread::spawn(move || {
+        // the `(qemu)` prompt has no newline, flush every read
+        let mut buf = [0u8; 4096];
+        let mut stdout = io::stdout();
+        while let Ok(n @ 1..) = reader.read(&mut buf) {
+            if stdout
+                .write_all(&buf[..n])
+                .and_then(|_| stdout.flush())
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    let mut writer = stream;
+    for line in io::stdin().lines() {
+        writeln!(writer, "{}", line?)?;
+    }
+    Ok(())
+}
@@ -1,15 +1,23 @@
-use std::str::FromStr;
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Context, Result, anyhow};
 use serial_test::serial;
 use toolup::{
     config::ToolchainConfigResult,
+    error::Failure,
+    hooks::Hook,
     packages::{
+        SourceOverride,
         binutils::{Binutils, BinutilsVersion},
+        expand,
         gcc::{GCC, GCCVersion},
+        gdb::{Gdb, GdbVersion},
         glibc::GlibcVersion,
+        llvm::{Llvm, LlvmVersion},
+        newlib::NewlibVersion,
     },
     profile::{Libc, Target, Toolchain},
+    stage::{RetryPolicy, Stage},
 };
 
 fn test_config_dir() -> tempfile::TempDir {
@@ -108,3 +116,425 @@ fn test_local_takes_precedence_over_global() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_workspace_settings_are_inherited() -> Result<()> {
+    let test_config = test_config_dir();
+    let global_config = test_config.path().join("toolup.toml");
+
+    let working_dir = tempfile::TempDir::new().expect("failed to create temp dir");
+    let local_config = working_dir.path().join("toolup.toml");
+    std::env::set_current_dir(working_dir.path())?;
+
+    let global = toml::toml! {
+        [workspace]
+        jobs = 2
+        cflags = ["-O1"]
+        default_flags = ["-Os"]
+    };
+    std::fs::write(&global_config, global.to_string())?;
+
+    let local = toml::toml! {
+        [workspace]
+        cflags = ["-O2"]
+
+        [toolchain.aarch64-unknown-linux-gnu]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "2.42"
+        jobs = 4
+        cflags = ["-march=armv8.2-a"]
+        default_flags = ["-mcpu=cortex-a76"]
+
+        [toolchain.x86_64-unknown-linux-musl]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "1.2.5"
+    };
+    std::fs::write(&local_config, local.to_string())?;
+
+    let settings = toolup::config::resolve_target_settings("aarch64-unknown-linux-gnu")?;
+    assert_eq!(settings.jobs, Some(4));
+    assert_eq!(settings.cflags, vec!["-O2", "-march=armv8.2-a"]);
+    assert_eq!(settings.default_flags, vec!["-Os", "-mcpu=cortex-a76"]);
+
+    let settings = toolup::config::resolve_target_settings("x86_64-unknown-linux-musl")?;
+    assert_eq!(settings.jobs, Some(2));
+    assert_eq!(settings.cflags, vec!["-O2"]);
+    assert_eq!(settings.default_flags, vec!["-Os"]);
+
+    let config = toolup::config::load_local_config()?.expect("local config exists");
+    let targets: Vec<String> = config
+        .toolchains()?
+        .into_iter()
+        .map(|(toolchain, _)| toolchain.target.to_string())
+        .collect();
+    assert_eq!(
+        targets,
+        vec!["aarch64-unknown-linux-gnu", "x86_64-unknown-linux-musl"]
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_source_overrides() -> Result<()> {
+    let test_config = test_config_dir();
+    let global_config = test_config.path().join("toolup.toml");
+
+    let working_dir = tempfile::TempDir::new().expect("failed to create temp dir");
+    let local_config = working_dir.path().join("toolup.toml");
+    std::env::set_current_dir(working_dir.path())?;
+
+    let global = toml::toml! {
+        [sources]
+        gcc = "https://artifactory.example.com/gcc-{version}.tar.xz"
+        linux = "https://mirror.example.com/v{major}.x/linux-{version}.tar.xz"
+    };
+    std::fs::write(&global_config, global.to_string())?;
+
+    let local = toml::toml! {
+        [sources]
+        linux = "https://kernel.example.com/linux-{major}.{minor}.tar.xz"
+        busybox = { url = "https://busybox.net/downloads/busybox-{version}.tar.bz2", dirname = "busybox-{version}" }
+    };
+    std::fs::write(&local_config, local.to_string())?;
+
+    let sources = toolup::config::resolve_sources()?;
+    assert_eq!(
+        sources["gcc"],
+        SourceOverride::Url("https://artifactory.example.com/gcc-{version}.tar.xz".to_string())
+    );
+    let SourceOverride::Url(linux) = &sources["linux"] else {
+        panic!("expected a url override for linux");
+    };
+    assert_eq!(
+        expand(linux, "6.17.2"),
+        "https://kernel.example.com/linux-6.17.tar.xz"
+    );
+    assert_eq!(
+        sources["busybox"],
+        SourceOverride::Full {
+            url: "https://busybox.net/downloads/busybox-{version}.tar.bz2".to_string(),
+            dirname: "busybox-{version}".to_string(),
+        }
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_qemu_settings() -> Result<()> {
+    let test_config = test_config_dir();
+    let global_config = test_config.path().join("toolup.toml");
+
+    let working_dir = tempfile::TempDir::new().expect("failed to create temp dir");
+    std::env::set_current_dir(working_dir.path())?;
+
+    let global = toml::toml! {
+        [workspace]
+        qemu_args = ["-device", "virtio-rng-pci"]
+
+        [toolchain.aarch64-unknown-linux-gnu]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "2.42"
+        qemu_binary = "/opt/qemu/bin/qemu-system-aarch64"
+        qemu_args = ["-smp", "4"]
+    };
+    std::fs::write(&global_config, global.to_string())?;
+
+    let settings = toolup::config::resolve_target_settings("aarch64-unknown-linux-gnu")?;
+    assert_eq!(
+        settings.qemu_binary.as_deref(),
+        Some(std::path::Path::new("/opt/qemu/bin/qemu-system-aarch64"))
+    );
+    assert_eq!(
+        settings.qemu_args,
+        vec!["-device", "virtio-rng-pci", "-smp", "4"]
+    );
+
+    let settings = toolup::config::resolve_target_settings("riscv64-elf")?;
+    assert_eq!(settings.qemu_binary, None);
+    assert_eq!(settings.qemu_args, vec!["-device", "virtio-rng-pci"]);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_hooks() -> Result<()> {
+    let test_config = test_config_dir();
+    let global_config = test_config.path().join("toolup.toml");
+
+    let working_dir = tempfile::TempDir::new().expect("failed to create temp dir");
+    let local_config = working_dir.path().join("toolup.toml");
+    std::env::set_current_dir(working_dir.path())?;
+
+    let global = toml::toml! {
+        [workspace.hooks]
+        post_sysroot = ["/opt/hooks/certificates.sh"]
+        post_install = ["/opt/hooks/notify.sh"]
+    };
+    std::fs::write(&global_config, global.to_string())?;
+
+    let local = toml::toml! {
+        [workspace.hooks]
+        pre_rootfs_pack = ["./add-test-data.sh"]
+        post_install = ["./strip.sh", "./notify.sh"]
+    };
+    std::fs::write(&local_config, local.to_string())?;
+
+    // a local hook replaces the global programs of the same stage
+    let hooks = toolup::config::resolve_workspace()?.hooks;
+    assert_eq!(
+        hooks,
+        BTreeMap::from([
+            (
+                Hook::PostInstall,
+                vec!["./strip.sh".into(), "./notify.sh".into()]
+            ),
+            (Hook::PostSysroot, vec!["/opt/hooks/certificates.sh".into()]),
+            (Hook::PreRootfsPack, vec!["./add-test-data.sh".into()]),
+        ])
+    );
+
+    std::fs::write(
+        &local_config,
+        "[workspace.hooks]\npre_boot = [\"./x.sh\"]\n",
+    )?;
+    assert!(toolup::config::resolve_workspace().is_err());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_gdb() -> Result<()> {
+    let _test_config = test_config_dir();
+    let working_dir = tempfile::TempDir::new().expect("failed to create temp dir");
+    std::env::set_current_dir(working_dir.path())?;
+
+    let local = toml::toml! {
+        [toolchain.aarch64-unknown-linux-gnu]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "2.42"
+        gdb = "15.2"
+
+        [toolchain.riscv64-elf]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "2.42"
+        gdb_python = true
+
+        [toolchain.x86_64-unknown-linux-gnu]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "2.42"
+    };
+    std::fs::write(working_dir.path().join("toolup.toml"), local.to_string())?;
+
+    let config = toolup::config::load_local_config()?.context("toolup.toml was written")?;
+    let gdbs: Vec<Option<Gdb>> = config
+        .toolchains()?
+        .into_iter()
+        .map(|(toolchain, _)| toolchain.gdb)
+        .collect();
+    assert_eq!(
+        gdbs,
+        vec![
+            Some(Gdb {
+                version: GdbVersion(15, 2),
+                python: false
+            }),
+            Some(Gdb {
+                version: GdbVersion::default(),
+                python: true
+            }),
+            None,
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_llvm() -> Result<()> {
+    let _test_config = test_config_dir();
+    let working_dir = tempfile::TempDir::new().expect("failed to create temp dir");
+    std::env::set_current_dir(working_dir.path())?;
+
+    let local = toml::toml! {
+        [toolchain.aarch64-unknown-linux-gnu]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "2.42"
+        llvm = "19.1.7"
+
+        [toolchain.x86_64-unknown-linux-gnu]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "2.42"
+    };
+    std::fs::write(working_dir.path().join("toolup.toml"), local.to_string())?;
+
+    let config = toolup::config::load_local_config()?.context("toolup.toml was written")?;
+    let llvms: Vec<Option<Llvm>> = config
+        .toolchains()?
+        .into_iter()
+        .map(|(toolchain, _)| toolchain.llvm)
+        .collect();
+    assert_eq!(
+        llvms,
+        vec![
+            Some(Llvm {
+                version: LlvmVersion(19, 1, 7)
+            }),
+            None,
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_newlib() -> Result<()> {
+    let _test_config = test_config_dir();
+    let working_dir = tempfile::TempDir::new().expect("failed to create temp dir");
+    std::env::set_current_dir(working_dir.path())?;
+
+    let local = toml::toml! {
+        [toolchain.arm-none-eabi]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "newlib-4.4.0.20231231"
+
+        [toolchain.riscv64-elf]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "newlib"
+    };
+    std::fs::write(working_dir.path().join("toolup.toml"), local.to_string())?;
+
+    let config = toolup::config::load_local_config()?.context("toolup.toml was written")?;
+    let libcs: Vec<Libc> = config
+        .toolchains()?
+        .into_iter()
+        .map(|(toolchain, _)| toolchain.libc)
+        .collect();
+    assert_eq!(
+        libcs,
+        vec![
+            Libc::Newlib(NewlibVersion(4, 4, 0, Some(20231231))),
+            Libc::Newlib(NewlibVersion::default()),
+        ]
+    );
+    // only freestanding targets use newlib
+    let hosted = toolup::parse_toolchain(
+        "aarch64-unknown-linux-gnu",
+        "15.2.0",
+        "newlib",
+        "2.45",
+        None,
+    );
+    assert!(hosted.is_err());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_toolchain_variant() -> Result<()> {
+    let test_config = test_config_dir();
+    let working_dir = tempfile::TempDir::new().expect("failed to create temp dir");
+    std::env::set_current_dir(working_dir.path())?;
+
+    let local = r#"
+        [toolchain.aarch64-unknown-linux-gnu]
+        gcc = "15.2.0"
+        binutils = "2.45"
+        libc = "2.42"
+
+        [toolchain."aarch64-unknown-linux-gnu@gcc13"]
+        gcc = "13.4.0"
+        binutils = "2.45"
+        libc = "2.42"
+        default_flags = ["-O1"]
+    "#;
+    std::fs::write(working_dir.path().join("toolup.toml"), local)?;
+
+    let toolchain: Toolchain =
+        toolup::config::resolve_target_toolchain("aarch64-unknown-linux-gnu@gcc13")?.into();
+    assert_eq!(toolchain.variant.as_deref(), Some("gcc13"));
+    assert_eq!(toolchain.gcc.version, GCCVersion::from_str("13.4.0")?);
+    let settings = toolup::config::resolve_target_settings("aarch64-unknown-linux-gnu@gcc13")?;
+    assert_eq!(settings.default_flags, vec!["-O1"]);
+    let toolchain: Toolchain =
+        toolup::config::resolve_target_toolchain("aarch64-unknown-linux-gnu")?.into();
+    assert_eq!(toolchain.variant, None);
+
+    // an undeclared variant is added to the global configuration under its name
+    std::fs::remove_file(working_dir.path().join("toolup.toml"))?;
+    let result = toolup::config::resolve_target_toolchain("riscv64-unknown-linux-gnu@next")?;
+    assert!(matches!(result, ToolchainConfigResult::GlobalCreated(_)));
+    let global = std::fs::read_to_string(test_config.path().join("toolup.toml"))?;
+    assert!(
+        global.contains("\"riscv64-unknown-linux-gnu@next\""),
+        "{global}"
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_retry_policy() -> Result<()> {
+    let test_config = test_config_dir();
+    let global_config = test_config.path().join("toolup.toml");
+
+    let working_dir = tempfile::TempDir::new().expect("failed to create temp dir");
+    std::env::set_current_dir(working_dir.path())?;
+
+    let global = toml::toml! {
+        [workspace.retry]
+        retries = 2
+        backoff_secs = 10
+        stages = ["binutils", "gcc-final"]
+    };
+    std::fs::write(&global_config, global.to_string())?;
+
+    let policy = toolup::config::resolve_workspace()?
+        .retry
+        .context("the global retry policy")?;
+    assert_eq!(
+        policy,
+        RetryPolicy {
+            retries: 2,
+            backoff_secs: 10,
+            stages: vec![Stage::Binutils, Stage::GccFinal],
+        }
+    );
+
+    let build = Err::<(), _>(anyhow!("make exited with 2"))
+        .context(Failure::Build)
+        .unwrap_err();
+    assert_eq!(
+        policy.delay(Stage::Binutils, 1, &build),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(
+        policy.delay(Stage::Binutils, 2, &build),
+        Some(Duration::from_secs(20))
+    );
+    assert_eq!(policy.delay(Stage::Binutils, 3, &build), None);
+    // only the listed stages are retried
+    assert_eq!(policy.delay(Stage::Libc, 1, &build), None);
+    // configuration errors don't go away by retrying
+    let usage = Err::<(), _>(anyhow!("bad profile"))
+        .context(Failure::Usage)
+        .unwrap_err();
+    assert_eq!(policy.delay(Stage::Binutils, 1, &usage), None);
+    assert_eq!(
+        RetryPolicy::default().delay(Stage::Binutils, 1, &build),
+        None
+    );
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use serial_test::serial;
+use toolup::{
+    Profile, Target, Toolchain, ToolchainPaths,
+    pack::PackMetadata,
+    prebuilt::{Entry, Index, host},
+    profile::parse_name,
+};
+
+#[test]
+#[serial]
+fn test_toolchain_paths_layout() -> Result<()> {
+    let home = tempfile::TempDir::new()?;
+    unsafe {
+        std::env::set_var("HOME", home.path());
+    };
+
+    let target = Target::from_str("aarch64-unknown-linux-musl")?;
+    let toolchain = Toolchain::target_default(&target);
+    let id = "aarch64-unknown-linux-musl-gcc-15.2.0-bin-2.45-musl-1.2.5";
+    assert_eq!(toolchain.id(), id);
+
+    let prefix = home.path().join(".toolup/toolchains").join(id);
+    let expected = ToolchainPaths {
+        id: id.into(),
+        bin_dir: prefix.join("bin"),
+        gcc: prefix.join("bin/aarch64-unknown-linux-musl-gcc"),
+        prefix,
+        sysroot: home
+            .path()
+            .join(".toolup/sysroot")
+            .join(format!("sysroot-{id}")),
+    };
+    let paths = toolchain.paths()?;
+    assert_eq!(paths, expected);
+    assert_eq!(paths.bin_dir, toolchain.bin_dir()?);
+    assert_eq!(paths.gcc, toolchain.gcc_bin()?);
+    assert_eq!(paths.sysroot, toolchain.sysroot()?);
+
+    let json = serde_json::to_string(&paths)?;
+    assert_eq!(serde_json::from_str::<ToolchainPaths>(&json)?, paths);
+
+    let mut nano = Toolchain::target_default(&Target::from_str("riscv64-elf")?);
+    nano.profile = Profile::Nano;
+    assert!(nano.paths()?.id.ends_with("-nano"));
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_toolchain_variants() -> Result<()> {
+    let home = tempfile::TempDir::new()?;
+    unsafe {
+        std::env::set_var("HOME", home.path());
+    };
+
+    let (target, variant) = parse_name("aarch64-linux-gnu@hardened")?;
+    assert_eq!(target, Target::from_str("aarch64-unknown-linux-gnu")?);
+    assert_eq!(variant.as_deref(), Some("hardened"));
+    assert!(parse_name("aarch64-unknown-linux-gnu@").is_err());
+    assert!(parse_name("aarch64-unknown-linux-gnu@a/b").is_err());
+
+    let toolchain = Toolchain::target_default(&target);
+    let mut hardened = toolchain.clone();
+    hardened.variant = variant;
+    assert_eq!(toolchain.name(), "aarch64-unknown-linux-gnu");
+    assert_eq!(hardened.name(), "aarch64-unknown-linux-gnu@hardened");
+    assert_eq!(hardened.id(), format!("{}@hardened", toolchain.id()));
+    assert_ne!(hardened.dir()?, toolchain.dir()?);
+    assert_ne!(hardened.sysroot()?, toolchain.sysroot()?);
+    Ok(())
+}
+
+#[test]
+fn test_prebuilt_index_find() -> Result<()> {
+    let toolchain = Toolchain::target_default(&Target::from_str("aarch64-unknown-linux-musl")?);
+    let entry = |id: String, host: String| Entry {
+        url: format!("https://toolchains.example.com/{id}-{host}.tar.gz"),
+        pack: PackMetadata {
+            id,
+            host,
+            ..PackMetadata::new(&toolchain)
+        },
+        size: 0,
+        blake3: "0".repeat(64),
+    };
+    let other_host = entry(toolchain.id(), "riscv64-linux".into());
+    let other_gcc = entry(toolchain.id().replace("gcc-15.2.0", "gcc-14.3.0"), host());
+    let usable = entry(toolchain.id(), host());
+
+    let index = Index {
+        toolchains: vec![other_host.clone(), other_gcc.clone()],
+    };
+    assert_eq!(index.find(&toolchain), None);
+
+    let index = Index {
+        toolchains: vec![other_host, other_gcc, usable.clone()],
+    };
+    assert_eq!(index.find(&toolchain), Some(&usable));
+    let json = serde_json::to_string(&index)?;
+    assert_eq!(serde_json::from_str::<Index>(&json)?, index);
+    Ok(())
+}
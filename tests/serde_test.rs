@@ -0,0 +1,111 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::Result;
+use toolup::{
+    BinutilsVersion, GCCVersion, GlibcVersion, KernelVersion, Libc, MuslVersion, Profile, Target,
+    Toolchain,
+};
+
+#[test]
+fn test_target_roundtrip() -> Result<()> {
+    for target in [
+        "aarch64-unknown-linux-gnu",
+        "x86_64-unknown-linux-gnux32",
+        "riscv64-elf",
+        "armv7-unknown-none-eabihf",
+        "bpf-unknown-none",
+        "xtensa-esp32-elf",
+        "x86_64-w64-mingw32",
+        "riscv64-unknown-freebsd15",
+        "aarch64-linux-android",
+    ] {
+        let json = serde_json::to_string(&Target::from_str(target)?)?;
+        assert_eq!(json, format!("\"{target}\""));
+        assert_eq!(
+            serde_json::from_str::<Target>(&json)?,
+            Target::from_str(target)?
+        );
+    }
+    // aliases are accepted, and written back in toolup's format
+    let target: Target = serde_json::from_str("\"arm64-linux-gnu\"")?;
+    assert_eq!(target.to_string(), "aarch64-unknown-linux-gnu");
+    assert!(serde_json::from_str::<Target>("\"aarch64-unknown-linux\"").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_versions_roundtrip() -> Result<()> {
+    assert_eq!(serde_json::to_string(&GCCVersion(15, 2, 0))?, "\"15.2.0\"");
+    assert_eq!(
+        serde_json::from_str::<GCCVersion>("\"15.2.0\"")?,
+        GCCVersion(15, 2, 0)
+    );
+    assert_eq!(
+        serde_json::to_string(&BinutilsVersion(2, 45, 0))?,
+        "\"2.45\""
+    );
+    assert_eq!(
+        serde_json::from_str::<BinutilsVersion>("\"2.45\"")?,
+        BinutilsVersion(2, 45, 0)
+    );
+    assert_eq!(
+        serde_json::to_string(&KernelVersion::new(6, 6, 58))?,
+        "\"6.6.58\""
+    );
+    assert_eq!(
+        serde_json::from_str::<KernelVersion>("\"6.17\"")?,
+        KernelVersion::new(6, 17, 0)
+    );
+    assert_eq!(
+        serde_json::from_str::<GlibcVersion>("\"2.16.0\"")?,
+        GlibcVersion(2, 16, 0)
+    );
+    assert!(serde_json::from_str::<GCCVersion>("\"15\"").is_err());
+
+    for libc in [
+        "glibc-2.42",
+        "musl-1.2.5",
+        "mingw-w64-13.0.0",
+        "freebsd-14.3",
+        "bionic-35",
+        "newlib-4.5.0.20241231",
+        "newlib-4.1.0",
+    ] {
+        let parsed: Libc = serde_json::from_str(&format!("\"{libc}\""))?;
+        assert_eq!(parsed.to_string(), libc);
+    }
+    assert_eq!(
+        serde_json::from_str::<Libc>("\"musl-1.2.5\"")?,
+        Libc::Musl(MuslVersion::from_str("1.2.5")?)
+    );
+    assert!(serde_json::from_str::<Libc>("\"uclibc-1.0.50\"").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_toolchain_roundtrip() -> Result<()> {
+    let mut toolchain = Toolchain::target_default(&Target::from_str("riscv64-elf")?);
+    toolchain.profile = Profile::Nano;
+    toolchain.kernel = Some(KernelVersion::new(6, 6, 0));
+    toolchain.prefix = Some(PathBuf::from("/opt/cross"));
+
+    let json = serde_json::to_string(&toolchain)?;
+    assert_eq!(serde_json::from_str::<Toolchain>(&json)?, toolchain);
+    let toml = toml::to_string(&toolchain)?;
+    assert_eq!(toml::from_str::<Toolchain>(&toml)?, toolchain);
+
+    // the optional fields can be left out
+    let toolchain: Toolchain = toml::from_str(
+        r#"
+        target = "aarch64-unknown-linux-musl"
+        binutils = { version = "2.45" }
+        gcc = { version = "15.2.0" }
+        libc = "musl-1.2.5"
+        "#,
+    )?;
+    assert_eq!(
+        toolchain,
+        Toolchain::target_default(&Target::from_str("aarch64-unknown-linux-musl")?)
+    );
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use anyhow::Result;
+use serial_test::serial;
+use toolup::{
+    download::{archive_filename, archives_dir},
+    vendor::{import_bundle, write_bundle},
+};
+
+#[test]
+#[serial]
+fn test_bundle_roundtrip() -> Result<()> {
+    let url = "https://ftp.gnu.org/gnu/binutils/binutils-2.45.tar.xz".to_string();
+    let bundle_dir = tempfile::TempDir::new()?;
+    let bundle = bundle_dir.path().join("sources.tar");
+
+    // a cached archive is bundled without downloading it
+    let home = tempfile::TempDir::new()?;
+    unsafe {
+        std::env::set_var("HOME", home.path());
+    };
+    std::fs::write(archives_dir()?.join(archive_filename(&url)?), b"binutils")?;
+    let manifest = write_bundle(std::slice::from_ref(&url), &bundle)?;
+    assert_eq!(manifest.archives.len(), 1);
+    assert_eq!(manifest.archives[0].url, url);
+
+    let offline_home = tempfile::TempDir::new()?;
+    unsafe {
+        std::env::set_var("HOME", offline_home.path());
+    };
+    assert_eq!(import_bundle(&bundle)?, manifest);
+    assert_eq!(
+        std::fs::read(archives_dir()?.join(archive_filename(&url)?))?,
+        b"binutils"
+    );
+
+    Ok(())
+}